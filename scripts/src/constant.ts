@@ -1,4 +1,6 @@
 export const PROGRAM_ID = "9Ef7uzrdsFCjb3jCqR9YERTKAKnmpxj8QMRGKED1Csq5";
+// First byte of every instruction, ahead of the instruction tag
+export const INSTRUCTION_VERSION = 0x81;
 export const TOKEN_DECIMALS = 9;
 export const FEE_BASIS_POINTS = 800;
 export const MAX_FEE = 1000000;
@@ -22,7 +22,8 @@ import {
 import {
     PROGRAM_ID, MAX_FEE, FEE_BASIS_POINTS,
     TOKEN_DECIMALS, DEVNET_CONNECTION_URL,
-    MAINNET_CONNECTION_URL, LOCALNET_CONNECTION_URL
+    MAINNET_CONNECTION_URL, LOCALNET_CONNECTION_URL,
+    INSTRUCTION_VERSION
 } from "./constant";
 
 import {getCluster, getKeypair, getPublicKey, writePublicKey, writeSecretKey} from "./utils";
@@ -150,6 +151,7 @@ const setup = async (
         console.log(MAX_FEE)
         const instructionData = Buffer.from(
             Uint8Array.of(
+                INSTRUCTION_VERSION,
                 0,
                 ...new BN(minimumStakeAmount * 10**TOKEN_DECIMALS).toArray("le", 8),
                 ...new BN(minimumLockDuration).toArray("le", 8),
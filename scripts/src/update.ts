@@ -6,7 +6,7 @@ import {
 } from "@solana/web3.js";
 import BN from "bn.js";
 import {getCluster, getKeypair} from "./utils";
-import { PROGRAM_ID } from "./constant";
+import { INSTRUCTION_VERSION, PROGRAM_ID } from "./constant";
 
 const updateAPY = async (
     network: string,
@@ -29,6 +29,7 @@ const updateAPY = async (
     const dataAccountPubKey = new PublicKey("994NbZhmVGDAvXHWW8VMA4kBgeHpKF8xebncag4KnRVE");
     const instructionData = Buffer.from(
         Uint8Array.of(
+            INSTRUCTION_VERSION,
             3,
             ...new BN(normalStakingApy * 10).toArray("le", 8),
             ...new BN(lockedStakingApy * 10).toArray("le", 8)
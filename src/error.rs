@@ -0,0 +1,105 @@
+use solana_program::program_error::ProgramError;
+use thiserror::Error;
+
+/// Errors returned by the staking program as `ProgramError::Custom(code)`
+///
+/// The discriminant of each variant is its error code, so new variants must only be appended
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum StakingError {
+    #[error("Account does not match the expected program derived address")]
+    PdaMismatch,
+    #[error("Token account mint does not match the stake token mint")]
+    MintMismatch,
+    #[error("Only TOKEN 2022 program, mints and accounts are supported")]
+    UnsupportedTokenProgram,
+    #[error("Invalid user token account")]
+    InvalidUserTokenAccount,
+    #[error("Invalid contract token account")]
+    InvalidContractTokenAccount,
+    #[error("Invalid destination token account")]
+    InvalidDestinationTokenAccount,
+    #[error("Insufficient user token balance for staking")]
+    InsufficientStakeBalance,
+    #[error("Minimum stake amount must be greater than zero")]
+    InvalidMinimumStakeAmount,
+    #[error("Lock duration is less than the minimum lock duration")]
+    LockDurationTooShort,
+    #[error("Lock duration is greater than the maximum lock duration")]
+    LockDurationTooLong,
+    #[error("Maximum lock duration is less than the minimum lock duration")]
+    InvalidLockDurationConfig,
+    #[error("Staking APY must be greater than zero")]
+    InvalidApy,
+    #[error("Signer is not the admin of the contract")]
+    Unauthorized,
+    #[error("Stake type does not match the existing position")]
+    StakeTypeMismatch,
+    #[error("Normal staking positions cannot be unstaked before 24 hrs")]
+    LockNotExpired,
+    #[error("Early withdrawal charge exceeds the staked amount")]
+    PenaltyExceedsPrincipal,
+    #[error("Payout exceeds the contract token balance")]
+    InsufficientContractBalance,
+    #[error("User data account still holds a position")]
+    PositionNotEmpty,
+    #[error("Wrapping and unwrapping SOL is only supported for native SOL pools")]
+    NativeMintRequired,
+    #[error("Pool is paused")]
+    PoolPaused,
+    #[error("Pool is not paused")]
+    PoolNotPaused,
+    #[error("Partial unstake would leave less than the minimum stake amount")]
+    UnstakeLeavesDust,
+    #[error("Deposit fee cannot exceed 100%")]
+    InvalidDepositFee,
+    #[error("Staker does not hold an NFT of the gate collection")]
+    GateNftRequired,
+    #[error("Boost is zero or exceeds the maximum boost")]
+    InvalidBoost,
+    #[error("Rewards were claimed too recently")]
+    ClaimCooldownActive,
+    #[error("Decimals do not match the mint decimals")]
+    DecimalsMismatch,
+    #[error("Re-staked too soon after the last stake")]
+    RestakeTooSoon,
+    #[error("Unsupported instruction version")]
+    UnsupportedInstructionVersion,
+    #[error("Penalty burn cannot exceed 100%")]
+    InvalidPenaltyBurn,
+    #[error("User data account has not been created")]
+    UserDataNotCreated,
+    #[error("Pool already has the maximum number of stakers")]
+    MaxStakersReached,
+    #[error("Compounded too soon after the last compound")]
+    CompoundCooldownActive,
+    #[error("Lock of the position has not ended")]
+    LockNotEnded,
+    #[error("User data accounts do not match the open positions of the pool")]
+    PositionsMismatch,
+    #[error("Contract token account holds less than the required initial rewards")]
+    InsufficientInitialRewards,
+    #[error("Claimed share of the interest cannot exceed 100%")]
+    InvalidClaimSplit,
+    #[error("Amount too small to unstake after the penalty and the transfer fee")]
+    UnstakeAmountTooSmall,
+    #[error("Promotional boost window is invalid")]
+    InvalidPromoBoost,
+    #[error("Promotional boost window is running")]
+    PromoBoostActive,
+    #[error("User data account holds a position of another pool")]
+    PositionPoolMismatch,
+    #[error("Token account is frozen")]
+    TokenAccountFrozen,
+    #[error("Same account passed for more than one account role")]
+    DuplicateAccount,
+    #[error("Staking APY is above the maximum APY of the pool")]
+    ApyAboveMaximum,
+    #[error("User index already holds the maximum number of positions")]
+    UserIndexFull,
+}
+
+impl From<StakingError> for ProgramError {
+    fn from(e: StakingError) -> Self {
+        ProgramError::Custom(e as u32)
+    }
+}
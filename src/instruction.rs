@@ -1,8 +1,16 @@
 use arrayref::{array_ref, array_refs};
 use solana_program::program_error::ProgramError;
-use crate::state::StakeType;
+use solana_program::pubkey::Pubkey;
+use solana_program::msg;
+use crate::error::StakingError;
+use crate::state::{InterestRounding, StakeType};
 
+/// First byte of every instruction, ahead of the instruction tag. The high bit keeps it apart
+/// from the unversioned single byte tags of earlier clients, which are rejected instead of misread
+pub const INSTRUCTION_VERSION: u8 = 0x81;
 
+// Unpacked once per call, boxing the arguments of Init would gain nothing
+#[allow(clippy::large_enum_variant)]
 pub enum Instruction {
     /// Initialize the staking contract by setting necessary states needed for the contract
     ///
@@ -15,78 +23,617 @@ pub enum Instruction {
     /// 5. `[]` Token program address
     /// 6. `[]` Rent info
     /// 7. `[]` system program
+    /// 8. `[]` The reward token mint address [Optional, interest is paid in the stake token when omitted]
+    /// 9. `[writable]` The token account for storing reward tokens [Required with a reward mint]
     Init {
         /// Minimum amount of tokens to be staked
         minimum_stake_amount: u64,
         /// Minimum amount of time interval(in seconds) for locking
         minimum_lock_duration: u64,
-        /// APY For normal staking (decimals = 1)
+        /// APY For normal staking (decimals = 1), must be nonzero
         normal_staking_apy: u64,
-        /// APY For locked staking (decimals = 1)
+        /// APY For locked staking (decimals = 1), must be nonzero
         locked_staking_apy: u64,
-        /// Penalty for early withdrawal in locked staking (decimals = 1)
+        /// Penalty for early withdrawal in locked staking (decimals = 1), at most 1000
         early_withdrawal_fee: u64,
-        /// percentage tax for TOKEN_2022 (decimals = 100)
+        /// percentage tax for TOKEN_2022 (decimals = 100), recorded only, transfers use the live mint fee
         fee_basis_points: u64,
-        /// max fee for TOKEN_2022 (decimals = mint decimals)
-        max_fee: u64
+        /// max fee for TOKEN_2022 (decimals = mint decimals), recorded only, transfers use the live mint fee
+        max_fee: u64,
+        /// Maximum amount of time interval(in seconds) for locking [Optional, 0 = unlimited]
+        max_lock_duration: u64,
+        /// Maximum interest paid out per position [Optional, 0 = uncapped]
+        max_reward_per_position: u64,
+        /// Seconds before the end of a lock within which unstaking is treated as on time [Optional, 0 = no grace]
+        lock_grace_period: u64,
+        /// Fee taken from each stake deposit (decimals = 100) [Optional, 0 = no fee]
+        deposit_fee_bps: u64,
+        /// Token group stakers must hold an NFT of [Optional, default pubkey = ungated]
+        gate_collection: Pubkey,
+        /// Maximum boost the admin can set on a position (decimals = 10000) [Optional, 0 = no boost above 1x]
+        max_boost_bps: u64,
+        /// Minimum seconds between two reward claims of a position [Optional, 0 = no cooldown]
+        claim_cooldown: u64,
+        /// Seconds per interest accrual period [Optional, 0 or 1 = per second]
+        accrual_period: u64,
+        /// Let claims pay whatever interest the vault can cover, forfeiting the rest [Optional, false = claim nothing until funded]
+        partial_reward_ok: bool,
+        /// Append stakes and unstakes to the pool history log [Optional, false = no history]
+        history_enabled: bool,
+        /// Minimum seconds between two stakes into the same position [Optional, 0 = no limit]
+        min_restake_interval: u64,
+        /// Share of early withdrawal charges burned, decimals = 10000 [Optional, 0 = nothing burned]
+        penalty_burn_bps: u64,
+        /// Signer allowed to stake and unstake for program controlled owners [Optional, default pubkey = none]
+        delegate_authority: Pubkey,
+        /// Rounding of interest, 0 = FLOOR and 1 = NEAREST [Optional, FLOOR]
+        interest_rounding: InterestRounding,
+        /// Maximum number of open positions [Optional, 0 = unlimited]
+        max_stakers: u64,
+        /// Minimum seconds between two compounds of a position [Optional, 0 = no cooldown]
+        compound_cooldown: u64,
+        /// Interest streamed per second across all positions in proportion to their stake [Optional, 0 = accrue at the APY]
+        reward_per_second: u64,
+        /// Minimum balance the contract token account must hold before init [Optional, 0 = no check]
+        min_initial_rewards: u64,
+        /// Highest APY UpdateAPY can set (decimals = 1), fixed after init [Optional, 0 = uncapped]
+        max_apy: u64,
+        /// Record total_earned in the earned checkpoint log on each unstake and claim [Optional, false = no checkpoints]
+        earned_checkpoints_enabled: bool
     },
 
     /// Stake tokens
     ///
     /// Accounts Expected
     ///
-    /// 1. `[Signer]` The user signer
-    /// 2. `[writable]` The token account of the user
-    /// 3. `[writable]` The user data account for the contract
+    /// 1. `[Signer]` The user signer, a non signing program controlled owner when the delegate authority signs
+    /// 2. `[writable]` The token account of the user, approving the delegate authority when it signs, not frozen
+    /// 3. `[writable]` The user data account for the contract, created beforehand with `CreateUserData`
     /// 4. `[writable]` The token account for the contract
     /// 5. `[writable]` The data account for the contract
     /// 6. `[]` Mint info
     /// 7. `[]` TOKEN 2022 PROGRAM ID
     /// 8. `[]` System program info
+    /// 9. `[writable]` The user index account of the user, created on the first stake
+    /// 10. `[]` Token account of the user holding the gate NFT [Required for gated pools]
+    /// 11. `[]` Mint of the gate NFT, a member of the gate collection [Required for gated pools]
+    /// 12. `[writable]` The history log account of the contract [Required for pools keeping a history, follows the applicable accounts above]
+    /// 13. `[Signer, writable]` The delegate authority of the pool, paying in place of the user, positions it opens stay bound to it [Required when the user does not sign, follows the applicable accounts above]
+    /// 14. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
     Stake {
         stake_type: StakeType,
         amount: u64,
         decimals: u64,
-        lock_duration: u64
+        lock_duration: u64,
+        /// Wrap `amount` lamports from the user into the user's native SOL token account before staking [Optional]
+        wrap_native: bool
     },
 
-    /// Unstake tokens
+    /// Unstake tokens. LOCKED positions leaving before the end of the lock pay the early withdrawal fee
+    /// and forfeit the interest of the running lock, interest accrued before the last re-stake is still paid.
+    /// The `UnstakeReceipt` of the payout is written as return data. Unstaking everything closes the
+    /// user data account and returns its rent to the user
     ///
     /// Accounts Expected
     ///
-    /// 1. `[Signer]` The user signer
-    /// 2. `[writable]` The token account of the user
-    /// 3. `[writable]` The user data account for the contract
+    /// 1. `[Signer, writable]` The user signer, a non signing program controlled owner when the delegate authority signs.
+    ///    Must be writable when the unstake closes the position, it receives the rent
+    /// 2. `[writable]` The token account of the user, its associated token account is created when the account was closed
+    /// 3. `[writable]` The user data account for the contract, holding a position of this pool
     /// 4. `[writable]` The token account for the contract
     /// 5. `[writable]` The data account for the contract
-    /// 6. `[]` Token mint
+    /// 6. `[]` Token mint [writable when the pool burns part of the early withdrawal charge]
     /// 7. `[]` TOKEN 2022 PROGRAM ID
+    /// 8. `[writable]` The user index account of the user, the closed position leaves it
+    /// 9. `[writable]` Destination token account [Optional, defaults to the user token account, required when any account below is passed, the user token account again keeps the default]
+    /// 10. `[writable]` The reward token account for the contract [Required for reward token pools]
+    /// 11. `[]` Reward mint info [Required for reward token pools]
+    /// 12. `[writable]` The reward token account of the user [Required for reward token pools]
+    /// 13. `[writable]` The history log account of the contract [Required for pools keeping a history, follows the applicable accounts above]
+    /// 14. `[]` System program info [Required for pools keeping a history]
+    /// 15. `[writable]` The earned checkpoint log account of the contract [Required for pools keeping earned checkpoints, follows the applicable accounts above]
+    /// 16. `[]` System program info [Required for pools keeping earned checkpoints]
+    /// 17. `[Signer, writable]` The delegate authority of the pool, only for positions it opened and only paying into accounts of the user [Required when the user does not sign, follows the applicable accounts above]
+    /// 18. `[]` System program info [Required when the user token account does not exist, follows the applicable accounts above]
+    /// 19. `[]` Associated token program [Required when the user token account does not exist]
+    /// 20. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
     UnStake {
-        decimals: u64
+        decimals: u64,
+        /// Close the native SOL destination token account after the payout, returning lamports [Optional]
+        unwrap_native: bool,
+        /// Principal to withdraw, the rest stays staked [Optional, 0 = full exit]
+        amount: u64
     },
 
-    /// Change normal and locked staking apy
+    /// Change normal and locked staking apy, either immediately or from `apy_effective_ts`
     ///
     /// Accounts Expected
     ///
-    /// 1. `[Signer]` The admin of the contract data account
+    /// 1. `[Signer]` The admin or the operator of the contract data account
     /// 2. `[writable]` The contract data account
+    /// 3. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
     UpdateAPY {
-        /// APY For normal staking (decimals = 1)
+        /// APY For normal staking (decimals = 1), must be nonzero
         normal_staking_apy: u64,
-        /// APY For locked staking (decimals = 1)
-        locked_staking_apy: u64
-    }
+        /// APY For locked staking (decimals = 1), must be nonzero
+        locked_staking_apy: u64,
+        /// Unix timestamp from which the new APY applies [Optional, 0 or past = immediately]
+        apy_effective_ts: u64
+    },
+
+    /// Close an empty user data account and return its rent to the owner
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer, writable]` The owner of the user data account
+    /// 2. `[writable]` The user data account for the contract
+    /// 3. `[]` The data account of the pool the user data account belongs to
+    CloseUserData,
+
+    /// Move the pool balance to a new contract token account and make it the stake token account
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` The admin of the contract data account
+    /// 2. `[writable]` The contract data account
+    /// 3. `[writable]` The current token account for the contract
+    /// 4. `[writable]` The new token account, owned by the contract data pda
+    /// 5. `[]` Mint info
+    /// 6. `[]` TOKEN 2022 PROGRAM ID
+    UpdateTokenAccount {
+        decimals: u64
+    },
+
+    /// Write a `PositionSnapshot` of a user position as return data without mutating state
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[]` The user data account for the contract
+    /// 2. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
+    SnapshotPosition {
+        /// Scale LOCKED positions by their remaining lock, up to 2x the staked amount [Optional]
+        weight_by_lock: bool
+    },
+
+    /// Pause or resume staking and unstaking, replacing a pause by the reward depletion circuit breaker
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` The admin of the contract data account
+    /// 2. `[writable]` The contract data account
+    SetPaused {
+        paused: bool
+    },
+
+    /// Withdraw tokens from the contract token account to any destination while the pool is paused
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` The admin of the contract data account
+    /// 2. `[writable]` The contract data account
+    /// 3. `[writable]` The token account for the contract
+    /// 4. `[writable]` The destination token account
+    /// 5. `[]` Mint info
+    /// 6. `[]` TOKEN 2022 PROGRAM ID
+    EmergencyWithdraw {
+        amount: u64,
+        decimals: u64
+    },
+
+    /// Write the `UserIndex` of the owner's open positions across pools as return data without mutating state
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[]` The owner of the positions
+    /// 2. `[]` The user index account of the owner, empty until the owner first stakes
+    GetUserIndex,
+
+    /// Write the `RewardPreview` of a hypothetical stake as return data without mutating state
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[]` The data account for the contract
+    /// 2. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
+    PreviewRewards {
+        stake_type: StakeType,
+        amount: u64,
+        /// Seconds the stake would be held for
+        duration: u64
+    },
+
+    /// Switch an open position to another stake type without moving tokens.
+    /// Interest accrued so far is settled into the position first
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` The user signer
+    /// 2. `[writable]` The user data account for the contract
+    /// 3. `[writable]` The data account for the contract
+    /// 4. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
+    ConvertStakeType {
+        new_type: StakeType,
+        /// Lock duration of the converted position, ignored when converting to NORMAL
+        lock_duration: u64
+    },
+
+    /// Set the interest multiplier of a position, applied to interest accrued since its last stake
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` The admin of the contract data account
+    /// 2. `[]` The contract data account
+    /// 3. `[writable]` The user data account for the contract
+    SetBoost {
+        /// Interest multiplier (decimals = 10000), at most the pool's max_boost_bps or 1x
+        boost_bps: u64
+    },
+
+    /// Pay out the interest of a position without unstaking, at most once per claim cooldown.
+    /// LOCKED positions can only claim once the lock is over
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` The user signer, writable when it pays for the creation of the earned checkpoint log
+    /// 2. `[writable]` The token account of the user receiving the interest, in the reward mint for reward token pools
+    /// 3. `[writable]` The user data account for the contract
+    /// 4. `[writable]` The token account for the contract paying the interest, the reward vault for reward token pools
+    /// 5. `[writable]` The data account for the contract
+    /// 6. `[]` Mint of the interest, the reward mint for reward token pools
+    /// 7. `[]` TOKEN 2022 PROGRAM ID
+    /// 8. `[writable]` The earned checkpoint log account of the contract [Required for pools keeping earned checkpoints]
+    /// 9. `[]` System program info [Required for pools keeping earned checkpoints]
+    /// 10. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
+    ClaimRewards,
+
+    /// Close a position on behalf of its owner, paying the full principal without any early
+    /// withdrawal fee back to the owner. Funds and rent can only go to the position's owner
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` The admin of the contract data account, writable when it pays for the creation of a log account
+    /// 2. `[writable]` The data account for the contract
+    /// 3. `[writable]` The user data account of the position
+    /// 4. `[writable]` The owner of the position, receiving the user data account rent
+    /// 5. `[writable]` The token account of the owner receiving the principal
+    /// 6. `[writable]` The token account for the contract
+    /// 7. `[]` Mint info
+    /// 8. `[]` TOKEN 2022 PROGRAM ID
+    /// 9. `[writable]` The user index account of the owner, the closed position leaves it
+    /// 10. `[writable]` Reward token account for the contract [Reward token pools with `include_interest` set only]
+    /// 11. `[]` Reward token mint [Reward token pools with `include_interest` set only]
+    /// 12. `[writable]` Reward token account of the owner receiving the interest [Reward token pools with `include_interest` set only]
+    /// 13. `[writable]` The history log account of the contract [Required for pools keeping a history, follows the applicable accounts above]
+    /// 14. `[]` System program info [Required for pools keeping a history]
+    /// 15. `[writable]` The earned checkpoint log account of the contract [Required for pools keeping earned checkpoints, follows the applicable accounts above]
+    /// 16. `[]` System program info [Required for pools keeping earned checkpoints]
+    /// 17. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
+    ForceUnstake {
+        decimals: u64,
+        /// Also pay the interest accrued by the position
+        include_interest: bool
+    },
+
+    /// Write a page of the pool history log as Borsh `Vec<HistoryRecord>` return data without mutating state
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[]` The data account for the contract
+    /// 2. `[]` The history log account of the contract
+    ReadHistory {
+        /// Index of the first record of the page
+        start: u64,
+        /// Records in the page, at most `HistoryLog::PAGE_SIZE`
+        count: u64
+    },
+
+    /// Add the interest of a position to its principal without a token transfer, restarting the
+    /// position and its claim cooldown. Only the interest the contract token account can cover next to the
+    /// staked principal is added, like `ClaimRewards`. Not available in reward token pools
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` The user signer, writable when it pays for the creation of the earned checkpoint log
+    /// 2. `[writable]` The user data account for the contract
+    /// 3. `[writable]` The data account for the contract
+    /// 4. `[]` The token account for the contract
+    /// 5. `[writable]` The earned checkpoint log account of the contract [Required for pools keeping earned checkpoints]
+    /// 6. `[]` System program info [Required for pools keeping earned checkpoints]
+    /// 7. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
+    ClaimAndStake,
+
+    /// Write the `PoolApr` of a stake type, the current APY net of the mint's transfer fees,
+    /// as return data without mutating state
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[]` The data account for the contract
+    /// 2. `[]` Mint info
+    /// 3. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
+    GetPoolApr {
+        stake_type: StakeType
+    },
+
+    /// Write the `PoolLiabilities` of the pool, its principal plus the interest accrued by open
+    /// positions, as return data without mutating state
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[]` The data account for the contract
+    /// 2. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
+    GetLiabilities,
+
+    /// Grow a contract data account created with an older, shorter layout to the current layout.
+    /// Fields added since are zeroed, which is their default
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer, writable]` The admin of the contract data account, paying the extra rent
+    /// 2. `[writable]` The contract data account
+    /// 3. `[]` System program info
+    ReallocContractData,
+
+    /// Write the `PoolCoverage` of the pool, how much of the interest accrued by open positions
+    /// the contract token account can pay, as return data without mutating state
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[]` The data account for the contract
+    /// 2. `[]` The token account interest is paid from, the reward vault in reward token pools
+    ///    and the contract token account otherwise
+    /// 3. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
+    GetCoverageRatio,
+
+    /// Create the user data account of an owner ahead of its first stake, which requires it.
+    /// Anyone may pay for it, the rent goes back to the owner when the account is closed
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer, writable]` The payer of the rent
+    /// 2. `[]` The owner of the user data account
+    /// 3. `[writable]` The user data account for the contract
+    /// 4. `[]` System program info
+    /// 5. `[]` The data account of the pool the position will be opened in
+    CreateUserData,
+
+    /// Change how the early withdrawal fee of LOCKED positions is read, the charge becomes
+    /// `early_withdrawal_fee / denominator` of the principal
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` The admin or the operator of the contract data account
+    /// 2. `[writable]` The contract data account
+    SetEarlyWithdrawalFee {
+        /// Charge for early withdrawal, at most `denominator`
+        early_withdrawal_fee: u64,
+        /// Scale of the fee (i.e. 1000 for 1/1000ths, 10000 for basis points), must be nonzero
+        denominator: u64
+    },
+
+    /// Write the unlock timestamp of a position as a little endian u64 return data without mutating state.
+    /// Re-stakes never bring it forward, NORMAL positions report 0
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[]` The user data account for the contract
+    GetUnlockTs,
+
+    /// Close a LOCKED position whose unlock timestamp has passed, paying the full principal and
+    /// interest back to the owner. Anyone may call it, funds and rent can only go to the position's owner
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` Any signer triggering the payout, writable when it pays for the creation of a log account
+    /// 2. `[writable]` The data account for the contract
+    /// 3. `[writable]` The user data account of the position
+    /// 4. `[writable]` The owner of the position, receiving the user data account rent
+    /// 5. `[writable]` The token account of the owner receiving the principal
+    /// 6. `[writable]` The token account for the contract
+    /// 7. `[]` Mint info
+    /// 8. `[]` TOKEN 2022 PROGRAM ID
+    /// 9. `[writable]` The user index account of the owner, the closed position leaves it
+    /// 10. `[writable]` Reward token account for the contract [Reward token pools only]
+    /// 11. `[]` Reward token mint [Reward token pools only]
+    /// 12. `[writable]` Reward token account of the owner receiving the interest [Reward token pools only]
+    /// 13. `[writable]` The history log account of the contract [Required for pools keeping a history, follows the applicable accounts above]
+    /// 14. `[]` System program info [Required for pools keeping a history]
+    /// 15. `[writable]` The earned checkpoint log account of the contract [Required for pools keeping earned checkpoints, follows the applicable accounts above]
+    /// 16. `[]` System program info [Required for pools keeping earned checkpoints]
+    /// 17. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
+    LiquidateExpired {
+        decimals: u64
+    },
+
+    /// Run every check of `Stake` without moving tokens and write the `StakeValidation` outcome
+    /// as return data. The failing check is reported in the return data, not as an error
+    ///
+    /// Accounts Expected
+    ///
+    /// Same as `Stake`
+    ValidateStake {
+        stake_type: StakeType,
+        amount: u64,
+        lock_duration: u64
+    },
+
+    /// Waive or restore the deposit fee and the early withdrawal charge of a position.
+    /// The Token 2022 transfer fee of the mint still applies
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` The admin of the contract data account
+    /// 2. `[]` The contract data account
+    /// 3. `[writable]` The user data account for the contract, created beforehand with `CreateUserData`
+    SetFeeExempt {
+        is_fee_exempt: bool
+    },
+
+    /// Recompute `total_staked` and `total_locked_staked` of the pool from its open positions,
+    /// repairing drifted bookkeeping. Running it again with the same positions changes nothing
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` The admin of the contract data account
+    /// 2. `[writable]` The contract data account
+    /// 3. `[]` The user data accounts of all open positions of the pool, each passed once
+    /// 4. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
+    RepairTotals,
+
+    /// Extend the lock of a LOCKED position without a token transfer, `new_lock_duration` counting
+    /// from the last stake like `lock_duration`. The unlock can only move out. LOCKED positions earn
+    /// the same APY whatever their lock duration, so the rate does not change
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` The user signer
+    /// 2. `[writable]` The user data account for the contract
+    /// 3. `[]` The data account for the contract
+    ExtendLock {
+        new_lock_duration: u64
+    },
+
+    /// Claim a share of the interest of a position and compound the rest into its principal. The
+    /// compounded share restarts the position like `ClaimAndStake` and is not available in reward
+    /// token pools, claiming 10000 is the same as `ClaimRewards`
+    ///
+    /// Accounts Expected
+    ///
+    /// Same as `ClaimRewards`
+    ClaimSplit {
+        /// Share of the interest transferred to the user (decimals = 10000), at most 10000
+        claim_bps: u64
+    },
+
+    /// Waive the early withdrawal charge of the next early unstake of a LOCKED position, full
+    /// or partial. The interest of the running lock is still forfeited
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` The admin of the contract data account
+    /// 2. `[]` The contract data account
+    /// 3. `[writable]` The user data account of the position
+    WaivePenalty,
+
+    /// Schedule a promotional boost window multiplying the APY of every position for the time it overlaps
+    /// the window, or remove the scheduled window. A running window cannot be changed. Positions
+    /// that did not settle their interest since an ended window lose its boost once it is replaced.
+    /// Streaming pools are not boosted
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` The admin of the contract data account
+    /// 2. `[writable]` The contract data account
+    /// 3. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
+    SetPromoBoost {
+        /// Unix timestamp the window starts at, not in the past
+        start_ts: u64,
+        /// Unix timestamp the window ends at, after `start_ts`
+        end_ts: u64,
+        /// Multiplier of the APY (decimals = 10000), above 10000 up to 100000, 0 removes the window
+        multiplier_bps: u64
+    },
+
+    /// Write the `ConfigSnapshot` of the pool, every field the admin configures, as return data
+    /// without mutating state
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[]` The data account for the contract
+    GetConfig,
+
+    /// Set the operator allowed to make non fund moving config updates (`UpdateAPY`, `SetEarlyWithdrawalFee`)
+    /// next to the admin. Everything else stays admin only
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` The admin of the contract data account
+    /// 2. `[writable]` The contract data account
+    SetOperator {
+        /// New operator, the default pubkey removes the operator
+        operator: Pubkey
+    },
+
+    /// Create the user data account of the signing user when it does not exist yet, paid by the user,
+    /// then stake exactly like `Stake`. Lets a new user open a position in a single instruction
+    ///
+    /// Accounts Expected
+    ///
+    /// Same as `Stake`, the user has to sign when the user data account is created
+    StakeWithSetup {
+        stake_type: StakeType,
+        amount: u64,
+        decimals: u64,
+        lock_duration: u64,
+        /// Wrap `amount` lamports from the user into the user's native SOL token account before staking [Optional]
+        wrap_native: bool
+    },
+
+    /// Freeze or resume interest accrual of every position, staking and unstaking stay open.
+    /// The frozen time earns nothing, including streamed rewards and the promotional boost
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer]` The admin of the contract data account
+    /// 2. `[writable]` The contract data account
+    /// 3. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
+    SetRewardsPaused {
+        paused: bool
+    },
+
+    /// Write the `ClaimableRewards` of a position, what ClaimRewards would pay right now next to
+    /// the gross accrued interest, as return data without mutating state
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[]` The user data account for the contract
+    /// 2. `[]` The data account for the contract
+    /// 3. `[]` The token account interest is paid from, the reward vault in reward token pools
+    ///    and the contract token account otherwise
+    /// 4. `[]` Clock sysvar [Optional, always the last account, defaults to the sysvar cache]
+    GetClaimable,
+
+    /// Write a page of the earned checkpoint log as Borsh `Vec<EarnedCheckpoint>` return data without mutating
+    /// state. Checkpoints already overwritten by the ring buffer are skipped, the page starts at the oldest kept one
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[]` The data account for the contract
+    /// 2. `[]` The earned checkpoint log account of the contract
+    ReadEarnedCheckpoints {
+        /// Number of the first checkpoint of the page, counted from the first checkpoint ever recorded
+        start: u64,
+        /// Checkpoints in the page, at most `EarnedLog::PAGE_SIZE`
+        count: u64
+    },
+
+    /// Move a user data account derived from the owner alone to the PDA of the owner and its pool. The
+    /// legacy account is closed and its rent returned to the owner. Positions that never recorded their
+    /// pool can only be assigned to a pool with its admin's signature
+    ///
+    /// Accounts Expected
+    ///
+    /// 1. `[Signer, writable]` The owner of the position, paying for the new user data account
+    /// 2. `[writable]` The legacy user data account of the owner
+    /// 3. `[writable]` The user data account of the owner in the pool
+    /// 4. `[]` The data account for the contract
+    /// 5. `[]` System program info
+    /// 6. `[writable]` The user index account of the owner, an open position is added to it
+    /// 7. `[Signer]` The admin of the contract data account [Required when the legacy account does not record its pool]
+    MigrateUserData
 }
 
 impl Instruction {
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        let (version, input) = input.split_first().ok_or(ProgramError::InvalidInstructionData)?;
+        if *version != INSTRUCTION_VERSION {
+            msg!("Staking [Error]: Unsupported instruction version {}", version);
+            return Err(StakingError::UnsupportedInstructionVersion.into())
+        }
         let (tag, rest) = input.split_first().ok_or(ProgramError::InvalidInstructionData)?;
         Ok(
             match tag {
                 0 => {
-                    let rest = array_ref![rest, 0, 56];
+                    Self::check_len(rest, 56)?;
+                    let fields = array_ref![rest, 0, 56];
                     let (
                         min_stk_dst,
                         min_lk_dst,
@@ -95,7 +642,7 @@ impl Instruction {
                         e_wdf_dst,
                         fee_b_pt_dst,
                         max_fee_dst
-                    ) = array_refs![rest, 8, 8, 8, 8, 8, 8, 8];
+                    ) = array_refs![fields, 8, 8, 8, 8, 8, 8, 8];
                     Self::Init {
                         minimum_stake_amount: Self::unpack_u64(min_stk_dst)?,
                         minimum_lock_duration: Self::unpack_u64(min_lk_dst)?,
@@ -103,10 +650,35 @@ impl Instruction {
                         locked_staking_apy: Self::unpack_u64(ls_apy_dst)?,
                         early_withdrawal_fee: Self::unpack_u64(e_wdf_dst)?,
                         fee_basis_points: Self::unpack_u64(fee_b_pt_dst)?,
-                        max_fee: Self::unpack_u64(max_fee_dst)?
+                        max_fee: Self::unpack_u64(max_fee_dst)?,
+                        max_lock_duration: Self::unpack_optional_u64(rest, 56)?,
+                        max_reward_per_position: Self::unpack_optional_u64(rest, 64)?,
+                        lock_grace_period: Self::unpack_optional_u64(rest, 72)?,
+                        deposit_fee_bps: Self::unpack_optional_u64(rest, 80)?,
+                        gate_collection: Self::unpack_optional_pubkey(rest, 88)?,
+                        max_boost_bps: Self::unpack_optional_u64(rest, 120)?,
+                        claim_cooldown: Self::unpack_optional_u64(rest, 128)?,
+                        accrual_period: Self::unpack_optional_u64(rest, 136)?,
+                        partial_reward_ok: Self::unpack_optional_bool(rest, 144)?,
+                        history_enabled: Self::unpack_optional_bool(rest, 145)?,
+                        min_restake_interval: Self::unpack_optional_u64(rest, 146)?,
+                        penalty_burn_bps: Self::unpack_optional_u64(rest, 154)?,
+                        delegate_authority: Self::unpack_optional_pubkey(rest, 162)?,
+                        interest_rounding: match rest.get(194) {
+                            None | Some(0) => InterestRounding::FLOOR,
+                            Some(1) => InterestRounding::NEAREST,
+                            _ => return Err(ProgramError::InvalidInstructionData)
+                        },
+                        max_stakers: Self::unpack_optional_u64(rest, 195)?,
+                        compound_cooldown: Self::unpack_optional_u64(rest, 203)?,
+                        reward_per_second: Self::unpack_optional_u64(rest, 211)?,
+                        min_initial_rewards: Self::unpack_optional_u64(rest, 219)?,
+                        max_apy: Self::unpack_optional_u64(rest, 227)?,
+                        earned_checkpoints_enabled: Self::unpack_optional_bool(rest, 235)?
                     }
                 },
                 1 => {
+                    Self::check_len(rest, 25)?;
                     let rest = array_ref![rest, 0, 25];
                     let (
                         stake_type_dst,
@@ -114,38 +686,234 @@ impl Instruction {
                         dec_dst,
                         lock_duration_dst
                     ) = array_refs![rest, 1, 8, 8, 8];
-                    let stake_type = match stake_type_dst[0] {
-                        0 => StakeType::NORMAL,
-                        1 => StakeType::LOCKED,
-                        _ => return Err(ProgramError::InvalidInstructionData.into())
-                    };
+                    let stake_type = Self::unpack_stake_type(stake_type_dst[0])?;
                     Self::Stake {
                         stake_type,
                         amount: Self::unpack_u64(amount_dst)?,
-                        decimals: Self::unpack_u64(dec_dst)?,
-                        lock_duration: Self::unpack_u64(lock_duration_dst)?
+                        decimals: Self::unpack_decimals(dec_dst)?,
+                        lock_duration: Self::unpack_u64(lock_duration_dst)?,
+                        wrap_native: Self::unpack_optional_bool(input, 26)?
                     }
                 },
                 2 => {
+                    Self::check_len(rest, 8)?;
                     Self::UnStake {
-                        decimals: Self::unpack_u64(rest)?
+                        decimals: Self::unpack_decimals(rest)?,
+                        unwrap_native: Self::unpack_optional_bool(rest, 8)?,
+                        amount: Self::unpack_optional_u64(rest, 9)?
                     }
                 },
                 3 => {
-                    let rest = array_ref![rest, 0, 16];
-                    let (normal_apy_dst, locked_apy_dst) = array_refs![rest, 8, 8];
+                    Self::check_len(rest, 16)?;
+                    let fields = array_ref![rest, 0, 16];
+                    let (normal_apy_dst, locked_apy_dst) = array_refs![fields, 8, 8];
                     Self::UpdateAPY {
                         normal_staking_apy: Self::unpack_u64(normal_apy_dst)?,
-                        locked_staking_apy: Self::unpack_u64(locked_apy_dst)?
+                        locked_staking_apy: Self::unpack_u64(locked_apy_dst)?,
+                        apy_effective_ts: Self::unpack_optional_u64(rest, 16)?
+                    }
+                },
+                4 => Self::CloseUserData,
+                5 => {
+                    Self::check_len(rest, 8)?;
+                    Self::UpdateTokenAccount {
+                        decimals: Self::unpack_decimals(rest)?
+                    }
+                },
+                6 => {
+                    Self::SnapshotPosition {
+                        weight_by_lock: Self::unpack_optional_bool(rest, 0)?
+                    }
+                },
+                7 => {
+                    Self::check_len(rest, 1)?;
+                    Self::SetPaused {
+                        paused: Self::unpack_optional_bool(rest, 0)?
+                    }
+                },
+                8 => {
+                    Self::check_len(rest, 16)?;
+                    let fields = array_ref![rest, 0, 16];
+                    let (amount_dst, dec_dst) = array_refs![fields, 8, 8];
+                    Self::EmergencyWithdraw {
+                        amount: Self::unpack_u64(amount_dst)?,
+                        decimals: Self::unpack_decimals(dec_dst)?
+                    }
+                },
+                9 => Self::GetUserIndex,
+                10 => {
+                    Self::check_len(rest, 17)?;
+                    let fields = array_ref![rest, 0, 17];
+                    let (stake_type_dst, amount_dst, duration_dst) = array_refs![fields, 1, 8, 8];
+                    let stake_type = Self::unpack_stake_type(stake_type_dst[0])?;
+                    Self::PreviewRewards {
+                        stake_type,
+                        amount: Self::unpack_u64(amount_dst)?,
+                        duration: Self::unpack_u64(duration_dst)?
+                    }
+                },
+                11 => {
+                    Self::check_len(rest, 9)?;
+                    let fields = array_ref![rest, 0, 9];
+                    let (new_type_dst, lock_duration_dst) = array_refs![fields, 1, 8];
+                    let new_type = Self::unpack_stake_type(new_type_dst[0])?;
+                    Self::ConvertStakeType {
+                        new_type,
+                        lock_duration: Self::unpack_u64(lock_duration_dst)?
+                    }
+                },
+                12 => {
+                    Self::check_len(rest, 8)?;
+                    Self::SetBoost {
+                        boost_bps: Self::unpack_u64(rest)?
+                    }
+                },
+                13 => Self::ClaimRewards,
+                14 => {
+                    Self::check_len(rest, 8)?;
+                    Self::ForceUnstake {
+                        decimals: Self::unpack_decimals(rest)?,
+                        include_interest: Self::unpack_optional_bool(rest, 8)?
+                    }
+                },
+                15 => {
+                    Self::check_len(rest, 16)?;
+                    let fields = array_ref![rest, 0, 16];
+                    let (start_dst, count_dst) = array_refs![fields, 8, 8];
+                    Self::ReadHistory {
+                        start: Self::unpack_u64(start_dst)?,
+                        count: Self::unpack_u64(count_dst)?
+                    }
+                },
+                16 => Self::ClaimAndStake,
+                17 => {
+                    Self::check_len(rest, 1)?;
+                    let stake_type = Self::unpack_stake_type(rest[0])?;
+                    Self::GetPoolApr { stake_type }
+                },
+                18 => Self::GetLiabilities,
+                19 => Self::ReallocContractData,
+                20 => Self::GetCoverageRatio,
+                21 => Self::CreateUserData,
+                22 => {
+                    Self::check_len(rest, 16)?;
+                    let fields = array_ref![rest, 0, 16];
+                    let (fee_dst, denominator_dst) = array_refs![fields, 8, 8];
+                    Self::SetEarlyWithdrawalFee {
+                        early_withdrawal_fee: Self::unpack_u64(fee_dst)?,
+                        denominator: Self::unpack_u64(denominator_dst)?
+                    }
+                },
+                23 => Self::GetUnlockTs,
+                24 => {
+                    Self::check_len(rest, 8)?;
+                    Self::LiquidateExpired {
+                        decimals: Self::unpack_decimals(rest)?
+                    }
+                },
+                25 => {
+                    Self::check_len(rest, 17)?;
+                    let fields = array_ref![rest, 0, 17];
+                    let (stake_type_dst, amount_dst, lock_duration_dst) = array_refs![fields, 1, 8, 8];
+                    let stake_type = Self::unpack_stake_type(stake_type_dst[0])?;
+                    Self::ValidateStake {
+                        stake_type,
+                        amount: Self::unpack_u64(amount_dst)?,
+                        lock_duration: Self::unpack_u64(lock_duration_dst)?
+                    }
+                },
+                26 => {
+                    Self::check_len(rest, 1)?;
+                    Self::SetFeeExempt {
+                        is_fee_exempt: Self::unpack_optional_bool(rest, 0)?
                     }
                 },
+                27 => Self::RepairTotals,
+                28 => {
+                    Self::check_len(rest, 8)?;
+                    Self::ExtendLock {
+                        new_lock_duration: Self::unpack_u64(rest)?
+                    }
+                },
+                29 => {
+                    Self::check_len(rest, 8)?;
+                    Self::ClaimSplit {
+                        claim_bps: Self::unpack_u64(rest)?
+                    }
+                },
+                30 => Self::WaivePenalty,
+                31 => {
+                    Self::check_len(rest, 24)?;
+                    let fields = array_ref![rest, 0, 24];
+                    let (start_ts_dst, end_ts_dst, multiplier_dst) = array_refs![fields, 8, 8, 8];
+                    Self::SetPromoBoost {
+                        start_ts: Self::unpack_u64(start_ts_dst)?,
+                        end_ts: Self::unpack_u64(end_ts_dst)?,
+                        multiplier_bps: Self::unpack_u64(multiplier_dst)?
+                    }
+                },
+                32 => Self::GetConfig,
+                33 => {
+                    Self::check_len(rest, 32)?;
+                    Self::SetOperator {
+                        operator: Self::unpack_optional_pubkey(rest, 0)?
+                    }
+                },
+                34 => {
+                    Self::check_len(rest, 25)?;
+                    let rest = array_ref![rest, 0, 25];
+                    let (
+                        stake_type_dst,
+                        amount_dst,
+                        dec_dst,
+                        lock_duration_dst
+                    ) = array_refs![rest, 1, 8, 8, 8];
+                    let stake_type = Self::unpack_stake_type(stake_type_dst[0])?;
+                    Self::StakeWithSetup {
+                        stake_type,
+                        amount: Self::unpack_u64(amount_dst)?,
+                        decimals: Self::unpack_decimals(dec_dst)?,
+                        lock_duration: Self::unpack_u64(lock_duration_dst)?,
+                        wrap_native: Self::unpack_optional_bool(input, 26)?
+                    }
+                },
+                35 => {
+                    Self::check_len(rest, 1)?;
+                    Self::SetRewardsPaused {
+                        paused: Self::unpack_optional_bool(rest, 0)?
+                    }
+                },
+                36 => Self::GetClaimable,
+                37 => {
+                    Self::check_len(rest, 16)?;
+                    let fields = array_ref![rest, 0, 16];
+                    let (start_dst, count_dst) = array_refs![fields, 8, 8];
+                    Self::ReadEarnedCheckpoints {
+                        start: Self::unpack_u64(start_dst)?,
+                        count: Self::unpack_u64(count_dst)?
+                    }
+                },
+                38 => Self::MigrateUserData,
                 _ => {
-                    return Err(ProgramError::InvalidInstructionData.into())
+                    return Err(ProgramError::InvalidInstructionData)
                 },
             }
         )
     }
 
+    /// Unpacks a stake type discriminant, rejecting unknown stake types
+    fn unpack_stake_type(value: u8) -> Result<StakeType, ProgramError> {
+        StakeType::try_from(value).map_err(|_| ProgramError::InvalidInstructionData)
+    }
+
+    /// Rejects instruction data shorter than the fixed layout of the instruction
+    fn check_len(input: &[u8], len: usize) -> Result<(), ProgramError> {
+        if input.len() < len {
+            return Err(ProgramError::InvalidInstructionData)
+        }
+        Ok(())
+    }
+
     fn unpack_u64(input: &[u8]) -> Result<u64, ProgramError> {
         let value = input
             .get(..8)
@@ -154,4 +922,41 @@ impl Instruction {
             .ok_or(ProgramError::InvalidInstructionData)?;
         Ok(value)
     }
+
+    /// Unpacks mint decimals, carried as u64 but handed to the token program as u8
+    fn unpack_decimals(input: &[u8]) -> Result<u64, ProgramError> {
+        let decimals = Self::unpack_u64(input)?;
+        if decimals > u8::MAX as u64 {
+            return Err(ProgramError::InvalidInstructionData)
+        }
+        Ok(decimals)
+    }
+
+    /// Unpacks an optional trailing boolean flag at `offset`, defaulting to false when the caller left it out
+    fn unpack_optional_bool(input: &[u8], offset: usize) -> Result<bool, ProgramError> {
+        match input.get(offset) {
+            None | Some(0) => Ok(false),
+            Some(1) => Ok(true),
+            _ => Err(ProgramError::InvalidInstructionData)
+        }
+    }
+
+    /// Unpacks an optional trailing u64 at `offset`, defaulting to 0 when the caller left it out
+    fn unpack_optional_u64(input: &[u8], offset: usize) -> Result<u64, ProgramError> {
+        match input.get(offset..) {
+            Some(slice) if !slice.is_empty() => Self::unpack_u64(slice),
+            _ => Ok(0)
+        }
+    }
+
+    /// Unpacks an optional trailing pubkey at `offset`, defaulting to the default pubkey when the caller left it out
+    fn unpack_optional_pubkey(input: &[u8], offset: usize) -> Result<Pubkey, ProgramError> {
+        match input.get(offset..) {
+            Some(slice) if !slice.is_empty() => slice
+                .get(..32)
+                .and_then(|slice| Pubkey::try_from(slice).ok())
+                .ok_or(ProgramError::InvalidInstructionData),
+            _ => Ok(Pubkey::default())
+        }
+    }
 }
\ No newline at end of file
@@ -1,4 +1,7 @@
+pub mod error;
 pub mod state;
 pub mod instruction;
+pub mod pda;
+pub mod math;
 pub mod processor;
 pub mod entrypoint;
\ No newline at end of file
@@ -0,0 +1,90 @@
+use solana_program::program_error::ProgramError;
+
+/// Denominator of shares expressed in basis points, 10000 is 100%
+pub const BPS_DENOMINATOR: u64 = 10000;
+
+/// `value * numerator / denominator` rounded toward zero. The product is taken in u128 so only
+/// a result that does not fit a u64 or a zero denominator fail
+pub fn mul_div_u64(value: u64, numerator: u64, denominator: u64) -> Result<u64, ProgramError> {
+    if denominator == 0 {
+        return Err(ProgramError::ArithmeticOverflow)
+    }
+    u64::try_from(value as u128 * numerator as u128 / denominator as u128).map_err(|_| ProgramError::ArithmeticOverflow)
+}
+
+/// Share `bps` of `amount` with decimals equals 10000, rounded toward zero
+pub fn bps(amount: u64, bps: u64) -> Result<u64, ProgramError> {
+    mul_div_u64(amount, bps, BPS_DENOMINATOR)
+}
+
+/// Interest owed to a position, `accrued_interest` scaled by the position's `boost_bps` plus the
+/// `snapshotted_interest` it already holds. A zero boost reads as 1x like `UserData::apply_boost`
+pub fn checked_interest(accrued_interest: u64, boost_bps: u64, snapshotted_interest: u64) -> Result<u64, ProgramError> {
+    let boosted_interest = match boost_bps {
+        0 => accrued_interest,
+        boost_bps => bps(accrued_interest, boost_bps)?
+    };
+    checked_add(boosted_interest, snapshotted_interest)
+}
+
+/// `a + b`, failing instead of wrapping
+pub fn checked_add(a: u64, b: u64) -> Result<u64, ProgramError> {
+    a.checked_add(b).ok_or(ProgramError::ArithmeticOverflow)
+}
+
+/// `a - b`, failing instead of wrapping below zero
+pub fn checked_sub(a: u64, b: u64) -> Result<u64, ProgramError> {
+    a.checked_sub(b).ok_or(ProgramError::ArithmeticOverflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::UserData;
+
+    #[test]
+    fn test_mul_div_u64_rounds_down_and_rejects_overflow() {
+        assert_eq!(mul_div_u64(10, 1, 3), Ok(3));
+        assert_eq!(mul_div_u64(11, 1, 3), Ok(3));
+        assert_eq!(mul_div_u64(12, 1, 3), Ok(4));
+        assert_eq!(mul_div_u64(0, u64::MAX, 1), Ok(0));
+        // The product may exceed a u64 as long as the result fits
+        assert_eq!(mul_div_u64(u64::MAX, u64::MAX, u64::MAX), Ok(u64::MAX));
+        assert_eq!(mul_div_u64(u64::MAX, 100, 109), Ok(((u64::MAX as u128 * 100) / 109) as u64));
+        assert_eq!(mul_div_u64(u64::MAX, 2, 1), Err(ProgramError::ArithmeticOverflow));
+        assert_eq!(mul_div_u64(u64::MAX / 2 + 1, 2, 1), Err(ProgramError::ArithmeticOverflow));
+        assert_eq!(mul_div_u64(1, 1, 0), Err(ProgramError::ArithmeticOverflow));
+    }
+
+    #[test]
+    fn test_bps_rounds_down_and_rejects_overflow() {
+        assert_eq!(bps(9999, 1), Ok(0));
+        assert_eq!(bps(10000, 1), Ok(1));
+        assert_eq!(bps(19999, 1), Ok(1));
+        assert_eq!(bps(1000, 250), Ok(25));
+        assert_eq!(bps(u64::MAX, BPS_DENOMINATOR), Ok(u64::MAX));
+        assert_eq!(bps(u64::MAX, 0), Ok(0));
+        assert_eq!(bps(u64::MAX, BPS_DENOMINATOR + 1), Err(ProgramError::ArithmeticOverflow));
+    }
+
+    #[test]
+    fn test_checked_interest_boosts_and_rejects_overflow() {
+        // A zero boost reads as 1x like UserData::apply_boost
+        assert_eq!(checked_interest(3, 0, 0), Ok(3));
+        assert_eq!(checked_interest(3, UserData::BASE_BOOST_BPS, 2), Ok(5));
+        assert_eq!(checked_interest(3, 15000, 0), Ok(4));
+        assert_eq!(checked_interest(3, 15000, 10), Ok(14));
+        assert_eq!(checked_interest(u64::MAX, UserData::BASE_BOOST_BPS, 0), Ok(u64::MAX));
+        assert_eq!(checked_interest(u64::MAX, 15000, 0), Err(ProgramError::ArithmeticOverflow));
+        assert_eq!(checked_interest(u64::MAX, 0, 1), Err(ProgramError::ArithmeticOverflow));
+        assert_eq!(checked_interest(u64::MAX - 1, 0, 1), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn test_checked_add_and_sub_reject_wrapping() {
+        assert_eq!(checked_add(u64::MAX - 1, 1), Ok(u64::MAX));
+        assert_eq!(checked_add(u64::MAX, 1), Err(ProgramError::ArithmeticOverflow));
+        assert_eq!(checked_sub(1, 1), Ok(0));
+        assert_eq!(checked_sub(0, 1), Err(ProgramError::ArithmeticOverflow));
+    }
+}
@@ -0,0 +1,49 @@
+use solana_program::pubkey::Pubkey;
+
+/// Seed prefix of the contract data account, followed by the admin and the stake token mint
+pub const CONTRACT_DATA_SEED: &[u8] = b"spl_staking";
+
+/// Seed prefix of a user data account, followed by the owner of the position and the contract data account
+/// of its pool. Accounts created before the pool was part of the seeds only follow it with the owner
+pub const USER_DATA_SEED: &[u8] = b"spl_staking_user";
+
+/// Seed prefix of the index of the open positions of a user, followed by the owner
+pub const USER_INDEX_SEED: &[u8] = b"spl_staking_index";
+
+/// Seed prefix of the history log of a pool, followed by the contract data account
+pub const HISTORY_LOG_SEED: &[u8] = b"spl_staking_history";
+
+/// Seed prefix of the earned checkpoint log of a pool, followed by the contract data account
+pub const EARNED_LOG_SEED: &[u8] = b"spl_staking_earned";
+
+/// Contract data account of the `mint` pool initialized by `admin` and its bump seed.
+/// The account also owns the contract token accounts
+pub fn contract_data_pda(admin: &Pubkey, mint: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[CONTRACT_DATA_SEED, admin.as_ref(), mint.as_ref()], program_id)
+}
+
+/// User data account holding the position of `user` in the pool at `contract_data` and its bump seed
+pub fn user_data_pda(user: &Pubkey, contract_data: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[USER_DATA_SEED, user.as_ref(), contract_data.as_ref()], program_id)
+}
+
+/// User data account of `user` derived before the pool was part of the seeds and its bump seed,
+/// only read by `MigrateUserData`
+pub fn legacy_user_data_pda(user: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[USER_DATA_SEED, user.as_ref()], program_id)
+}
+
+/// Index account listing the open positions of `user` across pools and its bump seed
+pub fn user_index_pda(user: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[USER_INDEX_SEED, user.as_ref()], program_id)
+}
+
+/// History log account of the pool at `contract_data` and its bump seed
+pub fn history_log_pda(contract_data: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[HISTORY_LOG_SEED, contract_data.as_ref()], program_id)
+}
+
+/// Earned checkpoint log account of the pool at `contract_data` and its bump seed
+pub fn earned_log_pda(contract_data: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[EARNED_LOG_SEED, contract_data.as_ref()], program_id)
+}
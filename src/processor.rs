@@ -1,4 +1,3 @@
-use std::ops::Add;
 use solana_program::{
     account_info::{AccountInfo, next_account_info},
     entrypoint::ProgramResult,
@@ -6,16 +5,23 @@ use solana_program::{
     program_pack::{Pack},
     pubkey::Pubkey,
     system_instruction,
-    sysvar::{Sysvar},
-    program::{invoke_signed, invoke},
+    system_program,
+    sysvar::{self, Sysvar},
+    program::{invoke_signed, invoke, set_return_data},
     msg,
 };
+use borsh::{BorshDeserialize, BorshSerialize};
+use spl_token_group_interface::state::TokenGroupMember;
+use spl_associated_token_account::{get_associated_token_address_with_program_id, instruction::create_associated_token_account_idempotent};
 use solana_program::clock::Clock;
 use solana_program::rent::Rent;
 use spl_token_2022::state::{Account as TokenAccount, Mint};
 use spl_token_2022::extension::{BaseStateWithExtensions, StateWithExtensions, transfer_fee::{TransferFeeConfig, instruction::transfer_checked_with_fee}};
+use crate::error::StakingError;
 use crate::instruction::Instruction as ContractInstruction;
-use crate::state::{ContractData, StakeType, UserData};
+use crate::math;
+use crate::pda;
+use crate::state::{ApyCheckpoint, ClaimableRewards, ConfigSnapshot, ContractData, EarnedCheckpoint, EarnedLog, HistoryAction, HistoryLog, HistoryRecord, InterestRounding, PoolApr, PoolCoverage, PoolLiabilities, PositionIndex, PositionSnapshot, RewardPreview, StakeType, StakeValidation, UnstakeReceipt, UserData, UserIndex, APY_CHECKPOINTS, EARLY_WITHDRAWAL_FEE_DENOMINATOR, MAX_PROMO_MULTIPLIER_BPS, NORMAL_MIN_HOLD, PROMO_BASE_MULTIPLIER_BPS};
 
 
 pub struct Processor;
@@ -27,24 +33,42 @@ impl Processor {
         instruction_data: &[u8]
     ) -> ProgramResult {
         let instruction = ContractInstruction::unpack(instruction_data)?;
+        let (accounts, clock) = Self::split_clock_account(accounts)?;
         match instruction {
             ContractInstruction::Init {
                 minimum_stake_amount, minimum_lock_duration,
                 normal_staking_apy, locked_staking_apy,
                 early_withdrawal_fee, fee_basis_points,
-                max_fee
+                max_fee, max_lock_duration,
+                max_reward_per_position, lock_grace_period,
+                deposit_fee_bps, gate_collection,
+                max_boost_bps, claim_cooldown,
+                accrual_period, partial_reward_ok, history_enabled, min_restake_interval, penalty_burn_bps, delegate_authority,
+                interest_rounding, max_stakers, compound_cooldown, reward_per_second,
+                min_initial_rewards, max_apy, earned_checkpoints_enabled
             } => {
                 msg!("Staking [Info]: Init contract instruction");
                 Self::init(
                     program_id, accounts,
                     minimum_stake_amount, minimum_lock_duration,
                     normal_staking_apy, locked_staking_apy,
-                    early_withdrawal_fee, fee_basis_points, max_fee
+                    early_withdrawal_fee, fee_basis_points, max_fee,
+                    max_lock_duration, max_reward_per_position,
+                    lock_grace_period, deposit_fee_bps,
+                    gate_collection, max_boost_bps,
+                    claim_cooldown, accrual_period,
+                    partial_reward_ok, history_enabled,
+                    min_restake_interval, penalty_burn_bps,
+                    delegate_authority, interest_rounding,
+                    max_stakers, compound_cooldown,
+                    reward_per_second, min_initial_rewards,
+                    max_apy, earned_checkpoints_enabled
                 )
             },
             ContractInstruction::Stake {
                 stake_type, amount,
-                lock_duration, decimals
+                lock_duration, decimals,
+                wrap_native
             } => {
                 msg!("Staking [Info]: Stake Instruction");
                 Self::stake(
@@ -53,32 +77,195 @@ impl Processor {
                     stake_type,
                     amount,
                     lock_duration,
-                    decimals
+                    decimals,
+                    wrap_native,
+                    false,
+                    &clock
                 )
             },
-            ContractInstruction::UnStake { decimals} => {
+            ContractInstruction::UnStake { decimals, unwrap_native, amount } => {
                 msg!("Staking [Info]: Unstake Instruction");
                 Self::unstake(
                     program_id,
                     accounts,
-                    decimals
+                    decimals,
+                    unwrap_native,
+                    amount,
+                    &clock
                 )
             },
             ContractInstruction::UpdateAPY {
                 normal_staking_apy,
-                locked_staking_apy
+                locked_staking_apy,
+                apy_effective_ts
             } => {
                 msg!("Staking [Info]: Change Tax Percent");
                 Self::update_apy(
                     program_id,
                     accounts,
                     normal_staking_apy,
-                    locked_staking_apy
+                    locked_staking_apy,
+                    apy_effective_ts,
+                    &clock
+                )
+            },
+            ContractInstruction::CloseUserData => {
+                msg!("Staking [Info]: Close User Data Instruction");
+                Self::close_user_data(program_id, accounts)
+            },
+            ContractInstruction::UpdateTokenAccount { decimals } => {
+                msg!("Staking [Info]: Update Token Account Instruction");
+                Self::update_token_account(program_id, accounts, decimals)
+            },
+            ContractInstruction::SnapshotPosition { weight_by_lock } => {
+                msg!("Staking [Info]: Snapshot Position Instruction");
+                Self::snapshot_position(program_id, accounts, weight_by_lock, &clock)
+            },
+            ContractInstruction::SetPaused { paused } => {
+                msg!("Staking [Info]: Set Paused Instruction");
+                Self::set_paused(program_id, accounts, paused)
+            },
+            ContractInstruction::EmergencyWithdraw { amount, decimals } => {
+                msg!("Staking [Info]: Emergency Withdraw Instruction");
+                Self::emergency_withdraw(program_id, accounts, amount, decimals)
+            },
+            ContractInstruction::GetUserIndex => {
+                msg!("Staking [Info]: Get User Index Instruction");
+                Self::get_user_index(program_id, accounts)
+            },
+            ContractInstruction::PreviewRewards { stake_type, amount, duration } => {
+                msg!("Staking [Info]: Preview Rewards Instruction");
+                Self::preview_rewards(program_id, accounts, stake_type, amount, duration, &clock)
+            },
+            ContractInstruction::ConvertStakeType { new_type, lock_duration } => {
+                msg!("Staking [Info]: Convert Stake Type Instruction");
+                Self::convert_stake_type(program_id, accounts, new_type, lock_duration, &clock)
+            },
+            ContractInstruction::SetBoost { boost_bps } => {
+                msg!("Staking [Info]: Set Boost Instruction");
+                Self::set_boost(program_id, accounts, boost_bps)
+            },
+            ContractInstruction::ClaimRewards => {
+                msg!("Staking [Info]: Claim Rewards Instruction");
+                Self::claim_rewards(program_id, accounts, 10000, &clock)
+            },
+            ContractInstruction::ForceUnstake { decimals, include_interest } => {
+                msg!("Staking [Info]: Force Unstake Instruction");
+                Self::force_unstake(program_id, accounts, decimals, include_interest, &clock)
+            },
+            ContractInstruction::ReadHistory { start, count } => {
+                msg!("Staking [Info]: Read History Instruction");
+                Self::read_history(program_id, accounts, start, count)
+            },
+            ContractInstruction::ClaimAndStake => {
+                msg!("Staking [Info]: Claim And Stake Instruction");
+                Self::claim_and_stake(program_id, accounts, &clock)
+            },
+            ContractInstruction::GetPoolApr { stake_type } => {
+                msg!("Staking [Info]: Get Pool Apr Instruction");
+                Self::get_pool_apr(program_id, accounts, stake_type, &clock)
+            },
+            ContractInstruction::GetLiabilities => {
+                msg!("Staking [Info]: Get Liabilities Instruction");
+                Self::get_liabilities(program_id, accounts, &clock)
+            },
+            ContractInstruction::ReallocContractData => {
+                msg!("Staking [Info]: Realloc Contract Data Instruction");
+                Self::realloc_contract_data(program_id, accounts)
+            },
+            ContractInstruction::GetCoverageRatio => {
+                msg!("Staking [Info]: Get Coverage Ratio Instruction");
+                Self::get_coverage_ratio(program_id, accounts, &clock)
+            },
+            ContractInstruction::CreateUserData => {
+                msg!("Staking [Info]: Create User Data Instruction");
+                Self::create_user_data(program_id, accounts)
+            },
+            ContractInstruction::SetEarlyWithdrawalFee { early_withdrawal_fee, denominator } => {
+                msg!("Staking [Info]: Set Early Withdrawal Fee Instruction");
+                Self::set_early_withdrawal_fee(program_id, accounts, early_withdrawal_fee, denominator)
+            },
+            ContractInstruction::GetUnlockTs => {
+                msg!("Staking [Info]: Get Unlock Ts Instruction");
+                Self::get_unlock_ts(program_id, accounts)
+            },
+            ContractInstruction::LiquidateExpired { decimals } => {
+                msg!("Staking [Info]: Liquidate Expired Instruction");
+                Self::liquidate_expired(program_id, accounts, decimals, &clock)
+            },
+            ContractInstruction::ValidateStake { stake_type, amount, lock_duration } => {
+                msg!("Staking [Info]: Validate Stake Instruction");
+                Self::validate_stake(program_id, accounts, stake_type, amount, lock_duration, &clock)
+            },
+            ContractInstruction::SetFeeExempt { is_fee_exempt } => {
+                msg!("Staking [Info]: Set Fee Exempt Instruction");
+                Self::set_fee_exempt(program_id, accounts, is_fee_exempt)
+            },
+            ContractInstruction::RepairTotals => {
+                msg!("Staking [Info]: Repair Totals Instruction");
+                Self::repair_totals(program_id, accounts, &clock)
+            },
+            ContractInstruction::ExtendLock { new_lock_duration } => {
+                msg!("Staking [Info]: Extend Lock Instruction");
+                Self::extend_lock(program_id, accounts, new_lock_duration)
+            },
+            ContractInstruction::ClaimSplit { claim_bps } => {
+                msg!("Staking [Info]: Claim Split Instruction");
+                Self::claim_rewards(program_id, accounts, claim_bps, &clock)
+            },
+            ContractInstruction::WaivePenalty => {
+                msg!("Staking [Info]: Waive Penalty Instruction");
+                Self::waive_penalty(program_id, accounts)
+            },
+            ContractInstruction::SetPromoBoost { start_ts, end_ts, multiplier_bps } => {
+                msg!("Staking [Info]: Set Promo Boost Instruction");
+                Self::set_promo_boost(program_id, accounts, start_ts, end_ts, multiplier_bps, &clock)
+            },
+            ContractInstruction::GetConfig => {
+                msg!("Staking [Info]: Get Config Instruction");
+                Self::get_config(program_id, accounts)
+            },
+            ContractInstruction::SetOperator { operator } => {
+                msg!("Staking [Info]: Set Operator Instruction");
+                Self::set_operator(program_id, accounts, operator)
+            },
+            ContractInstruction::StakeWithSetup {
+                stake_type, amount,
+                lock_duration, decimals,
+                wrap_native
+            } => {
+                msg!("Staking [Info]: Stake With Setup Instruction");
+                Self::stake_with_setup(
+                    program_id,
+                    accounts,
+                    stake_type,
+                    amount,
+                    lock_duration,
+                    decimals,
+                    wrap_native,
+                    &clock
                 )
+            },
+            ContractInstruction::SetRewardsPaused { paused } => {
+                msg!("Staking [Info]: Set Rewards Paused Instruction");
+                Self::set_rewards_paused(program_id, accounts, paused, &clock)
+            },
+            ContractInstruction::GetClaimable => {
+                msg!("Staking [Info]: Get Claimable Instruction");
+                Self::get_claimable(program_id, accounts, &clock)
+            },
+            ContractInstruction::ReadEarnedCheckpoints { start, count } => {
+                msg!("Staking [Info]: Read Earned Checkpoints Instruction");
+                Self::read_earned_checkpoints(program_id, accounts, start, count)
+            },
+            ContractInstruction::MigrateUserData => {
+                msg!("Staking [Info]: Migrate User Data Instruction");
+                Self::migrate_user_data(program_id, accounts)
             }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn init(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
@@ -88,7 +275,27 @@ impl Processor {
         locked_staking_apy: u64,
         early_withdrawal_fee: u64,
         fee_basis_points: u64,
-        max_fee: u64
+        max_fee: u64,
+        max_lock_duration: u64,
+        max_reward_per_position: u64,
+        lock_grace_period: u64,
+        deposit_fee_bps: u64,
+        gate_collection: Pubkey,
+        max_boost_bps: u64,
+        claim_cooldown: u64,
+        accrual_period: u64,
+        partial_reward_ok: bool,
+        history_enabled: bool,
+        min_restake_interval: u64,
+        penalty_burn_bps: u64,
+        delegate_authority: Pubkey,
+        interest_rounding: InterestRounding,
+        max_stakers: u64,
+        compound_cooldown: u64,
+        reward_per_second: u64,
+        min_initial_rewards: u64,
+        max_apy: u64,
+        earned_checkpoints_enabled: bool
     ) -> ProgramResult {
         // Get all accounts sent to the instruction
         let accounts_info_iter = &mut accounts.iter();
@@ -97,64 +304,102 @@ impl Processor {
         let token_account = next_account_info(accounts_info_iter)?;
         let mint_info = next_account_info(accounts_info_iter)?;
         let token_program_info = next_account_info(accounts_info_iter)?;
-        let system_program_account = next_account_info(accounts_info_iter)?;
+        let _rent_info = next_account_info(accounts_info_iter)?;
+        let system_program_info = next_account_info(accounts_info_iter)?;
+        let reward_mint_info = accounts_info_iter.next();
+        let reward_vault_info = match reward_mint_info {
+            Some(_) => Some(next_account_info(accounts_info_iter)?),
+            None => None
+        };
 
         // perform necessary checks
+        Self::check_distinct_accounts(&[admin, data_account, token_account, mint_info])?;
+        if let Some(reward_vault_info) = reward_vault_info {
+            Self::check_distinct_accounts(&[admin, data_account, token_account, reward_vault_info])?;
+        }
         if !admin.is_signer {
-            return Err(ProgramError::MissingRequiredSignature.into());
+            return Err(ProgramError::MissingRequiredSignature);
         }
         if !data_account.is_writable {
-            return Err(ProgramError::InvalidAccountData.into());
+            return Err(ProgramError::InvalidAccountData);
         }
         if minimum_stake_amount == 0 {
             msg!("Staking [Error]: Cannot init contract with zero minimum stake amount");
-            return Err(ProgramError::InvalidInstructionData.into());
+            return Err(StakingError::InvalidMinimumStakeAmount.into());
+        }
+        if max_lock_duration != 0 && max_lock_duration < minimum_lock_duration {
+            msg!("Staking [Error]: Maximum lock duration is less than minimum lock duration");
+            return Err(StakingError::InvalidLockDurationConfig.into());
+        }
+        if normal_staking_apy < 1 || locked_staking_apy < 1 {
+            msg!("Staking [Error]: Cannot init contract with zero staking apy");
+            return Err(StakingError::InvalidApy.into());
+        }
+        if max_apy != 0 && (normal_staking_apy > max_apy || locked_staking_apy > max_apy) {
+            msg!("Staking [Error]: Staking apy cannot exceed the maximum apy of {}", max_apy);
+            return Err(StakingError::ApyAboveMaximum.into());
+        }
+        if early_withdrawal_fee > EARLY_WITHDRAWAL_FEE_DENOMINATOR {
+            msg!("Staking [Error]: Early withdrawal fee cannot exceed 100% of the principal");
+            return Err(StakingError::PenaltyExceedsPrincipal.into());
+        }
+        if deposit_fee_bps > 10000 {
+            msg!("Staking [Error]: Deposit fee cannot exceed 100%");
+            return Err(StakingError::InvalidDepositFee.into());
+        }
+        if penalty_burn_bps > 10000 {
+            msg!("Staking [Error]: Penalty burn cannot exceed 100%");
+            return Err(StakingError::InvalidPenaltyBurn.into());
         }
         if token_program_info.key != &spl_token_2022::ID {
             msg!("Staking [Error]: Invalid Token Program. Contract supports TOKEN 2022 only");
-            return Err(ProgramError::InvalidInstructionData.into())
+            return Err(StakingError::UnsupportedTokenProgram.into())
+        }
+        if system_program_info.key != &system_program::ID {
+            msg!("Staking [Error]: Invalid System Program");
+            return Err(ProgramError::IncorrectProgramId)
         }
         if token_account.owner != &spl_token_2022::ID {
             msg!("Staking [Error]: Invalid Token Account. Supports only Token 2022 Accounts");
-            return Err(ProgramError::InvalidAccountData.into())
+            return Err(StakingError::UnsupportedTokenProgram.into())
         }
         if mint_info.owner != &spl_token_2022::ID {
             msg!("Staking [Error]: Invalid Mint Account. Supports only Token 2022 Mint Accounts");
-            return Err(ProgramError::InvalidAccountData.into())
+            return Err(StakingError::UnsupportedTokenProgram.into())
+        }
+        let token_account_data = TokenAccount::unpack_from_slice(&token_account.data.borrow())?;
+        if token_account_data.mint != *mint_info.key {
+            msg!("Staking [Error]: Invalid contract token account mint");
+            return Err(StakingError::MintMismatch.into())
+        }
+        // Checked before the authority moves to the PDA, an unfunded account can't be taken back
+        if token_account_data.amount < min_initial_rewards {
+            msg!("Staking [Error]: Contract token account holds less than the required initial rewards");
+            return Err(StakingError::InsufficientInitialRewards.into())
         }
 
         // Create Contract Data account with the PDA
-        let seeds: &[&[u8]] = &[
-            b"spl_staking",
-            admin.key.as_ref(),
-            mint_info.key.as_ref()
-        ];
-        let (pda_addr, pda_bump) = Pubkey::find_program_address(seeds, program_id);
+        let (pda_addr, pda_bump) = pda::contract_data_pda(admin.key, mint_info.key, program_id);
         if &pda_addr != data_account.key {
             msg!("PDA Addr Account Mismatch");
-            return Err(ProgramError::InvalidAccountData.into());
+            return Err(StakingError::PdaMismatch.into());
         };
-        let rent = &Rent::get()?;
-        let required_lamports = rent
-            .minimum_balance(ContractData::LEN)
-            .max(1)
-            .saturating_sub(data_account.lamports());
-        let contract_seeds: &[&[u8]] = &[b"spl_staking", admin.key.as_ref(), mint_info.key.as_ref(), &[pda_bump]];
-        invoke_signed(
-            &system_instruction::create_account(
-                admin.key,
-                data_account.key,
-                required_lamports,
-                ContractData::LEN as u64,
-                program_id,
-            ),
-            &[
-                admin.clone(),
-                data_account.clone(),
-                mint_info.clone(),
-                system_program_account.clone(),
-            ],
-            &[contract_seeds],
+        // Creating the account again would fail in the system program with a less telling error. The
+        // first byte is is_initialized whatever the layout version of the account
+        if data_account.data_len() != 0
+            && data_account.owner == program_id
+            && data_account.data.borrow()[0] != 0 {
+            msg!("Staking [Error]: A pool for this admin and mint is already initialized");
+            return Err(ProgramError::AccountAlreadyInitialized)
+        }
+        let contract_seeds: &[&[u8]] = &[pda::CONTRACT_DATA_SEED, admin.key.as_ref(), mint_info.key.as_ref(), &[pda_bump]];
+        Self::create_pda_account(
+            program_id,
+            admin,
+            data_account,
+            system_program_info,
+            ContractData::LEN,
+            contract_seeds
         )?;
 
         // Change ownership of the token account
@@ -164,7 +409,7 @@ impl Processor {
             Some(&pda_addr),
             spl_token_2022::instruction::AuthorityType::AccountOwner,
             admin.key,
-            &[&admin.key]
+            &[admin.key]
         )?;
         invoke(
             &change_owner_ix,
@@ -175,10 +420,38 @@ impl Processor {
             ],
         )?;
 
+        // Validate the reward vault and hand it over to the contract data pda
+        if let (Some(reward_mint_info), Some(reward_vault_info)) = (reward_mint_info, reward_vault_info) {
+            if reward_mint_info.owner != &spl_token_2022::ID || reward_vault_info.owner != &spl_token_2022::ID {
+                msg!("Staking [Error]: Invalid reward accounts. Supports only Token 2022 Accounts");
+                return Err(StakingError::UnsupportedTokenProgram.into())
+            }
+            let reward_vault_data = TokenAccount::unpack_from_slice(&reward_vault_info.data.borrow())?;
+            if reward_vault_data.mint != *reward_mint_info.key {
+                msg!("Staking [Error]: Invalid reward token account mint");
+                return Err(StakingError::MintMismatch.into())
+            }
+            invoke(
+                &spl_token_2022::instruction::set_authority(
+                    &spl_token_2022::id(),
+                    reward_vault_info.key,
+                    Some(&pda_addr),
+                    spl_token_2022::instruction::AuthorityType::AccountOwner,
+                    admin.key,
+                    &[admin.key]
+                )?,
+                &[
+                    reward_vault_info.clone(),
+                    admin.clone(),
+                    token_program_info.clone(),
+                ],
+            )?;
+        }
+
         // Update contract data
         let mut contract_data = ContractData::unpack_unchecked(&data_account.data.borrow())?;
         if contract_data.is_initialized {
-            return Err(ProgramError::AccountAlreadyInitialized.into())
+            return Err(ProgramError::AccountAlreadyInitialized)
         }
         contract_data.is_initialized = true;
         contract_data.admin_pubkey = *admin.key;
@@ -193,18 +466,62 @@ impl Processor {
         contract_data.total_staked = 0;
         contract_data.fee_basis_points = fee_basis_points;
         contract_data.max_fee = max_fee;
+        contract_data.max_lock_duration = max_lock_duration;
+        contract_data.max_reward_per_position = max_reward_per_position;
+        contract_data.lock_grace_period = lock_grace_period;
+        contract_data.deposit_fee_bps = deposit_fee_bps;
+        contract_data.total_deposit_fees = 0;
+        contract_data.total_locked_staked = 0;
+        contract_data.total_pending_interest = 0;
+        contract_data.pending_interest_ts = 0;
+        contract_data.gate_collection = gate_collection;
+        contract_data.max_boost_bps = max_boost_bps;
+        contract_data.claim_cooldown = claim_cooldown;
+        contract_data.accrual_period = accrual_period.max(1);
+        contract_data.partial_reward_ok = partial_reward_ok;
+        contract_data.history_enabled = history_enabled;
+        contract_data.min_restake_interval = min_restake_interval;
+        contract_data.penalty_burn_bps = penalty_burn_bps;
+        contract_data.delegate_authority = delegate_authority;
+        contract_data.interest_rounding = interest_rounding;
+        contract_data.max_stakers = max_stakers;
+        contract_data.compound_cooldown = compound_cooldown;
+        contract_data.reward_per_second = reward_per_second;
+        contract_data.early_withdrawal_fee_denominator = EARLY_WITHDRAWAL_FEE_DENOMINATOR;
+        contract_data.reward_mint = reward_mint_info.map_or(Pubkey::default(), |info| *info.key);
+        contract_data.reward_vault = reward_vault_info.map_or(Pubkey::default(), |info| *info.key);
+        contract_data.is_paused = false;
+        contract_data.rewards_depleted = false;
+        contract_data.token_program_id = *token_program_info.key;
+        contract_data.pending_normal_apy = 0;
+        contract_data.pending_locked_apy = 0;
+        contract_data.apy_effective_ts = 0;
+        contract_data.apy_checkpoint_count = 0;
+        contract_data.apy_checkpoints = [ApyCheckpoint::default(); APY_CHECKPOINTS];
+        contract_data.promo_start_ts = 0;
+        contract_data.promo_end_ts = 0;
+        contract_data.promo_multiplier_bps = 0;
+        contract_data.operator_pubkey = Pubkey::default();
+        contract_data.rewards_paused = false;
+        contract_data.accrual_frozen_since = 0;
+        contract_data.max_apy = max_apy;
+        contract_data.earned_checkpoints_enabled = earned_checkpoints_enabled;
 
         ContractData::pack(contract_data, &mut data_account.try_borrow_mut_data()?)?;
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn stake(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
         stake_type: StakeType,
         amount: u64,
         lock_duration: u64,
-        decimals: u64
+        decimals: u64,
+        wrap_native: bool,
+        dry_run: bool,
+        clock: &Clock
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         let user_info = next_account_info(account_info_iter)?;
@@ -215,49 +532,109 @@ impl Processor {
         let mint_info = next_account_info(account_info_iter)?;
         let token_program_info = next_account_info(account_info_iter)?;
         let system_program_info = next_account_info(account_info_iter)?;
+        let user_index_info = next_account_info(account_info_iter)?;
+        // The user token account is checked against the contract token account below
+        Self::check_distinct_accounts(&[
+            user_info, user_data_account_info, contract_token_account_info, contract_data_account_info, mint_info
+        ])?;
 
-        let contract_data = ContractData::unpack_from_slice(&contract_data_account_info.data.borrow())?;
+        // The system program funds wrapped SOL, the user index and the growth of the history log
+        if system_program_info.key != &system_program::ID {
+            msg!("Staking [Error]: Invalid System Program");
+            return Err(ProgramError::IncorrectProgramId)
+        }
+        let contract_data = Self::unpack_contract_data(program_id, contract_data_account_info)?;
+        if token_program_info.key != &contract_data.token_program_id {
+            msg!("Staking [Error]: Token program does not match the token program of the pool");
+            return Err(ProgramError::IncorrectProgramId)
+        }
+        if contract_data.is_paused {
+            msg!("Staking [Error]: Pool is paused");
+            return Err(StakingError::PoolPaused.into())
+        }
         let user_token_account_data = TokenAccount::unpack_from_slice(&user_token_account_info.data.borrow())?;
         let contract_token_account_data = TokenAccount::unpack_from_slice(&contract_token_account_info.data.borrow())?;
 
 
-        if !user_info.is_signer {
-            return Err(ProgramError::MissingRequiredSignature.into())
+        if contract_data.is_gated() {
+            let gate_token_account_info = next_account_info(account_info_iter)?;
+            let gate_mint_info = next_account_info(account_info_iter)?;
+            Self::verify_gate_nft(&contract_data, user_info, gate_token_account_info, gate_mint_info)?;
         }
+        let history_accounts = if contract_data.history_enabled {
+            Some((next_account_info(account_info_iter)?, system_program_info))
+        } else {
+            None
+        };
+        // The position is only known once the user data account is checked, perform_staking holds it to its delegate
+        let authority_info = Self::position_authority(&contract_data, None, user_info, account_info_iter)?;
         // Verify user and contract token accounts
+        if user_token_account_info.key == contract_token_account_info.key {
+            msg!("Staking [Error]: User token account cannot be the contract token account");
+            return Err(StakingError::InvalidUserTokenAccount.into())
+        }
         if user_token_account_data.owner != *user_info.key {
             msg!("Staking [Error]: Invalid user token account");
-            return Err(ProgramError::InvalidAccountData.into())
+            return Err(StakingError::InvalidUserTokenAccount.into())
         }
         if user_token_account_data.mint != contract_data.stake_token_mint {
             msg!("Staking [Error]: Invalid user token account mint");
-            return Err(ProgramError::InvalidAccountData.into())
+            return Err(StakingError::MintMismatch.into())
+        }
+        // Mints with a frozen DefaultAccountState hand out frozen accounts, the transfer would fail inside the CPI
+        if user_token_account_data.is_frozen() || contract_token_account_data.is_frozen() {
+            msg!("Staking [Error]: Token account is frozen, it has to be thawed by the freeze authority of the mint first");
+            return Err(StakingError::TokenAccountFrozen.into())
+        }
+        if mint_info.key != &contract_data.stake_token_mint {
+            msg!("Staking [Error]: Mint account does not match the staking mint");
+            return Err(StakingError::MintMismatch.into())
+        }
+        Self::check_decimals(mint_info, decimals)?;
+        let mut user_token_balance = user_token_account_data.amount;
+        if wrap_native {
+            if contract_data.stake_token_mint != spl_token_2022::native_mint::ID {
+                msg!("Staking [Error]: Only native SOL pools support wrapping");
+                return Err(StakingError::NativeMintRequired.into())
+            }
+            Self::wrap_native(
+                authority_info,
+                user_token_account_info,
+                system_program_info,
+                token_program_info,
+                amount
+            )?;
+            user_token_balance = math::checked_add(user_token_balance, amount)?;
         }
-        if user_token_account_data.amount < contract_data.minimum_stake_amount {
+        if user_token_balance < contract_data.minimum_stake_amount {
             msg!("Staking [Error]: Insufficient user token balance for staking");
-            return Err(ProgramError::InsufficientFunds.into())
+            return Err(StakingError::InsufficientStakeBalance.into())
+        }
+        // A real stake finds out from the failing transfer
+        if dry_run && user_token_balance < amount {
+            msg!("Staking [Error]: Insufficient user token balance for staking");
+            return Err(StakingError::InsufficientStakeBalance.into())
         }
 
         // verify the contract data pda
-        let (contract_data_pda, _c_bump) = Pubkey::find_program_address(
-            &[b"spl_staking", contract_data.admin_pubkey.as_ref(), contract_data.stake_token_mint.as_ref()],
-            program_id
+        let (contract_data_pda, _c_bump) = pda::contract_data_pda(
+            &contract_data.admin_pubkey, &contract_data.stake_token_mint, program_id
         );
         if &contract_data_pda != contract_data_account_info.key {
             msg!("Staking [Error]: Invalid contract data account");
-            return Err(ProgramError::InvalidAccountData.into())
+            return Err(StakingError::PdaMismatch.into())
         }
         if contract_token_account_info.key != &contract_data.stake_token_account {
             msg!("Staking [Error]: Invalid contract token account");
-            return Err(ProgramError::InvalidAccountData.into())
+            return Err(StakingError::InvalidContractTokenAccount.into())
         }
         if contract_data.stake_token_mint != contract_token_account_data.mint {
             msg!("Staking [Error]: Invalid contract token account mint");
-            return Err(ProgramError::InvalidAccountData.into())
+            return Err(StakingError::MintMismatch.into())
         }
         if contract_data_pda != contract_token_account_data.owner {
             msg!("Staking [Error]: Invalid contract token account owner");
-            return Err(ProgramError::InvalidAccountData.into())
+            return Err(StakingError::InvalidContractTokenAccount.into())
         }
         match stake_type {
             StakeType::NORMAL => {
@@ -265,9 +642,9 @@ impl Processor {
                 Self::perform_staking(
                     program_id,
                     user_info,
+                    authority_info,
                     user_token_account_info,
                     user_data_account_info,
-                    system_program_info,
                     token_program_info,
                     contract_token_account_info,
                     contract_data_account_info,
@@ -275,22 +652,33 @@ impl Processor {
                     StakeType::NORMAL,
                     amount,
                     decimals,
-                    contract_data.normal_staking_apy,
-                    0
+                    0,
+                    (user_index_info, system_program_info),
+                    history_accounts,
+                    dry_run,
+                    clock
                 )
             },
             StakeType::LOCKED => {
                 msg!("Staking [Info]: Locked Staking");
                 if lock_duration < contract_data.minimum_lock_duration {
                     msg!("Staking [Error]: Lock duration is less than minimum lock duration❌");
-                    return Err(ProgramError::InvalidInstructionData.into())
+                    return Err(StakingError::LockDurationTooShort.into())
+                }
+                if contract_data.max_lock_duration != 0 && lock_duration > contract_data.max_lock_duration {
+                    msg!("Staking [Error]: Lock duration is greater than maximum lock duration❌");
+                    return Err(StakingError::LockDurationTooLong.into())
+                }
+                if (clock.unix_timestamp as u64).checked_add(lock_duration).is_none() {
+                    msg!("Staking [Error]: Lock would end past the maximum timestamp❌");
+                    return Err(StakingError::LockDurationTooLong.into())
                 }
                 Self::perform_staking(
                     program_id,
                     user_info,
+                    authority_info,
                     user_token_account_info,
                     user_data_account_info,
-                    system_program_info,
                     token_program_info,
                     contract_token_account_info,
                     contract_data_account_info,
@@ -298,8 +686,11 @@ impl Processor {
                     StakeType::LOCKED,
                     amount,
                     decimals,
-                    contract_data.locked_staking_apy,
-                    lock_duration
+                    lock_duration,
+                    (user_index_info, system_program_info),
+                    history_accounts,
+                    dry_run,
+                    clock
                 )
             }
         }
@@ -308,7 +699,10 @@ impl Processor {
     fn unstake(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        decimals: u64
+        decimals: u64,
+        unwrap_native: bool,
+        amount: u64,
+        clock: &Clock
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         let user_info = next_account_info(account_info_iter)?;
@@ -318,64 +712,216 @@ impl Processor {
         let contract_data_account_info = next_account_info(account_info_iter)?;
         let mint_info = next_account_info(account_info_iter)?;
         let token_program_info = next_account_info(account_info_iter)?;
+        let user_index_info = next_account_info(account_info_iter)?;
+        let destination_token_account_info = account_info_iter.next();
+        // The user token account is checked against the contract token account below
+        Self::check_distinct_accounts(&[
+            user_info, user_data_account_info, contract_token_account_info, contract_data_account_info, mint_info
+        ])?;
+        Self::check_user_index(program_id, user_index_info, user_info.key)?;
 
-        let contract_data = ContractData::unpack_from_slice(&contract_data_account_info.data.borrow())?;
+        let contract_data = Self::unpack_contract_data(program_id, contract_data_account_info)?;
+        if token_program_info.key != &contract_data.token_program_id {
+            msg!("Staking [Error]: Token program does not match the token program of the pool");
+            return Err(ProgramError::IncorrectProgramId)
+        }
+        // A pool that paused itself over depleted rewards still lets stakers withdraw
+        if contract_data.is_paused && !contract_data.rewards_depleted {
+            msg!("Staking [Error]: Pool is paused");
+            return Err(StakingError::PoolPaused.into())
+        }
+        if user_data_account_info.owner != program_id {
+            msg!("Staking [Error]: User data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
         let user_data = UserData::unpack_from_slice(&user_data_account_info.data.borrow())?;
-        let user_token_account_data = TokenAccount::unpack_from_slice(&user_token_account_info.data.borrow())?;
+        Self::check_position_pool(&user_data, contract_data_account_info)?;
         let contract_token_account_data = TokenAccount::unpack_from_slice(&contract_token_account_info.data.borrow())?;
-        if !user_info.is_signer {
-            return Err(ProgramError::MissingRequiredSignature.into())
-        }
         // Verify user and contract token accounts
-        if user_token_account_data.owner != *user_info.key {
-            msg!("Staking [Error]: Invalid user token account");
-            return Err(ProgramError::InvalidAccountData.into())
+        if user_token_account_info.key == contract_token_account_info.key {
+            msg!("Staking [Error]: User token account cannot be the contract token account");
+            return Err(StakingError::InvalidUserTokenAccount.into())
         }
-        if user_token_account_data.mint != contract_data.stake_token_mint {
-            msg!("Staking [Error]: Invalid user token account mint");
-            return Err(ProgramError::InvalidAccountData.into())
+        // A user token account closed since staking is recreated below as the user's associated token account
+        let user_token_account_missing = user_token_account_info.data_is_empty();
+        if user_token_account_missing {
+            let associated_token_account = get_associated_token_address_with_program_id(
+                user_info.key, &contract_data.stake_token_mint, &spl_token_2022::ID
+            );
+            if *user_token_account_info.key != associated_token_account {
+                msg!("Staking [Error]: A missing user token account can only be replaced by the associated token account");
+                return Err(StakingError::InvalidUserTokenAccount.into())
+            }
+        } else {
+            let user_token_account_data = TokenAccount::unpack_from_slice(&user_token_account_info.data.borrow())?;
+            if user_token_account_data.owner != *user_info.key {
+                msg!("Staking [Error]: Invalid user token account");
+                return Err(StakingError::InvalidUserTokenAccount.into())
+            }
+            if user_token_account_data.mint != contract_data.stake_token_mint {
+                msg!("Staking [Error]: Invalid user token account mint");
+                return Err(StakingError::MintMismatch.into())
+            }
         }
-        if user_token_account_data.amount < contract_data.minimum_stake_amount {
-            msg!("Staking [Error]: Insufficient user token balance for staking");
-            return Err(ProgramError::InsufficientFunds.into())
+        if mint_info.key != &contract_data.stake_token_mint {
+            msg!("Staking [Error]: Mint account does not match the staking mint");
+            return Err(StakingError::MintMismatch.into())
         }
-
+        Self::check_decimals(mint_info, decimals)?;
         // verify the contract data pda
-        let (contract_data_pda, _c_bump) = Pubkey::find_program_address(
-            &[b"spl_staking", contract_data.admin_pubkey.as_ref(), contract_data.stake_token_mint.as_ref()],
-            program_id
+        let (contract_data_pda, _c_bump) = pda::contract_data_pda(
+            &contract_data.admin_pubkey, &contract_data.stake_token_mint, program_id
         );
         if &contract_data_pda != contract_data_account_info.key {
             msg!("Staking [Error]: Invalid contract data account");
-            return Err(ProgramError::InvalidAccountData.into())
+            return Err(StakingError::PdaMismatch.into())
         }
         if contract_token_account_info.key != &contract_data.stake_token_account {
             msg!("Staking [Error]: Invalid contract token account");
-            return Err(ProgramError::InvalidAccountData.into())
+            return Err(StakingError::InvalidContractTokenAccount.into())
         }
         if contract_data.stake_token_mint != contract_token_account_data.mint {
             msg!("Staking [Error]: Invalid contract token account mint");
-            return Err(ProgramError::InvalidAccountData.into())
+            return Err(StakingError::MintMismatch.into())
         }
         if contract_data_pda != contract_token_account_data.owner {
             msg!("Staking [Error]: Invalid contract token account owner");
-            return Err(ProgramError::InvalidAccountData.into())
+            return Err(StakingError::InvalidContractTokenAccount.into())
+        };
+        // Withdraw to the signer's token account unless another destination is given
+        let destination_token_account_info = match destination_token_account_info {
+            Some(destination_info) if destination_info.key != user_token_account_info.key => {
+                if destination_info.owner != &spl_token_2022::ID {
+                    msg!("Staking [Error]: Invalid destination token account. Supports only Token 2022 Accounts");
+                    return Err(StakingError::InvalidDestinationTokenAccount.into())
+                }
+                if destination_info.key == contract_token_account_info.key {
+                    msg!("Staking [Error]: Destination token account cannot be the contract token account");
+                    return Err(StakingError::InvalidDestinationTokenAccount.into())
+                }
+                let destination_data = TokenAccount::unpack_from_slice(&destination_info.data.borrow())?;
+                if destination_data.mint != contract_data.stake_token_mint {
+                    msg!("Staking [Error]: Invalid destination token account mint");
+                    return Err(StakingError::MintMismatch.into())
+                }
+                destination_info
+            },
+            _ => user_token_account_info
+        };
+        // Reward token pools pay interest from the reward vault into the user's reward token account
+        let reward_accounts = if contract_data.has_reward_token() {
+            let reward_vault_info = next_account_info(account_info_iter)?;
+            let reward_mint_info = next_account_info(account_info_iter)?;
+            let user_reward_account_info = next_account_info(account_info_iter)?;
+            if reward_vault_info.key != &contract_data.reward_vault {
+                msg!("Staking [Error]: Invalid reward token account");
+                return Err(StakingError::InvalidContractTokenAccount.into())
+            }
+            if reward_mint_info.key != &contract_data.reward_mint {
+                msg!("Staking [Error]: Reward mint account does not match the reward mint");
+                return Err(StakingError::MintMismatch.into())
+            }
+            if user_reward_account_info.owner != &spl_token_2022::ID {
+                msg!("Staking [Error]: Invalid user reward token account. Supports only Token 2022 Accounts");
+                return Err(StakingError::InvalidDestinationTokenAccount.into())
+            }
+            let user_reward_account_data = TokenAccount::unpack_from_slice(&user_reward_account_info.data.borrow())?;
+            if user_reward_account_data.mint != contract_data.reward_mint {
+                msg!("Staking [Error]: Invalid user reward token account mint");
+                return Err(StakingError::MintMismatch.into())
+            }
+            Some((reward_vault_info, reward_mint_info, user_reward_account_info))
+        } else {
+            None
+        };
+        let history_accounts = if contract_data.history_enabled {
+            Some((next_account_info(account_info_iter)?, next_account_info(account_info_iter)?))
+        } else {
+            None
+        };
+        let earned_log_accounts = if contract_data.earned_checkpoints_enabled {
+            Some((next_account_info(account_info_iter)?, next_account_info(account_info_iter)?))
+        } else {
+            None
         };
-        match user_data.stake_type {
+        let authority_info = Self::position_authority(&contract_data, Some(&user_data), user_info, account_info_iter)?;
+        if user_token_account_missing {
+            let system_program_info = next_account_info(account_info_iter)?;
+            let associated_token_program_info = next_account_info(account_info_iter)?;
+            if system_program_info.key != &system_program::ID
+                || associated_token_program_info.key != &spl_associated_token_account::ID {
+                msg!("Staking [Error]: Invalid System or Associated Token Program");
+                return Err(ProgramError::IncorrectProgramId)
+            }
+            msg!("Staking [Info]: Creating the associated token account of the user");
+            invoke(
+                &create_associated_token_account_idempotent(
+                    authority_info.key,
+                    user_info.key,
+                    &contract_data.stake_token_mint,
+                    &spl_token_2022::ID
+                ),
+                &[
+                    authority_info.clone(),
+                    user_token_account_info.clone(),
+                    user_info.clone(),
+                    mint_info.clone(),
+                    system_program_info.clone(),
+                    token_program_info.clone(),
+                    associated_token_program_info.clone()
+                ]
+            )?;
+        }
+        if unwrap_native {
+            if contract_data.stake_token_mint != spl_token_2022::native_mint::ID {
+                msg!("Staking [Error]: Only native SOL pools support unwrapping");
+                return Err(StakingError::NativeMintRequired.into())
+            }
+            let destination_data = TokenAccount::unpack_from_slice(&destination_token_account_info.data.borrow())?;
+            if destination_data.owner != *user_info.key {
+                msg!("Staking [Error]: Can only unwrap into a token account owned by the signer");
+                return Err(StakingError::InvalidDestinationTokenAccount.into())
+            }
+        }
+        // A delegate can only withdraw into accounts of the owner
+        if authority_info.key != user_info.key {
+            let destination_data = TokenAccount::unpack_from_slice(&destination_token_account_info.data.borrow())?;
+            let reward_owner = match reward_accounts {
+                Some((_, _, user_reward_account_info)) => {
+                    TokenAccount::unpack_from_slice(&user_reward_account_info.data.borrow())?.owner
+                },
+                None => *user_info.key
+            };
+            if destination_data.owner != *user_info.key || reward_owner != *user_info.key {
+                msg!("Staking [Error]: A delegate can only unstake into token accounts of the owner");
+                return Err(StakingError::InvalidDestinationTokenAccount.into())
+            }
+        }
+        // Closing the position returns its rent to the owner, which has to be writable to receive it
+        if (amount == 0 || amount == user_data.total_staked) && !user_info.is_writable {
+            msg!("Staking [Error]: The owner must be writable to receive the rent of the closed position");
+            return Err(ProgramError::InvalidAccountData)
+        }
+        let closed = match user_data.stake_type {
             StakeType::NORMAL => {
                 msg!("Staking [Info]: Performing Normal Un-staking");
                 Self::perform_unstake(
                     program_id,
                     user_info,
-                    user_token_account_info,
+                    authority_info,
+                    destination_token_account_info,
                     user_data_account_info,
                     token_program_info,
                     contract_token_account_info,
                     contract_data_account_info,
                     mint_info,
+                    reward_accounts,
+                    history_accounts,
+                    earned_log_accounts,
                     StakeType::NORMAL,
-                    contract_data.normal_staking_apy,
-                    decimals
+                    amount,
+                    decimals,
+                    clock
                 )
             },
             StakeType::LOCKED => {
@@ -383,25 +929,145 @@ impl Processor {
                 Self::perform_unstake(
                     program_id,
                     user_info,
-                    user_token_account_info,
+                    authority_info,
+                    destination_token_account_info,
                     user_data_account_info,
                     token_program_info,
                     contract_token_account_info,
                     contract_data_account_info,
                     mint_info,
+                    reward_accounts,
+                    history_accounts,
+                    earned_log_accounts,
                     StakeType::LOCKED,
-                    contract_data.locked_staking_apy,
-                    decimals
+                    amount,
+                    decimals,
+                    clock
                 )
             }
+        }?;
+        if unwrap_native {
+            // Close the wrapped SOL account so the payout is returned as lamports
+            Self::unwrap_native(user_info, destination_token_account_info, token_program_info)?;
+        }
+        if closed {
+            // The rent goes back to the owner like on CloseUserData, after the last CPI above since it
+            // leaves the user data account out
+            Self::close_pda_account(user_data_account_info, user_info)?;
+            Self::unindex_position(program_id, user_index_info, user_info.key, contract_data_account_info)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the pool total staked once `unstaked_amount` leaves it. A position larger than the
+    /// pool total means the bookkeeping drifted, which fails loudly instead of clamping to zero
+    fn checked_unstake_total(contract_data: &ContractData, unstaked_amount: u64) -> Result<u64, ProgramError> {
+        contract_data.total_staked.checked_sub(unstaked_amount).ok_or_else(|| {
+            msg!(
+                "Staking [Error]: Unstaked amount {} exceeds the pool total staked {}",
+                unstaked_amount, contract_data.total_staked
+            );
+            ProgramError::ArithmeticOverflow
+        })
+    }
+
+    /// Fee the contract adds on top of every stake token unstake payout to cover the transfer fee
+    fn unstake_fee(amount_out: u64) -> Result<u64, ProgramError> {
+        math::mul_div_u64(amount_out, 9, 100)
+    }
+
+    /// Interest `vault_balance` can pay next to `principal_out` without dipping into the principal
+    /// of `remaining_staked`. Stake token pools keep that principal in the same account and pay it back
+    /// grossed up by `unstake_fee`, so it is reserved with its fee. `grossed_up` tells whether the interest
+    /// and `principal_out` leave with the fee too, as they do in an unstake
+    fn affordable_interest(
+        contract_data: &ContractData,
+        vault_balance: u64,
+        remaining_staked: u64,
+        principal_out: u64,
+        grossed_up: bool
+    ) -> Result<u64, ProgramError> {
+        // Reward vaults hold no principal
+        if contract_data.has_reward_token() {
+            return Ok(vault_balance)
+        }
+        let reserved = math::checked_add(remaining_staked, Self::unstake_fee(remaining_staked)?)?;
+        if reserved >= vault_balance {
+            return Ok(0)
+        }
+        let free_balance = math::checked_sub(vault_balance, reserved)?;
+        let payable = if grossed_up {
+            math::mul_div_u64(free_balance, 100, 109)?
+        } else {
+            free_balance
+        };
+        if principal_out >= payable {
+            return Ok(0)
+        }
+        math::checked_sub(payable, principal_out)
+    }
+
+    /// Removes `amount` from a position that stays open and returns the principal to pay out.
+    /// The withdrawn amount keeps the interest it earned since `stake_ts` in `interest_accrued`,
+    /// except when it leaves a lock early, in which case the early withdrawal fee is charged on it instead
+    fn partial_unstake_payout(
+        contract_data: &ContractData,
+        user_data: &mut UserData,
+        stake_type: &StakeType,
+        amount: u64,
+        current_ts: u64
+    ) -> Result<(u64, u64), ProgramError> {
+        if amount > user_data.total_staked {
+            msg!("Staking [Error]: Unstake amount exceeds the staked amount");
+            return Err(StakingError::InsufficientStakeBalance.into())
+        }
+        if math::checked_sub(user_data.total_staked, amount)? < contract_data.minimum_stake_amount {
+            msg!("Staking [Error]: Partial unstake would leave less than the minimum stake amount");
+            return Err(StakingError::UnstakeLeavesDust.into())
         }
+        let on_time = match stake_type {
+            StakeType::NORMAL => {
+                // Held since the first stake, so re-staking dust does not restart the hold
+                if math::checked_sub(current_ts, user_data.created_ts)? < NORMAL_MIN_HOLD {
+                    msg!("Staking [Info]: Cannot Unstake before 24 hrs");
+                    return Err(StakingError::LockNotExpired.into());
+                }
+                true
+            },
+            StakeType::LOCKED => Self::lock_ended(contract_data, user_data, current_ts)?
+        };
+        let interest = user_data.apply_boost(
+            contract_data.accrued_interest(user_data, amount, current_ts)
+        );
+        let (principal_out, forfeited_interest) = if on_time {
+            user_data.interest_accrued = math::checked_add(user_data.interest_accrued, interest)?;
+            (amount, 0)
+        } else {
+            let early_unstake_charge = if user_data.is_fee_exempt || user_data.penalty_waived {
+                0
+            } else {
+                contract_data.early_withdrawal_charge(amount)
+            };
+            // A waiver covers a single early unstake
+            user_data.penalty_waived = false;
+            if early_unstake_charge > amount as u128 {
+                msg!("Staking [Error]: Early withdrawal charge exceeds the staked amount");
+                return Err(StakingError::PenaltyExceedsPrincipal.into());
+            }
+            ((amount as u128 - early_unstake_charge) as u64, interest)
+        };
+        user_data.total_staked = math::checked_sub(user_data.total_staked, amount)?;
+        msg!("Staking [Info]: Partial Unstake: {} Remaining: {}", principal_out, user_data.total_staked);
+        Ok((principal_out, forfeited_interest))
     }
 
     fn update_apy(
         _program_id: &Pubkey,
         accounts: &[AccountInfo],
         normal_staking_apy: u64,
-        locked_staking_apy: u64
+        locked_staking_apy: u64,
+        apy_effective_ts: u64,
+        clock: &Clock
     ) -> ProgramResult {
         // Get all accounts sent to the instruction
         let accounts_info_iter = &mut accounts.iter();
@@ -410,112 +1076,560 @@ impl Processor {
 
         // perform necessary checks
         if !admin.is_signer {
-            return Err(ProgramError::MissingRequiredSignature.into());
+            return Err(ProgramError::MissingRequiredSignature);
         }
 
         if !data_account.is_writable {
-            return Err(ProgramError::InvalidAccountData.into());
+            return Err(ProgramError::InvalidAccountData);
         }
 
         if normal_staking_apy < 1 || locked_staking_apy < 1 {
             msg!("Staking [Error]: Invalid transfer config");
-            return Err(ProgramError::InvalidInstructionData.into())
+            return Err(StakingError::InvalidApy.into())
         }
 
         let mut contract_data = ContractData::unpack_from_slice(&data_account.data.borrow())?;
+        if !contract_data.is_admin_or_operator(admin.key) {
+            msg!("Staking [Error]: Invalid contract data");
+            return Err(StakingError::Unauthorized.into())
+        }
+        if contract_data.max_apy != 0 && (normal_staking_apy > contract_data.max_apy || locked_staking_apy > contract_data.max_apy) {
+            msg!("Staking [Error]: Staking apy cannot exceed the maximum apy of {}", contract_data.max_apy);
+            return Err(StakingError::ApyAboveMaximum.into())
+        }
+        // Promote a scheduled APY that already took effect before replacing the schedule. Nothing
+        // accrues while accrual is frozen, the checkpoint recorded when it resumes covers that time
+        let current_ts = clock.unix_timestamp as u64;
+        if contract_data.apy_effective_ts != 0 && current_ts >= contract_data.apy_effective_ts {
+            if !contract_data.rewards_paused {
+                contract_data.record_apy_checkpoint(contract_data.apy_effective_ts);
+            }
+            (contract_data.normal_staking_apy, contract_data.locked_staking_apy) = contract_data.apy_at(current_ts);
+        }
+        if apy_effective_ts > current_ts {
+            msg!("Staking [Info]: APY change scheduled for {}", apy_effective_ts);
+            contract_data.pending_normal_apy = normal_staking_apy;
+            contract_data.pending_locked_apy = locked_staking_apy;
+            contract_data.apy_effective_ts = apy_effective_ts;
+        } else {
+            if !contract_data.rewards_paused {
+                contract_data.record_apy_checkpoint(current_ts);
+            }
+            contract_data.normal_staking_apy = normal_staking_apy;
+            contract_data.locked_staking_apy = locked_staking_apy;
+            contract_data.pending_normal_apy = 0;
+            contract_data.pending_locked_apy = 0;
+            contract_data.apy_effective_ts = 0;
+        }
+        ContractData::pack(contract_data, &mut data_account.try_borrow_mut_data()?)?;
+        Ok(())
+    }
+
+    fn set_promo_boost(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        start_ts: u64,
+        end_ts: u64,
+        multiplier_bps: u64,
+        clock: &Clock
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let admin = next_account_info(accounts_info_iter)?;
+        let data_account = next_account_info(accounts_info_iter)?;
+
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !data_account.is_writable {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let mut contract_data = Self::unpack_contract_data(program_id, data_account)?;
         if &contract_data.admin_pubkey != admin.key {
             msg!("Staking [Error]: Invalid contract data");
-            return Err(ProgramError::InvalidAccountData.into())
+            return Err(StakingError::Unauthorized.into())
         }
-        contract_data.normal_staking_apy = normal_staking_apy;
-        contract_data.locked_staking_apy = locked_staking_apy;
+        let current_ts = clock.unix_timestamp as u64;
+        // Interest of a running window is already being earned, it has to run its course
+        if contract_data.promo_multiplier_bps != 0
+            && contract_data.promo_start_ts <= current_ts
+            && current_ts < contract_data.promo_end_ts {
+            msg!("Staking [Error]: Promotional boost window is running until {}", contract_data.promo_end_ts);
+            return Err(StakingError::PromoBoostActive.into())
+        }
+        if multiplier_bps != 0 && (
+            multiplier_bps <= PROMO_BASE_MULTIPLIER_BPS
+                || multiplier_bps > MAX_PROMO_MULTIPLIER_BPS
+                || start_ts < current_ts
+                || end_ts <= start_ts
+        ) {
+            msg!("Staking [Error]: Promotional boost must start in the future, end after it starts and be above 1x up to 10x");
+            return Err(StakingError::InvalidPromoBoost.into())
+        }
+        // Settle the pool totals under the old window before replacing it
+        contract_data.accrue_pending_interest(current_ts);
+        if multiplier_bps == 0 {
+            msg!("Staking [Info]: Promotional boost window removed");
+            (contract_data.promo_start_ts, contract_data.promo_end_ts) = (0, 0);
+        } else {
+            msg!("Staking [Info]: Promotional boost of {} bps from {} to {}", multiplier_bps, start_ts, end_ts);
+            (contract_data.promo_start_ts, contract_data.promo_end_ts) = (start_ts, end_ts);
+        }
+        contract_data.promo_multiplier_bps = multiplier_bps;
         ContractData::pack(contract_data, &mut data_account.try_borrow_mut_data()?)?;
         Ok(())
     }
 
-    fn perform_unstake<'a>(
+    fn close_user_data(
         program_id: &Pubkey,
-        user_info: &AccountInfo<'a>,
-        user_token_account_info: &AccountInfo<'a>,
-        user_data_account: &AccountInfo<'a>,
-        token_program_info: &AccountInfo<'a>,
-        contract_token_account_info: &AccountInfo<'a>,
-        contract_data_account: &AccountInfo<'a>,
-        mint_info: &AccountInfo<'a>,
-        stake_type: StakeType,
-        apy: u64,
-        decimals: u64
+        accounts: &[AccountInfo]
     ) -> ProgramResult {
-        // verify the user data account
-        let seeds: &[&[u8]] = &[b"spl_staking_user", user_info.key.as_ref()];
-        let (ns_user_data_pda, _bump) = Pubkey::find_program_address(
-            seeds,
-            program_id
-        );
-        if *user_data_account.key != ns_user_data_pda {
+        let accounts_info_iter = &mut accounts.iter();
+        let user_info = next_account_info(accounts_info_iter)?;
+        let user_data_account = next_account_info(accounts_info_iter)?;
+        let contract_data_account = next_account_info(accounts_info_iter)?;
+
+        if !user_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let (user_data_pda, _bump) = pda::user_data_pda(user_info.key, contract_data_account.key, program_id);
+        if *user_data_account.key != user_data_pda {
             msg!("Staking [Error]: User data account and generated pda mismatch");
-            return Err(ProgramError::InvalidAccountData.into())
+            return Err(StakingError::PdaMismatch.into())
+        }
+        if user_data_account.owner != program_id {
+            msg!("Staking [Error]: User data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let user_data = UserData::unpack_from_slice(&user_data_account.data.borrow())?;
+        if user_data.total_staked != 0 || user_data.interest_accrued != 0 {
+            msg!("Staking [Error]: Cannot close a user data account with an open position");
+            return Err(StakingError::PositionNotEmpty.into())
         }
 
-        let clock = Clock::get()?;
-        let current_ts = clock.unix_timestamp as u64;
-        let mut contract_data = ContractData::unpack_unchecked(&contract_data_account.data.borrow())?;
-        let user_data = UserData::unpack_from_slice(
-            &user_data_account.data.borrow()
-        )?;
+        // Return the rent to the owner and wipe the account
+        Self::close_pda_account(user_data_account, user_info)?;
+        user_data_account.try_borrow_mut_data()?.fill(0);
+        Ok(())
+    }
 
-        let  amount_out = match stake_type {
-            StakeType::NORMAL => {
-                let stake_duration = current_ts - user_data.stake_ts;
-                if stake_duration < 86400 {
-                    msg!("Staking [Info]: Cannot Unstake before 24 hrs");
-                    return Err(ProgramError::InvalidAccountData.into());
-                }
-                let mut interest_accrued = (
-                    (apy as u128 * user_data.total_staked as u128 * stake_duration as u128)/31536000000_u128
-                ) as u64;
-                contract_data.total_earned = contract_data.total_earned.saturating_add(interest_accrued);
-                interest_accrued = interest_accrued.add(user_data.interest_accrued);
-                msg!(
-                    "Staking[Info]: \nTotal Staked: {}\n Interest Accrued: {}\nStake Duration: {}",
-                    user_data.total_staked, interest_accrued, stake_duration
-                );
-                let amount_out = user_data.total_staked.add(interest_accrued);
-                amount_out
-            },
-            StakeType::LOCKED => {
-                let stake_duration = current_ts - user_data.stake_ts;
-                let amount_out: u64;
-                if stake_duration >= user_data.lock_duration {
-                    let mut interest_accrued = (
-                        (apy as u128 * user_data.total_staked as u128 * stake_duration as u128)/31536000000_u128
-                    ) as u64;
-                    contract_data.total_earned = contract_data.total_earned.saturating_add(interest_accrued);
-                    interest_accrued = interest_accrued.add(user_data.interest_accrued);
-                    amount_out = interest_accrued.add(user_data.total_staked);
-                } else {
-                    let early_unstake_charge = (contract_data.early_withdrawal_fee as u128 * user_data.total_staked as u128)/1000_u128;
-                    amount_out = (user_data.total_staked as u128 - early_unstake_charge) as u64;
-                }
-                msg!("Staking [Info]: Amount Out: {} Total Staked: {}", amount_out, user_data.total_staked);
-                amount_out
-            }
-        };
-        // Transfer tokens to the user
-        let seeds: &[&[u8]] = &[
-            b"spl_staking",
-            contract_data.admin_pubkey.as_ref(),
-            contract_data.stake_token_mint.as_ref()
-        ];
-        let (authority_pda, pda_bump) = Pubkey::find_program_address(seeds, program_id);
-        let fee = ((9 * amount_out as u128)/100) as u64;
-        let amount_out_with_fee = amount_out + fee;
-        let new_fee = Self::get_transfer_fee(mint_info, amount_out_with_fee);
-        msg!("Amount Out: {} Amount Out With Fee: {} Fee: {}", amount_out, amount_out_with_fee, new_fee);
+    fn migrate_user_data(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo]
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let owner_info = next_account_info(accounts_info_iter)?;
+        let legacy_user_data_account = next_account_info(accounts_info_iter)?;
+        let user_data_account = next_account_info(accounts_info_iter)?;
+        let contract_data_account = next_account_info(accounts_info_iter)?;
+        let system_program_info = next_account_info(accounts_info_iter)?;
+        let user_index_info = next_account_info(accounts_info_iter)?;
+
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !owner_info.is_writable || !legacy_user_data_account.is_writable || !user_data_account.is_writable {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if system_program_info.key != &system_program::ID {
+            msg!("Staking [Error]: Invalid System Program");
+            return Err(ProgramError::IncorrectProgramId)
+        }
+        let contract_data = Self::unpack_contract_data(program_id, contract_data_account)?;
+        let (legacy_user_data_pda, _bump) = pda::legacy_user_data_pda(owner_info.key, program_id);
+        if *legacy_user_data_account.key != legacy_user_data_pda {
+            msg!("Staking [Error]: Legacy user data account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        if legacy_user_data_account.owner != program_id {
+            msg!("Staking [Error]: Legacy user data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let mut user_data = UserData::unpack_from_slice(&legacy_user_data_account.data.borrow())?;
+        if user_data.pool == Pubkey::default() {
+            // Nothing on the account tells which pool the position was opened in, the pool admin vouches for it
+            let admin_info = next_account_info(accounts_info_iter)?;
+            if !admin_info.is_signer {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            if admin_info.key != &contract_data.admin_pubkey {
+                msg!("Staking [Error]: Only the pool admin can assign an unrecorded position to the pool");
+                return Err(StakingError::Unauthorized.into())
+            }
+            user_data.pool = *contract_data_account.key;
+        } else {
+            Self::check_position_pool(&user_data, contract_data_account)?;
+        }
+        let (user_data_pda, bump) = pda::user_data_pda(owner_info.key, contract_data_account.key, program_id);
+        if *user_data_account.key != user_data_pda {
+            msg!("Staking [Error]: User data account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        if user_data_account.data_len() != 0 || user_data_account.owner != &system_program::ID {
+            msg!("Staking [Error]: User data account already exists");
+            return Err(ProgramError::AccountAlreadyInitialized)
+        }
+
+        let signer_seeds: &[&[u8]] = &[
+            pda::USER_DATA_SEED,
+            owner_info.key.as_ref(),
+            contract_data_account.key.as_ref(),
+            &[bump]
+        ];
+        Self::create_pda_account(
+            program_id,
+            owner_info,
+            user_data_account,
+            system_program_info,
+            UserData::LEN,
+            signer_seeds
+        )?;
+        let open = user_data.is_initialized;
+        // Written in the current layout, whatever layout the legacy account was packed with
+        UserData::pack(user_data, &mut user_data_account.try_borrow_mut_data()?)?;
+        if open {
+            Self::index_position(
+                program_id,
+                user_index_info,
+                owner_info.key,
+                contract_data_account,
+                owner_info,
+                system_program_info
+            )?;
+        }
+        // The rent is moved after the CPI above, which leaves the legacy account out
+        legacy_user_data_account.try_borrow_mut_data()?.fill(0);
+        Self::close_pda_account(legacy_user_data_account, owner_info)?;
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn stake_with_setup(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        stake_type: StakeType,
+        amount: u64,
+        lock_duration: u64,
+        decimals: u64,
+        wrap_native: bool,
+        clock: &Clock
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let user_info = next_account_info(accounts_info_iter)?;
+        let _user_token_account_info = next_account_info(accounts_info_iter)?;
+        let user_data_account = next_account_info(accounts_info_iter)?;
+        let contract_data_account = accounts_info_iter.nth(1).ok_or(ProgramError::NotEnoughAccountKeys)?;
+        let system_program_info = accounts_info_iter.nth(2).ok_or(ProgramError::NotEnoughAccountKeys)?;
+
+        if user_data_account.data_len() == 0 {
+            // The user pays for its own account, create_user_data checks the signature and the pda
+            Self::create_user_data(
+                program_id,
+                &[
+                    user_info.clone(),
+                    user_info.clone(),
+                    user_data_account.clone(),
+                    system_program_info.clone(),
+                    contract_data_account.clone()
+                ]
+            )?;
+        }
+        Self::stake(
+            program_id,
+            accounts,
+            stake_type,
+            amount,
+            lock_duration,
+            decimals,
+            wrap_native,
+            false,
+            clock
+        )
+    }
+
+    fn create_user_data(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo]
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let payer_info = next_account_info(accounts_info_iter)?;
+        let owner_info = next_account_info(accounts_info_iter)?;
+        let user_data_account = next_account_info(accounts_info_iter)?;
+        let system_program_info = next_account_info(accounts_info_iter)?;
+        let contract_data_info = next_account_info(accounts_info_iter)?;
+
+        if !payer_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !user_data_account.is_writable {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if system_program_info.key != &system_program::ID {
+            msg!("Staking [Error]: Invalid System Program");
+            return Err(ProgramError::IncorrectProgramId)
+        }
+        // Only an initialized pool can hold positions
+        Self::unpack_contract_data(program_id, contract_data_info)?;
+        let (user_data_pda, bump) = pda::user_data_pda(owner_info.key, contract_data_info.key, program_id);
+        if *user_data_account.key != user_data_pda {
+            msg!("Staking [Error]: User data account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        if user_data_account.data_len() != 0 || user_data_account.owner != &system_program::ID {
+            msg!("Staking [Error]: User data account already exists");
+            return Err(ProgramError::AccountAlreadyInitialized)
+        }
+
+        let signer_seeds: &[&[u8]] = &[
+            pda::USER_DATA_SEED,
+            owner_info.key.as_ref(),
+            contract_data_info.key.as_ref(),
+            &[bump]
+        ];
+        Self::create_pda_account(
+            program_id,
+            payer_info,
+            user_data_account,
+            system_program_info,
+            UserData::LEN,
+            signer_seeds
+        )?;
+        // The position itself is opened by the first stake, which sets is_initialized
+        let mut user_data = UserData::unpack_from_slice(&user_data_account.data.borrow())?;
+        user_data.owner_pubkey = *owner_info.key;
+        user_data.boost_bps = UserData::BASE_BOOST_BPS;
+        user_data.pool = *contract_data_info.key;
+        user_data.pack_into_slice(&mut user_data_account.try_borrow_mut_data()?);
+        Ok(())
+    }
+
+    /// Pays out the unstake, returns whether the position was closed so the caller can move its rent
+    #[allow(clippy::too_many_arguments)]
+    fn perform_unstake<'a>(
+        program_id: &Pubkey,
+        user_info: &AccountInfo<'a>,
+        authority_info: &AccountInfo<'a>,
+        user_token_account_info: &AccountInfo<'a>,
+        user_data_account: &AccountInfo<'a>,
+        token_program_info: &AccountInfo<'a>,
+        contract_token_account_info: &AccountInfo<'a>,
+        contract_data_account: &AccountInfo<'a>,
+        mint_info: &AccountInfo<'a>,
+        reward_accounts: Option<(&AccountInfo<'a>, &AccountInfo<'a>, &AccountInfo<'a>)>,
+        history_accounts: Option<(&AccountInfo<'a>, &AccountInfo<'a>)>,
+        earned_log_accounts: Option<(&AccountInfo<'a>, &AccountInfo<'a>)>,
+        stake_type: StakeType,
+        amount: u64,
+        decimals: u64,
+        clock: &Clock
+    ) -> Result<bool, ProgramError> {
+        // verify the user data account
+        let (ns_user_data_pda, _bump) = pda::user_data_pda(user_info.key, contract_data_account.key, program_id);
+        if *user_data_account.key != ns_user_data_pda {
+            msg!("Staking [Error]: User data account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+
+        let current_ts = clock.unix_timestamp as u64;
+        let mut contract_data = ContractData::unpack_unchecked(&contract_data_account.data.borrow())?;
+        let mut user_data = UserData::unpack_from_slice(
+            &user_data_account.data.borrow()
+        )?;
+        contract_data.accrue_pending_interest(current_ts);
+
+        let partial = amount != 0 && amount != user_data.total_staked;
+        let (principal_out, interest_out) = if partial {
+            let (principal_out, forfeited_interest) = Self::partial_unstake_payout(
+                &contract_data, &mut user_data, &stake_type, amount, current_ts
+            )?;
+            contract_data.settle_pending_interest(forfeited_interest);
+            (principal_out, 0)
+        } else {
+            // Whatever part of the interest is not paid out below is forfeited
+            let position_interest = contract_data.position_interest(&user_data, current_ts);
+            contract_data.settle_pending_interest(position_interest);
+            match stake_type {
+                StakeType::NORMAL => {
+                    let stake_duration = math::checked_sub(current_ts, user_data.stake_ts)?;
+                    if math::checked_sub(current_ts, user_data.created_ts)? < NORMAL_MIN_HOLD {
+                        msg!("Staking [Info]: Cannot Unstake before 24 hrs");
+                        return Err(StakingError::LockNotExpired.into());
+                    }
+                    let interest_accrued = contract_data.cap_reward(math::checked_interest(
+                        contract_data.accrued_interest(&user_data, user_data.total_staked, current_ts),
+                        user_data.boost_bps,
+                        user_data.interest_accrued
+                    )?);
+                    contract_data.total_earned = math::checked_add(contract_data.total_earned, interest_accrued)?;
+                    msg!(
+                        "Staking[Info]: \nTotal Staked: {}\n Interest Accrued: {}\nStake Duration: {}",
+                        user_data.total_staked, interest_accrued, stake_duration
+                    );
+                    (user_data.total_staked, interest_accrued)
+                },
+                StakeType::LOCKED => {
+                    let payout: (u64, u64);
+                    // Unstaking within the grace period before the lock ends counts as on time
+                    if Self::lock_ended(&contract_data, &user_data, current_ts)? {
+                        let interest_accrued = contract_data.cap_reward(math::checked_interest(
+                            contract_data.accrued_interest(&user_data, user_data.total_staked, current_ts),
+                            user_data.boost_bps,
+                            user_data.interest_accrued
+                        )?);
+                        contract_data.total_earned = math::checked_add(contract_data.total_earned, interest_accrued)?;
+                        payout = (user_data.total_staked, interest_accrued);
+                    } else {
+                        let early_unstake_charge = if user_data.is_fee_exempt || user_data.penalty_waived {
+                            0
+                        } else {
+                            contract_data.early_withdrawal_charge(user_data.total_staked)
+                        };
+                        let principal_out = u64::try_from(early_unstake_charge).ok()
+                            .and_then(|charge| user_data.total_staked.checked_sub(charge))
+                            .ok_or_else(|| {
+                                msg!("Staking [Error]: Early withdrawal charge exceeds the staked amount");
+                                StakingError::PenaltyExceedsPrincipal
+                            })?;
+                        // Exiting early forfeits the interest of the running lock only, interest
+                        // snapshotted by earlier re-stakes or partial unstakes was already earned
+                        let interest_accrued = contract_data.cap_reward(user_data.interest_accrued);
+                        contract_data.total_earned = math::checked_add(contract_data.total_earned, interest_accrued)?;
+                        payout = (principal_out, interest_accrued);
+                    }
+                    msg!(
+                        "Staking [Info]: Amount Out: {} Total Staked: {}",
+                        math::checked_add(payout.0, payout.1)?, user_data.total_staked
+                    );
+                    payout
+                }
+            }
+        };
+        let unstaked_amount = if partial { amount } else { user_data.total_staked };
+        // The interest may not dip into the principal that stays staked. A vault running low pays whatever
+        // interest it can still cover and trips the circuit breaker, so the principal stays recoverable
+        let interest_out = if interest_out > 0 {
+            let vault_balance = match reward_accounts {
+                Some((reward_vault_info, _, _)) => TokenAccount::unpack_from_slice(&reward_vault_info.data.borrow())?.amount,
+                None => TokenAccount::unpack_from_slice(&contract_token_account_info.data.borrow())?.amount
+            };
+            let affordable_interest = Self::affordable_interest(
+                &contract_data,
+                vault_balance,
+                Self::checked_unstake_total(&contract_data, unstaked_amount)?,
+                principal_out,
+                true
+            )?;
+            if affordable_interest >= interest_out {
+                interest_out
+            } else {
+                let shortfall = math::checked_sub(interest_out, affordable_interest)?;
+                msg!(
+                    "Staking [Info]: Interest shortfall of {}, paying {} of {}",
+                    shortfall, affordable_interest, interest_out
+                );
+                contract_data.total_earned = math::checked_sub(contract_data.total_earned, shortfall)?;
+                Self::trip_circuit_breaker(&mut contract_data);
+                affordable_interest
+            }
+        } else {
+            interest_out
+        };
+        // Reward token pools pay the interest separately from the reward vault
+        let amount_out = match reward_accounts {
+            Some(_) => principal_out,
+            None => math::checked_add(principal_out, interest_out)?
+        };
+        // The early withdrawal charge is what the principal paid out falls short of the amount unstaked
+        let penalty_burn = math::bps(math::checked_sub(unstaked_amount, principal_out)?, contract_data.penalty_burn_bps)?;
+        // Checks: the vaults must cover the payout before any state changes
+        let fee = Self::unstake_fee(amount_out)?;
+        let amount_out_with_fee = math::checked_add(amount_out, fee)?;
+        let contract_token_account_data = TokenAccount::unpack_from_slice(&contract_token_account_info.data.borrow())?;
+        if math::checked_add(amount_out_with_fee, penalty_burn)? > contract_token_account_data.amount {
+            msg!(
+                "Staking [Error]: Payout {} exceeds the contract token balance {}",
+                amount_out_with_fee, contract_token_account_data.amount
+            );
+            return Err(StakingError::InsufficientContractBalance.into());
+        }
+        if let Some((reward_vault_info, _, _)) = reward_accounts {
+            let reward_vault_data = TokenAccount::unpack_from_slice(&reward_vault_info.data.borrow())?;
+            if interest_out > reward_vault_data.amount {
+                msg!(
+                    "Staking [Error]: Interest {} exceeds the reward token balance {}",
+                    interest_out, reward_vault_data.amount
+                );
+                return Err(StakingError::InsufficientContractBalance.into());
+            }
+        }
+        // The fee comes from the live mint only, it may differ from the fee recorded at init
+        let new_fee = Self::get_transfer_fee(mint_info, &contract_data.stake_token_mint, amount_out_with_fee)?;
+        // Dust payouts would mostly go to the mint fee. Nothing paid out is left alone, pools may confiscate
+        // the whole principal of an early exit
+        let net_payout = math::checked_sub(amount_out_with_fee, new_fee)?;
+        if amount_out > 0 && new_fee >= net_payout {
+            msg!(
+                "Staking [Error]: Payout of {} is too small to unstake, the transfer fee would withhold {}",
+                net_payout, new_fee
+            );
+            return Err(StakingError::UnstakeAmountTooSmall.into());
+        }
+        let (admin_pubkey, stake_token_mint, reward_mint) = (
+            contract_data.admin_pubkey, contract_data.stake_token_mint, contract_data.reward_mint
+        );
+
+        // Effects: settle the position and the pool before handing control to another program,
+        // a failed transfer below still reverts these writes with the transaction
+        contract_data.total_staked = Self::checked_unstake_total(&contract_data, unstaked_amount)?;
+        if let StakeType::LOCKED = user_data.stake_type {
+            contract_data.total_locked_staked = math::checked_sub(contract_data.total_locked_staked, unstaked_amount)?;
+        }
+        if partial {
+            // Keep the remaining position open
+            user_data.last_unstake_ts = current_ts;
+            UserData::pack(user_data, &mut user_data_account.try_borrow_mut_data()?)?;
+        } else {
+            // Reset User Account and Contract Account. The rent is moved once the transfers are done,
+            // lamports may not shift between accounts left out of a CPI before it runs
+            contract_data.active_stakers = math::checked_sub(contract_data.active_stakers, 1)?;
+            user_data_account.try_borrow_mut_data()?.fill(0);
+        }
+        // Taken after any interest shortfall was written off
+        let checkpoint = EarnedCheckpoint { ts: current_ts, total_earned: contract_data.total_earned };
+        ContractData::pack(contract_data, &mut contract_data_account.try_borrow_mut_data()?)?;
+
+        // Interactions
+        if let Some((history_log_info, system_program_info)) = history_accounts {
+            Self::append_history(
+                program_id,
+                history_log_info,
+                contract_data_account,
+                authority_info,
+                system_program_info,
+                HistoryRecord {
+                    ts: current_ts,
+                    user: *user_info.key,
+                    amount: unstaked_amount,
+                    action: HistoryAction::Unstake
+                }
+            )?;
+        }
+        if let Some((earned_log_info, system_program_info)) = earned_log_accounts {
+            Self::record_earned_checkpoint(
+                program_id,
+                earned_log_info,
+                contract_data_account,
+                authority_info,
+                system_program_info,
+                checkpoint
+            )?;
+        }
+        // Transfer tokens to the user
+        let (authority_pda, pda_bump) = pda::contract_data_pda(&admin_pubkey, &stake_token_mint, program_id);
+        msg!("Amount Out: {} Amount Out With Fee: {} Fee: {}", amount_out, amount_out_with_fee, new_fee);
         let token_transfer_ix = transfer_checked_with_fee(
             token_program_info.key,
             contract_token_account_info.key,
-            &contract_data.stake_token_mint,
+            &stake_token_mint,
             user_token_account_info.key,
             &authority_pda,
             &[&authority_pda],
@@ -524,54 +1638,2363 @@ impl Processor {
             new_fee
         )?;
         let signer_seeds: &[&[u8]] = &[
-            b"spl_staking",
-            contract_data.admin_pubkey.as_ref(),
-            contract_data.stake_token_mint.as_ref(),
+            pda::CONTRACT_DATA_SEED,
+            admin_pubkey.as_ref(),
+            stake_token_mint.as_ref(),
+            &[pda_bump]
+        ];
+        msg!("About to send tokens");
+        invoke_signed(
+            &token_transfer_ix,
+            &[
+                contract_token_account_info.clone(),
+                mint_info.clone(),
+                user_token_account_info.clone(),
+                contract_data_account.clone(),
+                token_program_info.clone(),
+            ],
+            &[signer_seeds],
+        )?;
+        msg!("Sent tokens");
+        if penalty_burn > 0 {
+            invoke_signed(
+                &spl_token_2022::instruction::burn_checked(
+                    token_program_info.key,
+                    contract_token_account_info.key,
+                    &stake_token_mint,
+                    &authority_pda,
+                    &[&authority_pda],
+                    penalty_burn,
+                    decimals as u8
+                )?,
+                &[
+                    contract_token_account_info.clone(),
+                    mint_info.clone(),
+                    contract_data_account.clone(),
+                    token_program_info.clone(),
+                ],
+                &[signer_seeds],
+            )?;
+            msg!("Burned penalty tokens: {}", penalty_burn);
+        }
+        if let Some((reward_vault_info, reward_mint_info, user_reward_account_info)) = reward_accounts {
+            if interest_out > 0 {
+                let reward_decimals = StateWithExtensions::<Mint>::unpack(&reward_mint_info.data.borrow())?.base.decimals;
+                let reward_fee = Self::get_transfer_fee(reward_mint_info, &reward_mint, interest_out)?;
+                invoke_signed(
+                    &transfer_checked_with_fee(
+                        token_program_info.key,
+                        reward_vault_info.key,
+                        reward_mint_info.key,
+                        user_reward_account_info.key,
+                        &authority_pda,
+                        &[&authority_pda],
+                        interest_out,
+                        reward_decimals,
+                        reward_fee
+                    )?,
+                    &[
+                        reward_vault_info.clone(),
+                        reward_mint_info.clone(),
+                        user_reward_account_info.clone(),
+                        contract_data_account.clone(),
+                        token_program_info.clone(),
+                    ],
+                    &[signer_seeds],
+                )?;
+                msg!("Sent reward tokens: {}", interest_out);
+            }
+        }
+        // Set after the last transfer, a program invoked by the contract could overwrite it otherwise
+        set_return_data(&UnstakeReceipt {
+            principal: unstaked_amount,
+            gross_interest: interest_out,
+            penalty: math::checked_sub(unstaked_amount, principal_out)?,
+            mint_transfer_fee: new_fee,
+            net_paid: net_payout
+        }.try_to_vec()?);
+        Ok(!partial)
+    }
+
+    fn update_token_account(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        decimals: u64
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let admin = next_account_info(accounts_info_iter)?;
+        let data_account = next_account_info(accounts_info_iter)?;
+        let token_account_info = next_account_info(accounts_info_iter)?;
+        let new_token_account_info = next_account_info(accounts_info_iter)?;
+        let mint_info = next_account_info(accounts_info_iter)?;
+        let token_program_info = next_account_info(accounts_info_iter)?;
+
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !data_account.is_writable {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let mut contract_data = ContractData::unpack_from_slice(&data_account.data.borrow())?;
+        if &contract_data.admin_pubkey != admin.key {
+            msg!("Staking [Error]: Invalid contract data");
+            return Err(StakingError::Unauthorized.into())
+        }
+        let (contract_data_pda, pda_bump) = pda::contract_data_pda(
+            &contract_data.admin_pubkey, &contract_data.stake_token_mint, program_id
+        );
+        if &contract_data_pda != data_account.key {
+            msg!("Staking [Error]: Invalid contract data account");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        if token_account_info.key != &contract_data.stake_token_account {
+            msg!("Staking [Error]: Invalid contract token account");
+            return Err(StakingError::InvalidContractTokenAccount.into())
+        }
+        if mint_info.key != &contract_data.stake_token_mint {
+            msg!("Staking [Error]: Mint account does not match the staking mint");
+            return Err(StakingError::MintMismatch.into())
+        }
+        if token_program_info.key != &spl_token_2022::ID {
+            msg!("Staking [Error]: Invalid Token Program. Contract supports TOKEN 2022 only");
+            return Err(StakingError::UnsupportedTokenProgram.into())
+        }
+        if new_token_account_info.owner != &spl_token_2022::ID {
+            msg!("Staking [Error]: Invalid Token Account. Supports only Token 2022 Accounts");
+            return Err(StakingError::UnsupportedTokenProgram.into())
+        }
+        if new_token_account_info.key == token_account_info.key {
+            msg!("Staking [Error]: New token account is the current token account");
+            return Err(StakingError::InvalidContractTokenAccount.into())
+        }
+        let new_token_account_data = TokenAccount::unpack_from_slice(&new_token_account_info.data.borrow())?;
+        if new_token_account_data.mint != contract_data.stake_token_mint {
+            msg!("Staking [Error]: Invalid new token account mint");
+            return Err(StakingError::MintMismatch.into())
+        }
+        if new_token_account_data.owner != contract_data_pda {
+            msg!("Staking [Error]: New token account is not owned by the contract data pda");
+            return Err(StakingError::InvalidContractTokenAccount.into())
+        }
+
+        // Move the existing balance into the new token account
+        let token_account_data = TokenAccount::unpack_from_slice(&token_account_info.data.borrow())?;
+        if token_account_data.amount > 0 {
+            let fee = Self::get_transfer_fee(mint_info, &contract_data.stake_token_mint, token_account_data.amount)?;
+            let token_transfer_ix = transfer_checked_with_fee(
+                token_program_info.key,
+                token_account_info.key,
+                mint_info.key,
+                new_token_account_info.key,
+                &contract_data_pda,
+                &[&contract_data_pda],
+                token_account_data.amount,
+                decimals as u8,
+                fee
+            )?;
+            let signer_seeds: &[&[u8]] = &[
+                pda::CONTRACT_DATA_SEED,
+                contract_data.admin_pubkey.as_ref(),
+                contract_data.stake_token_mint.as_ref(),
+                &[pda_bump]
+            ];
+            invoke_signed(
+                &token_transfer_ix,
+                &[
+                    token_account_info.clone(),
+                    mint_info.clone(),
+                    new_token_account_info.clone(),
+                    data_account.clone(),
+                    token_program_info.clone(),
+                ],
+                &[signer_seeds],
+            )?;
+        }
+        msg!("Staking [Info]: Stake token account rotated to {}", new_token_account_info.key);
+        contract_data.stake_token_account = *new_token_account_info.key;
+        ContractData::pack(contract_data, &mut data_account.try_borrow_mut_data()?)?;
+        Ok(())
+    }
+
+    fn snapshot_position(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        weight_by_lock: bool,
+        clock: &Clock
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let user_data_account = next_account_info(accounts_info_iter)?;
+
+        if user_data_account.owner != program_id {
+            msg!("Staking [Error]: User data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let user_data = UserData::unpack_from_slice(&user_data_account.data.borrow())?;
+        if !user_data.is_initialized {
+            return Err(ProgramError::UninitializedAccount)
+        }
+        let (user_data_pda, _bump) = pda::user_data_pda(&user_data.owner_pubkey, &user_data.pool, program_id);
+        if *user_data_account.key != user_data_pda {
+            msg!("Staking [Error]: User data account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+
+        let mut weight = user_data.total_staked;
+        if weight_by_lock {
+            if let StakeType::LOCKED = user_data.stake_type {
+                let current_ts = clock.unix_timestamp as u64;
+                // A re-stake, ExtendLock or ClaimAndStake may have pushed the unlock past the running lock
+                let lock_end = user_data.lock_end_ts().ok_or_else(|| {
+                    msg!("Staking [Error]: Lock end of the position overflows❌");
+                    ProgramError::from(StakingError::LockDurationTooLong)
+                })?;
+                let lock_span = lock_end.saturating_sub(user_data.stake_ts);
+                let remaining_lock = lock_end.saturating_sub(current_ts).min(lock_span);
+                if lock_span > 0 {
+                    weight = weight.saturating_add((
+                        (user_data.total_staked as u128 * remaining_lock as u128)/lock_span as u128
+                    ) as u64);
+                }
+            }
+        }
+        let snapshot = PositionSnapshot {
+            owner_pubkey: user_data.owner_pubkey,
+            total_staked: user_data.total_staked,
+            stake_type: user_data.stake_type,
+            stake_ts: user_data.stake_ts,
+            weight
+        };
+        set_return_data(&snapshot.try_to_vec()?);
+        Ok(())
+    }
+
+    fn get_user_index(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo]
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let owner_info = next_account_info(accounts_info_iter)?;
+        let user_index_info = next_account_info(accounts_info_iter)?;
+
+        let (user_index_pda, _bump) = pda::user_index_pda(owner_info.key, program_id);
+        if *user_index_info.key != user_index_pda {
+            msg!("Staking [Error]: User index account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        // An owner that never staked has no index account yet
+        let mut positions = vec![];
+        if user_index_info.data_len() != 0 {
+            if user_index_info.owner != program_id {
+                msg!("Staking [Error]: User index account is not owned by the program");
+                return Err(ProgramError::IllegalOwner)
+            }
+            let index_data = user_index_info.data.borrow();
+            let index = PositionIndex::unpack_from_slice(&index_data)?;
+            for slot in 0..index.position_count {
+                let offset = PositionIndex::slot_offset(slot);
+                positions.push(
+                    Pubkey::try_from(&index_data[offset..offset + 32]).map_err(|_| ProgramError::InvalidAccountData)?
+                );
+            }
+        }
+        let index = UserIndex {
+            owner_pubkey: *owner_info.key,
+            positions
+        };
+        set_return_data(&index.try_to_vec()?);
+        Ok(())
+    }
+
+    fn get_unlock_ts(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo]
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let user_data_account = next_account_info(accounts_info_iter)?;
+
+        if user_data_account.owner != program_id {
+            msg!("Staking [Error]: User data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let user_data = UserData::unpack_from_slice(&user_data_account.data.borrow())?;
+        if !user_data.is_initialized {
+            return Err(ProgramError::UninitializedAccount)
+        }
+        let unlock_ts = match user_data.stake_type {
+            StakeType::NORMAL => 0,
+            StakeType::LOCKED => user_data.lock_end_ts().ok_or_else(|| {
+                msg!("Staking [Error]: Lock end of the position overflows❌");
+                ProgramError::from(StakingError::LockDurationTooLong)
+            })?
+        };
+        set_return_data(&unlock_ts.try_to_vec()?);
+        Ok(())
+    }
+
+    fn validate_stake(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        stake_type: StakeType,
+        amount: u64,
+        lock_duration: u64,
+        clock: &Clock
+    ) -> ProgramResult {
+        // The decimals are taken from the mint, a mint that is not the staking mint fails the stake checks anyway
+        let decimals = match accounts.get(5) {
+            Some(mint_info) => StateWithExtensions::<Mint>::unpack(&mint_info.data.borrow())
+                .map_or(0, |mint| mint.base.decimals as u64),
+            None => 0
+        };
+        let result = Self::stake(program_id, accounts, stake_type, amount, lock_duration, decimals, false, true, clock);
+        let stake_validation = StakeValidation {
+            error_code: result.map_or_else(u64::from, |_| 0)
+        };
+        set_return_data(&stake_validation.try_to_vec()?);
+        Ok(())
+    }
+
+    fn read_history(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        start: u64,
+        count: u64
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let data_account = next_account_info(accounts_info_iter)?;
+        let history_log_info = next_account_info(accounts_info_iter)?;
+
+        if data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let (history_log_pda, _bump) = pda::history_log_pda(data_account.key, program_id);
+        if *history_log_info.key != history_log_pda {
+            msg!("Staking [Error]: History log account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        if history_log_info.owner != program_id {
+            msg!("Staking [Error]: History log account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let history_data = history_log_info.data.borrow();
+        let history_log = HistoryLog::unpack_from_slice(&history_data)?;
+        if !history_log.is_initialized {
+            return Err(ProgramError::UninitializedAccount)
+        }
+        let end = start
+            .saturating_add(count.min(HistoryLog::PAGE_SIZE))
+            .min(history_log.record_count);
+        let records = (start..end)
+            .map(|index| {
+                let offset = HistoryLog::record_offset(index);
+                HistoryRecord::try_from_slice(&history_data[offset..offset + HistoryRecord::LEN])
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        set_return_data(&records.try_to_vec()?);
+        Ok(())
+    }
+
+    fn read_earned_checkpoints(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        start: u64,
+        count: u64
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let data_account = next_account_info(accounts_info_iter)?;
+        let earned_log_info = next_account_info(accounts_info_iter)?;
+
+        if data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let (earned_log_pda, _bump) = pda::earned_log_pda(data_account.key, program_id);
+        if *earned_log_info.key != earned_log_pda {
+            msg!("Staking [Error]: Earned checkpoint log account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        if earned_log_info.owner != program_id {
+            msg!("Staking [Error]: Earned checkpoint log account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let earned_data = earned_log_info.data.borrow();
+        let earned_log = EarnedLog::unpack_from_slice(&earned_data)?;
+        if !earned_log.is_initialized {
+            return Err(ProgramError::UninitializedAccount)
+        }
+        let start = start.max(earned_log.first_retained());
+        let end = start
+            .saturating_add(count.min(EarnedLog::PAGE_SIZE))
+            .min(earned_log.checkpoint_count);
+        let checkpoints = (start..end)
+            .map(|index| {
+                let offset = EarnedLog::checkpoint_offset(index);
+                EarnedCheckpoint::try_from_slice(&earned_data[offset..offset + EarnedCheckpoint::LEN])
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        set_return_data(&checkpoints.try_to_vec()?);
+        Ok(())
+    }
+
+    fn preview_rewards(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        stake_type: StakeType,
+        amount: u64,
+        duration: u64,
+        clock: &Clock
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let data_account = next_account_info(accounts_info_iter)?;
+
+        if data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let contract_data = ContractData::unpack_from_slice(&data_account.data.borrow())?;
+        let current_ts = clock.unix_timestamp as u64;
+        let interest = if contract_data.streams_rewards() {
+            // A new stake shares the stream with the principal staked now
+            let pool_staked = contract_data.total_staked as u128 + amount as u128;
+            ((contract_data.reward_per_second as u128 * duration as u128 * amount as u128) / pool_staked.max(1)) as u64
+        } else {
+            contract_data.interest_between(&stake_type, amount, current_ts, current_ts.saturating_add(duration))
+        };
+        let early_withdrawal_charge = match stake_type {
+            StakeType::NORMAL => 0,
+            StakeType::LOCKED => contract_data.early_withdrawal_charge(amount) as u64
+        };
+        let preview = RewardPreview {
+            interest: contract_data.cap_reward(interest),
+            early_withdrawal_charge
+        };
+        set_return_data(&preview.try_to_vec()?);
+        Ok(())
+    }
+
+    fn get_claimable(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        clock: &Clock
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let user_data_account = next_account_info(accounts_info_iter)?;
+        let data_account = next_account_info(accounts_info_iter)?;
+        let vault_info = next_account_info(accounts_info_iter)?;
+
+        let contract_data = Self::unpack_contract_data(program_id, data_account)?;
+        if user_data_account.owner != program_id {
+            msg!("Staking [Error]: User data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let user_data = UserData::unpack_from_slice(&user_data_account.data.borrow())?;
+        if !user_data.is_initialized {
+            return Err(ProgramError::UninitializedAccount)
+        }
+        let (user_data_pda, _bump) = pda::user_data_pda(&user_data.owner_pubkey, data_account.key, program_id);
+        if *user_data_account.key != user_data_pda {
+            msg!("Staking [Error]: User data account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        Self::check_position_pool(&user_data, data_account)?;
+        let interest_vault = if contract_data.has_reward_token() {
+            contract_data.reward_vault
+        } else {
+            contract_data.stake_token_account
+        };
+        if vault_info.key != &interest_vault {
+            msg!("Staking [Error]: Invalid contract token account");
+            return Err(StakingError::InvalidContractTokenAccount.into())
+        }
+
+        // Same rules as ClaimRewards, a claim that would fail can claim nothing
+        let current_ts = clock.unix_timestamp as u64;
+        let gross_interest = user_data.apply_boost(contract_data.accrued_interest(
+            &user_data, user_data.total_staked, current_ts
+        )).saturating_add(user_data.interest_accrued);
+        let mut claimable_from_ts = user_data.last_claim_ts.saturating_add(contract_data.claim_cooldown);
+        if let StakeType::LOCKED = user_data.stake_type {
+            let unlock_ts = user_data.lock_end_ts().unwrap_or(u64::MAX);
+            claimable_from_ts = claimable_from_ts.max(unlock_ts.saturating_sub(contract_data.lock_grace_period));
+        }
+        let mut claimable = contract_data.cap_reward(gross_interest);
+        let vault_balance = TokenAccount::unpack_from_slice(&vault_info.data.borrow())?.amount;
+        let affordable_interest = Self::affordable_interest(
+            &contract_data, vault_balance, contract_data.total_staked, 0, false
+        )?;
+        if contract_data.partial_reward_ok {
+            claimable = claimable.min(affordable_interest);
+        }
+        if current_ts < claimable_from_ts || claimable > affordable_interest {
+            claimable = 0;
+        }
+        let rewards = ClaimableRewards { gross_interest, claimable, claimable_from_ts };
+        set_return_data(&rewards.try_to_vec()?);
+        Ok(())
+    }
+
+    fn get_pool_apr(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        stake_type: StakeType,
+        clock: &Clock
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let data_account = next_account_info(accounts_info_iter)?;
+        let mint_info = next_account_info(accounts_info_iter)?;
+
+        if data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let contract_data = ContractData::unpack_from_slice(&data_account.data.borrow())?;
+        if *mint_info.key != contract_data.stake_token_mint {
+            msg!("Staking [Error]: Mint account does not match the pool mint");
+            return Err(StakingError::MintMismatch.into())
+        }
+        let transfer_fee_bps = {
+            let mint_data = mint_info.data.borrow();
+            let mint = StateWithExtensions::<Mint>::unpack(&mint_data)?;
+            match mint.get_extension::<TransferFeeConfig>() {
+                Ok(transfer_fee_config) => u16::from(transfer_fee_config.get_epoch_fee(clock.epoch).transfer_fee_basis_points),
+                Err(_) => 0
+            }
+        };
+        let (normal_apy, locked_apy) = contract_data.apy_at(clock.unix_timestamp as u64);
+        let gross_apy = match stake_type {
+            StakeType::NORMAL => normal_apy,
+            StakeType::LOCKED => locked_apy
+        };
+
+        // Follow a notional stake through a year: the fee on the deposit, the deposit fee, the
+        // interest, and the fee on the payout, which unstake grosses up by 9%.
+        // The mint's maximum fee is not applied, so large stakes may fare better
+        let notional: u128 = 1_000_000_000_000;
+        let transfer_fee = |amount: u128| amount * transfer_fee_bps as u128 / 10000;
+        let received = notional - transfer_fee(notional);
+        let credited = received - received * contract_data.deposit_fee_bps as u128 / 10000;
+        let interest = credited * gross_apy as u128 / 1000;
+        // Interest in a separate reward token is counted 1:1 and does not pay the stake token fee
+        let (stake_token_out, reward_out) = if contract_data.has_reward_token() {
+            (credited, interest)
+        } else {
+            (credited + interest, 0)
+        };
+        let sent = stake_token_out + 9 * stake_token_out / 100;
+        let returned = sent - transfer_fee(sent) + reward_out;
+        let net_apr = ((returned as i128 - notional as i128) * 1000 / notional as i128) as i64;
+
+        let pool_apr = PoolApr {
+            gross_apy,
+            transfer_fee_bps,
+            net_apr
+        };
+        set_return_data(&pool_apr.try_to_vec()?);
+        Ok(())
+    }
+
+    fn get_liabilities(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        clock: &Clock
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let data_account = next_account_info(accounts_info_iter)?;
+
+        if data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let mut contract_data = ContractData::unpack_from_slice(&data_account.data.borrow())?;
+        // Accrued on the local copy only, the account is left untouched
+        contract_data.accrue_pending_interest(clock.unix_timestamp as u64);
+        let liabilities = PoolLiabilities {
+            total_staked: contract_data.total_staked,
+            total_pending_interest: contract_data.total_pending_interest,
+            total_liabilities: contract_data.total_staked.saturating_add(contract_data.total_pending_interest)
+        };
+        set_return_data(&liabilities.try_to_vec()?);
+        Ok(())
+    }
+
+    fn get_config(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo]
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let data_account = next_account_info(accounts_info_iter)?;
+
+        let contract_data = Self::unpack_contract_data(program_id, data_account)?;
+        set_return_data(&ConfigSnapshot::from_contract_data(&contract_data).try_to_vec()?);
+        Ok(())
+    }
+
+    fn get_coverage_ratio(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        clock: &Clock
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let data_account = next_account_info(accounts_info_iter)?;
+        let interest_vault_info = next_account_info(accounts_info_iter)?;
+
+        if data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let mut contract_data = ContractData::unpack_from_slice(&data_account.data.borrow())?;
+        let interest_vault = if contract_data.has_reward_token() {
+            contract_data.reward_vault
+        } else {
+            contract_data.stake_token_account
+        };
+        if interest_vault_info.key != &interest_vault {
+            msg!("Staking [Error]: Invalid contract token account");
+            return Err(StakingError::InvalidContractTokenAccount.into())
+        }
+        let vault_balance = TokenAccount::unpack_from_slice(&interest_vault_info.data.borrow())?.amount;
+        // Principal shares the contract token account with the rewards unless they have their own vault
+        let available_rewards = Self::affordable_interest(&contract_data, vault_balance, contract_data.total_staked, 0, false)?;
+        // Accrued on the local copy only, the account is left untouched
+        contract_data.accrue_pending_interest(clock.unix_timestamp as u64);
+        let coverage_bps = if contract_data.total_pending_interest == 0 {
+            u64::MAX
+        } else {
+            (available_rewards as u128 * 10000 / contract_data.total_pending_interest as u128).min(u64::MAX as u128) as u64
+        };
+        let coverage = PoolCoverage {
+            available_rewards,
+            total_pending_interest: contract_data.total_pending_interest,
+            coverage_bps
+        };
+        set_return_data(&coverage.try_to_vec()?);
+        Ok(())
+    }
+
+    fn convert_stake_type(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_type: StakeType,
+        lock_duration: u64,
+        clock: &Clock
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let user_info = next_account_info(accounts_info_iter)?;
+        let user_data_account = next_account_info(accounts_info_iter)?;
+        let contract_data_account = next_account_info(accounts_info_iter)?;
+
+        if !user_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature)
+        }
+        if !user_data_account.is_writable || !contract_data_account.is_writable {
+            return Err(ProgramError::InvalidAccountData)
+        }
+        if user_data_account.owner != program_id {
+            msg!("Staking [Error]: User data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        if contract_data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let (user_data_pda, _bump) = pda::user_data_pda(user_info.key, contract_data_account.key, program_id);
+        if *user_data_account.key != user_data_pda {
+            msg!("Staking [Error]: User data account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        let mut contract_data = ContractData::unpack_from_slice(&contract_data_account.data.borrow())?;
+        if contract_data.is_paused {
+            msg!("Staking [Error]: Pool is paused");
+            return Err(StakingError::PoolPaused.into())
+        }
+        let mut user_data = UserData::unpack_from_slice(&user_data_account.data.borrow())?;
+        Self::check_position_pool(&user_data, contract_data_account)?;
+        if !user_data.is_initialized {
+            return Err(ProgramError::UninitializedAccount)
+        }
+        if u8::from(&user_data.stake_type) == u8::from(&new_type) {
+            msg!("Staking [Error]: Position already has this stake type");
+            return Err(StakingError::StakeTypeMismatch.into())
+        }
+
+        let current_ts = clock.unix_timestamp as u64;
+        let lock_duration = match new_type {
+            StakeType::NORMAL => {
+                if !Self::lock_ended(&contract_data, &user_data, current_ts)? {
+                    msg!("Staking [Error]: Cannot convert a locked position before the lock ends");
+                    return Err(StakingError::LockNotExpired.into())
+                }
+                0
+            },
+            StakeType::LOCKED => {
+                if lock_duration < contract_data.minimum_lock_duration {
+                    msg!("Staking [Error]: Lock duration is less than minimum lock duration❌");
+                    return Err(StakingError::LockDurationTooShort.into())
+                }
+                if contract_data.max_lock_duration != 0 && lock_duration > contract_data.max_lock_duration {
+                    msg!("Staking [Error]: Lock duration is greater than maximum lock duration❌");
+                    return Err(StakingError::LockDurationTooLong.into())
+                }
+                if current_ts.checked_add(lock_duration).is_none() {
+                    msg!("Staking [Error]: Lock would end past the maximum timestamp❌");
+                    return Err(StakingError::LockDurationTooLong.into())
+                }
+                lock_duration
+            }
+        };
+
+        // Settle the interest earned under the old stake type before switching
+        let interest = user_data.apply_boost(contract_data.accrued_interest(
+            &user_data, user_data.total_staked, current_ts
+        ));
+        user_data.interest_accrued = math::checked_add(user_data.interest_accrued, interest)?;
+        // Move the principal to the new stake type's rate in the pool liabilities
+        contract_data.accrue_pending_interest(current_ts);
+        contract_data.total_locked_staked = match new_type {
+            StakeType::NORMAL => contract_data.total_locked_staked.saturating_sub(user_data.total_staked),
+            StakeType::LOCKED => math::checked_add(contract_data.total_locked_staked, user_data.total_staked)?
+        };
+        user_data.stake_type = new_type;
+        user_data.lock_duration = lock_duration;
+        user_data.stake_ts = current_ts;
+        user_data.unlock_ts = match user_data.stake_type {
+            StakeType::NORMAL => 0,
+            StakeType::LOCKED => current_ts.saturating_add(lock_duration)
+        };
+        user_data.reward_per_share_paid = contract_data.reward_per_share;
+        msg!("Staking [Info]: Settled Interest: {} Lock Duration: {}", interest, lock_duration);
+        UserData::pack(user_data, &mut user_data_account.try_borrow_mut_data()?)?;
+        ContractData::pack(contract_data, &mut contract_data_account.try_borrow_mut_data()?)?;
+        Ok(())
+    }
+
+    fn extend_lock(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_lock_duration: u64
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let user_info = next_account_info(accounts_info_iter)?;
+        let user_data_account = next_account_info(accounts_info_iter)?;
+        let contract_data_account = next_account_info(accounts_info_iter)?;
+
+        if !user_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature)
+        }
+        if !user_data_account.is_writable {
+            return Err(ProgramError::InvalidAccountData)
+        }
+        if user_data_account.owner != program_id {
+            msg!("Staking [Error]: User data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let (user_data_pda, _bump) = pda::user_data_pda(user_info.key, contract_data_account.key, program_id);
+        if *user_data_account.key != user_data_pda {
+            msg!("Staking [Error]: User data account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        let contract_data = Self::unpack_contract_data(program_id, contract_data_account)?;
+        if contract_data.is_paused {
+            msg!("Staking [Error]: Pool is paused");
+            return Err(StakingError::PoolPaused.into())
+        }
+        let mut user_data = UserData::unpack_from_slice(&user_data_account.data.borrow())?;
+        Self::check_position_pool(&user_data, contract_data_account)?;
+        if !user_data.is_initialized {
+            return Err(ProgramError::UninitializedAccount)
+        }
+        if let StakeType::NORMAL = user_data.stake_type {
+            msg!("Staking [Error]: Only LOCKED positions have a lock to extend");
+            return Err(StakingError::StakeTypeMismatch.into())
+        }
+        if contract_data.max_lock_duration != 0 && new_lock_duration > contract_data.max_lock_duration {
+            msg!("Staking [Error]: Lock duration is greater than maximum lock duration❌");
+            return Err(StakingError::LockDurationTooLong.into())
+        }
+        let new_unlock_ts = user_data.stake_ts.checked_add(new_lock_duration).ok_or_else(|| {
+            msg!("Staking [Error]: Lock would end past the maximum timestamp❌");
+            ProgramError::from(StakingError::LockDurationTooLong)
+        })?;
+        let unlock_ts = user_data.lock_end_ts().ok_or_else(|| {
+            msg!("Staking [Error]: Lock end of the position overflows❌");
+            ProgramError::from(StakingError::LockDurationTooLong)
+        })?;
+        if new_unlock_ts <= unlock_ts {
+            msg!("Staking [Error]: Lock ending at {} would not extend the unlock at {}", new_unlock_ts, unlock_ts);
+            return Err(StakingError::LockDurationTooShort.into())
+        }
+        // Interest keeps accruing from stake_ts at the same rate, nothing to settle
+        user_data.lock_duration = new_lock_duration;
+        user_data.unlock_ts = new_unlock_ts;
+        msg!("Staking [Info]: Lock of {} extended to {}", user_data.owner_pubkey, new_unlock_ts);
+        UserData::pack(user_data, &mut user_data_account.try_borrow_mut_data()?)?;
+        Ok(())
+    }
+
+    fn set_boost(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        boost_bps: u64
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let admin = next_account_info(accounts_info_iter)?;
+        let data_account = next_account_info(accounts_info_iter)?;
+        let user_data_account = next_account_info(accounts_info_iter)?;
+
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature)
+        }
+        if !user_data_account.is_writable {
+            return Err(ProgramError::InvalidAccountData)
+        }
+        if data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        if user_data_account.owner != program_id {
+            msg!("Staking [Error]: User data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let contract_data = ContractData::unpack_from_slice(&data_account.data.borrow())?;
+        if &contract_data.admin_pubkey != admin.key {
+            msg!("Staking [Error]: Invalid contract data");
+            return Err(StakingError::Unauthorized.into())
+        }
+        let mut user_data = UserData::unpack_from_slice(&user_data_account.data.borrow())?;
+        Self::check_position_pool(&user_data, data_account)?;
+        if !user_data.is_initialized {
+            return Err(ProgramError::UninitializedAccount)
+        }
+        let (user_data_pda, _bump) = pda::user_data_pda(&user_data.owner_pubkey, data_account.key, program_id);
+        if *user_data_account.key != user_data_pda {
+            msg!("Staking [Error]: User data account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        if boost_bps == 0 || boost_bps > contract_data.max_boost_bps.max(UserData::BASE_BOOST_BPS) {
+            msg!("Staking [Error]: Boost must be between 1 and {}", contract_data.max_boost_bps.max(UserData::BASE_BOOST_BPS));
+            return Err(StakingError::InvalidBoost.into())
+        }
+        user_data.boost_bps = boost_bps;
+        msg!("Staking [Info]: Boost of {} set to {}", user_data.owner_pubkey, boost_bps);
+        UserData::pack(user_data, &mut user_data_account.try_borrow_mut_data()?)?;
+        Ok(())
+    }
+
+    fn set_fee_exempt(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        is_fee_exempt: bool
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let admin = next_account_info(accounts_info_iter)?;
+        let data_account = next_account_info(accounts_info_iter)?;
+        let user_data_account = next_account_info(accounts_info_iter)?;
+
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature)
+        }
+        if !user_data_account.is_writable {
+            return Err(ProgramError::InvalidAccountData)
+        }
+        if data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        if user_data_account.owner != program_id {
+            msg!("Staking [Error]: User data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let contract_data = ContractData::unpack_from_slice(&data_account.data.borrow())?;
+        if &contract_data.admin_pubkey != admin.key {
+            msg!("Staking [Error]: Invalid contract data");
+            return Err(StakingError::Unauthorized.into())
+        }
+        // Unlike a boost the exemption can be set ahead of the first stake, waiving its deposit fee
+        let mut user_data = UserData::unpack_from_slice(&user_data_account.data.borrow())?;
+        Self::check_position_pool(&user_data, data_account)?;
+        let (user_data_pda, _bump) = pda::user_data_pda(&user_data.owner_pubkey, data_account.key, program_id);
+        if *user_data_account.key != user_data_pda {
+            msg!("Staking [Error]: User data account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        user_data.is_fee_exempt = is_fee_exempt;
+        msg!("Staking [Info]: Fee exemption of {} set to {}", user_data.owner_pubkey, is_fee_exempt);
+        UserData::pack(user_data, &mut user_data_account.try_borrow_mut_data()?)?;
+        Ok(())
+    }
+
+    fn waive_penalty(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo]
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let admin = next_account_info(accounts_info_iter)?;
+        let data_account = next_account_info(accounts_info_iter)?;
+        let user_data_account = next_account_info(accounts_info_iter)?;
+
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature)
+        }
+        if !user_data_account.is_writable {
+            return Err(ProgramError::InvalidAccountData)
+        }
+        if data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        if user_data_account.owner != program_id {
+            msg!("Staking [Error]: User data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let contract_data = ContractData::unpack_from_slice(&data_account.data.borrow())?;
+        if &contract_data.admin_pubkey != admin.key {
+            msg!("Staking [Error]: Invalid contract data");
+            return Err(StakingError::Unauthorized.into())
+        }
+        let mut user_data = UserData::unpack_from_slice(&user_data_account.data.borrow())?;
+        Self::check_position_pool(&user_data, data_account)?;
+        if !user_data.is_initialized {
+            return Err(ProgramError::UninitializedAccount)
+        }
+        let (user_data_pda, _bump) = pda::user_data_pda(&user_data.owner_pubkey, data_account.key, program_id);
+        if *user_data_account.key != user_data_pda {
+            msg!("Staking [Error]: User data account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        if let StakeType::NORMAL = user_data.stake_type {
+            msg!("Staking [Error]: Only LOCKED positions pay an early withdrawal charge");
+            return Err(StakingError::StakeTypeMismatch.into())
+        }
+        user_data.penalty_waived = true;
+        msg!("Staking [Info]: Early withdrawal charge of {} waived for the next early unstake", user_data.owner_pubkey);
+        UserData::pack(user_data, &mut user_data_account.try_borrow_mut_data()?)?;
+        Ok(())
+    }
+
+    fn repair_totals(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        clock: &Clock
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let admin = next_account_info(accounts_info_iter)?;
+        let data_account = next_account_info(accounts_info_iter)?;
+
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature)
+        }
+        let mut contract_data = Self::unpack_contract_data(program_id, data_account)?;
+        if &contract_data.admin_pubkey != admin.key {
+            msg!("Staking [Error]: Invalid contract data");
+            return Err(StakingError::Unauthorized.into())
+        }
+        let (contract_data_pda, _bump) = pda::contract_data_pda(
+            &contract_data.admin_pubkey, &contract_data.stake_token_mint, program_id
+        );
+        if &contract_data_pda != data_account.key {
+            msg!("Staking [Error]: Invalid contract data account");
+            return Err(StakingError::PdaMismatch.into())
+        }
+
+        let mut position_keys: Vec<&Pubkey> = Vec::new();
+        let (mut total_staked, mut total_locked_staked) = (0_u64, 0_u64);
+        for user_data_account in accounts_info_iter {
+            if user_data_account.owner != program_id {
+                msg!("Staking [Error]: User data account is not owned by the program");
+                return Err(ProgramError::IllegalOwner)
+            }
+            let user_data = UserData::unpack_from_slice(&user_data_account.data.borrow())?;
+            Self::check_position_pool(&user_data, data_account)?;
+            if !user_data.is_initialized {
+                return Err(ProgramError::UninitializedAccount)
+            }
+            let (user_data_pda, _bump) = pda::user_data_pda(&user_data.owner_pubkey, data_account.key, program_id);
+            if *user_data_account.key != user_data_pda {
+                msg!("Staking [Error]: User data account and generated pda mismatch");
+                return Err(StakingError::PdaMismatch.into())
+            }
+            total_staked = total_staked.checked_add(user_data.total_staked).ok_or(ProgramError::ArithmeticOverflow)?;
+            if let StakeType::LOCKED = user_data.stake_type {
+                total_locked_staked = math::checked_add(total_locked_staked, user_data.total_staked)?;
+            }
+            position_keys.push(user_data_account.key);
+        }
+        // Counting a position twice or leaving one out would only move the drift elsewhere
+        position_keys.sort();
+        position_keys.dedup();
+        if position_keys.len() as u64 != contract_data.active_stakers {
+            msg!(
+                "Staking [Error]: Passed {} distinct positions, the pool has {} open",
+                position_keys.len(), contract_data.active_stakers
+            );
+            return Err(StakingError::PositionsMismatch.into())
+        }
+
+        // Interest up to now accrues at the totals it was booked with
+        contract_data.accrue_pending_interest(clock.unix_timestamp as u64);
+        msg!(
+            "Staking [Event]: Total staked repaired from {} to {}, total locked staked from {} to {}",
+            contract_data.total_staked, total_staked, contract_data.total_locked_staked, total_locked_staked
+        );
+        contract_data.total_staked = total_staked;
+        contract_data.total_locked_staked = total_locked_staked;
+        ContractData::pack(contract_data, &mut data_account.try_borrow_mut_data()?)?;
+        Ok(())
+    }
+
+    /// Pays `claim_bps` (decimals = 10000) of the interest to the user and compounds the rest into the position
+    fn claim_rewards(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        claim_bps: u64,
+        clock: &Clock
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let user_info = next_account_info(accounts_info_iter)?;
+        let user_token_account_info = next_account_info(accounts_info_iter)?;
+        let user_data_account = next_account_info(accounts_info_iter)?;
+        let contract_token_account_info = next_account_info(accounts_info_iter)?;
+        let contract_data_account = next_account_info(accounts_info_iter)?;
+        let mint_info = next_account_info(accounts_info_iter)?;
+        let token_program_info = next_account_info(accounts_info_iter)?;
+
+        if !user_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature)
+        }
+        if user_data_account.owner != program_id {
+            msg!("Staking [Error]: User data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        if contract_data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        if token_program_info.key != &spl_token_2022::ID {
+            msg!("Staking [Error]: Invalid Token Program. Contract supports TOKEN 2022 only");
+            return Err(StakingError::UnsupportedTokenProgram.into())
+        }
+        if claim_bps > 10000 {
+            msg!("Staking [Error]: Claimed share cannot exceed 100%");
+            return Err(StakingError::InvalidClaimSplit.into())
+        }
+        let mut contract_data = ContractData::unpack_from_slice(&contract_data_account.data.borrow())?;
+        if contract_data.is_paused {
+            msg!("Staking [Error]: Pool is paused");
+            return Err(StakingError::PoolPaused.into())
+        }
+        let (contract_data_pda, pda_bump) = pda::contract_data_pda(
+            &contract_data.admin_pubkey, &contract_data.stake_token_mint, program_id
+        );
+        if &contract_data_pda != contract_data_account.key {
+            msg!("Staking [Error]: Invalid contract data account");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        let earned_log_accounts = if contract_data.earned_checkpoints_enabled {
+            Some((next_account_info(accounts_info_iter)?, next_account_info(accounts_info_iter)?))
+        } else {
+            None
+        };
+        let (user_data_pda, _bump) = pda::user_data_pda(user_info.key, contract_data_account.key, program_id);
+        if *user_data_account.key != user_data_pda {
+            msg!("Staking [Error]: User data account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        let mut user_data = UserData::unpack_from_slice(&user_data_account.data.borrow())?;
+        Self::check_position_pool(&user_data, contract_data_account)?;
+        if !user_data.is_initialized {
+            return Err(ProgramError::UninitializedAccount)
+        }
+
+        // Interest comes from the reward vault in reward token pools and from the stake token account otherwise
+        let (interest_mint, interest_vault) = if contract_data.has_reward_token() {
+            (contract_data.reward_mint, contract_data.reward_vault)
+        } else {
+            (contract_data.stake_token_mint, contract_data.stake_token_account)
+        };
+        if contract_token_account_info.key != &interest_vault {
+            msg!("Staking [Error]: Invalid contract token account");
+            return Err(StakingError::InvalidContractTokenAccount.into())
+        }
+        if user_token_account_info.key == contract_token_account_info.key {
+            msg!("Staking [Error]: User token account cannot be the contract token account");
+            return Err(StakingError::InvalidUserTokenAccount.into())
+        }
+        let user_token_account_data = TokenAccount::unpack_from_slice(&user_token_account_info.data.borrow())?;
+        if user_token_account_data.owner != *user_info.key {
+            msg!("Staking [Error]: Invalid user token account");
+            return Err(StakingError::InvalidUserTokenAccount.into())
+        }
+        if user_token_account_data.mint != interest_mint {
+            msg!("Staking [Error]: Invalid user token account mint");
+            return Err(StakingError::MintMismatch.into())
+        }
+
+        let current_ts = clock.unix_timestamp as u64;
+        if current_ts.saturating_sub(user_data.last_claim_ts) < contract_data.claim_cooldown {
+            msg!(
+                "Staking [Error]: Claim cooldown active until {}",
+                user_data.last_claim_ts.saturating_add(contract_data.claim_cooldown)
+            );
+            return Err(StakingError::ClaimCooldownActive.into())
+        }
+        let compounds = claim_bps < 10000;
+        if compounds {
+            // Same rules as ClaimAndStake for the compounded share
+            if contract_data.has_reward_token() {
+                msg!("Staking [Error]: Interest paid in the reward token cannot be staked");
+                return Err(StakingError::MintMismatch.into())
+            }
+            if current_ts.saturating_sub(user_data.last_claim_ts) < contract_data.compound_cooldown {
+                msg!(
+                    "Staking [Error]: Compound cooldown active until {}",
+                    user_data.last_claim_ts.saturating_add(contract_data.compound_cooldown)
+                );
+                return Err(StakingError::CompoundCooldownActive.into())
+            }
+        }
+        if let StakeType::LOCKED = user_data.stake_type {
+            if !Self::lock_ended(&contract_data, &user_data, current_ts)? {
+                msg!("Staking [Error]: Cannot claim rewards of a locked position before the lock ends");
+                return Err(StakingError::LockNotExpired.into())
+            }
+        }
+        let interest = user_data.apply_boost(contract_data.accrued_interest(
+            &user_data, user_data.total_staked, current_ts
+        ));
+        let mut interest_out = contract_data.cap_reward(math::checked_add(interest, user_data.interest_accrued)?);
+        let contract_token_account_data = TokenAccount::unpack_from_slice(&contract_token_account_info.data.borrow())?;
+        // The interest may not dip into the staked principal. A shortfall trips the circuit breaker either way,
+        // pools accepting partial rewards pay what the vault can cover and forfeit the rest of the interest,
+        // other pools pay nothing and leave the interest accrued in the position
+        let affordable_interest = Self::affordable_interest(
+            &contract_data, contract_token_account_data.amount, contract_data.total_staked, 0, false
+        )?;
+        if affordable_interest < interest_out {
+            Self::trip_circuit_breaker(&mut contract_data);
+            if !contract_data.partial_reward_ok {
+                msg!(
+                    "Staking [Info]: Interest {} exceeds the {} the vault can pay without touching staked principal, nothing claimed",
+                    interest_out, affordable_interest
+                );
+                ContractData::pack(contract_data, &mut contract_data_account.try_borrow_mut_data()?)?;
+                return Ok(())
+            }
+            msg!(
+                "Staking [Info]: Interest shortfall of {}, paying {} of {}",
+                interest_out - affordable_interest, affordable_interest, interest_out
+            );
+            interest_out = affordable_interest;
+        }
+        let interest_claimed = math::bps(interest_out, claim_bps)?;
+        let interest_staked = math::checked_sub(interest_out, interest_claimed)?;
+        if interest_claimed > 0 {
+            let mint_decimals = StateWithExtensions::<Mint>::unpack(&mint_info.data.borrow())?.base.decimals;
+            let fee = Self::get_transfer_fee(mint_info, &interest_mint, interest_claimed)?;
+            let signer_seeds: &[&[u8]] = &[
+                pda::CONTRACT_DATA_SEED,
+                contract_data.admin_pubkey.as_ref(),
+                contract_data.stake_token_mint.as_ref(),
+                &[pda_bump]
+            ];
+            invoke_signed(
+                &transfer_checked_with_fee(
+                    token_program_info.key,
+                    contract_token_account_info.key,
+                    mint_info.key,
+                    user_token_account_info.key,
+                    &contract_data_pda,
+                    &[&contract_data_pda],
+                    interest_claimed,
+                    mint_decimals,
+                    fee
+                )?,
+                &[
+                    contract_token_account_info.clone(),
+                    mint_info.clone(),
+                    user_token_account_info.clone(),
+                    contract_data_account.clone(),
+                    token_program_info.clone(),
+                ],
+                &[signer_seeds],
+            )?;
+        }
+        msg!("Staking [Info]: Claimed Interest: {}", interest_claimed);
+        contract_data.total_earned = math::checked_add(contract_data.total_earned, interest_out)?;
+        contract_data.accrue_pending_interest(current_ts);
+        contract_data.settle_pending_interest(math::checked_add(interest, user_data.interest_accrued)?);
+        user_data.interest_accrued = 0;
+        // A partial accrual period keeps accruing towards the next claim
+        user_data.last_claim_ts = contract_data.accrued_until(user_data.accrual_start_ts(), current_ts);
+        if compounds {
+            // The compounded share stays in the contract token account, restarting the position like ClaimAndStake
+            if let StakeType::LOCKED = user_data.stake_type {
+                contract_data.total_locked_staked = math::checked_add(contract_data.total_locked_staked, interest_staked)?;
+                user_data.unlock_ts = user_data.unlock_ts.max(current_ts.saturating_add(user_data.lock_duration));
+            }
+            user_data.total_staked = math::checked_add(user_data.total_staked, interest_staked)?;
+            user_data.stake_ts = current_ts;
+            user_data.last_claim_ts = current_ts;
+            contract_data.total_staked = math::checked_add(contract_data.total_staked, interest_staked)?;
+            msg!("Staking [Info]: Staked Interest: {} Total Staked: {}", interest_staked, user_data.total_staked);
+        }
+        user_data.reward_per_share_paid = contract_data.reward_per_share;
+        let checkpoint = EarnedCheckpoint { ts: current_ts, total_earned: contract_data.total_earned };
+        UserData::pack(user_data, &mut user_data_account.try_borrow_mut_data()?)?;
+        ContractData::pack(contract_data, &mut contract_data_account.try_borrow_mut_data()?)?;
+        if let Some((earned_log_info, system_program_info)) = earned_log_accounts {
+            Self::record_earned_checkpoint(
+                program_id,
+                earned_log_info,
+                contract_data_account,
+                user_info,
+                system_program_info,
+                checkpoint
+            )?;
+        }
+        Ok(())
+    }
+
+    fn claim_and_stake(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        clock: &Clock
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let user_info = next_account_info(accounts_info_iter)?;
+        let user_data_account = next_account_info(accounts_info_iter)?;
+        let contract_data_account = next_account_info(accounts_info_iter)?;
+        let contract_token_account_info = next_account_info(accounts_info_iter)?;
+
+        if !user_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature)
+        }
+        if user_data_account.owner != program_id {
+            msg!("Staking [Error]: User data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let mut contract_data = Self::unpack_contract_data(program_id, contract_data_account)?;
+        if contract_data.is_paused {
+            msg!("Staking [Error]: Pool is paused");
+            return Err(StakingError::PoolPaused.into())
+        }
+        let (contract_data_pda, _bump) = pda::contract_data_pda(
+            &contract_data.admin_pubkey, &contract_data.stake_token_mint, program_id
+        );
+        if &contract_data_pda != contract_data_account.key {
+            msg!("Staking [Error]: Invalid contract data account");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        let earned_log_accounts = if contract_data.earned_checkpoints_enabled {
+            Some((next_account_info(accounts_info_iter)?, next_account_info(accounts_info_iter)?))
+        } else {
+            None
+        };
+        let (user_data_pda, _bump) = pda::user_data_pda(user_info.key, contract_data_account.key, program_id);
+        if *user_data_account.key != user_data_pda {
+            msg!("Staking [Error]: User data account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        let mut user_data = UserData::unpack_from_slice(&user_data_account.data.borrow())?;
+        Self::check_position_pool(&user_data, contract_data_account)?;
+        if !user_data.is_initialized {
+            return Err(ProgramError::UninitializedAccount)
+        }
+        // Reward tokens sit in their own vault and cannot be credited as stake token principal
+        if contract_data.has_reward_token() {
+            msg!("Staking [Error]: Interest paid in the reward token cannot be staked");
+            return Err(StakingError::MintMismatch.into())
+        }
+        if contract_token_account_info.key != &contract_data.stake_token_account {
+            msg!("Staking [Error]: Invalid contract token account");
+            return Err(StakingError::InvalidContractTokenAccount.into())
+        }
+
+        let current_ts = clock.unix_timestamp as u64;
+        if current_ts.saturating_sub(user_data.last_claim_ts) < contract_data.claim_cooldown {
+            msg!(
+                "Staking [Error]: Claim cooldown active until {}",
+                user_data.last_claim_ts.saturating_add(contract_data.claim_cooldown)
+            );
+            return Err(StakingError::ClaimCooldownActive.into())
+        }
+        // Compounding every slot would collect the rounding of each accrual
+        if current_ts.saturating_sub(user_data.last_claim_ts) < contract_data.compound_cooldown {
+            msg!(
+                "Staking [Error]: Compound cooldown active until {}",
+                user_data.last_claim_ts.saturating_add(contract_data.compound_cooldown)
+            );
+            return Err(StakingError::CompoundCooldownActive.into())
+        }
+        if let StakeType::LOCKED = user_data.stake_type {
+            if !Self::lock_ended(&contract_data, &user_data, current_ts)? {
+                msg!("Staking [Error]: Cannot claim rewards of a locked position before the lock ends");
+                return Err(StakingError::LockNotExpired.into())
+            }
+        }
+        let interest = user_data.apply_boost(contract_data.accrued_interest(
+            &user_data, user_data.total_staked, current_ts
+        ));
+        let mut interest_staked = contract_data.cap_reward(math::checked_add(interest, user_data.interest_accrued)?);
+        // Same rule as ClaimRewards, the interest turned into principal must be held by the contract token
+        // account next to the staked principal
+        let vault_balance = TokenAccount::unpack_from_slice(&contract_token_account_info.data.borrow())?.amount;
+        let affordable_interest = Self::affordable_interest(
+            &contract_data, vault_balance, contract_data.total_staked, 0, false
+        )?;
+        if affordable_interest < interest_staked {
+            Self::trip_circuit_breaker(&mut contract_data);
+            if !contract_data.partial_reward_ok {
+                msg!(
+                    "Staking [Info]: Interest {} exceeds the {} the vault can pay without touching staked principal, nothing staked",
+                    interest_staked, affordable_interest
+                );
+                ContractData::pack(contract_data, &mut contract_data_account.try_borrow_mut_data()?)?;
+                return Ok(())
+            }
+            msg!(
+                "Staking [Info]: Interest shortfall of {}, staking {} of {}",
+                interest_staked - affordable_interest, affordable_interest, interest_staked
+            );
+            interest_staked = affordable_interest;
+        }
+        contract_data.accrue_pending_interest(current_ts);
+        contract_data.settle_pending_interest(math::checked_add(interest, user_data.interest_accrued)?);
+        if let StakeType::LOCKED = user_data.stake_type {
+            contract_data.total_locked_staked = math::checked_add(contract_data.total_locked_staked, interest_staked)?;
+        }
+        // The affordable interest is held by the contract token account, so only the books change
+        user_data.total_staked = math::checked_add(user_data.total_staked, interest_staked)?;
+        user_data.interest_accrued = 0;
+        user_data.stake_ts = current_ts;
+        user_data.last_claim_ts = current_ts;
+        if let StakeType::LOCKED = user_data.stake_type {
+            // The compounded position starts a new lock of the same duration, never shortening the unlock
+            user_data.unlock_ts = user_data.unlock_ts.max(current_ts.saturating_add(user_data.lock_duration));
+        }
+        user_data.reward_per_share_paid = contract_data.reward_per_share;
+        contract_data.total_staked = math::checked_add(contract_data.total_staked, interest_staked)?;
+        contract_data.total_earned = math::checked_add(contract_data.total_earned, interest_staked)?;
+        msg!("Staking [Info]: Staked Interest: {} Total Staked: {}", interest_staked, user_data.total_staked);
+        let checkpoint = EarnedCheckpoint { ts: current_ts, total_earned: contract_data.total_earned };
+        UserData::pack(user_data, &mut user_data_account.try_borrow_mut_data()?)?;
+        ContractData::pack(contract_data, &mut contract_data_account.try_borrow_mut_data()?)?;
+        if let Some((earned_log_info, system_program_info)) = earned_log_accounts {
+            Self::record_earned_checkpoint(
+                program_id,
+                earned_log_info,
+                contract_data_account,
+                user_info,
+                system_program_info,
+                checkpoint
+            )?;
+        }
+        Ok(())
+    }
+
+    fn set_paused(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        paused: bool
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let admin = next_account_info(accounts_info_iter)?;
+        let data_account = next_account_info(accounts_info_iter)?;
+
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !data_account.is_writable {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let mut contract_data = ContractData::unpack_from_slice(&data_account.data.borrow())?;
+        if &contract_data.admin_pubkey != admin.key {
+            msg!("Staking [Error]: Invalid contract data");
+            return Err(StakingError::Unauthorized.into())
+        }
+        msg!("Staking [Info]: Pool paused: {}", paused);
+        contract_data.is_paused = paused;
+        // The admin takes over from the circuit breaker either way
+        contract_data.rewards_depleted = false;
+        ContractData::pack(contract_data, &mut data_account.try_borrow_mut_data()?)?;
+        Ok(())
+    }
+
+    fn set_rewards_paused(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        paused: bool,
+        clock: &Clock
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let admin = next_account_info(accounts_info_iter)?;
+        let data_account = next_account_info(accounts_info_iter)?;
+
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !data_account.is_writable {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let mut contract_data = Self::unpack_contract_data(program_id, data_account)?;
+        if &contract_data.admin_pubkey != admin.key {
+            msg!("Staking [Error]: Invalid contract data");
+            return Err(StakingError::Unauthorized.into())
+        }
+        if contract_data.rewards_paused == paused {
+            msg!("Staking [Info]: Rewards paused already: {}", paused);
+            return Ok(())
+        }
+        let current_ts = clock.unix_timestamp as u64;
+        // Settle the pool totals up to the freeze, or up to its start when it ends
+        contract_data.accrue_pending_interest(current_ts);
+        // Promote a scheduled APY that took effect. Time before it ran at the old APY unless it took
+        // effect during the freeze, which the checkpoint recorded when accrual resumes covers
+        if contract_data.apy_effective_ts != 0 && current_ts >= contract_data.apy_effective_ts {
+            if paused {
+                contract_data.record_apy_checkpoint(contract_data.apy_effective_ts);
+            }
+            (contract_data.normal_staking_apy, contract_data.locked_staking_apy) = contract_data.apy_at(current_ts);
+            (contract_data.pending_normal_apy, contract_data.pending_locked_apy) = (0, 0);
+            contract_data.apy_effective_ts = 0;
+        }
+        if paused {
+            msg!("Staking [Info]: Interest accrual frozen from {}", current_ts);
+            contract_data.record_apy_checkpoint(current_ts);
+            contract_data.accrual_frozen_since = current_ts;
+        } else {
+            msg!("Staking [Info]: Interest accrual resumed, frozen since {}", contract_data.accrual_frozen_since);
+            contract_data.record_frozen_checkpoint(current_ts);
+            contract_data.accrual_frozen_since = 0;
+        }
+        contract_data.rewards_paused = paused;
+        ContractData::pack(contract_data, &mut data_account.try_borrow_mut_data()?)?;
+        Ok(())
+    }
+
+    /// Pauses the pool the first time the rewards cannot cover a payout, so no new
+    /// stakes enter an insolvent pool while open positions can still be withdrawn
+    fn trip_circuit_breaker(contract_data: &mut ContractData) {
+        if contract_data.is_paused {
+            return
+        }
+        msg!("Staking [Event]: Rewards depleted, pool paused for new stakes");
+        contract_data.is_paused = true;
+        contract_data.rewards_depleted = true;
+    }
+
+    fn set_early_withdrawal_fee(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        early_withdrawal_fee: u64,
+        denominator: u64
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let admin = next_account_info(accounts_info_iter)?;
+        let data_account = next_account_info(accounts_info_iter)?;
+
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !data_account.is_writable {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let mut contract_data = ContractData::unpack_from_slice(&data_account.data.borrow())?;
+        if !contract_data.is_admin_or_operator(admin.key) {
+            msg!("Staking [Error]: Invalid contract data");
+            return Err(StakingError::Unauthorized.into())
+        }
+        if denominator == 0 || early_withdrawal_fee > denominator {
+            msg!("Staking [Error]: Early withdrawal fee cannot exceed 100% of the principal");
+            return Err(StakingError::PenaltyExceedsPrincipal.into())
+        }
+        msg!("Staking [Info]: Early withdrawal fee: {}/{}", early_withdrawal_fee, denominator);
+        contract_data.early_withdrawal_fee = early_withdrawal_fee;
+        contract_data.early_withdrawal_fee_denominator = denominator;
+        ContractData::pack(contract_data, &mut data_account.try_borrow_mut_data()?)?;
+        Ok(())
+    }
+
+    fn set_operator(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        operator: Pubkey
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let admin = next_account_info(accounts_info_iter)?;
+        let data_account = next_account_info(accounts_info_iter)?;
+
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !data_account.is_writable {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let mut contract_data = Self::unpack_contract_data(program_id, data_account)?;
+        if &contract_data.admin_pubkey != admin.key {
+            msg!("Staking [Error]: Invalid contract data");
+            return Err(StakingError::Unauthorized.into())
+        }
+        msg!("Staking [Info]: Operator: {}", operator);
+        contract_data.operator_pubkey = operator;
+        ContractData::pack(contract_data, &mut data_account.try_borrow_mut_data()?)?;
+        Ok(())
+    }
+
+    fn realloc_contract_data(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo]
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let admin = next_account_info(accounts_info_iter)?;
+        let data_account = next_account_info(accounts_info_iter)?;
+        let system_program_info = next_account_info(accounts_info_iter)?;
+
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !data_account.is_writable {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        if data_account.data_len() >= ContractData::LEN {
+            msg!("Staking [Info]: Contract data account already has the current layout");
+            return Ok(())
+        }
+        // The admin and the mint lead every layout so far
+        let (admin_pubkey, stake_token_mint) = {
+            let data = data_account.data.borrow();
+            let read_pubkey = |offset: usize| data
+                .get(offset..offset + 32)
+                .and_then(|slice| slice.try_into().ok())
+                .map(Pubkey::new_from_array)
+                .ok_or(ProgramError::InvalidAccountData);
+            (read_pubkey(1)?, read_pubkey(33)?)
+        };
+        if &admin_pubkey != admin.key {
+            msg!("Staking [Error]: Invalid contract data");
+            return Err(StakingError::Unauthorized.into())
+        }
+        let (contract_data_pda, _bump) = pda::contract_data_pda(&admin_pubkey, &stake_token_mint, program_id);
+        if data_account.key != &contract_data_pda {
+            msg!("Staking [Error]: Contract data account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+
+        msg!("Staking [Info]: Growing contract data from {} to {} bytes", data_account.data_len(), ContractData::LEN);
+        let required_lamports = Rent::get()?
+            .minimum_balance(ContractData::LEN)
+            .saturating_sub(data_account.lamports());
+        if required_lamports > 0 {
+            invoke(
+                &system_instruction::transfer(admin.key, data_account.key, required_lamports),
+                &[
+                    admin.clone(),
+                    data_account.clone(),
+                    system_program_info.clone(),
+                ]
+            )?;
+        }
+        data_account.realloc(ContractData::LEN, true)?;
+        let mut contract_data = ContractData::unpack_from_slice(&data_account.data.borrow())?;
+        // Pools created before the token program was recorded all run on Token 2022
+        if contract_data.token_program_id == Pubkey::default() {
+            contract_data.token_program_id = spl_token_2022::ID;
+        }
+        ContractData::pack(contract_data, &mut data_account.try_borrow_mut_data()?)?;
+        Ok(())
+    }
+
+    fn emergency_withdraw(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+        decimals: u64
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let admin = next_account_info(accounts_info_iter)?;
+        let data_account = next_account_info(accounts_info_iter)?;
+        let token_account_info = next_account_info(accounts_info_iter)?;
+        let destination_token_account_info = next_account_info(accounts_info_iter)?;
+        let mint_info = next_account_info(accounts_info_iter)?;
+        let token_program_info = next_account_info(accounts_info_iter)?;
+
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let contract_data = ContractData::unpack_from_slice(&data_account.data.borrow())?;
+        if &contract_data.admin_pubkey != admin.key {
+            msg!("Staking [Error]: Invalid contract data");
+            return Err(StakingError::Unauthorized.into())
+        }
+        if !contract_data.is_paused {
+            msg!("Staking [Error]: Emergency withdrawal is only allowed while the pool is paused");
+            return Err(StakingError::PoolNotPaused.into())
+        }
+        let (contract_data_pda, pda_bump) = pda::contract_data_pda(
+            &contract_data.admin_pubkey, &contract_data.stake_token_mint, program_id
+        );
+        if &contract_data_pda != data_account.key {
+            msg!("Staking [Error]: Invalid contract data account");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        if token_account_info.key != &contract_data.stake_token_account {
+            msg!("Staking [Error]: Invalid contract token account");
+            return Err(StakingError::InvalidContractTokenAccount.into())
+        }
+        if mint_info.key != &contract_data.stake_token_mint {
+            msg!("Staking [Error]: Mint account does not match the staking mint");
+            return Err(StakingError::MintMismatch.into())
+        }
+        if token_program_info.key != &spl_token_2022::ID {
+            msg!("Staking [Error]: Invalid Token Program. Contract supports TOKEN 2022 only");
+            return Err(StakingError::UnsupportedTokenProgram.into())
+        }
+
+        let fee = Self::get_transfer_fee(mint_info, &contract_data.stake_token_mint, amount)?;
+        let token_transfer_ix = transfer_checked_with_fee(
+            token_program_info.key,
+            token_account_info.key,
+            mint_info.key,
+            destination_token_account_info.key,
+            &contract_data_pda,
+            &[&contract_data_pda],
+            amount,
+            decimals as u8,
+            fee
+        )?;
+        let signer_seeds: &[&[u8]] = &[
+            pda::CONTRACT_DATA_SEED,
+            contract_data.admin_pubkey.as_ref(),
+            contract_data.stake_token_mint.as_ref(),
+            &[pda_bump]
+        ];
+        invoke_signed(
+            &token_transfer_ix,
+            &[
+                token_account_info.clone(),
+                mint_info.clone(),
+                destination_token_account_info.clone(),
+                data_account.clone(),
+                token_program_info.clone(),
+            ],
+            &[signer_seeds],
+        )?;
+        msg!(
+            "Staking [Event]: Emergency withdrawal of {} to {} by {}",
+            amount, destination_token_account_info.key, admin.key
+        );
+        Ok(())
+    }
+
+    fn force_unstake(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        decimals: u64,
+        include_interest: bool,
+        clock: &Clock
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let admin = next_account_info(accounts_info_iter)?;
+        let data_account = next_account_info(accounts_info_iter)?;
+        let user_data_account = next_account_info(accounts_info_iter)?;
+        let owner_info = next_account_info(accounts_info_iter)?;
+        let owner_token_account_info = next_account_info(accounts_info_iter)?;
+        let contract_token_account_info = next_account_info(accounts_info_iter)?;
+        let mint_info = next_account_info(accounts_info_iter)?;
+        let token_program_info = next_account_info(accounts_info_iter)?;
+        let user_index_info = next_account_info(accounts_info_iter)?;
+
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature)
+        }
+        if !user_data_account.is_writable || !owner_info.is_writable {
+            return Err(ProgramError::InvalidAccountData)
+        }
+        let contract_data = Self::unpack_contract_data(program_id, data_account)?;
+        if &contract_data.admin_pubkey != admin.key {
+            msg!("Staking [Error]: Invalid contract data");
+            return Err(StakingError::Unauthorized.into())
+        }
+        Self::close_position_to_owner(
+            program_id,
+            accounts_info_iter,
+            contract_data,
+            data_account,
+            user_data_account,
+            owner_info,
+            owner_token_account_info,
+            contract_token_account_info,
+            mint_info,
+            token_program_info,
+            user_index_info,
+            decimals,
+            include_interest,
+            false,
+            admin,
+            clock
+        )
+    }
+
+    fn liquidate_expired(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        decimals: u64,
+        clock: &Clock
+    ) -> ProgramResult {
+        let accounts_info_iter = &mut accounts.iter();
+        let keeper = next_account_info(accounts_info_iter)?;
+        let data_account = next_account_info(accounts_info_iter)?;
+        let user_data_account = next_account_info(accounts_info_iter)?;
+        let owner_info = next_account_info(accounts_info_iter)?;
+        let owner_token_account_info = next_account_info(accounts_info_iter)?;
+        let contract_token_account_info = next_account_info(accounts_info_iter)?;
+        let mint_info = next_account_info(accounts_info_iter)?;
+        let token_program_info = next_account_info(accounts_info_iter)?;
+        let user_index_info = next_account_info(accounts_info_iter)?;
+
+        if !keeper.is_signer {
+            return Err(ProgramError::MissingRequiredSignature)
+        }
+        if !user_data_account.is_writable || !owner_info.is_writable {
+            return Err(ProgramError::InvalidAccountData)
+        }
+        let contract_data = Self::unpack_contract_data(program_id, data_account)?;
+        // Like unstaking, liquidations stay open while the pool paused itself over depleted rewards
+        if contract_data.is_paused && !contract_data.rewards_depleted {
+            msg!("Staking [Error]: Pool is paused");
+            return Err(StakingError::PoolPaused.into())
+        }
+        Self::close_position_to_owner(
+            program_id,
+            accounts_info_iter,
+            contract_data,
+            data_account,
+            user_data_account,
+            owner_info,
+            owner_token_account_info,
+            contract_token_account_info,
+            mint_info,
+            token_program_info,
+            user_index_info,
+            decimals,
+            true,
+            true,
+            keeper,
+            clock
+        )
+    }
+
+    /// Pays out and closes the position in `user_data_account` for `ForceUnstake` and `LiquidateExpired`.
+    /// The principal, the interest when `include_interest` is set and the rent all go to the owner
+    /// of the position. With `expired_only` only LOCKED positions past their unlock are closed
+    #[allow(clippy::too_many_arguments)]
+    fn close_position_to_owner<'a, 'b>(
+        program_id: &Pubkey,
+        accounts_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+        mut contract_data: ContractData,
+        data_account: &'b AccountInfo<'a>,
+        user_data_account: &'b AccountInfo<'a>,
+        owner_info: &'b AccountInfo<'a>,
+        owner_token_account_info: &'b AccountInfo<'a>,
+        contract_token_account_info: &'b AccountInfo<'a>,
+        mint_info: &'b AccountInfo<'a>,
+        token_program_info: &'b AccountInfo<'a>,
+        user_index_info: &'b AccountInfo<'a>,
+        decimals: u64,
+        include_interest: bool,
+        expired_only: bool,
+        caller: &'b AccountInfo<'a>,
+        clock: &Clock
+    ) -> ProgramResult {
+        let (contract_data_pda, pda_bump) = pda::contract_data_pda(
+            &contract_data.admin_pubkey, &contract_data.stake_token_mint, program_id
+        );
+        if &contract_data_pda != data_account.key {
+            msg!("Staking [Error]: Invalid contract data account");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        if user_data_account.owner != program_id {
+            msg!("Staking [Error]: User data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let user_data = UserData::unpack_from_slice(&user_data_account.data.borrow())?;
+        Self::check_position_pool(&user_data, data_account)?;
+        if !user_data.is_initialized {
+            return Err(ProgramError::UninitializedAccount)
+        }
+        if expired_only {
+            if let StakeType::NORMAL = user_data.stake_type {
+                msg!("Staking [Error]: Only LOCKED positions can be liquidated");
+                return Err(StakingError::StakeTypeMismatch.into())
+            }
+            // No grace period and no early exit, the lock must really be over
+            let unlock_ts = user_data.lock_end_ts().ok_or_else(|| {
+                msg!("Staking [Error]: Lock end of the position overflows❌");
+                ProgramError::from(StakingError::LockDurationTooLong)
+            })?;
+            if (clock.unix_timestamp as u64) < unlock_ts {
+                msg!("Staking [Error]: Lock of the position ends at {}", unlock_ts);
+                return Err(StakingError::LockNotEnded.into())
+            }
+        }
+        let (user_data_pda, _bump) = pda::user_data_pda(&user_data.owner_pubkey, data_account.key, program_id);
+        if *user_data_account.key != user_data_pda {
+            msg!("Staking [Error]: User data account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        // Everything released by the position goes back to its owner
+        if owner_info.key != &user_data.owner_pubkey {
+            msg!("Staking [Error]: Force unstake can only pay the owner of the position");
+            return Err(StakingError::Unauthorized.into())
+        }
+        Self::check_user_index(program_id, user_index_info, owner_info.key)?;
+        if owner_token_account_info.key == contract_token_account_info.key {
+            msg!("Staking [Error]: Owner token account cannot be the contract token account");
+            return Err(StakingError::InvalidDestinationTokenAccount.into())
+        }
+        let owner_token_account_data = TokenAccount::unpack_from_slice(&owner_token_account_info.data.borrow())?;
+        if owner_token_account_data.owner != user_data.owner_pubkey {
+            msg!("Staking [Error]: Owner token account is not owned by the owner of the position");
+            return Err(StakingError::InvalidDestinationTokenAccount.into())
+        }
+        if owner_token_account_data.mint != contract_data.stake_token_mint {
+            msg!("Staking [Error]: Invalid owner token account mint");
+            return Err(StakingError::MintMismatch.into())
+        }
+        if contract_token_account_info.key != &contract_data.stake_token_account {
+            msg!("Staking [Error]: Invalid contract token account");
+            return Err(StakingError::InvalidContractTokenAccount.into())
+        }
+        if mint_info.key != &contract_data.stake_token_mint {
+            msg!("Staking [Error]: Mint account does not match the staking mint");
+            return Err(StakingError::MintMismatch.into())
+        }
+        if token_program_info.key != &spl_token_2022::ID {
+            msg!("Staking [Error]: Invalid Token Program. Contract supports TOKEN 2022 only");
+            return Err(StakingError::UnsupportedTokenProgram.into())
+        }
+
+        let interest_out = if include_interest {
+            contract_data.cap_reward(math::checked_interest(
+                contract_data.accrued_interest(&user_data, user_data.total_staked, clock.unix_timestamp as u64),
+                user_data.boost_bps,
+                user_data.interest_accrued
+            )?)
+        } else {
+            0
+        };
+        // Reward token pools pay the interest from the reward vault into the owner's reward token account.
+        // Whether the accounts follow depends on the instruction like Unstake, not on the interest turning out zero
+        let reward_accounts = if contract_data.has_reward_token() && include_interest {
+            let reward_vault_info = next_account_info(accounts_info_iter)?;
+            let reward_mint_info = next_account_info(accounts_info_iter)?;
+            let owner_reward_account_info = next_account_info(accounts_info_iter)?;
+            if reward_vault_info.key != &contract_data.reward_vault {
+                msg!("Staking [Error]: Invalid reward token account");
+                return Err(StakingError::InvalidContractTokenAccount.into())
+            }
+            if reward_mint_info.key != &contract_data.reward_mint {
+                msg!("Staking [Error]: Reward mint account does not match the reward mint");
+                return Err(StakingError::MintMismatch.into())
+            }
+            if owner_reward_account_info.owner != &spl_token_2022::ID {
+                msg!("Staking [Error]: Invalid owner reward token account. Supports only Token 2022 Accounts");
+                return Err(StakingError::InvalidDestinationTokenAccount.into())
+            }
+            let owner_reward_account_data = TokenAccount::unpack_from_slice(&owner_reward_account_info.data.borrow())?;
+            if owner_reward_account_data.owner != user_data.owner_pubkey {
+                msg!("Staking [Error]: Owner reward token account is not owned by the owner of the position");
+                return Err(StakingError::InvalidDestinationTokenAccount.into())
+            }
+            if owner_reward_account_data.mint != contract_data.reward_mint {
+                msg!("Staking [Error]: Invalid owner reward token account mint");
+                return Err(StakingError::MintMismatch.into())
+            }
+            Some((reward_vault_info, reward_mint_info, owner_reward_account_info))
+        } else {
+            None
+        };
+        let history_accounts = if contract_data.history_enabled {
+            Some((next_account_info(accounts_info_iter)?, next_account_info(accounts_info_iter)?))
+        } else {
+            None
+        };
+        let earned_log_accounts = if contract_data.earned_checkpoints_enabled {
+            Some((next_account_info(accounts_info_iter)?, next_account_info(accounts_info_iter)?))
+        } else {
+            None
+        };
+        // Same rule as Unstake, the interest may not dip into the principal that stays staked
+        let interest_out = if interest_out > 0 {
+            let vault_balance = match reward_accounts {
+                Some((reward_vault_info, _, _)) => TokenAccount::unpack_from_slice(&reward_vault_info.data.borrow())?.amount,
+                None => TokenAccount::unpack_from_slice(&contract_token_account_info.data.borrow())?.amount
+            };
+            let affordable_interest = Self::affordable_interest(
+                &contract_data,
+                vault_balance,
+                Self::checked_unstake_total(&contract_data, user_data.total_staked)?,
+                user_data.total_staked,
+                true
+            )?;
+            if affordable_interest >= interest_out {
+                interest_out
+            } else {
+                msg!(
+                    "Staking [Info]: Interest shortfall of {}, paying {} of {}",
+                    interest_out - affordable_interest, affordable_interest, interest_out
+                );
+                Self::trip_circuit_breaker(&mut contract_data);
+                affordable_interest
+            }
+        } else {
+            0
+        };
+        let amount_out = match reward_accounts {
+            Some(_) => user_data.total_staked,
+            None => math::checked_add(user_data.total_staked, interest_out)?
+        };
+        // Checks: the vaults must cover the payout before any state changes
+        let fee = Self::unstake_fee(amount_out)?;
+        let amount_out_with_fee = math::checked_add(amount_out, fee)?;
+        let contract_token_account_data = TokenAccount::unpack_from_slice(&contract_token_account_info.data.borrow())?;
+        if amount_out_with_fee > contract_token_account_data.amount {
+            msg!(
+                "Staking [Error]: Payout {} exceeds the contract token balance {}",
+                amount_out_with_fee, contract_token_account_data.amount
+            );
+            return Err(StakingError::InsufficientContractBalance.into());
+        }
+        if let Some((reward_vault_info, _, _)) = reward_accounts {
+            let reward_vault_data = TokenAccount::unpack_from_slice(&reward_vault_info.data.borrow())?;
+            if interest_out > reward_vault_data.amount {
+                msg!(
+                    "Staking [Error]: Interest {} exceeds the reward token balance {}",
+                    interest_out, reward_vault_data.amount
+                );
+                return Err(StakingError::InsufficientContractBalance.into());
+            }
+        }
+        let transfer_fee = Self::get_transfer_fee(mint_info, &contract_data.stake_token_mint, amount_out_with_fee)?;
+        let (admin_pubkey, stake_token_mint, reward_mint) = (
+            contract_data.admin_pubkey, contract_data.stake_token_mint, contract_data.reward_mint
+        );
+
+        // Effects: settle the position and the pool before handing control to another program, the rent
+        // is moved once the transfers are done
+        let current_ts = clock.unix_timestamp as u64;
+        contract_data.accrue_pending_interest(current_ts);
+        contract_data.settle_pending_interest(contract_data.position_interest(&user_data, current_ts));
+        if let StakeType::LOCKED = user_data.stake_type {
+            contract_data.total_locked_staked = math::checked_sub(contract_data.total_locked_staked, user_data.total_staked)?;
+        }
+        contract_data.total_staked = Self::checked_unstake_total(&contract_data, user_data.total_staked)?;
+        contract_data.active_stakers = math::checked_sub(contract_data.active_stakers, 1)?;
+        contract_data.total_earned = math::checked_add(contract_data.total_earned, interest_out)?;
+        let checkpoint = EarnedCheckpoint { ts: current_ts, total_earned: contract_data.total_earned };
+        ContractData::pack(contract_data, &mut data_account.try_borrow_mut_data()?)?;
+        user_data_account.try_borrow_mut_data()?.fill(0);
+
+        // Interactions
+        if let Some((history_log_info, system_program_info)) = history_accounts {
+            Self::append_history(
+                program_id,
+                history_log_info,
+                data_account,
+                caller,
+                system_program_info,
+                HistoryRecord {
+                    ts: current_ts,
+                    user: user_data.owner_pubkey,
+                    amount: user_data.total_staked,
+                    action: HistoryAction::Unstake
+                }
+            )?;
+        }
+        if let Some((earned_log_info, system_program_info)) = earned_log_accounts {
+            Self::record_earned_checkpoint(
+                program_id,
+                earned_log_info,
+                data_account,
+                caller,
+                system_program_info,
+                checkpoint
+            )?;
+        }
+        let signer_seeds: &[&[u8]] = &[
+            pda::CONTRACT_DATA_SEED,
+            admin_pubkey.as_ref(),
+            stake_token_mint.as_ref(),
             &[pda_bump]
         ];
-        msg!("About to send tokens");
         invoke_signed(
-            &token_transfer_ix,
+            &transfer_checked_with_fee(
+                token_program_info.key,
+                contract_token_account_info.key,
+                mint_info.key,
+                owner_token_account_info.key,
+                &contract_data_pda,
+                &[&contract_data_pda],
+                amount_out_with_fee,
+                decimals as u8,
+                transfer_fee
+            )?,
             &[
                 contract_token_account_info.clone(),
                 mint_info.clone(),
-                user_token_account_info.clone(),
-                contract_data_account.clone(),
+                owner_token_account_info.clone(),
+                data_account.clone(),
                 token_program_info.clone(),
             ],
             &[signer_seeds],
         )?;
-        msg!("Sent tokens");
-        // Reset User Account and Contract Account
-        contract_data.total_staked = contract_data.total_staked.saturating_sub(user_data.total_staked);
-        let data_lamports = user_data_account.lamports();
-        **user_data_account.try_borrow_mut_lamports()? = 0;
-        **contract_data_account.try_borrow_mut_lamports()? += data_lamports;
-        ContractData::pack(contract_data, &mut contract_data_account.try_borrow_mut_data()?)?;
+        if let Some((reward_vault_info, reward_mint_info, owner_reward_account_info)) = reward_accounts {
+            if interest_out > 0 {
+                let reward_decimals = StateWithExtensions::<Mint>::unpack(&reward_mint_info.data.borrow())?.base.decimals;
+                let reward_fee = Self::get_transfer_fee(reward_mint_info, &reward_mint, interest_out)?;
+                invoke_signed(
+                    &transfer_checked_with_fee(
+                        token_program_info.key,
+                        reward_vault_info.key,
+                        reward_mint_info.key,
+                        owner_reward_account_info.key,
+                        &contract_data_pda,
+                        &[&contract_data_pda],
+                        interest_out,
+                        reward_decimals,
+                        reward_fee
+                    )?,
+                    &[
+                        reward_vault_info.clone(),
+                        reward_mint_info.clone(),
+                        owner_reward_account_info.clone(),
+                        data_account.clone(),
+                        token_program_info.clone(),
+                    ],
+                    &[signer_seeds],
+                )?;
+            }
+        }
+        msg!(
+            "Staking [Event]: {} of {} paid {} principal and {} interest by {}",
+            if expired_only { "Liquidation" } else { "Force unstake" },
+            user_data.owner_pubkey, user_data.total_staked, interest_out, caller.key
+        );
+        // Return the rent to the owner
+        Self::close_pda_account(user_data_account, owner_info)?;
+        Self::unindex_position(program_id, user_index_info, owner_info.key, data_account)?;
+        Ok(())
+    }
+
+    /// Creates a program owned PDA account of `space` bytes funded by `payer`.
+    /// `create_account` fails for an address that already holds lamports, so a pre-funded
+    /// PDA is topped up to rent exemption, allocated and assigned instead
+    fn create_pda_account<'a>(
+        program_id: &Pubkey,
+        payer: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        space: usize,
+        signer_seeds: &[&[u8]]
+    ) -> ProgramResult {
+        let rent = &Rent::get()?;
+        let required_lamports = rent
+            .minimum_balance(space)
+            .max(1)
+            .saturating_sub(pda_account.lamports());
+        if pda_account.lamports() == 0 {
+            return invoke_signed(
+                &system_instruction::create_account(
+                    payer.key,
+                    pda_account.key,
+                    required_lamports,
+                    space as u64,
+                    program_id,
+                ),
+                &[
+                    payer.clone(),
+                    pda_account.clone(),
+                    system_program_info.clone(),
+                ],
+                &[signer_seeds],
+            )
+        }
+        if required_lamports > 0 {
+            invoke(
+                &system_instruction::transfer(payer.key, pda_account.key, required_lamports),
+                &[
+                    payer.clone(),
+                    pda_account.clone(),
+                    system_program_info.clone(),
+                ]
+            )?;
+        }
+        invoke_signed(
+            &system_instruction::allocate(pda_account.key, space as u64),
+            &[
+                pda_account.clone(),
+                system_program_info.clone(),
+            ],
+            &[signer_seeds],
+        )?;
+        invoke_signed(
+            &system_instruction::assign(pda_account.key, program_id),
+            &[
+                pda_account.clone(),
+                system_program_info.clone(),
+            ],
+            &[signer_seeds],
+        )
+    }
+
+    /// Moves `amount` lamports from the user into their native SOL token account and syncs its balance
+    fn wrap_native<'a>(
+        user_info: &AccountInfo<'a>,
+        user_token_account_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        token_program_info: &AccountInfo<'a>,
+        amount: u64
+    ) -> ProgramResult {
+        invoke(
+            &system_instruction::transfer(user_info.key, user_token_account_info.key, amount),
+            &[
+                user_info.clone(),
+                user_token_account_info.clone(),
+                system_program_info.clone()
+            ]
+        )?;
+        invoke(
+            &spl_token_2022::instruction::sync_native(token_program_info.key, user_token_account_info.key)?,
+            &[
+                user_token_account_info.clone(),
+                token_program_info.clone()
+            ]
+        )
+    }
+
+    /// Closes the user's native SOL token account, returning its lamports to the user
+    fn unwrap_native<'a>(
+        user_info: &AccountInfo<'a>,
+        user_token_account_info: &AccountInfo<'a>,
+        token_program_info: &AccountInfo<'a>
+    ) -> ProgramResult {
+        invoke(
+            &spl_token_2022::instruction::close_account(
+                token_program_info.key,
+                user_token_account_info.key,
+                user_info.key,
+                user_info.key,
+                &[]
+            )?,
+            &[
+                user_token_account_info.clone(),
+                user_info.clone(),
+                token_program_info.clone()
+            ]
+        )
+    }
+
+    /// Unpacks the contract data after checking the account is owned by the program and large enough,
+    /// so a foreign or short account fails cleanly instead of panicking in the unpack
+    fn unpack_contract_data(program_id: &Pubkey, contract_data_account: &AccountInfo) -> Result<ContractData, ProgramError> {
+        if contract_data_account.owner != program_id {
+            msg!("Staking [Error]: Contract data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        if contract_data_account.data_len() < ContractData::LEN {
+            msg!("Staking [Error]: Contract data account is too small");
+            return Err(ProgramError::InvalidAccountData)
+        }
+        ContractData::unpack_from_slice(&contract_data_account.data.borrow())
+    }
+
+    /// Rejects the same account passed for two of the distinct roles in `accounts`, aliased accounts
+    /// share their data and would otherwise fail on overlapping borrows or overwrite each other
+    fn check_distinct_accounts(accounts: &[&AccountInfo]) -> ProgramResult {
+        for (i, account) in accounts.iter().enumerate() {
+            if accounts[i + 1..].iter().any(|other| other.key == account.key) {
+                msg!("Staking [Error]: Account {} passed for more than one account role", account.key);
+                return Err(StakingError::DuplicateAccount.into())
+            }
+        }
+        Ok(())
+    }
+
+    /// Rejects a position opened in another pool than the one at `contract_data_account`. Positions opened
+    /// before their pool was recorded never match, they have to go through `MigrateUserData` first
+    fn check_position_pool(user_data: &UserData, contract_data_account: &AccountInfo) -> ProgramResult {
+        if &user_data.pool != contract_data_account.key {
+            msg!("Staking [Error]: User data account holds a position of the pool {}", user_data.pool);
+            return Err(StakingError::PositionPoolMismatch.into())
+        }
+        Ok(())
+    }
+
+    /// Moves all lamports of a program owned account into `destination`,
+    /// making sure the account left open is still rent exempt afterwards
+    fn close_pda_account(account: &AccountInfo, destination: &AccountInfo) -> ProgramResult {
+        let destination_lamports = destination.lamports()
+            .checked_add(account.lamports())
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        **account.try_borrow_mut_lamports()? = 0;
+        **destination.try_borrow_mut_lamports()? = destination_lamports;
+        if !Rent::get()?.is_exempt(destination.lamports(), destination.data_len()) {
+            msg!("Staking [Error]: Account would not be rent exempt after closing");
+            return Err(ProgramError::AccountNotRentExempt)
+        }
         Ok(())
     }
 
+    /// Splits off a trailing Clock sysvar account, reading the clock from it when present
+    /// and from the sysvar cache otherwise
+    fn split_clock_account<'a, 'b>(
+        accounts: &'b [AccountInfo<'a>]
+    ) -> Result<(&'b [AccountInfo<'a>], Clock), ProgramError> {
+        match accounts.split_last() {
+            Some((clock_info, rest)) if sysvar::clock::check_id(clock_info.key) => {
+                Ok((rest, Clock::from_account_info(clock_info)?))
+            },
+            _ => Ok((accounts, Clock::get()?))
+        }
+    }
+
+    /// Whether the lock of `user_data` has ended at `current_ts`, counting the pool's grace period.
+    /// Fails if the unlock time overflows instead of treating such a lock as never ending
+    fn lock_ended(contract_data: &ContractData, user_data: &UserData, current_ts: u64) -> Result<bool, ProgramError> {
+        match user_data.lock_end_ts() {
+            Some(unlock_ts) => Ok(current_ts.saturating_add(contract_data.lock_grace_period) >= unlock_ts),
+            None => {
+                msg!("Staking [Error]: Lock end of the position overflows❌");
+                Err(StakingError::LockDurationTooLong.into())
+            }
+        }
+    }
+
+    /// Account authorizing and paying for a stake or unstake of `user_info`: the user itself when it
+    /// signs, otherwise the next account, which must be the pool's delegate authority and sign for a
+    /// program controlled owner. An open `position` only accepts the delegate that opened it
+    fn position_authority<'a, 'b>(
+        contract_data: &ContractData,
+        position: Option<&UserData>,
+        user_info: &'b AccountInfo<'a>,
+        account_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>
+    ) -> Result<&'b AccountInfo<'a>, ProgramError> {
+        if user_info.is_signer {
+            return Ok(user_info)
+        }
+        if contract_data.delegate_authority == Pubkey::default() {
+            return Err(ProgramError::MissingRequiredSignature)
+        }
+        let delegate_info = next_account_info(account_info_iter)?;
+        if !delegate_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature)
+        }
+        if *delegate_info.key != contract_data.delegate_authority {
+            msg!("Staking [Error]: Signer is not the delegate authority of the pool");
+            return Err(StakingError::Unauthorized.into())
+        }
+        if let Some(user_data) = position {
+            if user_data.delegate != *delegate_info.key {
+                msg!("Staking [Error]: Position was not opened by the delegate authority");
+                return Err(StakingError::Unauthorized.into())
+            }
+        }
+        Ok(delegate_info)
+    }
+
+    /// Checks that the user holds an NFT whose mint is a member of the pool's gate collection
+    fn verify_gate_nft(
+        contract_data: &ContractData,
+        user_info: &AccountInfo,
+        gate_token_account_info: &AccountInfo,
+        gate_mint_info: &AccountInfo
+    ) -> ProgramResult {
+        if gate_token_account_info.owner != &spl_token_2022::ID || gate_mint_info.owner != &spl_token_2022::ID {
+            msg!("Staking [Error]: Gate NFT accounts must be Token 2022 accounts");
+            return Err(StakingError::GateNftRequired.into())
+        }
+        let gate_token_account_data = TokenAccount::unpack_from_slice(&gate_token_account_info.data.borrow())?;
+        if gate_token_account_data.owner != *user_info.key
+            || gate_token_account_data.mint != *gate_mint_info.key
+            || gate_token_account_data.amount == 0 {
+            msg!("Staking [Error]: User does not hold the gate NFT");
+            return Err(StakingError::GateNftRequired.into())
+        }
+        let gate_mint_data = gate_mint_info.data.borrow();
+        let gate_mint = StateWithExtensions::<Mint>::unpack(&gate_mint_data)?;
+        if gate_mint.base.decimals != 0 || gate_mint.base.supply != 1 {
+            msg!("Staking [Error]: Gate mint is not an NFT");
+            return Err(StakingError::GateNftRequired.into())
+        }
+        match gate_mint.get_extension::<TokenGroupMember>() {
+            Ok(member) if member.mint == *gate_mint_info.key && member.group == contract_data.gate_collection => Ok(()),
+            _ => {
+                msg!("Staking [Error]: Gate NFT is not a member of the gate collection");
+                Err(StakingError::GateNftRequired.into())
+            }
+        }
+    }
+
+    /// Transfer fee charged by `mint_info` on `amount`, which must be the `expected_mint` of the pool.
+    /// Read from the mint's fee config of the current epoch, capped by its maximum fee, so a fee changed
+    /// after init is honored. `fee_basis_points` and `max_fee` recorded at init are never used here
     fn get_transfer_fee(
         mint_info: &AccountInfo,
+        expected_mint: &Pubkey,
         amount: u64
-    ) -> u64 {
+    ) -> Result<u64, ProgramError> {
+        if mint_info.key != expected_mint {
+            msg!("Staking [Error]: Mint account does not match the pool mint");
+            return Err(StakingError::MintMismatch.into())
+        }
         let mint_data = mint_info.data.borrow();
-        let mint = StateWithExtensions::<Mint>::unpack(&mint_data).unwrap();
+        let mint = StateWithExtensions::<Mint>::unpack(&mint_data)?;
         if let Ok(transfer_fee_config) = mint.get_extension::<TransferFeeConfig>() {
             transfer_fee_config
-                .calculate_epoch_fee(Clock::get().unwrap().epoch, amount)
-                .ok_or(ProgramError::InvalidArgument).unwrap()
+                .calculate_epoch_fee(Clock::get()?.epoch, amount)
+                .ok_or(ProgramError::InvalidArgument)
         } else {
-            0
+            Ok(0)
+        }
+    }
+
+    /// Rejects a `decimals` argument that differs from the mint, which the TOKEN 2022 program
+    /// would otherwise fail the checked transfer on without a descriptive error
+    fn check_decimals(mint_info: &AccountInfo, decimals: u64) -> ProgramResult {
+        let mint_decimals = StateWithExtensions::<Mint>::unpack(&mint_info.data.borrow())?.base.decimals;
+        if decimals != mint_decimals as u64 {
+            msg!("Staking [Error]: Decimals {} do not match the mint decimals {}", decimals, mint_decimals);
+            return Err(StakingError::DecimalsMismatch.into())
         }
+        Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn perform_staking<'a>(
         program_id: &Pubkey,
         user_info: &AccountInfo<'a>,
+        authority_info: &AccountInfo<'a>,
         user_token_account_info: &AccountInfo<'a>,
         user_data_account: &AccountInfo<'a>,
-        system_program_info: &AccountInfo<'a>,
         token_program_info: &AccountInfo<'a>,
         contract_token_account_info: &AccountInfo<'a>,
         contract_data_account: &AccountInfo<'a>,
@@ -579,75 +4002,90 @@ impl Processor {
         stake_type: StakeType,
         amount: u64,
         decimals: u64,
-        apy: u64,
-        lock_duration: u64
+        lock_duration: u64,
+        user_index_accounts: (&AccountInfo<'a>, &AccountInfo<'a>),
+        history_accounts: Option<(&AccountInfo<'a>, &AccountInfo<'a>)>,
+        dry_run: bool,
+        clock: &Clock
     ) -> ProgramResult {
         // verify the user data account
-        let seeds: &[&[u8]] = &[b"spl_staking_user", user_info.key.as_ref()];
-        let (ns_user_data_pda, bump) = Pubkey::find_program_address(
-            seeds,
-            program_id
-        );
+        let (ns_user_data_pda, _bump) = pda::user_data_pda(user_info.key, contract_data_account.key, program_id);
         if *user_data_account.key != ns_user_data_pda {
             msg!("Staking [Error]: User data account and generated pda mismatch");
-            return Err(ProgramError::InvalidAccountData.into())
+            return Err(StakingError::PdaMismatch.into())
         }
 
-        let clock = Clock::get()?;
         let current_ts = clock.unix_timestamp as u64;
         let mut contract_data = ContractData::unpack_unchecked(&contract_data_account.data.borrow())?;
-        let mut user_data = if user_data_account.data_len() == 0 {
-            // Create the PDA Account
-            let rent = &Rent::get()?;
-            let required_lamports = rent
-                .minimum_balance(UserData::LEN)
-                .max(1)
-                .saturating_sub(user_data_account.lamports());
-            let signer_seeds: &[&[u8]] = &[b"spl_staking_user", user_info.key.as_ref(), &[bump]];
-            invoke_signed(
-                &system_instruction::create_account(
-                    user_info.key,
-                    &ns_user_data_pda,
-                    required_lamports,
-                    UserData::LEN as u64,
-                    program_id,
-                ),
-                &[
-                    user_info.clone(),
-                    user_data_account.clone(),
-                    system_program_info.clone(),
-                ],
-                &[signer_seeds],
-            )?;
-            let mut data = UserData::unpack_unchecked(
-                &user_data_account.data.borrow()
-            )?;
-            data.stake_type = stake_type.clone();
-            data.owner_pubkey = *user_info.key;
-            data.is_initialized = false;
-            data.total_staked = 0;
-            data.interest_accrued = 0;
-            data.last_claim_ts = 0;
-            data.last_unstake_ts = 0;
-            data.lock_duration = lock_duration;
-            data.stake_ts = current_ts;
-            data
+        contract_data.accrue_pending_interest(current_ts);
+        // An account closed earlier in the same transaction keeps its size until the transaction ends,
+        // but without lamports it is removed then together with anything staked into it
+        if user_data_account.data_len() == 0 || user_data_account.lamports() == 0 {
+            msg!("Staking [Error]: User data account must be created with CreateUserData before staking");
+            return Err(StakingError::UserDataNotCreated.into())
+        }
+        if user_data_account.owner != program_id {
+            msg!("Staking [Error]: User data account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let mut user_data = UserData::unpack_unchecked(
+            &user_data_account.data.borrow()
+        )?;
+        if !user_data.is_initialized {
+            // Either fresh from CreateUserData or left behind without being initialized, in both
+            // cases start the position from scratch with the requested type. Only a live position
+            // is held to its stake type below
+            user_data.stake_type = stake_type.clone();
+            user_data.owner_pubkey = *user_info.key;
+            user_data.total_staked = 0;
+            user_data.interest_accrued = 0;
+            user_data.last_claim_ts = 0;
+            user_data.last_unstake_ts = 0;
+            user_data.lock_duration = lock_duration;
+            user_data.stake_ts = current_ts;
+            user_data.created_ts = current_ts;
+            user_data.boost_bps = UserData::BASE_BOOST_BPS;
+            user_data.unlock_ts = 0;
+            user_data.pool = *contract_data_account.key;
+            // Only the delegate opening the position may act for its owner later on
+            user_data.delegate = if authority_info.key == user_info.key {
+                Pubkey::default()
+            } else {
+                *authority_info.key
+            };
+        } else {
+            Self::check_position_pool(&user_data, contract_data_account)?;
+            if authority_info.key != user_info.key && user_data.delegate != *authority_info.key {
+                msg!("Staking [Error]: Position was not opened by the delegate authority");
+                return Err(StakingError::Unauthorized.into())
+            }
+        }
+        // The deposit fee stays in the contract token account and is not credited to the position
+        let deposit_fee = if user_data.is_fee_exempt {
+            0
         } else {
-            UserData::unpack_from_slice(
-                &user_data_account.data.borrow()
-            )?
+            math::bps(amount, contract_data.deposit_fee_bps)?
         };
+        let credited_amount = math::checked_sub(amount, deposit_fee)?;
+        contract_data.total_deposit_fees = math::checked_add(contract_data.total_deposit_fees, deposit_fee)?;
         // First time staking
         if !user_data.is_initialized {
             msg!("Staking [Info]: First time staking");
-            let fee = Self::get_transfer_fee(mint_account, amount);
+            if contract_data.max_stakers != 0 && contract_data.active_stakers >= contract_data.max_stakers {
+                msg!("Staking [Error]: Pool already has the maximum of {} stakers", contract_data.max_stakers);
+                return Err(StakingError::MaxStakersReached.into())
+            }
+            if dry_run {
+                return Ok(())
+            }
+            let fee = Self::get_transfer_fee(mint_account, &contract_data.stake_token_mint, amount)?;
             let transfer_tkn_ix = transfer_checked_with_fee(
                 &spl_token_2022::ID,
                 user_token_account_info.key,
                 &contract_data.stake_token_mint,
                 contract_token_account_info.key,
-                user_info.key,
-                &[user_info.key],
+                authority_info.key,
+                &[authority_info.key],
                 amount,
                 decimals as u8,
                 fee
@@ -658,28 +4096,40 @@ impl Processor {
                     user_token_account_info.clone(),
                     mint_account.clone(),
                     contract_token_account_info.clone(),
-                    user_info.clone(),
+                    authority_info.clone(),
                     token_program_info.clone()
                 ]
             )?;
             user_data.is_initialized = true;
-            user_data.total_staked = amount;
-            contract_data.total_staked = contract_data.total_staked.add(amount);
+            user_data.total_staked = credited_amount;
+            user_data.created_ts = current_ts;
+            contract_data.total_staked = math::checked_add(contract_data.total_staked, credited_amount)?;
+            contract_data.active_stakers = math::checked_add(contract_data.active_stakers, 1)?;
         } else {
             msg!("Staking [Info]: Re-staking");
-            if stake_type as u8 != user_data.stake_type.clone() as u8 {
+            if u8::from(&stake_type) != u8::from(&user_data.stake_type) {
                 msg!("Staking [Info]: Stake type mismatch");
-                return Err(ProgramError::InvalidInstructionData.into())
+                return Err(StakingError::StakeTypeMismatch.into())
+            }
+            if current_ts < math::checked_add(user_data.stake_ts, contract_data.min_restake_interval)? {
+                msg!(
+                    "Staking [Error]: Cannot re-stake within {} seconds of the last stake",
+                    contract_data.min_restake_interval
+                );
+                return Err(StakingError::RestakeTooSoon.into())
+            }
+            if dry_run {
+                return Ok(())
             }
             // Transfer tokens to contract pda
-            let fee = Self::get_transfer_fee(mint_account, amount);
+            let fee = Self::get_transfer_fee(mint_account, &contract_data.stake_token_mint, amount)?;
             let transfer_tkn_ix = transfer_checked_with_fee(
                 &spl_token_2022::ID,
                 user_token_account_info.key,
                 &contract_data.stake_token_mint,
                 contract_token_account_info.key,
-                user_info.key,
-                &[user_info.key],
+                authority_info.key,
+                &[authority_info.key],
                 amount,
                 decimals as u8,
                 fee
@@ -690,25 +4140,263 @@ impl Processor {
                     user_token_account_info.clone(),
                     mint_account.clone(),
                     contract_token_account_info.clone(),
-                    user_info.clone(),
+                    authority_info.clone(),
                     token_program_info.clone()
                 ]
             )?;
             // Calculate the interest accrued from stake_ts till now
-            let stake_interval = current_ts - user_data.stake_ts;
-            let interest_accrued = (
-                (apy as u128 * user_data.total_staked as u128 * stake_interval as u128)/31536000000_u128
-            ) as u64;
+            let stake_interval = math::checked_sub(current_ts, user_data.stake_ts)?;
+            let interest_accrued = math::checked_interest(
+                contract_data.accrued_interest(&user_data, user_data.total_staked, current_ts),
+                user_data.boost_bps,
+                0
+            )?;
             msg!("Staking[Info]: Interest Accrued: {}\nStake Interval: {}", interest_accrued, stake_interval);
-            user_data.interest_accrued = user_data.interest_accrued.add(interest_accrued);
-            user_data.total_staked = user_data.total_staked.add(amount);
+            user_data.interest_accrued = math::checked_add(user_data.interest_accrued, interest_accrued)?;
+            user_data.total_staked = math::checked_add(user_data.total_staked, credited_amount)?;
             user_data.stake_ts = current_ts;
             user_data.lock_duration = lock_duration;
-            contract_data.total_staked = contract_data.total_staked.add(amount);
-            contract_data.total_earned = contract_data.total_earned.add(interest_accrued);
+            // total_earned is only updated when the interest is actually paid out in perform_unstake
+            contract_data.total_staked = math::checked_add(contract_data.total_staked, credited_amount)?;
+        }
+        if let StakeType::LOCKED = user_data.stake_type {
+            contract_data.total_locked_staked = math::checked_add(contract_data.total_locked_staked, credited_amount)?;
+            // A re-stake can push the unlock out but never bring it forward
+            user_data.unlock_ts = user_data.unlock_ts.max(math::checked_add(current_ts, lock_duration)?);
+        }
+        // Interest up to now was snapshotted above, the whole position streams from here
+        user_data.reward_per_share_paid = contract_data.reward_per_share;
+        if let Some((history_log_info, system_program_info)) = history_accounts {
+            Self::append_history(
+                program_id,
+                history_log_info,
+                contract_data_account,
+                authority_info,
+                system_program_info,
+                HistoryRecord {
+                    ts: current_ts,
+                    user: *user_info.key,
+                    amount: credited_amount,
+                    action: HistoryAction::Stake
+                }
+            )?;
         }
+        // Also picks up positions opened before the user index existed
+        let (user_index_info, system_program_info) = user_index_accounts;
+        Self::index_position(
+            program_id,
+            user_index_info,
+            user_info.key,
+            contract_data_account,
+            authority_info,
+            system_program_info
+        )?;
         UserData::pack(user_data, &mut user_data_account.try_borrow_mut_data()?)?;
         ContractData::pack(contract_data, &mut contract_data_account.try_borrow_mut_data()?)?;
         Ok(())
     }
+
+    /// Verifies `user_index_info` is the user index account of `owner`, writable, and owned by the
+    /// program once it exists
+    fn check_user_index(program_id: &Pubkey, user_index_info: &AccountInfo, owner: &Pubkey) -> Result<u8, ProgramError> {
+        let (user_index_pda, bump) = pda::user_index_pda(owner, program_id);
+        if *user_index_info.key != user_index_pda {
+            msg!("Staking [Error]: User index account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        if !user_index_info.is_writable {
+            return Err(ProgramError::InvalidAccountData)
+        }
+        if user_index_info.data_len() != 0 && user_index_info.owner != program_id {
+            msg!("Staking [Error]: User index account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        Ok(bump)
+    }
+
+    /// Adds the position of `owner` in the pool at `contract_data_account` to the owner's user index,
+    /// creating the index at its full size on first use with `payer` covering the rent. A pool already
+    /// in the index is left as is
+    fn index_position<'a>(
+        program_id: &Pubkey,
+        user_index_info: &AccountInfo<'a>,
+        owner: &Pubkey,
+        contract_data_account: &AccountInfo<'a>,
+        payer: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>
+    ) -> ProgramResult {
+        let bump = Self::check_user_index(program_id, user_index_info, owner)?;
+        if user_index_info.data_len() == 0 {
+            let signer_seeds: &[&[u8]] = &[pda::USER_INDEX_SEED, owner.as_ref(), &[bump]];
+            Self::create_pda_account(
+                program_id,
+                payer,
+                user_index_info,
+                system_program_info,
+                PositionIndex::ACCOUNT_LEN,
+                signer_seeds
+            )?;
+            let index = PositionIndex {
+                is_initialized: true,
+                owner_pubkey: *owner,
+                position_count: 0
+            };
+            index.pack_into_slice(&mut user_index_info.try_borrow_mut_data()?);
+        }
+        let mut index_data = user_index_info.try_borrow_mut_data()?;
+        let mut index = PositionIndex::unpack_from_slice(&index_data)?;
+        let indexed = (0..index.position_count).any(|slot| {
+            let offset = PositionIndex::slot_offset(slot);
+            index_data[offset..offset + 32] == contract_data_account.key.to_bytes()
+        });
+        if indexed {
+            return Ok(())
+        }
+        if index.position_count >= PositionIndex::CAPACITY {
+            msg!("Staking [Error]: User already holds the maximum of {} positions", PositionIndex::CAPACITY);
+            return Err(StakingError::UserIndexFull.into())
+        }
+        let offset = PositionIndex::slot_offset(index.position_count);
+        index_data[offset..offset + 32].copy_from_slice(contract_data_account.key.as_ref());
+        index.position_count += 1;
+        index.pack_into_slice(&mut index_data);
+        Ok(())
+    }
+
+    /// Removes the position of `owner` in the pool at `contract_data_account` from the owner's user index
+    /// by moving the last slot in its place. Positions opened before the index existed are not in it
+    fn unindex_position(
+        program_id: &Pubkey,
+        user_index_info: &AccountInfo,
+        owner: &Pubkey,
+        contract_data_account: &AccountInfo
+    ) -> ProgramResult {
+        Self::check_user_index(program_id, user_index_info, owner)?;
+        if user_index_info.data_len() == 0 {
+            return Ok(())
+        }
+        let mut index_data = user_index_info.try_borrow_mut_data()?;
+        let mut index = PositionIndex::unpack_from_slice(&index_data)?;
+        let slot = (0..index.position_count).find(|slot| {
+            let offset = PositionIndex::slot_offset(*slot);
+            index_data[offset..offset + 32] == contract_data_account.key.to_bytes()
+        });
+        if let Some(slot) = slot {
+            let last_offset = PositionIndex::slot_offset(index.position_count - 1);
+            index_data.copy_within(last_offset..last_offset + 32, PositionIndex::slot_offset(slot));
+            index_data[last_offset..last_offset + 32].fill(0);
+            index.position_count -= 1;
+            index.pack_into_slice(&mut index_data);
+        }
+        Ok(())
+    }
+
+    /// Records `checkpoint` in the pool earned checkpoint log, overwriting the oldest checkpoint once the
+    /// ring buffer is full. The log is created at its full size on first use, with `payer` covering the rent
+    fn record_earned_checkpoint<'a>(
+        program_id: &Pubkey,
+        earned_log_info: &AccountInfo<'a>,
+        contract_data_account: &AccountInfo<'a>,
+        payer: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        checkpoint: EarnedCheckpoint
+    ) -> ProgramResult {
+        let (earned_log_pda, bump) = pda::earned_log_pda(contract_data_account.key, program_id);
+        if *earned_log_info.key != earned_log_pda {
+            msg!("Staking [Error]: Earned checkpoint log account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        if !earned_log_info.is_writable {
+            return Err(ProgramError::InvalidAccountData)
+        }
+        if earned_log_info.data_len() == 0 {
+            let signer_seeds: &[&[u8]] = &[pda::EARNED_LOG_SEED, contract_data_account.key.as_ref(), &[bump]];
+            Self::create_pda_account(
+                program_id,
+                payer,
+                earned_log_info,
+                system_program_info,
+                EarnedLog::ACCOUNT_LEN,
+                signer_seeds
+            )?;
+            let earned_log = EarnedLog {
+                is_initialized: true,
+                contract_data: *contract_data_account.key,
+                checkpoint_count: 0
+            };
+            earned_log.pack_into_slice(&mut earned_log_info.try_borrow_mut_data()?);
+        } else if earned_log_info.owner != program_id {
+            msg!("Staking [Error]: Earned checkpoint log account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let mut earned_data = earned_log_info.try_borrow_mut_data()?;
+        let mut earned_log = EarnedLog::unpack_from_slice(&earned_data)?;
+        let offset = EarnedLog::checkpoint_offset(earned_log.checkpoint_count);
+        earned_data[offset..offset + EarnedCheckpoint::LEN].copy_from_slice(&checkpoint.try_to_vec()?);
+        earned_log.checkpoint_count += 1;
+        earned_log.pack_into_slice(&mut earned_data);
+        Ok(())
+    }
+
+    /// Appends `record` to the pool history log, creating the log on first use.
+    /// The log grows by one record per append, with `payer` covering the extra rent
+    fn append_history<'a>(
+        program_id: &Pubkey,
+        history_log_info: &AccountInfo<'a>,
+        contract_data_account: &AccountInfo<'a>,
+        payer: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        record: HistoryRecord
+    ) -> ProgramResult {
+        let (history_log_pda, bump) = pda::history_log_pda(contract_data_account.key, program_id);
+        if *history_log_info.key != history_log_pda {
+            msg!("Staking [Error]: History log account and generated pda mismatch");
+            return Err(StakingError::PdaMismatch.into())
+        }
+        if !history_log_info.is_writable {
+            return Err(ProgramError::InvalidAccountData)
+        }
+        if history_log_info.data_len() == 0 {
+            let signer_seeds: &[&[u8]] = &[pda::HISTORY_LOG_SEED, contract_data_account.key.as_ref(), &[bump]];
+            Self::create_pda_account(
+                program_id,
+                payer,
+                history_log_info,
+                system_program_info,
+                HistoryLog::LEN,
+                signer_seeds
+            )?;
+            let history_log = HistoryLog {
+                is_initialized: true,
+                contract_data: *contract_data_account.key,
+                record_count: 0
+            };
+            history_log.pack_into_slice(&mut history_log_info.try_borrow_mut_data()?);
+        } else if history_log_info.owner != program_id {
+            msg!("Staking [Error]: History log account is not owned by the program");
+            return Err(ProgramError::IllegalOwner)
+        }
+        let mut history_log = HistoryLog::unpack_from_slice(&history_log_info.data.borrow())?;
+        let offset = HistoryLog::record_offset(history_log.record_count);
+        let new_len = offset + HistoryRecord::LEN;
+        let required_lamports = Rent::get()?
+            .minimum_balance(new_len)
+            .saturating_sub(history_log_info.lamports());
+        if required_lamports > 0 {
+            invoke(
+                &system_instruction::transfer(payer.key, history_log_info.key, required_lamports),
+                &[
+                    payer.clone(),
+                    history_log_info.clone(),
+                    system_program_info.clone(),
+                ]
+            )?;
+        }
+        history_log_info.realloc(new_len, false)?;
+        let mut history_data = history_log_info.try_borrow_mut_data()?;
+        history_data[offset..new_len].copy_from_slice(&record.try_to_vec()?);
+        history_log.record_count += 1;
+        history_log.pack_into_slice(&mut history_data);
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -1,3 +1,5 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use std::io::{self, Read, Write};
 use solana_program::{
     program_pack::{IsInitialized, Pack, Sealed},
     pubkey::Pubkey
@@ -5,12 +7,92 @@ use solana_program::{
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
 use solana_program::program_error::ProgramError;
 
+/// Discriminants are the byte stored in instructions and in `UserData`, new types take the next free value
 #[derive(Clone)]
+#[repr(u8)]
 pub enum StakeType {
-    NORMAL,
-    LOCKED
+    NORMAL = 0,
+    LOCKED = 1
 }
 
+/// The only conversion from a stored or passed discriminant, unknown values are returned as the error
+impl TryFrom<u8> for StakeType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(StakeType::NORMAL),
+            1 => Ok(StakeType::LOCKED),
+            unknown => Err(unknown)
+        }
+    }
+}
+
+impl From<&StakeType> for u8 {
+    fn from(stake_type: &StakeType) -> Self {
+        stake_type.clone() as u8
+    }
+}
+
+/// Stake type occupies 8 bytes in the packed `UserData` layout,
+/// so Borsh encodes it as a little endian u64 to stay byte compatible with `Pack`
+impl BorshSerialize for StakeType {
+    fn serialize<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (u8::from(self) as u64).serialize(writer)
+    }
+}
+
+impl BorshDeserialize for StakeType {
+    fn deserialize_reader<R: Read>(reader: &mut R) -> io::Result<Self> {
+        u8::try_from(u64::deserialize_reader(reader)?)
+            .ok()
+            .and_then(|value| StakeType::try_from(value).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid stake type"))
+    }
+}
+
+/// Rounding of interest to whole token units. FLOOR is the default and favors the pool,
+/// NEAREST rounds halves up so small positions are not systematically under paid
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy)]
+pub enum InterestRounding {
+    FLOOR,
+    NEAREST
+}
+
+
+/// Number of APY changes remembered by the contract for piecewise interest
+pub const APY_CHECKPOINTS: usize = 4;
+
+/// Default scale of `early_withdrawal_fee`, in 1/1000ths of the principal. Pools may store another
+/// `early_withdrawal_fee_denominator`, which the fee can never exceed
+pub const EARLY_WITHDRAWAL_FEE_DENOMINATOR: u64 = 1000;
+
+/// Scale of `reward_per_share`, keeping the interest of small stakes in large pools from rounding away
+pub const REWARD_PER_SHARE_PRECISION: u128 = 1_000_000_000_000;
+
+/// 1x, `promo_multiplier_bps` of a window that leaves the APY unchanged
+pub const PROMO_BASE_MULTIPLIER_BPS: u64 = 10000;
+
+/// Largest `promo_multiplier_bps` the admin can set, 10x
+pub const MAX_PROMO_MULTIPLIER_BPS: u64 = 100000;
+
+/// Version of the `ConfigSnapshot` layout returned by `GetConfig`, bumped whenever fields are appended
+pub const CONFIG_VERSION: u64 = 5;
+
+/// Seconds a NORMAL position must be held before unstaking, counted from its first stake
+pub const NORMAL_MIN_HOLD: u64 = 24 * 60 * 60;
+
+/// APY that was in effect until `ts`, recorded whenever the live APY changes
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, Default)]
+pub struct ApyCheckpoint {
+    pub ts: u64,
+    pub normal_apy: u64,
+    pub locked_apy: u64
+}
+
+impl ApyCheckpoint {
+    pub const LEN: usize = 8 + 8 + 8;
+}
 
 /// Struct for packing and unpacking contract data
 ///
@@ -24,11 +106,57 @@ pub enum StakeType {
 /// 6. minimum_stake_amount [u64]: Minimum number of tokens allowed for staking(in decimals format)
 /// 7. normal_staking_apy [u64]: % Interest per year for normal staking with decimal equals 1 (i.e. 10 = 1%)
 /// 8. locked_staking_apy [u64]: % Interest per year for locked staking with decimal equals 1 (i.e. 10 = 1%)
-/// 9. early_withdrawal_fee [u64]: % Charge for locked staking early withdrawal with decimal equals 1 (i.e. 10 = 1%)
+/// 9. early_withdrawal_fee [u64]: Charge for locked staking early withdrawal out of early_withdrawal_fee_denominator (i.e. 10 = 1% by default), at most 100%
 /// 10. total_staked [u64]: Total amount staked in the contract
-/// 11. total_earned [u64]: Total amount of interest earned on savings
-/// 12. fee_basis_points [u64]: % Tax for TOKEN_2022 with decimals equals 100 (i.e. 1% = 100)
-/// 13. max_fee [u64]: Maximum fee for TOKEN_2022 with decimals equals mint decimals
+/// 11. total_earned [u64]: Total amount of interest actually paid out to users
+/// 12. fee_basis_points [u64]: % Tax for TOKEN_2022 with decimals equals 100 (i.e. 1% = 100), as configured at init.
+///     Transfers always read the fee from the live mint
+/// 13. max_fee [u64]: Maximum fee for TOKEN_2022 with decimals equals mint decimals, as configured at init.
+///     Transfers always read the fee from the live mint
+/// 14. max_lock_duration [u64]: Maximum duration for token lock in seconds (0 = unlimited)
+/// 15. pending_normal_apy [u64]: Scheduled APY for normal staking with decimal equals 1
+/// 16. pending_locked_apy [u64]: Scheduled APY for locked staking with decimal equals 1
+/// 17. apy_effective_ts [u64]: Unix timestamp from which the scheduled APY applies (0 = nothing scheduled)
+/// 18. max_reward_per_position [u64]: Maximum interest paid out per position, zero means uncapped
+/// 19. is_paused [boolean]: Whether staking and unstaking are suspended, unstaking stays open while rewards_depleted
+/// 20. lock_grace_period [u64]: Seconds before the end of a lock within which a LOCKED unstake is treated as on time
+/// 21. reward_mint [Pubkey]: Mint interest is paid in, default pubkey when interest is paid in the stake token
+/// 22. reward_vault [Pubkey]: Token account holding the reward tokens, default pubkey when unused
+/// 23. apy_checkpoint_count [u64]: Number of APY changes recorded so far, the next checkpoint slot is this modulo APY_CHECKPOINTS
+/// 24. apy_checkpoints [[ApyCheckpoint; APY_CHECKPOINTS]]: Ring buffer of the most recent APY changes
+/// 25. deposit_fee_bps [u64]: Fee taken from each stake deposit with decimals equals 100 (i.e. 1% = 100), zero means no fee
+/// 26. total_deposit_fees [u64]: Total deposit fees kept in the contract token account
+/// 27. gate_collection [Pubkey]: Token group an NFT held by the staker must belong to, default pubkey when staking is not gated
+/// 28. max_boost_bps [u64]: Maximum boost the admin can give a position with decimals equals 10000, zero allows no boost above 1x
+/// 29. claim_cooldown [u64]: Minimum seconds between two reward claims of a position, zero means no cooldown
+/// 30. accrual_period [u64]: Interest accrues in whole periods of this many seconds, 1 means per second
+/// 31. partial_reward_ok [boolean]: Whether a claim pays only the interest the vault can cover instead of nothing. Unstakes always pay the principal and the affordable interest
+/// 32. history_enabled [boolean]: Whether stakes and unstakes are appended to the pool history log
+/// 33. min_restake_interval [u64]: Minimum seconds between the last stake of a position and a re-stake, zero means no limit
+/// 34. penalty_burn_bps [u64]: Share of early withdrawal charges burned with decimals equals 10000, the rest stays in the contract token account
+/// 35. total_locked_staked [u64]: Principal of LOCKED positions, the rest of total_staked is staked NORMAL
+/// 36. total_pending_interest [u64]: Interest accrued by open positions up to pending_interest_ts and not paid out or forfeited yet
+/// 37. pending_interest_ts [u64]: Unix timestamp total_pending_interest is accrued up to
+/// 38. delegate_authority [Pubkey]: Account allowed to stake and unstake for program controlled owners that cannot sign, default pubkey when no delegate is allowed
+/// 39. interest_rounding [InterestRounding]: Rounding of interest to whole token units, FLOOR rounds toward zero and NEAREST to the closest unit
+/// 40. active_stakers [u64]: Number of open positions
+/// 41. max_stakers [u64]: Maximum number of open positions, 0 when unlimited
+/// 42. early_withdrawal_fee_denominator [u64]: Denominator early_withdrawal_fee is a share of, 0 reads as EARLY_WITHDRAWAL_FEE_DENOMINATOR
+/// 43. rewards_depleted [boolean]: Whether the pool paused itself because the rewards could not cover a payout, principal stays withdrawable
+/// 44. token_program_id [Pubkey]: Token program the pool was initialized with, stake and unstake must pass the same program
+/// 45. compound_cooldown [u64]: Minimum seconds between two compounds of a position, zero means no cooldown
+/// 46. reward_per_second [u64]: Interest streamed per second across all positions in proportion to their stake, 0 accrues at the APY instead
+/// 47. reward_per_share [u128]: Interest accrued per staked token unit while streaming, scaled by REWARD_PER_SHARE_PRECISION
+/// 48. reward_per_share_ts [u64]: Unix timestamp reward_per_share is accrued up to
+/// 49. promo_start_ts [u64]: Unix timestamp the promotional boost window starts at
+/// 50. promo_end_ts [u64]: Unix timestamp the promotional boost window ends at, exclusive
+/// 51. promo_multiplier_bps [u64]: Multiplier of the APY inside the promotional boost window with decimals equals 10000, 0 when there is no window
+/// 52. operator_pubkey [Pubkey]: Key allowed to make non fund moving config updates next to the admin, default pubkey when there is no operator
+/// 53. rewards_paused [boolean]: Whether interest accrual is frozen, staking and unstaking stay open
+/// 54. accrual_frozen_since [u64]: Unix timestamp interest accrual is frozen from while rewards_paused
+/// 55. max_apy [u64]: Highest normal or locked staking APY the pool accepts with decimals equals 1, set at init, 0 when uncapped
+/// 56. earned_checkpoints_enabled [boolean]: Whether unstakes and claims record total_earned in the earned checkpoint log
+#[derive(BorshDeserialize, BorshSerialize)]
 pub struct ContractData {
     pub is_initialized: bool,
     pub admin_pubkey: Pubkey,
@@ -42,7 +170,50 @@ pub struct ContractData {
     pub total_staked: u64,
     pub total_earned: u64,
     pub fee_basis_points: u64,
-    pub max_fee: u64
+    pub max_fee: u64,
+    pub max_lock_duration: u64,
+    pub pending_normal_apy: u64,
+    pub pending_locked_apy: u64,
+    pub apy_effective_ts: u64,
+    pub max_reward_per_position: u64,
+    pub is_paused: bool,
+    pub lock_grace_period: u64,
+    pub reward_mint: Pubkey,
+    pub reward_vault: Pubkey,
+    pub apy_checkpoint_count: u64,
+    pub apy_checkpoints: [ApyCheckpoint; APY_CHECKPOINTS],
+    pub deposit_fee_bps: u64,
+    pub total_deposit_fees: u64,
+    pub gate_collection: Pubkey,
+    pub max_boost_bps: u64,
+    pub claim_cooldown: u64,
+    pub accrual_period: u64,
+    pub partial_reward_ok: bool,
+    pub history_enabled: bool,
+    pub min_restake_interval: u64,
+    pub penalty_burn_bps: u64,
+    pub total_locked_staked: u64,
+    pub total_pending_interest: u64,
+    pub pending_interest_ts: u64,
+    pub delegate_authority: Pubkey,
+    pub interest_rounding: InterestRounding,
+    pub active_stakers: u64,
+    pub max_stakers: u64,
+    pub early_withdrawal_fee_denominator: u64,
+    pub rewards_depleted: bool,
+    pub token_program_id: Pubkey,
+    pub compound_cooldown: u64,
+    pub reward_per_second: u64,
+    pub reward_per_share: u128,
+    pub reward_per_share_ts: u64,
+    pub promo_start_ts: u64,
+    pub promo_end_ts: u64,
+    pub promo_multiplier_bps: u64,
+    pub operator_pubkey: Pubkey,
+    pub rewards_paused: bool,
+    pub accrual_frozen_since: u64,
+    pub max_apy: u64,
+    pub earned_checkpoints_enabled: bool
 }
 
 impl Sealed for ContractData {}
@@ -67,7 +238,260 @@ impl ContractData {
         + 8
         + 8
         + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 1
+        + 8
+        + 32
+        + 32
+        + 8
+        + ApyCheckpoint::LEN * APY_CHECKPOINTS
+        + 8
+        + 8
+        + 32
+        + 8
+        + 8
+        + 8
+        + 1
+        + 1
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 32
+        + 1
+        + 8
+        + 8
+        + 8
+        + 1
+        + 32
+        + 8
+        + 8
+        + 16
+        + 8
+        + 8
+        + 8
+        + 8
+        + 32
+        + 1
+        + 8
+        + 8
+        + 1
     ;
+
+    /// Returns the (normal, locked) staking APY in effect at `current_ts`,
+    /// taking a scheduled APY change into account
+    pub fn apy_at(&self, current_ts: u64) -> (u64, u64) {
+        if self.apy_effective_ts != 0 && current_ts >= self.apy_effective_ts {
+            (self.pending_normal_apy, self.pending_locked_apy)
+        } else {
+            (self.normal_staking_apy, self.locked_staking_apy)
+        }
+    }
+
+    /// Whether `key` may make config updates that move no funds, the admin or the operator
+    pub fn is_admin_or_operator(&self, key: &Pubkey) -> bool {
+        &self.admin_pubkey == key || (self.operator_pubkey != Pubkey::default() && &self.operator_pubkey == key)
+    }
+
+    /// Records that the current live APY stayed in effect until `ts`
+    pub fn record_apy_checkpoint(&mut self, ts: u64) {
+        self.push_apy_checkpoint(ApyCheckpoint {
+            ts,
+            normal_apy: self.normal_staking_apy,
+            locked_apy: self.locked_staking_apy
+        });
+    }
+
+    /// Records that nothing accrued from `accrual_frozen_since` until `ts`, the end of a freeze
+    pub fn record_frozen_checkpoint(&mut self, ts: u64) {
+        self.push_apy_checkpoint(ApyCheckpoint { ts, normal_apy: 0, locked_apy: 0 });
+    }
+
+    fn push_apy_checkpoint(&mut self, checkpoint: ApyCheckpoint) {
+        let slot = (self.apy_checkpoint_count % APY_CHECKPOINTS as u64) as usize;
+        self.apy_checkpoints[slot] = checkpoint;
+        self.apy_checkpoint_count = self.apy_checkpoint_count.saturating_add(1);
+    }
+
+    /// `current_ts`, or when it is later the start of the running accrual freeze
+    pub fn accrual_end_ts(&self, current_ts: u64) -> u64 {
+        if self.rewards_paused {
+            current_ts.min(self.accrual_frozen_since)
+        } else {
+            current_ts
+        }
+    }
+
+    /// End of the last whole accrual period between `start_ts` and `end_ts`
+    pub fn accrued_until(&self, start_ts: u64, end_ts: u64) -> u64 {
+        let period = self.accrual_period.max(1);
+        start_ts.saturating_add(end_ts.saturating_sub(start_ts) / period * period)
+    }
+
+    /// Interest earned by `amount` staked from `start_ts` to `end_ts`, integrating the APY piecewise
+    /// across the recorded APY changes, a scheduled APY change and the promotional boost window.
+    /// Time before the oldest remembered change is charged at that change's previous APY,
+    /// and only whole accrual periods since `start_ts` earn interest
+    pub fn interest_between(&self, stake_type: &StakeType, amount: u64, start_ts: u64, end_ts: u64) -> u64 {
+        self.interest_over(stake_type, amount, start_ts, self.accrued_until(start_ts, end_ts))
+    }
+
+    /// Interest earned by `amount` staked from `start_ts` to `end_ts` without rounding down to whole accrual periods.
+    /// The part of the span inside the promotional boost window earns `promo_multiplier_bps` of the APY
+    fn interest_over(&self, stake_type: &StakeType, amount: u64, start_ts: u64, end_ts: u64) -> u64 {
+        let base = PROMO_BASE_MULTIPLIER_BPS as u128;
+        let mut numerator = self.apy_seconds(stake_type, start_ts, end_ts) * base;
+        let (promo_start, promo_end) = (start_ts.max(self.promo_start_ts), end_ts.min(self.promo_end_ts));
+        if self.promo_multiplier_bps > PROMO_BASE_MULTIPLIER_BPS && promo_start < promo_end {
+            numerator += self.apy_seconds(stake_type, promo_start, promo_end)
+                * (self.promo_multiplier_bps - PROMO_BASE_MULTIPLIER_BPS) as u128;
+        }
+        self.round_interest(numerator * amount as u128, 31536000000_u128 * base)
+    }
+
+    /// APY of `stake_type` integrated over the seconds from `start_ts` to `end_ts`, nothing
+    /// accrues during the running accrual freeze
+    fn apy_seconds(&self, stake_type: &StakeType, start_ts: u64, end_ts: u64) -> u128 {
+        let end_ts = self.accrual_end_ts(end_ts);
+        let rate = |normal: u64, locked: u64| match stake_type {
+            StakeType::NORMAL => normal,
+            StakeType::LOCKED => locked
+        };
+        let recorded = (self.apy_checkpoint_count as usize).min(APY_CHECKPOINTS);
+        let mut boundaries: Vec<(u64, u64)> = self.apy_checkpoints[..recorded]
+            .iter()
+            .map(|checkpoint| (checkpoint.ts, rate(checkpoint.normal_apy, checkpoint.locked_apy)))
+            .collect();
+        let final_rate = if self.apy_effective_ts != 0 {
+            boundaries.push((self.apy_effective_ts, rate(self.normal_staking_apy, self.locked_staking_apy)));
+            rate(self.pending_normal_apy, self.pending_locked_apy)
+        } else {
+            rate(self.normal_staking_apy, self.locked_staking_apy)
+        };
+        boundaries.sort_unstable_by_key(|&(ts, _)| ts);
+
+        let mut cursor = start_ts;
+        let mut rate_seconds: u128 = 0;
+        for (ts, apy) in boundaries {
+            if cursor >= end_ts {
+                break
+            }
+            if ts <= cursor {
+                continue
+            }
+            let segment_end = ts.min(end_ts);
+            rate_seconds += apy as u128 * (segment_end - cursor) as u128;
+            cursor = segment_end;
+        }
+        if cursor < end_ts {
+            rate_seconds += final_rate as u128 * (end_ts - cursor) as u128;
+        }
+        rate_seconds
+    }
+
+    /// Divides an interest numerator by its denominator with the pool's `interest_rounding`
+    fn round_interest(&self, numerator: u128, denominator: u128) -> u64 {
+        match self.interest_rounding {
+            InterestRounding::FLOOR => (numerator/denominator) as u64,
+            InterestRounding::NEAREST => ((numerator + denominator/2)/denominator) as u64
+        }
+    }
+
+    /// Whether interest is streamed at `reward_per_second` across the pool instead of accruing at the APY
+    pub fn streams_rewards(&self) -> bool {
+        self.reward_per_second != 0
+    }
+
+    /// `reward_per_share` accrued up to `current_ts`, nothing is streamed while the pool is empty
+    pub fn reward_per_share_at(&self, current_ts: u64) -> u128 {
+        // There are no shares to divide an empty pool's stream over. It is owed to nobody, and
+        // `accrue_pending_interest` moves `reward_per_share_ts` past it so later stakers don't get it
+        let current_ts = self.accrual_end_ts(current_ts);
+        if self.total_staked == 0 || current_ts <= self.reward_per_share_ts {
+            return self.reward_per_share
+        }
+        let streamed = self.reward_per_second as u128 * (current_ts - self.reward_per_share_ts) as u128;
+        self.reward_per_share.saturating_add(
+            streamed.saturating_mul(REWARD_PER_SHARE_PRECISION) / self.total_staked as u128
+        )
+    }
+
+    /// Interest earned by `amount` of a position since its interest was last settled, before its boost.
+    /// Streaming pools pay the position's share of `reward_per_second`, other pools accrue at the APY
+    pub fn accrued_interest(&self, user_data: &UserData, amount: u64, current_ts: u64) -> u64 {
+        if self.streams_rewards() {
+            let per_share = self.reward_per_share_at(current_ts).saturating_sub(user_data.reward_per_share_paid);
+            (per_share.saturating_mul(amount as u128) / REWARD_PER_SHARE_PRECISION) as u64
+        } else {
+            self.interest_between(&user_data.stake_type, amount, user_data.accrual_start_ts(), current_ts)
+        }
+    }
+
+    /// Accrues `total_pending_interest` up to `current_ts` at the pool's NORMAL and LOCKED principal,
+    /// or at `reward_per_second` while streaming, and brings `reward_per_share` up to date.
+    /// Must run before `total_staked` changes. Boosts, accrual periods and reward caps of single
+    /// positions are not reflected, what they leave behind is dropped once the last position closes
+    pub fn accrue_pending_interest(&mut self, current_ts: u64) {
+        self.reward_per_share = self.reward_per_share_at(current_ts);
+        self.reward_per_share_ts = self.reward_per_share_ts.max(current_ts);
+        if self.total_staked == 0 {
+            self.total_pending_interest = 0;
+        } else if current_ts > self.pending_interest_ts {
+            let accrued = if self.streams_rewards() {
+                self.reward_per_second.saturating_mul(self.accrual_end_ts(current_ts).saturating_sub(self.pending_interest_ts))
+            } else {
+                let locked = self.total_locked_staked.min(self.total_staked);
+                self.interest_over(&StakeType::NORMAL, self.total_staked - locked, self.pending_interest_ts, current_ts)
+                    .saturating_add(self.interest_over(&StakeType::LOCKED, locked, self.pending_interest_ts, current_ts))
+            };
+            self.total_pending_interest = self.total_pending_interest.saturating_add(accrued);
+        }
+        self.pending_interest_ts = self.pending_interest_ts.max(current_ts);
+    }
+
+    /// Interest owed to a position at `current_ts`, boosted and including interest it snapshotted, before the reward cap
+    pub fn position_interest(&self, user_data: &UserData, current_ts: u64) -> u64 {
+        user_data.apply_boost(self.accrued_interest(user_data, user_data.total_staked, current_ts))
+            .saturating_add(user_data.interest_accrued)
+    }
+
+    /// Removes interest paid out or forfeited by a position from `total_pending_interest`
+    pub fn settle_pending_interest(&mut self, interest: u64) {
+        self.total_pending_interest = self.total_pending_interest.saturating_sub(interest);
+    }
+
+    /// Charge taken from `amount` of a LOCKED position leaving early, `early_withdrawal_fee` out of the pool's denominator
+    pub fn early_withdrawal_charge(&self, amount: u64) -> u128 {
+        let denominator = match self.early_withdrawal_fee_denominator {
+            0 => EARLY_WITHDRAWAL_FEE_DENOMINATOR,
+            denominator => denominator
+        };
+        (self.early_withdrawal_fee as u128 * amount as u128)/denominator as u128
+    }
+
+    /// Whether interest is paid in a separate reward token instead of the stake token
+    pub fn has_reward_token(&self) -> bool {
+        self.reward_mint != Pubkey::default()
+    }
+
+    /// Whether staking requires holding an NFT of `gate_collection`
+    pub fn is_gated(&self) -> bool {
+        self.gate_collection != Pubkey::default()
+    }
+
+    /// Clamps the interest paid out for a single position to `max_reward_per_position`
+    pub fn cap_reward(&self, interest: u64) -> u64 {
+        if self.max_reward_per_position == 0 {
+            interest
+        } else {
+            interest.min(self.max_reward_per_position)
+        }
+    }
 }
 
 impl Pack for ContractData {
@@ -88,8 +512,51 @@ impl Pack for ContractData {
             tot_stk_dst,
             tot_earn_dst,
             fee_b_pt_dst,
-            max_fee_dst
-        ) = mut_array_refs![dst, 1, 32, 32, 32, 8, 8, 8, 8, 8, 8, 8, 8, 8];
+            max_fee_dst,
+            max_lk_dst,
+            p_ns_apy_dst,
+            p_ls_apy_dst,
+            apy_eff_ts_dst,
+            max_rwd_dst,
+            paused_dst,
+            grace_dst,
+            rwd_mint_dst,
+            rwd_vault_dst,
+            ckpt_count_dst,
+            ckpts_dst,
+            dep_fee_dst,
+            dep_fees_dst,
+            gate_dst,
+            max_boost_dst,
+            claim_cd_dst,
+            accrual_dst,
+            partial_reward_dst,
+            history_dst,
+            restake_dst,
+            burn_dst,
+            locked_stk_dst,
+            pend_int_dst,
+            pend_ts_dst,
+            delegate_dst,
+            rounding_dst,
+            active_stakers_dst,
+            max_stakers_dst,
+            e_w_denom_dst,
+            rewards_depleted_dst,
+            token_program_dst,
+            compound_cd_dst,
+            rps_dst,
+            rps_acc_dst,
+            rps_ts_dst,
+            promo_start_dst,
+            promo_end_dst,
+            promo_mult_dst,
+            operator_dst,
+            rewards_paused_dst,
+            frozen_since_dst,
+            max_apy_dst,
+            earned_ckpts_dst
+        ) = mut_array_refs![dst, 1, 32, 32, 32, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 1, 8, 32, 32, 8, ApyCheckpoint::LEN * APY_CHECKPOINTS, 8, 8, 32, 8, 8, 8, 1, 1, 8, 8, 8, 8, 8, 32, 1, 8, 8, 8, 1, 32, 8, 8, 16, 8, 8, 8, 8, 32, 1, 8, 8, 1];
         init_state_dst[0] = self.is_initialized as u8;
         admin_pk_dst.copy_from_slice(self.admin_pubkey.as_ref());
         stake_tkn_dst.copy_from_slice(self.stake_token_mint.as_ref());
@@ -103,6 +570,53 @@ impl Pack for ContractData {
         *tot_earn_dst = self.total_earned.to_le_bytes();
         *fee_b_pt_dst = self.fee_basis_points.to_le_bytes();
         *max_fee_dst = self.max_fee.to_le_bytes();
+        *max_lk_dst = self.max_lock_duration.to_le_bytes();
+        *p_ns_apy_dst = self.pending_normal_apy.to_le_bytes();
+        *p_ls_apy_dst = self.pending_locked_apy.to_le_bytes();
+        *apy_eff_ts_dst = self.apy_effective_ts.to_le_bytes();
+        *max_rwd_dst = self.max_reward_per_position.to_le_bytes();
+        paused_dst[0] = self.is_paused as u8;
+        *grace_dst = self.lock_grace_period.to_le_bytes();
+        rwd_mint_dst.copy_from_slice(self.reward_mint.as_ref());
+        rwd_vault_dst.copy_from_slice(self.reward_vault.as_ref());
+        *ckpt_count_dst = self.apy_checkpoint_count.to_le_bytes();
+        for (checkpoint, ckpt_dst) in self.apy_checkpoints.iter().zip(ckpts_dst.chunks_exact_mut(ApyCheckpoint::LEN)) {
+            ckpt_dst[0..8].copy_from_slice(&checkpoint.ts.to_le_bytes());
+            ckpt_dst[8..16].copy_from_slice(&checkpoint.normal_apy.to_le_bytes());
+            ckpt_dst[16..24].copy_from_slice(&checkpoint.locked_apy.to_le_bytes());
+        }
+        *dep_fee_dst = self.deposit_fee_bps.to_le_bytes();
+        *dep_fees_dst = self.total_deposit_fees.to_le_bytes();
+        gate_dst.copy_from_slice(self.gate_collection.as_ref());
+        *max_boost_dst = self.max_boost_bps.to_le_bytes();
+        *claim_cd_dst = self.claim_cooldown.to_le_bytes();
+        *accrual_dst = self.accrual_period.to_le_bytes();
+        partial_reward_dst[0] = self.partial_reward_ok as u8;
+        history_dst[0] = self.history_enabled as u8;
+        *restake_dst = self.min_restake_interval.to_le_bytes();
+        *burn_dst = self.penalty_burn_bps.to_le_bytes();
+        *locked_stk_dst = self.total_locked_staked.to_le_bytes();
+        *pend_int_dst = self.total_pending_interest.to_le_bytes();
+        *pend_ts_dst = self.pending_interest_ts.to_le_bytes();
+        delegate_dst.copy_from_slice(self.delegate_authority.as_ref());
+        rounding_dst[0] = self.interest_rounding as u8;
+        *active_stakers_dst = self.active_stakers.to_le_bytes();
+        *max_stakers_dst = self.max_stakers.to_le_bytes();
+        *e_w_denom_dst = self.early_withdrawal_fee_denominator.to_le_bytes();
+        rewards_depleted_dst[0] = self.rewards_depleted as u8;
+        token_program_dst.copy_from_slice(self.token_program_id.as_ref());
+        *compound_cd_dst = self.compound_cooldown.to_le_bytes();
+        *rps_dst = self.reward_per_second.to_le_bytes();
+        *rps_acc_dst = self.reward_per_share.to_le_bytes();
+        *rps_ts_dst = self.reward_per_share_ts.to_le_bytes();
+        *promo_start_dst = self.promo_start_ts.to_le_bytes();
+        *promo_end_dst = self.promo_end_ts.to_le_bytes();
+        *promo_mult_dst = self.promo_multiplier_bps.to_le_bytes();
+        operator_dst.copy_from_slice(self.operator_pubkey.as_ref());
+        rewards_paused_dst[0] = self.rewards_paused as u8;
+        *frozen_since_dst = self.accrual_frozen_since.to_le_bytes();
+        *max_apy_dst = self.max_apy.to_le_bytes();
+        earned_ckpts_dst[0] = self.earned_checkpoints_enabled as u8;
     }
 
     fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
@@ -120,12 +634,70 @@ impl Pack for ContractData {
             tot_stk_dst,
             tot_earn_dst,
             fee_b_pt_dst,
-            max_fee_dst
-        ) = array_refs![src, 1, 32, 32, 32, 8, 8, 8, 8, 8, 8, 8, 8, 8];
+            max_fee_dst,
+            max_lk_dst,
+            p_ns_apy_dst,
+            p_ls_apy_dst,
+            apy_eff_ts_dst,
+            max_rwd_dst,
+            paused_dst,
+            grace_dst,
+            rwd_mint_dst,
+            rwd_vault_dst,
+            ckpt_count_dst,
+            ckpts_dst,
+            dep_fee_dst,
+            dep_fees_dst,
+            gate_dst,
+            max_boost_dst,
+            claim_cd_dst,
+            accrual_dst,
+            partial_reward_dst,
+            history_dst,
+            restake_dst,
+            burn_dst,
+            locked_stk_dst,
+            pend_int_dst,
+            pend_ts_dst,
+            delegate_dst,
+            rounding_dst,
+            active_stakers_dst,
+            max_stakers_dst,
+            e_w_denom_dst,
+            rewards_depleted_dst,
+            token_program_dst,
+            compound_cd_dst,
+            rps_dst,
+            rps_acc_dst,
+            rps_ts_dst,
+            promo_start_dst,
+            promo_end_dst,
+            promo_mult_dst,
+            operator_dst,
+            rewards_paused_dst,
+            frozen_since_dst,
+            max_apy_dst,
+            earned_ckpts_dst
+        ) = array_refs![src, 1, 32, 32, 32, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 1, 8, 32, 32, 8, ApyCheckpoint::LEN * APY_CHECKPOINTS, 8, 8, 32, 8, 8, 8, 1, 1, 8, 8, 8, 8, 8, 32, 1, 8, 8, 8, 1, 32, 8, 8, 16, 8, 8, 8, 8, 32, 1, 8, 8, 1];
+        let mut apy_checkpoints = [ApyCheckpoint::default(); APY_CHECKPOINTS];
+        for (checkpoint, ckpt_dst) in apy_checkpoints.iter_mut().zip(ckpts_dst.chunks_exact(ApyCheckpoint::LEN)) {
+            let ckpt_dst = array_ref![ckpt_dst, 0, ApyCheckpoint::LEN];
+            let (ts_dst, normal_dst, locked_dst) = array_refs![ckpt_dst, 8, 8, 8];
+            *checkpoint = ApyCheckpoint {
+                ts: u64::from_le_bytes(*ts_dst),
+                normal_apy: u64::from_le_bytes(*normal_dst),
+                locked_apy: u64::from_le_bytes(*locked_dst)
+            };
+        }
         let is_initialized = match init_dst[0] {
             0 => false,
             1 => true,
-            _ => return Err(ProgramError::InvalidAccountData.into())
+            _ => return Err(ProgramError::InvalidAccountData)
+        };
+        let interest_rounding = match rounding_dst[0] {
+            0 => InterestRounding::FLOOR,
+            1 => InterestRounding::NEAREST,
+            _ => return Err(ProgramError::InvalidAccountData)
         };
         Ok(ContractData {
             is_initialized,
@@ -140,7 +712,50 @@ impl Pack for ContractData {
             total_staked: u64::from_le_bytes(*tot_stk_dst),
             total_earned: u64::from_le_bytes(*tot_earn_dst),
             fee_basis_points: u64::from_le_bytes(*fee_b_pt_dst),
-            max_fee: u64::from_le_bytes(*max_fee_dst)
+            max_fee: u64::from_le_bytes(*max_fee_dst),
+            max_lock_duration: u64::from_le_bytes(*max_lk_dst),
+            pending_normal_apy: u64::from_le_bytes(*p_ns_apy_dst),
+            pending_locked_apy: u64::from_le_bytes(*p_ls_apy_dst),
+            apy_effective_ts: u64::from_le_bytes(*apy_eff_ts_dst),
+            max_reward_per_position: u64::from_le_bytes(*max_rwd_dst),
+            is_paused: paused_dst[0] != 0,
+            lock_grace_period: u64::from_le_bytes(*grace_dst),
+            reward_mint: Pubkey::new_from_array(*rwd_mint_dst),
+            reward_vault: Pubkey::new_from_array(*rwd_vault_dst),
+            apy_checkpoint_count: u64::from_le_bytes(*ckpt_count_dst),
+            apy_checkpoints,
+            deposit_fee_bps: u64::from_le_bytes(*dep_fee_dst),
+            total_deposit_fees: u64::from_le_bytes(*dep_fees_dst),
+            gate_collection: Pubkey::new_from_array(*gate_dst),
+            max_boost_bps: u64::from_le_bytes(*max_boost_dst),
+            claim_cooldown: u64::from_le_bytes(*claim_cd_dst),
+            accrual_period: u64::from_le_bytes(*accrual_dst),
+            partial_reward_ok: partial_reward_dst[0] != 0,
+            history_enabled: history_dst[0] != 0,
+            min_restake_interval: u64::from_le_bytes(*restake_dst),
+            penalty_burn_bps: u64::from_le_bytes(*burn_dst),
+            total_locked_staked: u64::from_le_bytes(*locked_stk_dst),
+            total_pending_interest: u64::from_le_bytes(*pend_int_dst),
+            pending_interest_ts: u64::from_le_bytes(*pend_ts_dst),
+            delegate_authority: Pubkey::new_from_array(*delegate_dst),
+            interest_rounding,
+            active_stakers: u64::from_le_bytes(*active_stakers_dst),
+            max_stakers: u64::from_le_bytes(*max_stakers_dst),
+            early_withdrawal_fee_denominator: u64::from_le_bytes(*e_w_denom_dst),
+            rewards_depleted: rewards_depleted_dst[0] != 0,
+            token_program_id: Pubkey::new_from_array(*token_program_dst),
+            compound_cooldown: u64::from_le_bytes(*compound_cd_dst),
+            reward_per_second: u64::from_le_bytes(*rps_dst),
+            reward_per_share: u128::from_le_bytes(*rps_acc_dst),
+            reward_per_share_ts: u64::from_le_bytes(*rps_ts_dst),
+            promo_start_ts: u64::from_le_bytes(*promo_start_dst),
+            promo_end_ts: u64::from_le_bytes(*promo_end_dst),
+            promo_multiplier_bps: u64::from_le_bytes(*promo_mult_dst),
+            operator_pubkey: Pubkey::new_from_array(*operator_dst),
+            rewards_paused: rewards_paused_dst[0] != 0,
+            accrual_frozen_since: u64::from_le_bytes(*frozen_since_dst),
+            max_apy: u64::from_le_bytes(*max_apy_dst),
+            earned_checkpoints_enabled: earned_ckpts_dst[0] != 0
         })
     }
 }
@@ -154,11 +769,20 @@ impl Pack for ContractData {
 /// 2. owner_pubkey [Pubkey]
 /// 3. stake_type [StakeType]: Locked staking or Normal staking
 /// 4. lock_duration [u64]: Duration in seconds to lock funds (Only applies to locked staking)
-/// 4. total_staked [u64]: Total amount staked
-/// 5. interest_accrued [u64]: Total interest accrued but not withdrawn
-/// 6. stake_ts [u64]: Unix timestamp of the stake initialization
-/// 6. last_claim_ts [u64]: Last claimed time stamp
-/// 7. last_unstake_ts [u64]: Last unstake time stamp
+/// 5. total_staked [u64]: Total amount staked
+/// 6. interest_accrued [u64]: Total interest accrued but not withdrawn
+/// 7. stake_ts [u64]: Unix timestamp of the stake initialization
+/// 8. last_claim_ts [u64]: Last claimed time stamp
+/// 9. last_unstake_ts [u64]: Last unstake time stamp
+/// 10. created_ts [u64]: Unix timestamp of the first stake of the position, never reset by re-staking
+/// 11. boost_bps [u64]: Interest multiplier of the position with decimals equals 10000 (i.e. 15000 = 1.5x), zero is treated as 1x
+/// 12. reward_per_share_paid [u128]: reward_per_share of the pool when the interest of the position was last settled
+/// 13. unlock_ts [u64]: Unix timestamp the lock of a LOCKED position ends, re-stakes can only push it out, 0 for NORMAL positions
+/// 14. is_fee_exempt [boolean]: Set by the admin to waive the deposit fee and the early withdrawal charge of the position
+/// 15. penalty_waived [boolean]: Set by the admin to waive the early withdrawal charge of the next early unstake only
+/// 16. pool [Pubkey]: Contract data account of the pool the position was opened in, default pubkey for positions opened before it was recorded
+/// 17. delegate [Pubkey]: Delegate authority that opened the position for a program controlled owner and may unstake it, default pubkey when the owner opened it
+#[derive(BorshDeserialize, BorshSerialize)]
 pub struct UserData {
     pub is_initialized: bool,
     pub owner_pubkey: Pubkey,
@@ -168,13 +792,40 @@ pub struct UserData {
     pub interest_accrued: u64,
     pub stake_ts: u64,
     pub last_claim_ts: u64,
-    pub last_unstake_ts: u64
+    pub last_unstake_ts: u64,
+    pub created_ts: u64,
+    pub boost_bps: u64,
+    pub reward_per_share_paid: u128,
+    pub unlock_ts: u64,
+    pub is_fee_exempt: bool,
+    pub penalty_waived: bool,
+    pub pool: Pubkey,
+    pub delegate: Pubkey
 }
 
 impl Sealed for UserData {}
 
 impl UserData {
     pub const LEN: usize = 1
+        + 32
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 16
+        + 8
+        + 1
+        + 1
+        + 32
+        + 32;
+
+    /// Length of accounts packed before `created_ts` and `boost_bps` were added
+    pub const LEGACY_LEN: usize = 1
         + 32
         + 8
         + 8
@@ -183,6 +834,28 @@ impl UserData {
         + 8
         + 8
         + 8;
+
+    /// 1x boost, `boost_bps` of a position that was never boosted
+    pub const BASE_BOOST_BPS: u64 = 10000;
+
+    /// Timestamp from which interest is not settled yet, the later of the last stake and the last claim
+    pub fn accrual_start_ts(&self) -> u64 {
+        self.stake_ts.max(self.last_claim_ts)
+    }
+
+    /// Timestamp at which the lock of the position ends, never before `unlock_ts`.
+    /// `None` if the end of the running lock does not fit in a u64
+    pub fn lock_end_ts(&self) -> Option<u64> {
+        self.stake_ts.checked_add(self.lock_duration).map(|end_ts| end_ts.max(self.unlock_ts))
+    }
+
+    /// Scales interest earned by the position by its boost
+    pub fn apply_boost(&self, interest: u64) -> u64 {
+        if self.boost_bps == 0 {
+            return interest
+        }
+        ((interest as u128 * self.boost_bps as u128)/UserData::BASE_BOOST_BPS as u128) as u64
+    }
 }
 
 impl Pack for UserData {
@@ -199,24 +872,45 @@ impl Pack for UserData {
             int_accr_dst,
             stake_ts_dst,
             last_clm_dst,
-            last_unst_dst
-        ) = mut_array_refs![dst, 1, 32, 8, 8, 8, 8, 8, 8, 8];
+            last_unst_dst,
+            created_ts_dst,
+            boost_dst,
+            rps_paid_dst,
+            unlock_ts_dst,
+            fee_exempt_dst,
+            penalty_waived_dst,
+            pool_dst,
+            delegate_dst
+        ) = mut_array_refs![dst, 1, 32, 8, 8, 8, 8, 8, 8, 8, 8, 8, 16, 8, 1, 1, 32, 32];
         is_init_dst[0] = self.is_initialized as u8;
         owner_pk_dst.copy_from_slice(self.owner_pubkey.as_ref());
-        stk_type_dst[0] = match self.stake_type {
-            StakeType::NORMAL => 0,
-            StakeType::LOCKED => 1
-        };
+        stk_type_dst[0] = u8::from(&self.stake_type);
         *lock_dur_dst = self.lock_duration.to_le_bytes();
         *tot_stk_dst = self.total_staked.to_le_bytes();
         *int_accr_dst = self.interest_accrued.to_le_bytes();
         *stake_ts_dst = self.stake_ts.to_le_bytes();
         *last_clm_dst = self.last_claim_ts.to_le_bytes();
-        *last_unst_dst = self.last_unstake_ts.to_le_bytes()
+        *last_unst_dst = self.last_unstake_ts.to_le_bytes();
+        *created_ts_dst = self.created_ts.to_le_bytes();
+        *boost_dst = self.boost_bps.to_le_bytes();
+        *rps_paid_dst = self.reward_per_share_paid.to_le_bytes();
+        *unlock_ts_dst = self.unlock_ts.to_le_bytes();
+        fee_exempt_dst[0] = self.is_fee_exempt as u8;
+        penalty_waived_dst[0] = self.penalty_waived as u8;
+        pool_dst.copy_from_slice(self.pool.as_ref());
+        delegate_dst.copy_from_slice(self.delegate.as_ref());
     }
 
     fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
-        let src = array_ref![src, 0, UserData::LEN];
+        // Accounts packed with an older, shorter layout are read with the missing
+        // trailing fields zeroed and defaulted below
+        if src.len() < UserData::LEGACY_LEN {
+            return Err(ProgramError::InvalidAccountData)
+        }
+        let legacy_len = src.len().min(UserData::LEN);
+        let mut padded = [0u8; UserData::LEN];
+        padded[..legacy_len].copy_from_slice(&src[..legacy_len]);
+        let src = &padded;
         let (
             is_init_dst,
             owner_pk_dst,
@@ -226,17 +920,46 @@ impl Pack for UserData {
             int_accr_dst,
             stake_ts_dst,
             last_clm_dst,
-            last_unst_dst
-        ) = array_refs![src, 1, 32, 8, 8, 8, 8, 8, 8, 8];
+            last_unst_dst,
+            created_ts_dst,
+            boost_dst,
+            rps_paid_dst,
+            unlock_ts_dst,
+            fee_exempt_dst,
+            penalty_waived_dst,
+            pool_dst,
+            delegate_dst
+        ) = array_refs![src, 1, 32, 8, 8, 8, 8, 8, 8, 8, 8, 8, 16, 8, 1, 1, 32, 32];
         let is_initialized = match is_init_dst[0] {
             0 => false,
             1 => true,
-            _ => return Err(ProgramError::InvalidAccountData.into())
+            _ => return Err(ProgramError::InvalidAccountData)
+        };
+        let stake_type = StakeType::try_from(stk_type_dst[0]).map_err(|_| ProgramError::InvalidAccountData)?;
+        let stake_ts = u64::from_le_bytes(*stake_ts_dst);
+        let created_ts = match u64::from_le_bytes(*created_ts_dst) {
+            // The creation time of a legacy position is unknown, its first stake is the best estimate
+            0 => stake_ts,
+            created_ts => created_ts
+        };
+        let unlock_ts = match (u64::from_le_bytes(*unlock_ts_dst), &stake_type) {
+            // Legacy LOCKED positions recorded only the running lock
+            (0, StakeType::LOCKED) => stake_ts.saturating_add(u64::from_le_bytes(*lock_dur_dst)),
+            (unlock_ts, _) => unlock_ts
+        };
+        let is_fee_exempt = match fee_exempt_dst[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(ProgramError::InvalidAccountData)
+        };
+        let penalty_waived = match penalty_waived_dst[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(ProgramError::InvalidAccountData)
         };
-        let stake_type = match stk_type_dst[0] {
-            0 => StakeType::NORMAL,
-            1 => StakeType::LOCKED,
-            _ => return Err(ProgramError::InvalidAccountData.into())
+        let boost_bps = match u64::from_le_bytes(*boost_dst) {
+            0 => UserData::BASE_BOOST_BPS,
+            boost_bps => boost_bps
         };
         Ok(UserData {
             is_initialized,
@@ -245,9 +968,544 @@ impl Pack for UserData {
             lock_duration: u64::from_le_bytes(*lock_dur_dst),
             total_staked: u64::from_le_bytes(*tot_stk_dst),
             interest_accrued: u64::from_le_bytes(*int_accr_dst),
-            stake_ts: u64::from_le_bytes(*stake_ts_dst),
+            stake_ts,
             last_claim_ts: u64::from_le_bytes(*last_clm_dst),
-            last_unstake_ts: u64::from_le_bytes(*last_unst_dst)
+            last_unstake_ts: u64::from_le_bytes(*last_unst_dst),
+            created_ts,
+            boost_bps,
+            reward_per_share_paid: u128::from_le_bytes(*rps_paid_dst),
+            unlock_ts,
+            is_fee_exempt,
+            penalty_waived,
+            pool: Pubkey::new_from_array(*pool_dst),
+            delegate: Pubkey::new_from_array(*delegate_dst)
         })
     }
-}
\ No newline at end of file
+}
+
+
+/// Voting snapshot of a single position, written as return data by `SnapshotPosition`
+///
+/// Fields [All are Public]
+///
+/// 1. owner_pubkey [Pubkey]
+/// 2. total_staked [u64]: Total amount staked
+/// 3. stake_type [StakeType]: Locked staking or Normal staking
+/// 4. stake_ts [u64]: Unix timestamp of the stake initialization
+/// 5. weight [u64]: Voting weight of the position, equals total_staked unless weighted by remaining lock
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PositionSnapshot {
+    pub owner_pubkey: Pubkey,
+    pub total_staked: u64,
+    pub stake_type: StakeType,
+    pub stake_ts: u64,
+    pub weight: u64
+}
+
+
+/// Open positions of a user, written as return data by `GetUserIndex`
+///
+/// Fields [All are Public]
+///
+/// 1. owner_pubkey [Pubkey]
+/// 2. positions [Vec<Pubkey>]: Data accounts of the pools the owner holds an open position in, the user
+///    data account of each is derived from the owner and the pool
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct UserIndex {
+    pub owner_pubkey: Pubkey,
+    pub positions: Vec<Pubkey>
+}
+
+
+/// Projected outcome of a hypothetical stake, written as return data by `PreviewRewards`
+///
+/// Fields [All are Public]
+///
+/// 1. interest [u64]: Interest earned when the stake is held for the whole duration
+/// 2. early_withdrawal_charge [u64]: Charge taken from the principal when a LOCKED stake exits early, 0 for NORMAL
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct RewardPreview {
+    pub interest: u64,
+    pub early_withdrawal_charge: u64
+}
+
+
+/// Interest of a position and the part of it a claim would pay right now, written as return data by `GetClaimable`
+///
+/// Fields [All are Public]
+///
+/// 1. gross_interest [u64]: Interest accrued by the position so far, boost included and before the reward cap
+/// 2. claimable [u64]: Interest ClaimRewards would pay now, 0 while the claim cooldown or the lock is running
+///    or the contract cannot pay it
+/// 3. claimable_from_ts [u64]: Unix timestamp from which the claim cooldown and the lock allow a claim
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ClaimableRewards {
+    pub gross_interest: u64,
+    pub claimable: u64,
+    pub claimable_from_ts: u64
+}
+
+
+/// Yield of a stake type, written as return data by `GetPoolApr`
+///
+/// Fields [All are Public]
+///
+/// 1. gross_apy [u64]: Current APY of the stake type with decimal equals 1 (i.e. 10 = 1%)
+/// 2. transfer_fee_bps [u16]: Transfer fee of the stake token mint in the current epoch
+/// 3. net_apr [i64]: Yield over a year held after the transfer fees of staking and unstaking,
+///    with decimal equals 1. Negative when the fees outweigh the interest
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PoolApr {
+    pub gross_apy: u64,
+    pub transfer_fee_bps: u16,
+    pub net_apr: i64
+}
+
+
+/// Outstanding obligations of a pool, written as return data by `GetLiabilities`
+///
+/// Fields [All are Public]
+///
+/// 1. total_staked [u64]: Principal of all open positions
+/// 2. total_pending_interest [u64]: Interest accrued by open positions and not paid out yet
+/// 3. total_liabilities [u64]: total_staked + total_pending_interest
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PoolLiabilities {
+    pub total_staked: u64,
+    pub total_pending_interest: u64,
+    pub total_liabilities: u64
+}
+
+
+/// Ability of a pool to pay the interest it owes, written as return data by `GetCoverageRatio`
+///
+/// Fields [All are Public]
+///
+/// 1. available_rewards [u64]: Balance of the interest vault not backing staked principal
+/// 2. total_pending_interest [u64]: Interest accrued by open positions and not paid out yet
+/// 3. coverage_bps [u64]: available_rewards / total_pending_interest in basis points, below 10000
+///    when the pool can't pay everything it owes. u64::MAX when nothing is owed
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PoolCoverage {
+    pub available_rewards: u64,
+    pub total_pending_interest: u64,
+    pub coverage_bps: u64
+}
+
+
+/// Outcome of a dry run stake, written as return data by `ValidateStake`
+///
+/// Fields [All are Public]
+///
+/// 1. error_code [u64]: 0 when the stake would pass every check, otherwise the failing `ProgramError`
+///    as a u64 (`ProgramError::from(error_code)`), `StakingError` codes for custom errors
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StakeValidation {
+    pub error_code: u64
+}
+
+
+/// Breakdown of an unstake, written as return data by `UnStake`
+///
+/// Fields [All are Public]
+///
+/// 1. principal [u64]: Principal unstaked from the position
+/// 2. gross_interest [u64]: Interest paid out, in the reward token for reward token pools
+/// 3. penalty [u64]: Early withdrawal charge kept from the principal, burned share included
+/// 4. mint_transfer_fee [u64]: Transfer fee of the stake token mint withheld from the payout
+/// 5. net_paid [u64]: Stake tokens received by the user
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct UnstakeReceipt {
+    pub principal: u64,
+    pub gross_interest: u64,
+    pub penalty: u64,
+    pub mint_transfer_fee: u64,
+    pub net_paid: u64
+}
+
+
+/// Configurable fields of a pool, written as return data by `GetConfig`. Fields after the first
+/// mean the same as in ContractData and are only ever appended, `config_version` tells clients
+/// how many to expect
+///
+/// Fields [All are Public]
+///
+/// 1. config_version [u64]: CONFIG_VERSION of the program that wrote the snapshot
+/// 2. minimum_stake_amount [u64]
+/// 3. minimum_lock_duration [u64]
+/// 4. max_lock_duration [u64]
+/// 5. normal_staking_apy [u64]
+/// 6. locked_staking_apy [u64]
+/// 7. pending_normal_apy [u64]
+/// 8. pending_locked_apy [u64]
+/// 9. apy_effective_ts [u64]
+/// 10. early_withdrawal_fee [u64]
+/// 11. early_withdrawal_fee_denominator [u64]
+/// 12. fee_basis_points [u64]
+/// 13. max_fee [u64]
+/// 14. deposit_fee_bps [u64]
+/// 15. penalty_burn_bps [u64]
+/// 16. max_reward_per_position [u64]
+/// 17. lock_grace_period [u64]
+/// 18. max_boost_bps [u64]
+/// 19. claim_cooldown [u64]
+/// 20. compound_cooldown [u64]
+/// 21. accrual_period [u64]
+/// 22. min_restake_interval [u64]
+/// 23. max_stakers [u64]
+/// 24. reward_per_second [u64]
+/// 25. promo_start_ts [u64]
+/// 26. promo_end_ts [u64]
+/// 27. promo_multiplier_bps [u64]
+/// 28. interest_rounding [InterestRounding]
+/// 29. partial_reward_ok [bool]
+/// 30. history_enabled [bool]
+/// 31. is_paused [bool]
+/// 32. rewards_depleted [bool]
+/// 33. stake_token_mint [Pubkey]
+/// 34. reward_mint [Pubkey]
+/// 35. gate_collection [Pubkey]
+/// 36. delegate_authority [Pubkey]
+/// 37. operator_pubkey [Pubkey]: Added in config_version 2
+/// 38. rewards_paused [boolean]: Added in config_version 3
+/// 39. accrual_frozen_since [u64]: Added in config_version 3
+/// 40. max_apy [u64]: Added in config_version 4
+/// 41. earned_checkpoints_enabled [bool]: Added in config_version 5
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ConfigSnapshot {
+    pub config_version: u64,
+    pub minimum_stake_amount: u64,
+    pub minimum_lock_duration: u64,
+    pub max_lock_duration: u64,
+    pub normal_staking_apy: u64,
+    pub locked_staking_apy: u64,
+    pub pending_normal_apy: u64,
+    pub pending_locked_apy: u64,
+    pub apy_effective_ts: u64,
+    pub early_withdrawal_fee: u64,
+    pub early_withdrawal_fee_denominator: u64,
+    pub fee_basis_points: u64,
+    pub max_fee: u64,
+    pub deposit_fee_bps: u64,
+    pub penalty_burn_bps: u64,
+    pub max_reward_per_position: u64,
+    pub lock_grace_period: u64,
+    pub max_boost_bps: u64,
+    pub claim_cooldown: u64,
+    pub compound_cooldown: u64,
+    pub accrual_period: u64,
+    pub min_restake_interval: u64,
+    pub max_stakers: u64,
+    pub reward_per_second: u64,
+    pub promo_start_ts: u64,
+    pub promo_end_ts: u64,
+    pub promo_multiplier_bps: u64,
+    pub interest_rounding: InterestRounding,
+    pub partial_reward_ok: bool,
+    pub history_enabled: bool,
+    pub is_paused: bool,
+    pub rewards_depleted: bool,
+    pub stake_token_mint: Pubkey,
+    pub reward_mint: Pubkey,
+    pub gate_collection: Pubkey,
+    pub delegate_authority: Pubkey,
+    pub operator_pubkey: Pubkey,
+    pub rewards_paused: bool,
+    pub accrual_frozen_since: u64,
+    pub max_apy: u64,
+    pub earned_checkpoints_enabled: bool
+}
+
+impl ConfigSnapshot {
+    pub fn from_contract_data(contract_data: &ContractData) -> Self {
+        Self {
+            config_version: CONFIG_VERSION,
+            minimum_stake_amount: contract_data.minimum_stake_amount,
+            minimum_lock_duration: contract_data.minimum_lock_duration,
+            max_lock_duration: contract_data.max_lock_duration,
+            normal_staking_apy: contract_data.normal_staking_apy,
+            locked_staking_apy: contract_data.locked_staking_apy,
+            pending_normal_apy: contract_data.pending_normal_apy,
+            pending_locked_apy: contract_data.pending_locked_apy,
+            apy_effective_ts: contract_data.apy_effective_ts,
+            early_withdrawal_fee: contract_data.early_withdrawal_fee,
+            early_withdrawal_fee_denominator: contract_data.early_withdrawal_fee_denominator,
+            fee_basis_points: contract_data.fee_basis_points,
+            max_fee: contract_data.max_fee,
+            deposit_fee_bps: contract_data.deposit_fee_bps,
+            penalty_burn_bps: contract_data.penalty_burn_bps,
+            max_reward_per_position: contract_data.max_reward_per_position,
+            lock_grace_period: contract_data.lock_grace_period,
+            max_boost_bps: contract_data.max_boost_bps,
+            claim_cooldown: contract_data.claim_cooldown,
+            compound_cooldown: contract_data.compound_cooldown,
+            accrual_period: contract_data.accrual_period,
+            min_restake_interval: contract_data.min_restake_interval,
+            max_stakers: contract_data.max_stakers,
+            reward_per_second: contract_data.reward_per_second,
+            promo_start_ts: contract_data.promo_start_ts,
+            promo_end_ts: contract_data.promo_end_ts,
+            promo_multiplier_bps: contract_data.promo_multiplier_bps,
+            interest_rounding: contract_data.interest_rounding,
+            partial_reward_ok: contract_data.partial_reward_ok,
+            history_enabled: contract_data.history_enabled,
+            is_paused: contract_data.is_paused,
+            rewards_depleted: contract_data.rewards_depleted,
+            stake_token_mint: contract_data.stake_token_mint,
+            reward_mint: contract_data.reward_mint,
+            gate_collection: contract_data.gate_collection,
+            delegate_authority: contract_data.delegate_authority,
+            operator_pubkey: contract_data.operator_pubkey,
+            rewards_paused: contract_data.rewards_paused,
+            accrual_frozen_since: contract_data.accrual_frozen_since,
+            max_apy: contract_data.max_apy,
+            earned_checkpoints_enabled: contract_data.earned_checkpoints_enabled
+        }
+    }
+}
+
+
+/// Header of the user index account of an owner, followed by `CAPACITY` slots of pool data account
+/// keys. The first `position_count` slots hold the pools of the open positions, in no particular order
+///
+/// Fields [All are Public]
+///
+/// 1. is_initialized [boolean]
+/// 2. owner_pubkey [Pubkey]: Owner of the indexed positions
+/// 3. position_count [u64]: Number of slots in use
+pub struct PositionIndex {
+    pub is_initialized: bool,
+    pub owner_pubkey: Pubkey,
+    pub position_count: u64
+}
+
+impl Sealed for PositionIndex {}
+
+impl PositionIndex {
+    pub const LEN: usize = 1
+        + 32
+        + 8;
+
+    /// Most open positions a single owner can hold across pools
+    pub const CAPACITY: u64 = 16;
+
+    /// Size of the index account, the header followed by every slot
+    pub const ACCOUNT_LEN: usize = PositionIndex::LEN + PositionIndex::CAPACITY as usize * 32;
+
+    /// Offset of the slot at `index` in the index account data
+    pub fn slot_offset(index: u64) -> usize {
+        PositionIndex::LEN + index as usize * 32
+    }
+}
+
+impl IsInitialized for PositionIndex {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for PositionIndex {
+    const LEN: usize = PositionIndex::LEN;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, PositionIndex::LEN];
+        let (is_init_dst, owner_dst, count_dst) = mut_array_refs![dst, 1, 32, 8];
+        is_init_dst[0] = self.is_initialized as u8;
+        owner_dst.copy_from_slice(self.owner_pubkey.as_ref());
+        *count_dst = self.position_count.to_le_bytes();
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, PositionIndex::LEN];
+        let (is_init_dst, owner_dst, count_dst) = array_refs![src, 1, 32, 8];
+        let is_initialized = match is_init_dst[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(ProgramError::InvalidAccountData)
+        };
+        Ok(PositionIndex {
+            is_initialized,
+            owner_pubkey: Pubkey::new_from_array(*owner_dst),
+            position_count: u64::from_le_bytes(*count_dst)
+        })
+    }
+}
+
+/// Header of the append-only history log of a pool, followed by `record_count` packed `HistoryRecord`s
+///
+/// Fields [All are Public]
+///
+/// 1. is_initialized [boolean]
+/// 2. contract_data [Pubkey]: Address of the contract data account the log belongs to
+/// 3. record_count [u64]: Number of records appended so far
+pub struct HistoryLog {
+    pub is_initialized: bool,
+    pub contract_data: Pubkey,
+    pub record_count: u64
+}
+
+impl Sealed for HistoryLog {}
+
+impl HistoryLog {
+    pub const LEN: usize = 1
+        + 32
+        + 8;
+
+    /// Most records returned by a single `ReadHistory`, keeping the page within the return data limit
+    pub const PAGE_SIZE: u64 = 20;
+
+    /// Offset of the record at `index` in the log account data
+    pub fn record_offset(index: u64) -> usize {
+        HistoryLog::LEN + index as usize * HistoryRecord::LEN
+    }
+}
+
+impl IsInitialized for HistoryLog {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for HistoryLog {
+    const LEN: usize = HistoryLog::LEN;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, HistoryLog::LEN];
+        let (is_init_dst, contract_data_dst, count_dst) = mut_array_refs![dst, 1, 32, 8];
+        is_init_dst[0] = self.is_initialized as u8;
+        contract_data_dst.copy_from_slice(self.contract_data.as_ref());
+        *count_dst = self.record_count.to_le_bytes();
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, HistoryLog::LEN];
+        let (is_init_dst, contract_data_dst, count_dst) = array_refs![src, 1, 32, 8];
+        let is_initialized = match is_init_dst[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(ProgramError::InvalidAccountData)
+        };
+        Ok(HistoryLog {
+            is_initialized,
+            contract_data: Pubkey::new_from_array(*contract_data_dst),
+            record_count: u64::from_le_bytes(*count_dst)
+        })
+    }
+}
+
+/// Header of the earned checkpoint log of a pool, a ring buffer of `CAPACITY` packed `EarnedCheckpoint`s
+/// following the header. The checkpoint numbered `n` is stored in slot `n % CAPACITY`
+///
+/// Fields [All are Public]
+///
+/// 1. is_initialized [boolean]
+/// 2. contract_data [Pubkey]: Address of the contract data account the log belongs to
+/// 3. checkpoint_count [u64]: Number of checkpoints recorded so far, only the last `CAPACITY` are kept
+pub struct EarnedLog {
+    pub is_initialized: bool,
+    pub contract_data: Pubkey,
+    pub checkpoint_count: u64
+}
+
+impl Sealed for EarnedLog {}
+
+impl EarnedLog {
+    pub const LEN: usize = 1
+        + 32
+        + 8;
+
+    /// Checkpoints kept before the oldest one is overwritten
+    pub const CAPACITY: u64 = 64;
+
+    /// Most checkpoints returned by a single `ReadEarnedCheckpoints`, keeping the page within the return data limit
+    pub const PAGE_SIZE: u64 = 32;
+
+    /// Size of the log account, the header followed by every slot of the ring buffer
+    pub const ACCOUNT_LEN: usize = EarnedLog::LEN + EarnedLog::CAPACITY as usize * EarnedCheckpoint::LEN;
+
+    /// Number of the oldest checkpoint still kept in the log
+    pub fn first_retained(&self) -> u64 {
+        self.checkpoint_count.saturating_sub(EarnedLog::CAPACITY)
+    }
+
+    /// Offset of the slot of the checkpoint numbered `index` in the log account data
+    pub fn checkpoint_offset(index: u64) -> usize {
+        EarnedLog::LEN + (index % EarnedLog::CAPACITY) as usize * EarnedCheckpoint::LEN
+    }
+}
+
+impl IsInitialized for EarnedLog {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for EarnedLog {
+    const LEN: usize = EarnedLog::LEN;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, EarnedLog::LEN];
+        let (is_init_dst, contract_data_dst, count_dst) = mut_array_refs![dst, 1, 32, 8];
+        is_init_dst[0] = self.is_initialized as u8;
+        contract_data_dst.copy_from_slice(self.contract_data.as_ref());
+        *count_dst = self.checkpoint_count.to_le_bytes();
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, EarnedLog::LEN];
+        let (is_init_dst, contract_data_dst, count_dst) = array_refs![src, 1, 32, 8];
+        let is_initialized = match is_init_dst[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(ProgramError::InvalidAccountData)
+        };
+        Ok(EarnedLog {
+            is_initialized,
+            contract_data: Pubkey::new_from_array(*contract_data_dst),
+            checkpoint_count: u64::from_le_bytes(*count_dst)
+        })
+    }
+}
+
+/// Running interest total of a pool after an unstake or claim, recorded in the earned checkpoint
+/// log and returned by `ReadEarnedCheckpoints`
+///
+/// Fields [All are Public]
+///
+/// 1. ts [u64]: Unix timestamp of the unstake or claim
+/// 2. total_earned [u64]: total_earned of the pool once the unstake or claim paid out
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, Debug, PartialEq)]
+pub struct EarnedCheckpoint {
+    pub ts: u64,
+    pub total_earned: u64
+}
+
+impl EarnedCheckpoint {
+    pub const LEN: usize = 8 + 8;
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, Debug, PartialEq)]
+pub enum HistoryAction {
+    Stake,
+    Unstake
+}
+
+/// Deposit or withdrawal of a position, appended to the pool history log and returned by `ReadHistory`
+///
+/// Fields [All are Public]
+///
+/// 1. ts [u64]: Unix timestamp of the deposit or withdrawal
+/// 2. user [Pubkey]: Owner of the position
+/// 3. amount [u64]: Principal credited to or removed from the position
+/// 4. action [HistoryAction]
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, Debug, PartialEq)]
+pub struct HistoryRecord {
+    pub ts: u64,
+    pub user: Pubkey,
+    pub amount: u64,
+    pub action: HistoryAction
+}
+
+impl HistoryRecord {
+    pub const LEN: usize = 8 + 32 + 8 + 1;
+}
@@ -2,17 +2,30 @@ mod utils;
 
 use utils::{set_up_mint, get_user_data, get_contract_data, get_token_account_data};
 use std::ops::Add;
+use solana_program::clock::Clock;
 use solana_program::native_token::LAMPORTS_PER_SOL;
 use spl_staking::{entrypoint::process_instruction};
 use solana_program_test::*;
 use solana_sdk::{
+    account::Account,
+    instruction::{AccountMeta, InstructionError},
     pubkey::Pubkey,
     signature::{Signer, keypair::Keypair},
+    transaction::{Transaction, TransactionError},
 };
-use solana_program::program_pack::{IsInitialized};
+use solana_program::program_pack::{IsInitialized, Pack};
 use solana_program::rent::Rent;
-use spl_staking::state::{StakeType};
-use crate::utils::{construct_init_txn, perform_stake, perform_unstake, perform_update_apy, set_up_token_account, transfer_sol};
+use solana_program::program_error::ProgramError;
+use spl_staking::error::StakingError;
+use spl_staking::instruction::INSTRUCTION_VERSION;
+use spl_staking::pda;
+use borsh::{BorshDeserialize, BorshSerialize};
+use spl_staking::state::{ApyCheckpoint, ContractData, EarnedCheckpoint, HistoryAction, HistoryRecord, InterestRounding, PositionIndex, StakeType, UserData, APY_CHECKPOINTS, CONFIG_VERSION, EARLY_WITHDRAWAL_FEE_DENOMINATOR, REWARD_PER_SHARE_PRECISION};
+use crate::utils::{
+    advance_clock, advance_epoch, construct_init_txn, delegate_pda, perform_create_user_data, init_instructions, process_delegate_instruction, init_instruction, expected_interest, fund_staker, perform_checkpoint_claim_and_stake, perform_checkpoint_claim_rewards, perform_checkpoint_unstake, perform_claim_and_stake, perform_claim_rewards, perform_claim_split, perform_close_user_data, perform_convert_stake_type, perform_emergency_withdraw, perform_extend_lock, perform_force_unstake, perform_gated_stake, perform_history_stake, perform_history_unstake, perform_liquidate_expired, perform_logged_force_unstake, perform_migrate_user_data, perform_partial_unstake, perform_repair_totals, perform_set_early_withdrawal_fee, perform_set_fee_exempt, perform_set_paused, perform_set_transfer_fee, perform_stake, perform_stake_with_setup,
+    perform_stake_wrapping, perform_raw_instruction, perform_set_boost, perform_unstake, perform_unstake_to, perform_unstake_with_reward, perform_update_apy, perform_set_operator, perform_set_promo_boost, perform_set_rewards_paused, perform_update_token_account, perform_waive_penalty, program_test, set_up_gate_nft, set_up_native_pool, set_up_native_token_account, set_up_pool, set_up_reward_pool, set_up_staker, try_set_up_pool, simulate_get_claimable, simulate_get_config, simulate_get_coverage_ratio, simulate_get_liabilities, simulate_get_pool_apr, simulate_get_unlock_ts, simulate_get_user_index, simulate_preview_rewards, simulate_read_earned_checkpoints, simulate_read_history, simulate_snapshot_position, simulate_unstake, simulate_validate_stake, set_up_token_account, stake_instruction, transfer_sol,
+    unstake_instruction, user_data_pda, user_index_pda, PoolConfig
+};
 
 #[tokio::test]
 async fn test_processor() {
@@ -32,7 +45,7 @@ async fn test_processor() {
     let mint_decimals = 9_u64;
     let fee_basis_point: u64 = 800;
     let max_fee: u64 = 9536743164 * 10u64.pow(mint_decimals as u32);
-    let data_acct_pda_seeds: &[&[u8]] = &[b"spl_staking", &payer_pubkey.as_ref(), &mint_pubkey.as_ref()];
+    let data_acct_pda_seeds: &[&[u8]] = &[b"spl_staking", payer_pubkey.as_ref(), mint_pubkey.as_ref()];
     let (data_acct_pda, _data_pda_bump) = Pubkey::find_program_address(
         data_acct_pda_seeds,
         &program_id
@@ -44,7 +57,7 @@ async fn test_processor() {
         &token_mint,
         &mut banks_client,
         recent_block_hash,
-        rent.clone(),
+        rent,
         mint_decimals,
         fee_basis_point,
         max_fee
@@ -58,15 +71,39 @@ async fn test_processor() {
     let normal_staking_apy: u64 = 26390; // 2639% per year
     let locked_staking_apy: u64 = 60570; // 6057% per year
     let early_withdrawal_fee: u64 = 100; // 5% per withdrawal
-    let mut transaction = construct_init_txn(
+    let config = PoolConfig {
+        mint_decimals,
         minimum_stake_amount,
         minimum_lock_duration,
         normal_staking_apy,
         locked_staking_apy,
-        mint_amount,
         early_withdrawal_fee,
-        fee_basis_point,
+        fee_basis_points: fee_basis_point,
         max_fee,
+        max_lock_duration: 0,
+        max_reward_per_position: 0,
+        lock_grace_period: 0,
+        deposit_fee_bps: 0,
+        gate_collection: Pubkey::default(),
+        max_boost_bps: 0,
+        claim_cooldown: 0,
+        accrual_period: 0,
+        partial_reward_ok: false,
+        history_enabled: false,
+        min_restake_interval: 0,
+        penalty_burn_bps: 0,
+        delegate_authority: Pubkey::default(),
+        interest_rounding: InterestRounding::FLOOR,
+        max_stakers: 0,
+        compound_cooldown: 0,
+        reward_per_second: 0,
+        min_initial_rewards: 0,
+        max_apy: 0,
+        earned_checkpoints_enabled: false,
+        reward_amount: mint_amount
+    };
+    let mut transaction = construct_init_txn(
+        &config,
         payer_pubkey,
         token_acct_keypair.pubkey(),
         rent,
@@ -79,9 +116,8 @@ async fn test_processor() {
     // Verify contract and token account states
     let contract_data = get_contract_data(&data_acct_pda, &mut banks_client).await;
     let contract_token_data = get_token_account_data(&token_acct_keypair.pubkey(), &mut banks_client).await;
-    assert_eq!(
-        contract_data.is_initialized,
-        true
+    assert!(
+        contract_data.is_initialized
     );
     assert_eq!(
         contract_data.minimum_lock_duration,
@@ -143,15 +179,14 @@ async fn test_processor() {
         contract_token_data.mint,
         mint_pubkey
     );
-    assert_eq!(
-        contract_token_data.is_initialized(),
-        true
+    assert!(
+        contract_token_data.is_initialized()
     );
 
     // --------------- Normal Staking Test ----------------------
     let user_token_account_keypair = Keypair::new();
     let (user_data_account_pubkey, _bump) = Pubkey::find_program_address(
-        &[b"spl_staking_user", payer_pubkey.as_ref()],
+        &[b"spl_staking_user", payer_pubkey.as_ref(), data_acct_pda.as_ref()],
         &program_id
     );
     let amount = 10000*10u64.pow(mint_decimals as u32);
@@ -163,33 +198,34 @@ async fn test_processor() {
         &payer,
         &user_token_account_keypair,
         None,
-        rent.clone(),
-        mint_pubkey.clone(),
+        rent,
+        mint_pubkey,
         mint_amount,
         &mut banks_client,
         recent_block_hash
     ).await;
+    perform_create_user_data(program_id, &payer, payer.pubkey(), data_acct_pda, &mut banks_client, recent_block_hash).await.unwrap();
     // perform normal stake
     perform_stake(
-        program_id.clone(),
+        program_id,
         &payer,
         user_token_account_keypair.pubkey(),
         token_acct_keypair.pubkey(),
-        user_data_account_pubkey.clone(),
-        data_acct_pda.clone(),
-        mint_pubkey.clone(),
+        user_data_account_pubkey,
+        data_acct_pda,
+        mint_pubkey,
         StakeType::NORMAL as u8,
         amount,
         mint_decimals,
         lock_duration,
         &mut banks_client,
         recent_block_hash
-    ).await;
+    ).await.unwrap();
     // Verify user data fields and token account balances
     let user_data = get_user_data(&user_data_account_pubkey, &mut banks_client).await.unwrap();
     println!("{}", user_data.is_initialized);
     let contract_data = get_contract_data(&data_acct_pda, &mut banks_client).await;
-    assert_eq!(user_data.is_initialized, true);
+    assert!(user_data.is_initialized);
     assert_eq!(user_data.stake_type as u8, StakeType::NORMAL as u8);
     assert_eq!(user_data.lock_duration, lock_duration);
     assert_ne!(user_data.stake_ts, 0);
@@ -200,20 +236,20 @@ async fn test_processor() {
     let re_stake_amount = 100*10u64.pow(mint_decimals as u32);
     let lock_duration: u64 = 0;
     perform_stake(
-        program_id.clone(),
+        program_id,
         &payer,
         user_token_account_keypair.pubkey(),
         token_acct_keypair.pubkey(),
-        user_data_account_pubkey.clone(),
-        data_acct_pda.clone(),
-        mint_pubkey.clone(),
+        user_data_account_pubkey,
+        data_acct_pda,
+        mint_pubkey,
         StakeType::NORMAL as u8,
         re_stake_amount,
         mint_decimals,
         lock_duration,
         &mut banks_client,
         recent_block_hash
-    ).await;
+    ).await.unwrap();
     // Verify Side Effects
     let user_data = get_user_data(&user_data_account_pubkey, &mut banks_client).await.unwrap();
     let contract_data = get_contract_data(&data_acct_pda, &mut banks_client).await;
@@ -243,7 +279,7 @@ async fn test_processor() {
     // --------------- Locked Staking Tests -----------------
     let new_payer = Keypair::new();
     let (new_payer_data_acct_pk, _bump) = Pubkey::find_program_address(
-        &[b"spl_staking_user", new_payer.pubkey().as_ref()],
+        &[b"spl_staking_user", new_payer.pubkey().as_ref(), data_acct_pda.as_ref()],
         &program_id
     );
     let payer_token_account_keypair = Keypair::new();
@@ -252,7 +288,7 @@ async fn test_processor() {
     let lock_duration = 24*60*60;
     transfer_sol(
         &payer,
-        new_payer.pubkey().clone(),
+        new_payer.pubkey(),
         10*LAMPORTS_PER_SOL,
         &mut banks_client,
         recent_block_hash
@@ -260,34 +296,35 @@ async fn test_processor() {
     set_up_token_account(
         &payer,
         &payer_token_account_keypair,
-        Some(new_payer.pubkey().clone()),
-        rent.clone(),
-        mint_pubkey.clone(),
+        Some(new_payer.pubkey()),
+        rent,
+        mint_pubkey,
         mint_amount,
         &mut banks_client,
         recent_block_hash
     ).await;
+    perform_create_user_data(program_id, &payer, new_payer.pubkey(), data_acct_pda, &mut banks_client, recent_block_hash).await.unwrap();
     perform_stake(
-        program_id.clone(),
+        program_id,
         &new_payer,
         payer_token_account_keypair.pubkey(),
         token_acct_keypair.pubkey(),
-        new_payer_data_acct_pk.clone(),
-        data_acct_pda.clone(),
-        mint_pubkey.clone(),
+        new_payer_data_acct_pk,
+        data_acct_pda,
+        mint_pubkey,
         StakeType::LOCKED as u8,
         stake_amount,
         mint_decimals,
         lock_duration,
         &mut banks_client,
         recent_block_hash
-    ).await;
+    ).await.unwrap();
     let expected_total_staked = amount.add(re_stake_amount).add(stake_amount);
     let user_data = get_user_data(&new_payer_data_acct_pk, &mut banks_client).await.unwrap();
     let contract_data = get_contract_data(&data_acct_pda, &mut banks_client).await;
     assert_eq!(user_data.total_staked, stake_amount);
     assert_eq!(user_data.stake_type as u8, StakeType::LOCKED as u8);
-    assert_eq!(user_data.is_initialized, true);
+    assert!(user_data.is_initialized);
     assert_eq!(user_data.lock_duration, lock_duration);
     assert_ne!(user_data.stake_ts, 0);
     assert_eq!(user_data.owner_pubkey, new_payer.pubkey());
@@ -297,20 +334,20 @@ async fn test_processor() {
     let new_lock_duration = 2*24*60*60;
     let _initial_user_data = get_user_data(&new_payer_data_acct_pk, &mut banks_client).await.unwrap();
     perform_stake(
-        program_id.clone(),
+        program_id,
         &new_payer,
         payer_token_account_keypair.pubkey(),
         token_acct_keypair.pubkey(),
-        new_payer_data_acct_pk.clone(),
-        data_acct_pda.clone(),
-        mint_pubkey.clone(),
+        new_payer_data_acct_pk,
+        data_acct_pda,
+        mint_pubkey,
         StakeType::LOCKED as u8,
         re_stake_amount,
         mint_decimals,
         new_lock_duration,
         &mut banks_client,
         recent_block_hash
-    ).await;
+    ).await.unwrap();
     let expected_total_staked = expected_total_staked.add(re_stake_amount);
     let expected_user_total_staked = user_data.total_staked.add(re_stake_amount);
     let final_user_data = get_user_data(&new_payer_data_acct_pk, &mut banks_client).await.unwrap();
@@ -320,52 +357,50 @@ async fn test_processor() {
     assert_eq!(contract_data.total_staked, expected_total_staked);
     // ---------- Locked Un-staking Tests -------------
     perform_unstake(
-        program_id.clone(),
+        program_id,
         &new_payer,
         payer_token_account_keypair.pubkey(),
         token_acct_keypair.pubkey(),
-        new_payer_data_acct_pk.clone(),
-        data_acct_pda.clone(),
-        mint_pubkey.clone(),
+        new_payer_data_acct_pk,
+        data_acct_pda,
+        mint_pubkey,
         &mut banks_client,
         recent_block_hash,
         mint_decimals
-    ).await;
+    ).await.unwrap();
     let user_data = get_user_data(&new_payer_data_acct_pk, &mut banks_client).await;
     let after_unstake_bal = get_token_account_data(
         &payer_token_account_keypair.pubkey(),
         &mut banks_client
     ).await;
-    match user_data {
-        Ok(_data) => assert!(false),
-        Err(_e) => assert!(true)
-    };
+    assert!(user_data.is_err());
     let expected_unstake_amt = expected_user_total_staked - (expected_user_total_staked * 10)/100;
     let expected_unstake_amt_with_fee = expected_unstake_amt + (expected_unstake_amt * 9)/100;
     let actual_unstake_amt = expected_unstake_amt_with_fee - (expected_unstake_amt_with_fee * fee_basis_point)/10000;
     assert_eq!(mint_amount - expected_user_total_staked + actual_unstake_amt, after_unstake_bal.amount);
 
-    // Stake After Un-staking
+    // Stake After Un-staking, the full unstake closed the user data account
     let stake_amount = 100*10u64.pow(mint_decimals as u32);
     let lock_duration = 50*60*60;
+    perform_create_user_data(program_id, &new_payer, new_payer.pubkey(), data_acct_pda, &mut banks_client, recent_block_hash).await.unwrap();
     perform_stake(
-        program_id.clone(),
+        program_id,
         &new_payer,
         payer_token_account_keypair.pubkey(),
         token_acct_keypair.pubkey(),
-        new_payer_data_acct_pk.clone(),
-        data_acct_pda.clone(),
-        mint_pubkey.clone(),
+        new_payer_data_acct_pk,
+        data_acct_pda,
+        mint_pubkey,
         StakeType::LOCKED as u8,
         stake_amount,
         mint_decimals,
         lock_duration,
         &mut banks_client,
         recent_block_hash
-    ).await;
+    ).await.unwrap();
     let user_data = get_user_data(&new_payer_data_acct_pk, &mut banks_client).await.unwrap();
     assert_eq!(user_data.total_staked, stake_amount);
-    assert_eq!(user_data.is_initialized, true);
+    assert!(user_data.is_initialized);
     assert_eq!(user_data.stake_type as u8, StakeType::LOCKED as u8);
     assert_eq!(user_data.interest_accrued, 0);
     assert_eq!(user_data.owner_pubkey, new_payer.pubkey());
@@ -376,13 +411,7214 @@ async fn test_processor() {
     perform_update_apy(
         program_id,
         &payer,
-        data_acct_pda.clone(),
+        data_acct_pda,
         normal_staking_apy,
         locked_staking_apy,
+        0,
         &mut banks_client,
         recent_block_hash
-    ).await;
+    ).await.unwrap();
     let contract_data = get_contract_data(&data_acct_pda, &mut banks_client).await;
     assert_eq!(contract_data.normal_staking_apy, normal_staking_apy);
     assert_eq!(contract_data.locked_staking_apy, locked_staking_apy)
-}
\ No newline at end of file
+}
+#[tokio::test]
+async fn test_total_earned_counts_paid_interest_once() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let amount = 1000 * unit;
+    let re_stake_amount = 500 * unit;
+    let day: u64 = 24*60*60;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, day as i64).await;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        re_stake_amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    // Re-staking only snapshots the interest, nothing has been paid yet
+    let snapshotted_interest = expected_interest(config.normal_staking_apy, amount, day);
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(user_data.interest_accrued, snapshotted_interest);
+    assert_eq!(contract_data.total_earned, 0);
+
+    advance_clock(&mut context, day as i64).await;
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    let paid_interest = snapshotted_interest + expected_interest(
+        config.normal_staking_apy,
+        amount + re_stake_amount,
+        day
+    );
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.total_earned, paid_interest);
+}
+
+#[tokio::test]
+async fn test_lock_at_max_lock_duration() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let max_lock_duration: u64 = 7*24*60*60;
+    let config = PoolConfig { max_lock_duration, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.max_lock_duration, max_lock_duration);
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    // Locking exactly at the ceiling is allowed
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        1000 * unit,
+        pool.decimals,
+        max_lock_duration,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.lock_duration, max_lock_duration);
+    assert_eq!(user_data.total_staked, 1000 * unit);
+}
+
+#[tokio::test]
+async fn test_lock_above_max_lock_duration_rejected() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let max_lock_duration: u64 = 7*24*60*60;
+    let config = PoolConfig { max_lock_duration, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    // One second past the ceiling is already too long
+    let err = perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        1000 * unit,
+        pool.decimals,
+        max_lock_duration + 1,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::LockDurationTooLong as u32))
+    );
+    assert!(!get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().is_initialized);
+    assert_eq!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount, 5000 * unit);
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.total_staked, 0);
+}
+
+#[tokio::test]
+async fn test_rejects_foreign_owned_user_data_account() {
+    let program_id = Pubkey::new_unique();
+    let user = Keypair::new();
+    let config = PoolConfig::default();
+    let unit = 10u64.pow(config.mint_decimals as u32);
+
+    // A position forged by another program at the user data PDA
+    let mut forged_data = vec![0; UserData::LEN];
+    UserData {
+        is_initialized: true,
+        owner_pubkey: user.pubkey(),
+        stake_type: StakeType::NORMAL,
+        lock_duration: 0,
+        total_staked: 5000 * unit,
+        interest_accrued: 0,
+        stake_ts: 0,
+        last_claim_ts: 0,
+        last_unstake_ts: 0,
+        created_ts: 0,
+        boost_bps: 0,
+        reward_per_share_paid: 0,
+        unlock_ts: 0,
+        is_fee_exempt: false,
+        penalty_waived: false,
+        pool: Pubkey::default(),
+        delegate: Pubkey::default()
+    }.pack_into_slice(&mut forged_data);
+    let mut context = program_test(program_id).start_with_context().await;
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let account = Account {
+        lamports: Rent::default().minimum_balance(UserData::LEN),
+        data: forged_data,
+        owner: Pubkey::new_unique(),
+        executable: false,
+        rent_epoch: 0
+    };
+    context.set_account(&user_data_pda(&user.pubkey(), &pool.data_account, &program_id), &account.into());
+    let (user_token_account, user_data_account) = fund_staker(&mut context, &pool, &user, 5000 * unit).await;
+
+    let err = perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::IllegalOwner));
+    let err = perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::IllegalOwner));
+}
+
+#[tokio::test]
+async fn test_close_empty_user_data_account() {
+    let program_id = Pubkey::new_unique();
+    let user = Keypair::new();
+    let rent = Rent::default().minimum_balance(UserData::LEN);
+
+    // An empty position left behind after a partial failure
+    let mut empty_data = vec![0; UserData::LEN];
+    UserData {
+        is_initialized: false,
+        owner_pubkey: user.pubkey(),
+        stake_type: StakeType::NORMAL,
+        lock_duration: 0,
+        total_staked: 0,
+        interest_accrued: 0,
+        stake_ts: 0,
+        last_claim_ts: 0,
+        last_unstake_ts: 0,
+        created_ts: 0,
+        boost_bps: 0,
+        reward_per_share_paid: 0,
+        unlock_ts: 0,
+        is_fee_exempt: false,
+        penalty_waived: false,
+        pool: Pubkey::default(),
+        delegate: Pubkey::default()
+    }.pack_into_slice(&mut empty_data);
+    let mut program_test = program_test(program_id);
+    let pool_data_account = Pubkey::new_unique();
+    let user_data_account = user_data_pda(&user.pubkey(), &pool_data_account, &program_id);
+    program_test.add_account(
+        user_data_account,
+        Account { lamports: rent, data: empty_data, owner: program_id, executable: false, rent_epoch: 0 }
+    );
+    let mut context = program_test.start_with_context().await;
+    transfer_sol(&context.payer, user.pubkey(), LAMPORTS_PER_SOL, &mut context.banks_client, context.last_blockhash).await;
+
+    // Only the owner can close the account
+    let other_user = Keypair::new();
+    transfer_sol(&context.payer, other_user.pubkey(), LAMPORTS_PER_SOL, &mut context.banks_client, context.last_blockhash).await;
+    assert!(perform_close_user_data(
+        program_id,
+        &other_user,
+        user_data_account,
+        pool_data_account,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.is_err());
+
+    let balance_before = context.banks_client.get_balance(user.pubkey()).await.unwrap();
+    perform_close_user_data(
+        program_id,
+        &user,
+        user_data_account,
+        pool_data_account,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let balance_after = context.banks_client.get_balance(user.pubkey()).await.unwrap();
+    // The owner gets the rent back minus the signature fee
+    assert_eq!(balance_after, balance_before + rent - 5000);
+    assert!(context.banks_client.get_account(user_data_account).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_truncated_instruction_data() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let truncated_payloads: Vec<Vec<u8>> = vec![
+        vec![],
+        vec![INSTRUCTION_VERSION],
+        // Init needs 56 bytes
+        vec![INSTRUCTION_VERSION, 0],
+        [vec![INSTRUCTION_VERSION, 0], vec![1; 55]].concat(),
+        // Stake needs 25 bytes
+        vec![INSTRUCTION_VERSION, 1],
+        [vec![INSTRUCTION_VERSION, 1], vec![0; 24]].concat(),
+        // UnStake needs 8 bytes
+        [vec![INSTRUCTION_VERSION, 2], vec![9; 7]].concat(),
+        vec![INSTRUCTION_VERSION, 2, 9],
+        // UpdateAPY needs 16 bytes
+        vec![INSTRUCTION_VERSION, 3],
+        [vec![INSTRUCTION_VERSION, 3], vec![1; 15]].concat(),
+        // UpdateTokenAccount needs 8 bytes
+        [vec![INSTRUCTION_VERSION, 5], vec![9; 7]].concat(),
+        // SetPaused needs 1 byte
+        vec![INSTRUCTION_VERSION, 7],
+        // EmergencyWithdraw needs 16 bytes
+        [vec![INSTRUCTION_VERSION, 8], vec![1; 15]].concat(),
+        // PreviewRewards needs 17 bytes
+        [vec![INSTRUCTION_VERSION, 10], vec![0; 16]].concat(),
+        // ConvertStakeType needs 9 bytes
+        vec![INSTRUCTION_VERSION, 11, 1],
+        // SetBoost needs 8 bytes
+        [vec![INSTRUCTION_VERSION, 12], vec![1; 7]].concat(),
+        // ForceUnstake needs 8 bytes
+        [vec![INSTRUCTION_VERSION, 14], vec![9; 7]].concat(),
+        // ReadHistory needs 16 bytes
+        [vec![INSTRUCTION_VERSION, 15], vec![0; 15]].concat(),
+        // GetPoolApr needs the stake type
+        vec![INSTRUCTION_VERSION, 17],
+        // SetEarlyWithdrawalFee needs 16 bytes
+        [vec![INSTRUCTION_VERSION, 22], vec![1; 15]].concat(),
+    ];
+    for payload in truncated_payloads {
+        let err = perform_raw_instruction(
+            program_id,
+            &context.payer,
+            &payload,
+            vec![],
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap_err().unwrap();
+        assert_eq!(err, TransactionError::InstructionError(0, InstructionError::InvalidInstructionData));
+    }
+
+    // CloseUserData carries no payload, the bare tag unpacks and only fails for the missing accounts
+    let err = perform_raw_instruction(
+        program_id,
+        &context.payer,
+        &[INSTRUCTION_VERSION, 4],
+        vec![],
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::NotEnoughAccountKeys));
+}
+
+#[tokio::test]
+async fn test_unversioned_instruction_rejected() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let pool = set_up_pool(&mut context, program_id, &PoolConfig::default()).await;
+    // Single byte tags of unversioned clients, here a valid SetPaused and a GetUserIndex
+    for (payload, accounts) in [
+        (vec![7, 1], vec![AccountMeta::new(context.payer.pubkey(), true), AccountMeta::new(pool.data_account, false)]),
+        (vec![9], vec![])
+    ] {
+        let err = perform_raw_instruction(
+            program_id,
+            &context.payer,
+            &payload,
+            accounts,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap_err().unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::Custom(StakingError::UnsupportedInstructionVersion as u32))
+        );
+    }
+    assert!(!get_contract_data(&pool.data_account, &mut context.banks_client).await.is_paused);
+}
+
+#[tokio::test]
+async fn test_dust_locked_early_exit_bounds() {
+    // A penalty larger than the principal is rejected when the pool is configured
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { early_withdrawal_fee: 2000, ..PoolConfig::default() };
+    let err = try_set_up_pool(&mut context, program_id, &config).await.err().unwrap().unwrap();
+    // Custom error codes are part of the client interface
+    assert_eq!(err, TransactionError::InstructionError(3, InstructionError::Custom(15)));
+    assert_eq!(StakingError::PenaltyExceedsPrincipal as u32, 15);
+    let unit = 10u64.pow(config.mint_decimals as u32);
+
+    // Payout larger than what the contract holds is rejected with a clear error
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { reward_amount: 0, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 1000 * unit).await;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        100,
+        pool.decimals,
+        config.minimum_lock_duration,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    // The contract only received the dust net of the transfer fee
+    let contract_balance = get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount;
+    assert!(contract_balance < 100);
+    let err = perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::InsufficientContractBalance as u32))
+    );
+}
+
+#[tokio::test]
+async fn test_unstake_to_another_token_account() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    // Token account owned by a different wallet
+    let (_cold_wallet, cold_token_account, _) = set_up_staker(&mut context, &pool, 0).await;
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, 24*60*60).await;
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    perform_unstake_to(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        Some(cold_token_account),
+        false,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    assert_eq!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount, user_balance);
+    assert!(get_token_account_data(&cold_token_account, &mut context.banks_client).await.amount > 1000 * unit);
+    assert!(get_user_data(&user_data_account, &mut context.banks_client).await.is_err());
+}
+
+#[tokio::test]
+async fn test_scheduled_apy_change() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let day: u64 = 24*60*60;
+    let amount = 1000 * unit;
+    let (early_user, early_token_account, early_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let (late_user, late_token_account, late_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    for (user, token_account, data_account) in [
+        (&early_user, early_token_account, early_data_account),
+        (&late_user, late_token_account, late_data_account)
+    ] {
+        perform_stake(
+            program_id,
+            user,
+            token_account,
+            pool.token_account,
+            data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            amount,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+    }
+
+    // Schedule a new APY two days from now
+    let clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+    let effective_ts = clock.unix_timestamp as u64 + 2 * day;
+    let new_normal_apy = 10000;
+    perform_update_apy(
+        program_id,
+        &context.payer,
+        pool.data_account,
+        new_normal_apy,
+        config.locked_staking_apy,
+        effective_ts,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.normal_staking_apy, config.normal_staking_apy);
+    assert_eq!(contract_data.pending_normal_apy, new_normal_apy);
+    assert_eq!(contract_data.apy_effective_ts, effective_ts);
+
+    // Before the effective time the old rate applies
+    advance_clock(&mut context, day as i64).await;
+    perform_unstake(
+        program_id,
+        &early_user,
+        early_token_account,
+        pool.token_account,
+        early_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    let early_interest = expected_interest(config.normal_staking_apy, amount, day);
+    assert_eq!(contract_data.total_earned, early_interest);
+
+    // After the effective time the new rate applies from the effective time onwards
+    advance_clock(&mut context, 2 * day as i64).await;
+    perform_unstake(
+        program_id,
+        &late_user,
+        late_token_account,
+        pool.token_account,
+        late_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    let late_interest = (
+        (config.normal_staking_apy as u128 * 2 * day as u128 + new_normal_apy as u128 * day as u128)
+            * amount as u128 / 31536000000_u128
+    ) as u64;
+    assert_eq!(contract_data.total_earned, early_interest + late_interest);
+}
+
+#[tokio::test]
+async fn test_created_ts_survives_re_stake() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    for amount in [1000 * unit, 500 * unit] {
+        perform_stake(
+            program_id,
+            &user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            amount,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+        if amount == 1000 * unit {
+            let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+            assert_eq!(user_data.created_ts, user_data.stake_ts);
+            advance_clock(&mut context, 60*60).await;
+        }
+    }
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.stake_ts, user_data.created_ts + 60*60);
+}
+
+#[tokio::test]
+async fn test_stake_native_sol_with_wrap() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig {
+        minimum_stake_amount: LAMPORTS_PER_SOL,
+        reward_amount: 10 * LAMPORTS_PER_SOL,
+        ..PoolConfig::default()
+    };
+    let pool = set_up_native_pool(&mut context, program_id, &config).await;
+    let user = Keypair::new();
+    transfer_sol(
+        &context.payer,
+        user.pubkey(),
+        10 * LAMPORTS_PER_SOL,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+    let user_token_account = set_up_native_token_account(&mut context, user.pubkey()).await;
+    perform_create_user_data(program_id, &context.payer, user.pubkey(), pool.data_account, &mut context.banks_client, context.last_blockhash).await.unwrap();
+    let user_data_account = user_data_pda(&user.pubkey(), &pool.data_account, &program_id);
+    let lamports_before = context.banks_client.get_balance(user.pubkey()).await.unwrap();
+
+    perform_stake_wrapping(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        0,
+        2 * LAMPORTS_PER_SOL,
+        pool.decimals,
+        0,
+        true,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.total_staked, 2 * LAMPORTS_PER_SOL);
+    assert_eq!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount, 0);
+
+    advance_clock(&mut context, 24*60*60).await;
+    perform_unstake_to(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        None,
+        true,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    assert!(context.banks_client.get_account(user_token_account).await.unwrap().is_none());
+    let lamports_after = context.banks_client.get_balance(user.pubkey()).await.unwrap();
+    assert!(lamports_after > lamports_before);
+}
+
+#[tokio::test]
+async fn test_wrap_rejected_for_non_native_pool() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let pool = set_up_pool(&mut context, program_id, &PoolConfig::default()).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 1000 * unit).await;
+    let err = perform_stake_wrapping(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        0,
+        200 * unit,
+        pool.decimals,
+        0,
+        true,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::NativeMintRequired as u32))
+    );
+}
+
+#[tokio::test]
+async fn test_stake_recovers_uninitialized_user_data_account() {
+    let program_id = Pubkey::new_unique();
+    let user = Keypair::new();
+    let config = PoolConfig::default();
+    let unit = 10u64.pow(config.mint_decimals as u32);
+
+    let mut context = program_test(program_id).start_with_context().await;
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    // A user data account left created but never initialized
+    let account = Account {
+        lamports: Rent::default().minimum_balance(UserData::LEN),
+        data: vec![0; UserData::LEN],
+        owner: program_id,
+        executable: false,
+        rent_epoch: 0
+    };
+    context.set_account(&user_data_pda(&user.pubkey(), &pool.data_account, &program_id), &account.into());
+    let (user_token_account, user_data_account) = fund_staker(&mut context, &pool, &user, 5000 * unit).await;
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        1000 * unit,
+        pool.decimals,
+        200,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let clock = context.banks_client.get_sysvar::<Clock>().await.unwrap();
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert!(user_data.is_initialized);
+    assert_eq!(user_data.owner_pubkey, user.pubkey());
+    assert_eq!(user_data.stake_type as u8, StakeType::LOCKED as u8);
+    assert_eq!(user_data.lock_duration, 200);
+    assert_eq!(user_data.total_staked, 1000 * unit);
+    assert_eq!(user_data.stake_ts, clock.unix_timestamp as u64);
+    assert_eq!(user_data.created_ts, clock.unix_timestamp as u64);
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.total_staked, 1000 * unit);
+}
+
+#[tokio::test]
+async fn test_reward_cap_per_position() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let unit = 10u64.pow(PoolConfig::default().mint_decimals as u32);
+    let config = PoolConfig { max_reward_per_position: 10 * unit, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    assert_eq!(
+        get_contract_data(&pool.data_account, &mut context.banks_client).await.max_reward_per_position,
+        10 * unit
+    );
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let amount = 1000 * unit;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    // A position left dormant for two years accrues well past the cap
+    let dormant: u64 = 2*365*24*60*60;
+    assert!(expected_interest(config.normal_staking_apy, amount, dormant) > config.max_reward_per_position);
+    advance_clock(&mut context, dormant as i64).await;
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.total_earned, config.max_reward_per_position);
+}
+
+#[tokio::test]
+async fn test_rejects_mismatched_mint_account() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    // A mint with a different transfer fee than the pool mint
+    let other_mint = Keypair::new();
+    set_up_mint(
+        &context.payer,
+        &other_mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        Rent::default(),
+        config.mint_decimals,
+        0,
+        0
+    ).await;
+    let err = perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        other_mint.pubkey(),
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::MintMismatch as u32))
+    );
+    assert_eq!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount, 5000 * unit);
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, 24*60*60).await;
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    let err = perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        other_mint.pubkey(),
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::MintMismatch as u32))
+    );
+    assert_eq!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount, user_balance);
+}
+
+#[tokio::test]
+async fn test_rotate_stake_token_account() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let new_token_account = Keypair::new();
+    set_up_token_account(
+        &context.payer,
+        &new_token_account,
+        Some(pool.data_account),
+        Rent::default(),
+        pool.mint,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+
+    perform_update_token_account(
+        program_id,
+        &context.payer,
+        pool.data_account,
+        pool.token_account,
+        new_token_account.pubkey(),
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.stake_token_account, new_token_account.pubkey());
+    assert_eq!(get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount, 0);
+    // The balance moves across minus the mint transfer fee
+    let transfer_fee = config.reward_amount * config.fee_basis_points / 10000;
+    assert_eq!(
+        get_token_account_data(&new_token_account.pubkey(), &mut context.banks_client).await.amount,
+        config.reward_amount - transfer_fee
+    );
+
+    // Staking against the old vault is rejected, the new one is accepted
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let err = perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::InvalidContractTokenAccount as u32))
+    );
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        new_token_account.pubkey(),
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_rotate_stake_token_account_rejects_wrong_mint() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let other_mint = Keypair::new();
+    set_up_mint(
+        &context.payer,
+        &other_mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        Rent::default(),
+        config.mint_decimals,
+        config.fee_basis_points,
+        config.max_fee
+    ).await;
+    let new_token_account = Keypair::new();
+    set_up_token_account(
+        &context.payer,
+        &new_token_account,
+        Some(pool.data_account),
+        Rent::default(),
+        other_mint.pubkey(),
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+
+    let err = perform_update_token_account(
+        program_id,
+        &context.payer,
+        pool.data_account,
+        pool.token_account,
+        new_token_account.pubkey(),
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::MintMismatch as u32))
+    );
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.stake_token_account, pool.token_account);
+    assert_eq!(get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount, config.reward_amount);
+}
+
+#[test]
+fn test_pda_helpers_match_seeds() {
+    let program_id = Pubkey::new_unique();
+    let admin = Pubkey::new_unique();
+    let mint = Pubkey::new_unique();
+    let user = Pubkey::new_unique();
+    let contract_data = Pubkey::find_program_address(&[b"spl_staking", admin.as_ref(), mint.as_ref()], &program_id);
+    assert_eq!(pda::contract_data_pda(&admin, &mint, &program_id), contract_data);
+    assert_eq!(
+        pda::user_data_pda(&user, &contract_data.0, &program_id),
+        Pubkey::find_program_address(&[b"spl_staking_user", user.as_ref(), contract_data.0.as_ref()], &program_id)
+    );
+    assert_eq!(
+        pda::legacy_user_data_pda(&user, &program_id),
+        Pubkey::find_program_address(&[b"spl_staking_user", user.as_ref()], &program_id)
+    );
+    assert_eq!(
+        pda::history_log_pda(&contract_data.0, &program_id),
+        Pubkey::find_program_address(&[b"spl_staking_history", contract_data.0.as_ref()], &program_id)
+    );
+    // The pool of another admin or mint lives at a different address
+    assert_ne!(pda::contract_data_pda(&user, &mint, &program_id).0, contract_data.0);
+    assert_ne!(pda::contract_data_pda(&admin, &user, &program_id).0, contract_data.0);
+    // So does the position the same user opens in another pool
+    assert_ne!(pda::user_data_pda(&user, &mint, &program_id).0, pda::user_data_pda(&user, &contract_data.0, &program_id).0);
+}
+
+#[test]
+fn test_borsh_layout_matches_pack() {
+    let contract_data = ContractData {
+        is_initialized: true,
+        admin_pubkey: Pubkey::new_unique(),
+        stake_token_mint: Pubkey::new_unique(),
+        stake_token_account: Pubkey::new_unique(),
+        minimum_stake_amount: 1,
+        minimum_lock_duration: 2,
+        normal_staking_apy: 3,
+        locked_staking_apy: 4,
+        early_withdrawal_fee: 5,
+        total_staked: 6,
+        total_earned: 7,
+        fee_basis_points: 8,
+        max_fee: 9,
+        max_lock_duration: 10,
+        pending_normal_apy: 11,
+        pending_locked_apy: 12,
+        apy_effective_ts: 13,
+        max_reward_per_position: 14,
+        is_paused: true,
+        lock_grace_period: 15,
+        reward_mint: Pubkey::new_unique(),
+        reward_vault: Pubkey::new_unique(),
+        apy_checkpoint_count: 1,
+        apy_checkpoints: [ApyCheckpoint { ts: 15, normal_apy: 16, locked_apy: 17 }; APY_CHECKPOINTS],
+        deposit_fee_bps: 18,
+        total_deposit_fees: 19,
+        gate_collection: Pubkey::new_unique(),
+        max_boost_bps: 20,
+        claim_cooldown: 21,
+        accrual_period: 22,
+        partial_reward_ok: true,
+        history_enabled: true,
+        min_restake_interval: 24,
+        penalty_burn_bps: 25,
+        total_locked_staked: 26,
+        total_pending_interest: 27,
+        pending_interest_ts: 28,
+        delegate_authority: Pubkey::new_unique(),
+        interest_rounding: InterestRounding::NEAREST,
+        active_stakers: 29,
+        max_stakers: 30,
+        early_withdrawal_fee_denominator: 31,
+        rewards_depleted: true,
+        token_program_id: Pubkey::new_unique(),
+        compound_cooldown: 32,
+        reward_per_second: 33,
+        reward_per_share: 34,
+        reward_per_share_ts: 35,
+        promo_start_ts: 36,
+        promo_end_ts: 37,
+        promo_multiplier_bps: 38,
+        operator_pubkey: Pubkey::new_unique(),
+        rewards_paused: true,
+        accrual_frozen_since: 39,
+        max_apy: 40,
+        earned_checkpoints_enabled: true
+    };
+    let borsh_data = contract_data.try_to_vec().unwrap();
+    assert_eq!(borsh_data.len(), ContractData::LEN);
+    let mut packed_data = vec![0; ContractData::LEN];
+    contract_data.pack_into_slice(&mut packed_data);
+    assert_eq!(borsh_data, packed_data);
+    let decoded = ContractData::try_from_slice(&packed_data).unwrap();
+    assert_eq!(decoded.try_to_vec().unwrap(), packed_data);
+
+    let user_data = UserData {
+        is_initialized: true,
+        owner_pubkey: Pubkey::new_unique(),
+        stake_type: StakeType::LOCKED,
+        lock_duration: 1,
+        total_staked: 2,
+        interest_accrued: 3,
+        stake_ts: 4,
+        last_claim_ts: 5,
+        last_unstake_ts: 6,
+        created_ts: 7,
+        boost_bps: 8,
+        reward_per_share_paid: 9,
+        unlock_ts: 10,
+        is_fee_exempt: true,
+        penalty_waived: true,
+        pool: Pubkey::new_unique(),
+        delegate: Pubkey::new_unique()
+    };
+    let borsh_data = user_data.try_to_vec().unwrap();
+    assert_eq!(borsh_data.len(), UserData::LEN);
+    let mut packed_data = vec![0; UserData::LEN];
+    user_data.pack_into_slice(&mut packed_data);
+    assert_eq!(borsh_data, packed_data);
+    let decoded = UserData::try_from_slice(&packed_data).unwrap();
+    assert_eq!(decoded.try_to_vec().unwrap(), packed_data);
+}
+
+#[tokio::test]
+async fn test_snapshot_positions() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (normal_user, normal_token_account, normal_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let (locked_user, locked_token_account, locked_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let lock_duration: u64 = 1000;
+    perform_stake(
+        program_id,
+        &normal_user,
+        normal_token_account,
+        pool.token_account,
+        normal_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    perform_stake(
+        program_id,
+        &locked_user,
+        locked_token_account,
+        pool.token_account,
+        locked_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        2000 * unit,
+        pool.decimals,
+        lock_duration,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, 250).await;
+
+    let normal = simulate_snapshot_position(
+        program_id, &context.payer, normal_data_account, true, &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(normal.owner_pubkey, normal_user.pubkey());
+    assert_eq!(normal.total_staked, 1000 * unit);
+    assert_eq!(normal.stake_type as u8, StakeType::NORMAL as u8);
+    assert_eq!(normal.weight, 1000 * unit);
+
+    let locked = simulate_snapshot_position(
+        program_id, &context.payer, locked_data_account, false, &mut context.banks_client, context.last_blockhash
+    ).await;
+    let user_data = get_user_data(&locked_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(locked.owner_pubkey, locked_user.pubkey());
+    assert_eq!(locked.stake_ts, user_data.stake_ts);
+    assert_eq!(locked.weight, 2000 * unit);
+    // 750 of the 1000 lock seconds remain, adding 75% of the stake as weight
+    let weighted = simulate_snapshot_position(
+        program_id, &context.payer, locked_data_account, true, &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(weighted.stake_type as u8, StakeType::LOCKED as u8);
+    assert_eq!(weighted.weight, 2000 * unit + 1500 * unit);
+    assert_eq!(normal.weight + weighted.weight, 4500 * unit);
+}
+
+#[tokio::test]
+async fn test_snapshot_weights_restaked_lock_until_unlock() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let lock_duration: u64 = 1000;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        2000 * unit,
+        pool.decimals,
+        lock_duration,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let unlock_ts = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().unlock_ts;
+
+    // The shorter re-stake restarts the position but keeps the original unlock
+    advance_clock(&mut context, 400).await;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        1000 * unit,
+        pool.decimals,
+        config.minimum_lock_duration,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.unlock_ts, unlock_ts);
+    assert_eq!(unlock_ts - user_data.stake_ts, 600);
+    advance_clock(&mut context, 100).await;
+
+    // Past the end of the shorter lock 500 of the 600 seconds until the unlock remain
+    let weighted = simulate_snapshot_position(
+        program_id, &context.payer, user_data_account, true, &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(weighted.total_staked, user_data.total_staked);
+    assert_eq!(weighted.weight, user_data.total_staked + user_data.total_staked * 500 / 600);
+}
+
+#[tokio::test]
+async fn test_emergency_withdraw_requires_pause() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let recovery_account = Keypair::new();
+    set_up_token_account(
+        &context.payer,
+        &recovery_account,
+        None,
+        Rent::default(),
+        pool.mint,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+    let amount = 1000 * unit;
+
+    // Blocked while the pool is active
+    let err = perform_emergency_withdraw(
+        program_id,
+        &context.payer,
+        pool.data_account,
+        pool.token_account,
+        recovery_account.pubkey(),
+        pool.mint,
+        amount,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::PoolNotPaused as u32))
+    );
+
+    // Only the admin can pause
+    let err = perform_set_paused(
+        program_id, &user, pool.data_account, true, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32))
+    );
+    perform_set_paused(
+        program_id, &context.payer, pool.data_account, true, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    assert!(get_contract_data(&pool.data_account, &mut context.banks_client).await.is_paused);
+    let err = perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::PoolPaused as u32))
+    );
+
+    // Allowed while paused, under a fresh blockhash so it is not a replay of the rejected transaction
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    perform_emergency_withdraw(
+        program_id,
+        &context.payer,
+        pool.data_account,
+        pool.token_account,
+        recovery_account.pubkey(),
+        pool.mint,
+        amount,
+        &mut context.banks_client,
+        recent_block_hash,
+        pool.decimals
+    ).await.unwrap();
+    let transfer_fee = amount * config.fee_basis_points / 10000;
+    assert_eq!(
+        get_token_account_data(&recovery_account.pubkey(), &mut context.banks_client).await.amount,
+        amount - transfer_fee
+    );
+    assert_eq!(
+        get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount,
+        config.reward_amount - amount
+    );
+}
+
+#[tokio::test]
+async fn test_emergency_withdraw_blocked_while_pool_active() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let recovery_account = Keypair::new();
+    set_up_token_account(
+        &context.payer,
+        &recovery_account,
+        None,
+        Rent::default(),
+        pool.mint,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+
+    // A pool that was paused and resumed is active again, the admin cannot withdraw in either state
+    perform_set_paused(
+        program_id, &context.payer, pool.data_account, true, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    perform_set_paused(
+        program_id, &context.payer, pool.data_account, false, &mut context.banks_client, recent_block_hash
+    ).await.unwrap();
+    assert!(!get_contract_data(&pool.data_account, &mut context.banks_client).await.is_paused);
+    let err = perform_emergency_withdraw(
+        program_id,
+        &context.payer,
+        pool.data_account,
+        pool.token_account,
+        recovery_account.pubkey(),
+        pool.mint,
+        1000 * unit,
+        &mut context.banks_client,
+        recent_block_hash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::PoolNotPaused as u32))
+    );
+    assert_eq!(get_token_account_data(&recovery_account.pubkey(), &mut context.banks_client).await.amount, 0);
+    assert_eq!(
+        get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount,
+        config.reward_amount
+    );
+}
+
+#[tokio::test]
+async fn test_init_with_pre_funded_data_account() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let rent = Rent::default();
+    let token_mint = Keypair::new();
+    let token_account = Keypair::new();
+    let payer_pubkey = context.payer.pubkey();
+    let (data_account, _bump) = Pubkey::find_program_address(
+        &[b"spl_staking", payer_pubkey.as_ref(), token_mint.pubkey().as_ref()],
+        &program_id
+    );
+    set_up_mint(
+        &context.payer,
+        &token_mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        rent,
+        config.mint_decimals,
+        config.fee_basis_points,
+        config.max_fee
+    ).await;
+    // Someone sends lamports to the contract data pda before the pool is created
+    transfer_sol(&context.payer, data_account, 1_000_000, &mut context.banks_client, context.last_blockhash).await;
+
+    let mut transaction = construct_init_txn(
+        &config,
+        payer_pubkey,
+        token_account.pubkey(),
+        rent,
+        token_mint.pubkey(),
+        program_id,
+        data_account
+    );
+    transaction.sign(&[&context.payer, &token_account], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+
+    let account = context.banks_client.get_account(data_account).await.unwrap().unwrap();
+    assert_eq!(account.owner, program_id);
+    assert_eq!(account.data.len(), ContractData::LEN);
+    assert_eq!(account.lamports, rent.minimum_balance(ContractData::LEN));
+    let contract_data = get_contract_data(&data_account, &mut context.banks_client).await;
+    assert!(contract_data.is_initialized);
+    assert_eq!(contract_data.stake_token_account, token_account.pubkey());
+}
+
+#[tokio::test]
+async fn test_init_rejects_zero_apy() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { normal_staking_apy: 0, ..PoolConfig::default() };
+    let rent = Rent::default();
+    let token_mint = Keypair::new();
+    let token_account = Keypair::new();
+    let payer_pubkey = context.payer.pubkey();
+    let (data_account, _bump) = Pubkey::find_program_address(
+        &[b"spl_staking", payer_pubkey.as_ref(), token_mint.pubkey().as_ref()],
+        &program_id
+    );
+    set_up_mint(
+        &context.payer,
+        &token_mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        rent,
+        config.mint_decimals,
+        config.fee_basis_points,
+        config.max_fee
+    ).await;
+    let mut transaction = construct_init_txn(
+        &config,
+        payer_pubkey,
+        token_account.pubkey(),
+        rent,
+        token_mint.pubkey(),
+        program_id,
+        data_account
+    );
+    transaction.sign(&[&context.payer, &token_account], context.last_blockhash);
+    let err = context.banks_client.process_transaction(transaction).await.unwrap_err().unwrap();
+    // The init instruction follows the token account create, init and mint instructions
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(3, InstructionError::Custom(StakingError::InvalidApy as u32))
+    );
+    assert!(context.banks_client.get_account(data_account).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_get_user_index() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let other_pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let (other_token_account, other_user_data_account) = fund_staker(&mut context, &other_pool, &user, 5000 * unit).await;
+
+    let index = simulate_get_user_index(
+        program_id, &context.payer, user.pubkey(), &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(index.owner_pubkey, user.pubkey());
+    assert!(index.positions.is_empty());
+    assert!(context.banks_client.get_account(user_index_pda(&user.pubkey(), &program_id)).await.unwrap().is_none());
+
+    // Both positions are indexed, a re-stake does not add the pool twice
+    for (token_account, user_data_account, pool) in [
+        (user_token_account, user_data_account, &pool),
+        (other_token_account, other_user_data_account, &other_pool),
+        (user_token_account, user_data_account, &pool)
+    ] {
+        perform_stake(
+            program_id,
+            &user,
+            token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            1000 * unit,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+    }
+    let account = context.banks_client.get_account(user_index_pda(&user.pubkey(), &program_id)).await.unwrap().unwrap();
+    assert_eq!(account.owner, program_id);
+    assert_eq!(account.data.len(), PositionIndex::ACCOUNT_LEN);
+    let index = simulate_get_user_index(
+        program_id, &context.payer, user.pubkey(), &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(index.positions, vec![pool.data_account, other_pool.data_account]);
+
+    // Closing the first position moves the last one in its place
+    advance_clock(&mut context, 24*60*60).await;
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    let index = simulate_get_user_index(
+        program_id, &context.payer, user.pubkey(), &mut context.banks_client, recent_block_hash
+    ).await;
+    assert_eq!(index.positions, vec![other_pool.data_account]);
+
+    // So does a position the admin closes for the owner
+    perform_force_unstake(
+        program_id,
+        &context.payer,
+        other_pool.data_account,
+        other_user_data_account,
+        user.pubkey(),
+        other_token_account,
+        other_pool.token_account,
+        other_pool.mint,
+        other_pool.decimals,
+        true,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await.unwrap();
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    let index = simulate_get_user_index(
+        program_id, &context.payer, user.pubkey(), &mut context.banks_client, recent_block_hash
+    ).await;
+    assert!(index.positions.is_empty());
+
+    // Only the index PDA of the owner is read
+    let err = perform_raw_instruction(
+        program_id,
+        &context.payer,
+        &[INSTRUCTION_VERSION, 9],
+        vec![
+            AccountMeta::new_readonly(user.pubkey(), false),
+            AccountMeta::new_readonly(user_index_pda(&context.payer.pubkey(), &program_id), false)
+        ],
+        &mut context.banks_client,
+        recent_block_hash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::PdaMismatch as u32)));
+}
+
+#[tokio::test]
+async fn test_locked_unstake_within_grace_period() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { lock_grace_period: 60*60, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.lock_grace_period, 60*60);
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let amount = 1000 * unit;
+    let lock_duration: u64 = 2*60*60;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        amount,
+        pool.decimals,
+        lock_duration,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    // Half an hour early, inside the one hour grace period
+    let stake_duration = lock_duration - 30*60;
+    advance_clock(&mut context, stake_duration as i64).await;
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    // Interest is paid for the time actually staked instead of charging the early withdrawal fee
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    let interest = expected_interest(config.locked_staking_apy, amount, stake_duration);
+    assert!(interest > 0);
+    assert_eq!(contract_data.total_earned, interest);
+    assert_eq!(contract_data.total_staked, 0);
+}
+
+#[tokio::test]
+async fn test_interest_paid_in_reward_token() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let unit = 10u64.pow(config.mint_decimals as u32);
+    let (pool, reward) = set_up_reward_pool(&mut context, program_id, &config, 10000 * unit).await;
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.reward_mint, reward.mint);
+    assert_eq!(contract_data.reward_vault, reward.vault);
+    assert_eq!(get_token_account_data(&reward.vault, &mut context.banks_client).await.owner, pool.data_account);
+
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let user_reward_account = Keypair::new();
+    set_up_token_account(
+        &context.payer,
+        &user_reward_account,
+        Some(user.pubkey()),
+        Rent::default(),
+        reward.mint,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+
+    let amount = 1000 * unit;
+    let day: u64 = 24*60*60;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, day as i64).await;
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    perform_unstake_with_reward(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &reward,
+        user_reward_account.pubkey(),
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+
+    // Principal comes back in the stake token, interest arrives in the reward token
+    let interest = expected_interest(config.normal_staking_apy, amount, day);
+    let principal_out = amount + 9 * amount / 100;
+    let principal_fee = principal_out * config.fee_basis_points / 10000;
+    assert_eq!(
+        get_token_account_data(&user_token_account, &mut context.banks_client).await.amount,
+        user_balance + principal_out - principal_fee
+    );
+    assert_eq!(
+        get_token_account_data(&user_reward_account.pubkey(), &mut context.banks_client).await.amount,
+        interest
+    );
+    assert_eq!(
+        get_token_account_data(&reward.vault, &mut context.banks_client).await.amount,
+        10000 * unit - interest
+    );
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.total_earned, interest);
+}
+
+#[tokio::test]
+async fn test_liquidate_expired_reward_pool_without_interest() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { history_enabled: true, ..PoolConfig::default() };
+    let unit = 10u64.pow(config.mint_decimals as u32);
+    let (pool, reward) = set_up_reward_pool(&mut context, program_id, &config, 10000 * unit).await;
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let user_reward_account = Keypair::new();
+    set_up_token_account(
+        &context.payer,
+        &user_reward_account,
+        Some(user.pubkey()),
+        Rent::default(),
+        reward.mint,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+    let history_log = pda::history_log_pda(&pool.data_account, &program_id).0;
+
+    let amount = 1000 * unit;
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 1, StakeType::LOCKED as u8];
+    instruction_data.extend(amount.to_le_bytes().iter());
+    instruction_data.extend(pool.decimals.to_le_bytes().iter());
+    instruction_data.extend(config.minimum_lock_duration.to_le_bytes().iter());
+    perform_raw_instruction(
+        program_id,
+        &user,
+        &instruction_data,
+        vec![
+            AccountMeta::new(user.pubkey(), true),
+            AccountMeta::new(user_token_account, false),
+            AccountMeta::new(user_data_account, false),
+            AccountMeta::new(pool.token_account, false),
+            AccountMeta::new(pool.data_account, false),
+            AccountMeta::new_readonly(pool.mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new_readonly(solana_program::system_program::ID, false),
+            AccountMeta::new(user_index_pda(&user.pubkey(), &program_id), false),
+            AccountMeta::new(history_log, false)
+        ],
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    // Accrual is frozen from the stake on, the position has no interest to pay
+    perform_set_rewards_paused(
+        program_id, &context.payer, pool.data_account, true, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, 24*60*60).await;
+
+    // The reward accounts still come ahead of the history accounts
+    let keeper = Keypair::new();
+    transfer_sol(&context.payer, keeper.pubkey(), LAMPORTS_PER_SOL, &mut context.banks_client, context.last_blockhash).await;
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 24];
+    instruction_data.extend(pool.decimals.to_le_bytes().iter());
+    perform_raw_instruction(
+        program_id,
+        &keeper,
+        &instruction_data,
+        vec![
+            AccountMeta::new(keeper.pubkey(), true),
+            AccountMeta::new(pool.data_account, false),
+            AccountMeta::new(user_data_account, false),
+            AccountMeta::new(user.pubkey(), false),
+            AccountMeta::new(user_token_account, false),
+            AccountMeta::new(pool.token_account, false),
+            AccountMeta::new_readonly(pool.mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new(user_index_pda(&user.pubkey(), &program_id), false),
+            AccountMeta::new(reward.vault, false),
+            AccountMeta::new_readonly(reward.mint, false),
+            AccountMeta::new(user_reward_account.pubkey(), false),
+            AccountMeta::new(history_log, false),
+            AccountMeta::new_readonly(solana_program::system_program::ID, false)
+        ],
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    assert!(context.banks_client.get_account(user_data_account).await.unwrap().is_none());
+    assert_eq!(get_token_account_data(&user_reward_account.pubkey(), &mut context.banks_client).await.amount, 0);
+    assert_eq!(get_token_account_data(&reward.vault, &mut context.banks_client).await.amount, 10000 * unit);
+    let records = simulate_read_history(
+        program_id, &context.payer, pool.data_account, 0, 100, &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[1].action, HistoryAction::Unstake);
+    assert_eq!(records[1].amount, amount);
+}
+
+#[tokio::test]
+async fn test_interest_blends_across_apy_change() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let amount = 1000 * unit;
+    let day: u64 = 24*60*60;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, day as i64).await;
+    let new_normal_apy = 2 * config.normal_staking_apy;
+    perform_update_apy(
+        program_id,
+        &context.payer,
+        pool.data_account,
+        new_normal_apy,
+        config.locked_staking_apy,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.apy_checkpoint_count, 1);
+    assert_eq!(contract_data.apy_checkpoints[0].normal_apy, config.normal_staking_apy);
+
+    advance_clock(&mut context, 2 * day as i64).await;
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    // One day at the old APY and two days at the new one, not three days at the new APY
+    let blended_interest = (
+        (config.normal_staking_apy as u128 * day as u128 + new_normal_apy as u128 * 2 * day as u128)
+            * amount as u128 / 31536000000_u128
+    ) as u64;
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.total_earned, blended_interest);
+    assert!(blended_interest < expected_interest(new_normal_apy, amount, 3 * day));
+}
+
+#[tokio::test]
+async fn test_partial_unstake_leaves_minimum_stake() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let amount = 1000 * unit;
+    let day: u64 = 24*60*60;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, day as i64).await;
+    let staked = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().total_staked;
+
+    // Leaving exactly the minimum keeps the position open
+    let withdraw_amount = staked - config.minimum_stake_amount;
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    perform_partial_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        withdraw_amount,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.total_staked, config.minimum_stake_amount);
+    assert_eq!(user_data.interest_accrued, expected_interest(config.normal_staking_apy, withdraw_amount, day));
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.total_staked, config.minimum_stake_amount);
+    let amount_out = withdraw_amount + 9 * withdraw_amount / 100;
+    let fee = amount_out * config.fee_basis_points / 10000;
+    assert_eq!(
+        get_token_account_data(&user_token_account, &mut context.banks_client).await.amount,
+        user_balance + amount_out - fee
+    );
+}
+
+
+#[tokio::test]
+async fn test_partial_unstake_leaving_dust_rejected() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let amount = 1000 * unit;
+    let day: u64 = 24*60*60;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, day as i64).await;
+    let staked = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().total_staked;
+
+    // Leaving less than the minimum stake amount behind is rejected
+    let dust_amount = staked - config.minimum_stake_amount + 1;
+    let err = perform_partial_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        dust_amount,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::UnstakeLeavesDust as u32))
+    );
+    assert_eq!(get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().total_staked, staked);
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.total_staked, staked);
+    assert_eq!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount, 4000 * unit);
+}
+
+#[tokio::test]
+async fn test_unstake_reads_injected_clock_account() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let amount = 1000 * unit;
+    let stake_duration: u64 = 2*24*60*60;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, stake_duration as i64).await;
+
+    // The clock sysvar trails the accounts without being taken as the optional destination
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 2];
+    instruction_data.extend(pool.decimals.to_le_bytes().iter());
+    perform_raw_instruction(
+        program_id,
+        &user,
+        &instruction_data,
+        vec![
+            AccountMeta::new(user.pubkey(), true),
+            AccountMeta::new(user_token_account, false),
+            AccountMeta::new(user_data_account, false),
+            AccountMeta::new(pool.token_account, false),
+            AccountMeta::new(pool.data_account, false),
+            AccountMeta::new_readonly(pool.mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new(user_index_pda(&user.pubkey(), &program_id), false),
+            AccountMeta::new_readonly(solana_program::sysvar::clock::ID, false)
+        ],
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.total_earned, expected_interest(config.normal_staking_apy, amount, stake_duration));
+    assert_eq!(contract_data.total_staked, 0);
+}
+
+#[tokio::test]
+async fn test_rejects_contract_token_account_as_user_account() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let err = perform_stake(
+        program_id,
+        &user,
+        pool.token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::InvalidUserTokenAccount as u32))
+    );
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, 24*60*60).await;
+    let err = perform_unstake(
+        program_id,
+        &user,
+        pool.token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::InvalidUserTokenAccount as u32))
+    );
+    let err = perform_unstake_to(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        Some(pool.token_account),
+        false,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::InvalidDestinationTokenAccount as u32))
+    );
+}
+
+#[tokio::test]
+async fn test_preview_rewards() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+
+    let amount = 1000 * unit;
+    let duration: u64 = 30*24*60*60;
+    let preview = simulate_preview_rewards(
+        program_id, &context.payer, pool.data_account, StakeType::NORMAL as u8, amount, duration,
+        &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(preview.interest, expected_interest(config.normal_staking_apy, amount, duration));
+    assert_eq!(preview.early_withdrawal_charge, 0);
+
+    let preview = simulate_preview_rewards(
+        program_id, &context.payer, pool.data_account, StakeType::LOCKED as u8, amount, duration,
+        &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(preview.interest, expected_interest(config.locked_staking_apy, amount, duration));
+    assert_eq!(preview.early_withdrawal_charge, config.early_withdrawal_fee * amount / 1000);
+}
+
+#[tokio::test]
+async fn test_init_early_withdrawal_fee_bounds() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { early_withdrawal_fee: 1001, ..PoolConfig::default() };
+    let err = try_set_up_pool(&mut context, program_id, &config).await.err().unwrap().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(3, InstructionError::Custom(StakingError::PenaltyExceedsPrincipal as u32))
+    );
+
+    // Confiscating the whole principal is still allowed
+    let config = PoolConfig { early_withdrawal_fee: 1000, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.early_withdrawal_fee, 1000);
+}
+
+#[tokio::test]
+async fn test_convert_stake_type() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let amount = 1000 * unit;
+    let day: u64 = 24*60*60;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let staked = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().total_staked;
+    let contract_balance = get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount;
+    advance_clock(&mut context, day as i64).await;
+
+    // NORMAL to LOCKED settles the NORMAL interest and keeps the principal in place
+    let lock_duration = 7 * day;
+    perform_convert_stake_type(
+        program_id,
+        &user,
+        user_data_account,
+        pool.data_account,
+        StakeType::LOCKED as u8,
+        lock_duration,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.stake_type as u8, StakeType::LOCKED as u8);
+    assert_eq!(user_data.lock_duration, lock_duration);
+    assert_eq!(user_data.total_staked, staked);
+    assert_eq!(user_data.interest_accrued, expected_interest(config.normal_staking_apy, staked, day));
+    assert_eq!(get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount, contract_balance);
+}
+
+#[tokio::test]
+async fn test_convert_locked_to_normal_during_lock_rejected() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let day: u64 = 24*60*60;
+    let lock_duration = 7 * day;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        1000 * unit,
+        pool.decimals,
+        lock_duration,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let before = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    advance_clock(&mut context, day as i64).await;
+
+    // A still-locked position cannot go back to NORMAL
+    let err = perform_convert_stake_type(
+        program_id,
+        &user,
+        user_data_account,
+        pool.data_account,
+        StakeType::NORMAL as u8,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::LockNotExpired as u32))
+    );
+    let after = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(after.stake_type as u8, StakeType::LOCKED as u8);
+    assert_eq!(after.lock_duration, before.lock_duration);
+    assert_eq!(after.total_staked, before.total_staked);
+    assert_eq!(after.interest_accrued, before.interest_accrued);
+}
+
+#[tokio::test]
+async fn test_unstake_returns_user_data_rent_to_owner() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let user_data_lamports = context.banks_client.get_balance(user_data_account).await.unwrap();
+    let contract_data_lamports = context.banks_client.get_balance(pool.data_account).await.unwrap();
+    let user_lamports = context.banks_client.get_balance(user.pubkey()).await.unwrap();
+    advance_clock(&mut context, 24*60*60).await;
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+
+    // The closed user data account's rent goes back to the user, only the transaction fee is kept
+    assert!(context.banks_client.get_account(user_data_account).await.unwrap().is_none());
+    assert_eq!(user_data_lamports, Rent::default().minimum_balance(UserData::LEN));
+    let user_lamports_after = context.banks_client.get_balance(user.pubkey()).await.unwrap();
+    assert!(user_lamports_after > user_lamports + user_data_lamports - 10000);
+    assert!(user_lamports_after <= user_lamports + user_data_lamports);
+    let contract_data_account = context.banks_client.get_account(pool.data_account).await.unwrap().unwrap();
+    assert_eq!(contract_data_account.lamports, contract_data_lamports);
+    assert!(Rent::default().is_exempt(contract_data_account.lamports, contract_data_account.data.len()));
+}
+
+#[tokio::test]
+async fn test_stake_deposit_fee() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { deposit_fee_bps: 250, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let amount = 1000 * unit;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    // 2.5% of the deposit is kept as the protocol fee
+    let deposit_fee = amount * 250 / 10000;
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.total_staked, amount - deposit_fee);
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.deposit_fee_bps, 250);
+    assert_eq!(contract_data.total_staked, amount - deposit_fee);
+    assert_eq!(contract_data.total_deposit_fees, deposit_fee);
+
+    let config = PoolConfig { deposit_fee_bps: 10001, ..PoolConfig::default() };
+    let err = try_set_up_pool(&mut context, program_id, &config).await.err().unwrap().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(3, InstructionError::Custom(StakingError::InvalidDepositFee as u32))
+    );
+}
+
+#[tokio::test]
+async fn test_emergency_withdraw_rejects_fee_free_mint() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (_user, user_token_account, _user_data_account) = set_up_staker(&mut context, &pool, 0).await;
+    perform_set_paused(program_id, &context.payer, pool.data_account, true, &mut context.banks_client, context.last_blockhash).await.unwrap();
+
+    // Same decimals as the pool mint but without the transfer fee
+    let fee_free_mint = Keypair::new();
+    set_up_mint(
+        &context.payer,
+        &fee_free_mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        Rent::default(),
+        config.mint_decimals,
+        0,
+        0
+    ).await;
+    let vault_balance = get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount;
+    let err = perform_emergency_withdraw(
+        program_id,
+        &context.payer,
+        pool.data_account,
+        pool.token_account,
+        user_token_account,
+        fee_free_mint.pubkey(),
+        100 * unit,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::MintMismatch as u32))
+    );
+    assert_eq!(get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount, vault_balance);
+}
+
+#[tokio::test]
+async fn test_gated_pool_requires_collection_nft() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let collection = Pubkey::new_unique();
+    let config = PoolConfig { gate_collection: collection, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.gate_collection, collection);
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (holder, holder_token_account, holder_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let (other, other_token_account, other_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let (gate_token_account, gate_mint) = set_up_gate_nft(&mut context, collection, holder.pubkey());
+    let (other_gate_token_account, other_gate_mint) = set_up_gate_nft(&mut context, Pubkey::new_unique(), other.pubkey());
+
+    // The gate accounts are required on gated pools
+    assert!(perform_stake(
+        program_id,
+        &holder,
+        holder_token_account,
+        pool.token_account,
+        holder_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.is_err());
+    perform_gated_stake(
+        program_id,
+        &holder,
+        holder_token_account,
+        pool.token_account,
+        holder_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        gate_token_account,
+        gate_mint,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    assert_eq!(get_user_data(&holder_data_account, &mut context.banks_client).await.unwrap().total_staked, 1000 * unit);
+
+    // Neither an NFT of another collection nor someone else's gate NFT lets a non-holder in
+    for (gate_token, gate_mint) in [(other_gate_token_account, other_gate_mint), (gate_token_account, gate_mint)] {
+        let err = perform_gated_stake(
+            program_id,
+            &other,
+            other_token_account,
+            pool.token_account,
+            other_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            1000 * unit,
+            pool.decimals,
+            0,
+            gate_token,
+            gate_mint,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap_err().unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::Custom(StakingError::GateNftRequired as u32))
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_boosted_position_interest() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { max_boost_bps: 20000, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let amount = 1000 * unit;
+    let day: u64 = 24*60*60;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    assert_eq!(get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().boost_bps, 10000);
+
+    // Only the admin can boost, and never above the pool maximum
+    let err = perform_set_boost(
+        program_id, &user, pool.data_account, user_data_account, 15000, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+    let err = perform_set_boost(
+        program_id, &context.payer, pool.data_account, user_data_account, 20001, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::InvalidBoost as u32)));
+    perform_set_boost(
+        program_id, &context.payer, pool.data_account, user_data_account, 15000, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+
+    advance_clock(&mut context, day as i64).await;
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    let interest = expected_interest(config.normal_staking_apy, amount, day);
+    assert_eq!(
+        get_contract_data(&pool.data_account, &mut context.banks_client).await.total_earned,
+        interest * 15000 / 10000
+    );
+}
+
+#[tokio::test]
+async fn test_claim_rewards_cooldown() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let hour: u64 = 60*60;
+    let config = PoolConfig { claim_cooldown: hour, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let amount = 1000 * unit;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, 24 * hour as i64).await;
+    perform_claim_rewards(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let first_claim = expected_interest(config.normal_staking_apy, amount, 24 * hour);
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.total_earned, first_claim);
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.total_staked, amount);
+    assert_eq!(user_data.interest_accrued, 0);
+
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+
+    // Claiming again inside the cooldown is rejected
+    advance_clock(&mut context, (hour - 1) as i64).await;
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    let err = perform_claim_rewards(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::ClaimCooldownActive as u32))
+    );
+    assert_eq!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount, user_balance);
+
+    // Once the cooldown passed only the interest since the last claim is paid
+    advance_clock(&mut context, 1).await;
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    perform_claim_rewards(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await.unwrap();
+    assert_eq!(
+        get_contract_data(&pool.data_account, &mut context.banks_client).await.total_earned,
+        first_claim + expected_interest(config.normal_staking_apy, amount, hour)
+    );
+    assert!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount > user_balance);
+}
+
+#[tokio::test]
+async fn test_rejects_short_contract_data_account() {
+    let program_id = Pubkey::new_unique();
+    let config = PoolConfig::default();
+    let unit = 10u64.pow(config.mint_decimals as u32);
+    let user = Keypair::new();
+
+    // Short accounts passed in place of the contract data account
+    let foreign_account = Pubkey::new_unique();
+    let short_program_account = Pubkey::new_unique();
+    let mut program_test = program_test(program_id);
+    for (pubkey, owner) in [(foreign_account, Pubkey::new_unique()), (short_program_account, program_id)] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: Rent::default().minimum_balance(10),
+                data: vec![1; 10],
+                owner,
+                executable: false,
+                rent_epoch: 0
+            }
+        );
+    }
+    let mut context = program_test.start_with_context().await;
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let (user_token_account, user_data_account) = fund_staker(&mut context, &pool, &user, 5000 * unit).await;
+
+    for (contract_data_account, expected) in [
+        (foreign_account, InstructionError::IllegalOwner),
+        (short_program_account, InstructionError::InvalidAccountData)
+    ] {
+        let err = perform_stake(
+            program_id,
+            &user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            contract_data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            1000 * unit,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap_err().unwrap();
+        assert_eq!(err, TransactionError::InstructionError(0, expected.clone()));
+        let err = perform_unstake(
+            program_id,
+            &user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            contract_data_account,
+            pool.mint,
+            &mut context.banks_client,
+            context.last_blockhash,
+            pool.decimals
+        ).await.unwrap_err().unwrap();
+        assert_eq!(err, TransactionError::InstructionError(0, expected));
+    }
+}
+
+#[tokio::test]
+async fn test_interest_accrues_in_whole_periods() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let day: u64 = 24*60*60;
+    let config = PoolConfig { accrual_period: 2 * day, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let amount = 1000 * unit;
+
+    // [stake duration, interest expected for it]
+    let cases = [
+        (day + day / 2, 0),
+        (4 * day + 5*60*60, expected_interest(config.normal_staking_apy, amount, 4 * day))
+    ];
+    for (stake_duration, interest) in cases {
+        let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+        perform_stake(
+            program_id,
+            &user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            amount,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+        let earned_before = get_contract_data(&pool.data_account, &mut context.banks_client).await.total_earned;
+        advance_clock(&mut context, stake_duration as i64).await;
+        perform_unstake(
+            program_id,
+            &user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            &mut context.banks_client,
+            context.last_blockhash,
+            pool.decimals
+        ).await.unwrap();
+        let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+        assert_eq!(contract_data.total_earned - earned_before, interest);
+    }
+
+    // Pools configured without a period accrue per second
+    let pool = set_up_pool(&mut context, program_id, &PoolConfig::default()).await;
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.accrual_period, 1);
+}
+
+#[tokio::test]
+async fn test_force_unstake_pays_owner() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let pool = set_up_pool(&mut context, program_id, &PoolConfig::default()).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let (other, other_token_account, _) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let amount = 1000 * unit;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        amount,
+        pool.decimals,
+        30*24*60*60,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, 24*60*60).await;
+
+    // Only the admin can force an unstake, and only into the owner's accounts
+    let err = perform_force_unstake(
+        program_id, &other, pool.data_account, user_data_account, user.pubkey(), user_token_account,
+        pool.token_account, pool.mint, pool.decimals, false, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+    let err = perform_force_unstake(
+        program_id, &context.payer, pool.data_account, user_data_account, other.pubkey(), other_token_account,
+        pool.token_account, pool.mint, pool.decimals, false, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+    let err = perform_force_unstake(
+        program_id, &context.payer, pool.data_account, user_data_account, user.pubkey(), other_token_account,
+        pool.token_account, pool.mint, pool.decimals, false, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::InvalidDestinationTokenAccount as u32))
+    );
+
+    // The lock is running, but the owner gets the full principal back without the early withdrawal fee
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    perform_force_unstake(
+        program_id, &context.payer, pool.data_account, user_data_account, user.pubkey(), user_token_account,
+        pool.token_account, pool.mint, pool.decimals, false, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    assert!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount >= user_balance + amount);
+    assert!(get_user_data(&user_data_account, &mut context.banks_client).await.is_err());
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.total_staked, 0);
+    assert_eq!(contract_data.total_earned, 0);
+}
+
+#[tokio::test]
+async fn test_decimals_mismatch() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let pool = set_up_pool(&mut context, program_id, &PoolConfig::default()).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    assert_eq!(pool.decimals, 9);
+
+    let err = perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        6,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::DecimalsMismatch as u32)));
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, 24*60*60).await;
+    let err = perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        6
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::DecimalsMismatch as u32)));
+    assert_eq!(get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().total_staked, 1000 * unit);
+}
+
+#[tokio::test]
+async fn test_partial_reward_from_underfunded_vault() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let unit = 10u64.pow(PoolConfig::default().mint_decimals as u32);
+    let amount = 1000 * unit;
+    let day: u64 = 24*60*60;
+    let interest = expected_interest(PoolConfig::default().normal_staking_apy, amount, day);
+
+    for partial_reward_ok in [false, true] {
+        let config = PoolConfig { partial_reward_ok, ..PoolConfig::default() };
+        let (pool, reward) = set_up_reward_pool(&mut context, program_id, &config, interest / 2).await;
+        let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+        let user_reward_account = Keypair::new();
+        set_up_token_account(
+            &context.payer,
+            &user_reward_account,
+            Some(user.pubkey()),
+            Rent::default(),
+            reward.mint,
+            0,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await;
+        perform_stake(
+            program_id,
+            &user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            amount,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+        advance_clock(&mut context, day as i64).await;
+        let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+        let result = perform_unstake_with_reward(
+            program_id,
+            &user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            &reward,
+            user_reward_account.pubkey(),
+            &mut context.banks_client,
+            context.last_blockhash,
+            pool.decimals
+        ).await;
+        result.unwrap();
+
+        // With or without partial rewards, the full principal is paid along with the interest the vault could cover
+        let principal_out = amount + 9 * amount / 100;
+        let principal_fee = principal_out * config.fee_basis_points / 10000;
+        assert_eq!(
+            get_token_account_data(&user_token_account, &mut context.banks_client).await.amount,
+            user_balance + principal_out - principal_fee
+        );
+        assert_eq!(
+            get_token_account_data(&user_reward_account.pubkey(), &mut context.banks_client).await.amount,
+            interest / 2
+        );
+        assert_eq!(get_token_account_data(&reward.vault, &mut context.banks_client).await.amount, 0);
+        let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+        assert_eq!(contract_data.total_earned, interest / 2);
+        assert!(contract_data.is_paused && contract_data.rewards_depleted);
+    }
+}
+
+#[tokio::test]
+async fn test_interest_never_paid_from_staked_principal() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let unit = 10u64.pow(PoolConfig::default().mint_decimals as u32);
+    let amount = 1000 * unit;
+    let day: u64 = 24*60*60;
+    // Far less than a day of interest, which the principal sharing the account could easily cover
+    let config = PoolConfig { reward_amount: unit, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let mut stakers = vec![];
+    for _ in 0..2 {
+        let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+        perform_stake(
+            program_id,
+            &user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            amount,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+        stakers.push((user, user_token_account, user_data_account));
+    }
+    advance_clock(&mut context, day as i64).await;
+
+    // A claim the vault cannot cover without the staked principal pays nothing and trips the breaker,
+    // the interest stays with the position
+    let (user, user_token_account, user_data_account) = &stakers[0];
+    let user_balance = get_token_account_data(user_token_account, &mut context.banks_client).await.amount;
+    let user_data = get_user_data(user_data_account, &mut context.banks_client).await.unwrap();
+    perform_claim_rewards(
+        program_id,
+        user,
+        *user_token_account,
+        pool.token_account,
+        *user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    assert_eq!(get_token_account_data(user_token_account, &mut context.banks_client).await.amount, user_balance);
+    let unchanged = get_user_data(user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(unchanged.try_to_vec().unwrap(), user_data.try_to_vec().unwrap());
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert!(contract_data.is_paused && contract_data.rewards_depleted);
+    assert_eq!(contract_data.total_earned, 0);
+
+    // The unstake still returns the principal, only the interest is left unpaid
+    perform_unstake(
+        program_id,
+        user,
+        *user_token_account,
+        pool.token_account,
+        *user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    let principal_out = amount + 9 * amount / 100;
+    let principal_fee = principal_out * config.fee_basis_points / 10000;
+    assert_eq!(
+        get_token_account_data(user_token_account, &mut context.banks_client).await.amount,
+        user_balance + principal_out - principal_fee
+    );
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.total_staked, amount);
+    assert_eq!(contract_data.total_earned, 0);
+}
+
+#[tokio::test]
+async fn test_reward_depletion_pauses_new_stakes() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let unit = 10u64.pow(PoolConfig::default().mint_decimals as u32);
+    let amount = 1000 * unit;
+    let day: u64 = 24*60*60;
+    let interest = expected_interest(PoolConfig::default().normal_staking_apy, amount, day);
+
+    // Default pools trip the breaker just like pools accepting partial rewards
+    for partial_reward_ok in [false, true] {
+        let config = PoolConfig { partial_reward_ok, ..PoolConfig::default() };
+        let (pool, reward) = set_up_reward_pool(&mut context, program_id, &config, interest / 2).await;
+        let mut stakers = vec![];
+        for _ in 0..3 {
+            let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+            let user_reward_account = Keypair::new();
+            set_up_token_account(
+                &context.payer,
+                &user_reward_account,
+                Some(user.pubkey()),
+                Rent::default(),
+                reward.mint,
+                0,
+                &mut context.banks_client,
+                context.last_blockhash
+            ).await;
+            stakers.push((user, user_token_account, user_data_account, user_reward_account.pubkey()));
+        }
+        for (user, user_token_account, user_data_account, _) in &stakers[..2] {
+            perform_stake(
+                program_id,
+                user,
+                *user_token_account,
+                pool.token_account,
+                *user_data_account,
+                pool.data_account,
+                pool.mint,
+                StakeType::NORMAL as u8,
+                amount,
+                pool.decimals,
+                0,
+                &mut context.banks_client,
+                context.last_blockhash
+            ).await.unwrap();
+        }
+        advance_clock(&mut context, day as i64).await;
+
+        // The first unstake the vault cannot fully pay trips the breaker
+        for (user, user_token_account, user_data_account, user_reward_account) in &stakers[..2] {
+            perform_unstake_with_reward(
+                program_id,
+                user,
+                *user_token_account,
+                pool.token_account,
+                *user_data_account,
+                pool.data_account,
+                pool.mint,
+                &reward,
+                *user_reward_account,
+                &mut context.banks_client,
+                context.last_blockhash,
+                pool.decimals
+            ).await.unwrap();
+            let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+            assert!(contract_data.is_paused);
+            assert!(contract_data.rewards_depleted);
+        }
+        // Both stakers still got their principal back, the second one without interest
+        assert_eq!(get_token_account_data(&stakers[0].3, &mut context.banks_client).await.amount, interest / 2);
+        assert_eq!(get_token_account_data(&stakers[1].3, &mut context.banks_client).await.amount, 0);
+        assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.total_staked, 0);
+
+        let (carol, carol_token_account, carol_data_account, _) = &stakers[2];
+        let err = perform_stake(
+            program_id,
+            carol,
+            *carol_token_account,
+            pool.token_account,
+            *carol_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            amount,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap_err().unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::Custom(StakingError::PoolPaused as u32))
+        );
+
+        // Resuming is left to the admin
+        perform_set_paused(
+            program_id, &context.payer, pool.data_account, false, &mut context.banks_client, context.last_blockhash
+        ).await.unwrap();
+        let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+        assert!(!contract_data.is_paused);
+        assert!(!contract_data.rewards_depleted);
+    }
+}
+
+#[tokio::test]
+async fn test_history_log_records_stakes_and_unstakes() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { history_enabled: true, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (alice, alice_token_account, alice_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let (bob, bob_token_account, bob_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let day: u64 = 24*60*60;
+    let start_ts = context.banks_client.get_sysvar::<Clock>().await.unwrap().unix_timestamp as u64;
+
+    // Pools keeping a history need the history log account
+    let err = perform_stake(
+        program_id,
+        &alice,
+        alice_token_account,
+        pool.token_account,
+        alice_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::NotEnoughAccountKeys));
+
+    for (user, token_account, data_account, amount) in [
+        (&alice, alice_token_account, alice_data_account, 1000 * unit),
+        (&bob, bob_token_account, bob_data_account, 2000 * unit),
+        (&alice, alice_token_account, alice_data_account, 500 * unit)
+    ] {
+        let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+        perform_history_stake(
+            program_id, user, token_account, pool.token_account, data_account, pool.data_account,
+            pool.mint, amount, pool.decimals, &mut context.banks_client, recent_block_hash
+        ).await.unwrap();
+    }
+    advance_clock(&mut context, day as i64).await;
+    perform_history_unstake(
+        program_id, &bob, bob_token_account, pool.token_account, bob_data_account, pool.data_account,
+        pool.mint, 500 * unit, pool.decimals, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    perform_history_unstake(
+        program_id, &alice, alice_token_account, pool.token_account, alice_data_account, pool.data_account,
+        pool.mint, 0, pool.decimals, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+
+    let record = |user: &Keypair, amount: u64, ts: u64, action: HistoryAction| HistoryRecord {
+        ts, user: user.pubkey(), amount, action
+    };
+    let expected = vec![
+        record(&alice, 1000 * unit, start_ts, HistoryAction::Stake),
+        record(&bob, 2000 * unit, start_ts, HistoryAction::Stake),
+        record(&alice, 500 * unit, start_ts, HistoryAction::Stake),
+        record(&bob, 500 * unit, start_ts + day, HistoryAction::Unstake),
+        record(&alice, 1500 * unit, start_ts + day, HistoryAction::Unstake)
+    ];
+    let records = simulate_read_history(
+        program_id, &context.payer, pool.data_account, 0, 100, &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(records, expected);
+    let page = simulate_read_history(
+        program_id, &context.payer, pool.data_account, 3, 1, &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(page, expected[3..4]);
+    let page = simulate_read_history(
+        program_id, &context.payer, pool.data_account, 7, 5, &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert!(page.is_empty());
+}
+
+#[tokio::test]
+async fn test_min_restake_interval() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let hour: u64 = 60*60;
+    let config = PoolConfig { min_restake_interval: hour, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    // [seconds after the previous attempt, rejected]
+    let stakes = [(0, false), (hour, false), (10*60, true), (hour - 10*60 - 1, true), (1, false)];
+    for (elapsed, rejected) in stakes {
+        advance_clock(&mut context, elapsed as i64).await;
+        let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+        let result = perform_stake(
+            program_id,
+            &user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            100 * unit,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            recent_block_hash
+        ).await;
+        if rejected {
+            assert_eq!(
+                result.unwrap_err().unwrap(),
+                TransactionError::InstructionError(0, InstructionError::Custom(StakingError::RestakeTooSoon as u32))
+            );
+        } else {
+            result.unwrap();
+        }
+    }
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.total_staked, 300 * unit);
+}
+
+#[tokio::test]
+async fn test_locked_early_exit_pays_restake_accrual() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let day: u64 = 24*60*60;
+    let amount = 1000 * unit;
+
+    for _ in 0..2 {
+        let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+        perform_stake(
+            program_id,
+            &user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::LOCKED as u8,
+            amount,
+            pool.decimals,
+            30 * day,
+            &mut context.banks_client,
+            recent_block_hash
+        ).await.unwrap();
+        advance_clock(&mut context, (10 * day) as i64).await;
+    }
+    // The re-stake snapshotted the interest of the first 10 days
+    let prior_interest = expected_interest(config.locked_staking_apy, amount, 10 * day);
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.interest_accrued, prior_interest);
+
+    // Leaving 10 days into the restarted lock forfeits only the interest of those 10 days
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    let amount_out = 2 * amount - config.early_withdrawal_fee * 2 * amount / 1000 + prior_interest;
+    let amount_out_with_fee = amount_out + 9 * amount_out / 100;
+    let transfer_fee = amount_out_with_fee * config.fee_basis_points / 10000;
+    assert_eq!(
+        get_token_account_data(&user_token_account, &mut context.banks_client).await.amount,
+        user_balance + amount_out_with_fee - transfer_fee
+    );
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.total_earned, prior_interest);
+}
+
+#[tokio::test]
+async fn test_claim_and_stake_compounds_interest() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let hour: u64 = 60*60;
+    let config = PoolConfig { claim_cooldown: hour, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let amount = 1000 * unit;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, (48 * hour) as i64).await;
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    perform_claim_and_stake(
+        program_id, &user, user_data_account, pool.data_account, pool.token_account, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+
+    // The principal grew by the interest without any tokens moving
+    let interest = expected_interest(config.normal_staking_apy, amount, 48 * hour);
+    let now = context.banks_client.get_sysvar::<Clock>().await.unwrap().unix_timestamp as u64;
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.total_staked, amount + interest);
+    assert_eq!(user_data.interest_accrued, 0);
+    assert_eq!(user_data.stake_ts, now);
+    assert_eq!(user_data.last_claim_ts, now);
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.total_staked, amount + interest);
+    assert_eq!(contract_data.total_earned, interest);
+    assert_eq!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount, user_balance);
+
+    // Compounding restarted the claim cooldown
+    advance_clock(&mut context, (hour - 1) as i64).await;
+    let err = perform_claim_rewards(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::ClaimCooldownActive as u32))
+    );
+}
+
+#[tokio::test]
+async fn test_claim_and_stake_from_underfunded_vault() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let unit = 10u64.pow(PoolConfig::default().mint_decimals as u32);
+    let amount = 1000 * unit;
+    let day: u64 = 24*60*60;
+    let interest = expected_interest(PoolConfig::default().normal_staking_apy, amount, day);
+
+    // Next to the staked principal, its 9% and the transfer fee withheld on the deposit the vault only
+    // holds half of the interest
+    let fee_basis_points = PoolConfig::default().fee_basis_points;
+    let reward_amount = 9 * amount / 100 + amount * fee_basis_points / 10000 + interest / 2;
+    for partial_reward_ok in [false, true] {
+        let config = PoolConfig { partial_reward_ok, reward_amount, ..PoolConfig::default() };
+        let pool = set_up_pool(&mut context, program_id, &config).await;
+        let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+        perform_stake(
+            program_id,
+            &user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            amount,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+        advance_clock(&mut context, day as i64).await;
+        let err = perform_claim_and_stake(
+            program_id, &user, user_data_account, pool.data_account, user_token_account, &mut context.banks_client, context.last_blockhash
+        ).await.unwrap_err().unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::Custom(StakingError::InvalidContractTokenAccount as u32))
+        );
+        let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+        let vault_balance = get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount;
+        perform_claim_and_stake(
+            program_id, &user, user_data_account, pool.data_account, pool.token_account, &mut context.banks_client, context.last_blockhash
+        ).await.unwrap();
+
+        // Only interest the vault holds becomes principal, the shortfall trips the breaker either way
+        let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+        assert!(contract_data.is_paused && contract_data.rewards_depleted);
+        if partial_reward_ok {
+            assert_eq!(get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().total_staked, amount + interest / 2);
+            assert_eq!(contract_data.total_staked, amount + interest / 2);
+            assert_eq!(contract_data.total_earned, interest / 2);
+        } else {
+            let unchanged = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+            assert_eq!(unchanged.try_to_vec().unwrap(), user_data.try_to_vec().unwrap());
+            assert_eq!(contract_data.total_staked, amount);
+            assert_eq!(contract_data.total_earned, 0);
+        }
+        assert_eq!(get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount, vault_balance);
+    }
+}
+
+#[tokio::test]
+async fn test_compound_cooldown() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let hour: u64 = 60*60;
+    let config = PoolConfig { compound_cooldown: hour, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.compound_cooldown, hour);
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, (48 * hour) as i64).await;
+    perform_claim_and_stake(
+        program_id, &user, user_data_account, pool.data_account, pool.token_account, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+
+    // Fresh blockhashes keep the repeated compounds from being deduplicated
+    advance_clock(&mut context, 60).await;
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    let err = perform_claim_and_stake(
+        program_id, &user, user_data_account, pool.data_account, pool.token_account, &mut context.banks_client, recent_block_hash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::CompoundCooldownActive as u32))
+    );
+
+    advance_clock(&mut context, hour as i64).await;
+    let total_staked = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().total_staked;
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    perform_claim_and_stake(
+        program_id, &user, user_data_account, pool.data_account, pool.token_account, &mut context.banks_client, recent_block_hash
+    ).await.unwrap();
+    assert!(get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().total_staked > total_staked);
+}
+
+#[tokio::test]
+async fn test_init_rejects_token_account_of_other_mint() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let rent = Rent::default();
+    let (mint, other_mint, token_account) = (Keypair::new(), Keypair::new(), Keypair::new());
+    for mint in [&mint, &other_mint] {
+        set_up_mint(
+            &context.payer,
+            mint,
+            &mut context.banks_client,
+            context.last_blockhash,
+            rent,
+            config.mint_decimals,
+            config.fee_basis_points,
+            config.max_fee
+        ).await;
+    }
+    set_up_token_account(
+        &context.payer,
+        &token_account,
+        None,
+        rent,
+        other_mint.pubkey(),
+        config.reward_amount,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+
+    let payer_pubkey = context.payer.pubkey();
+    let (data_account, _bump) = pda::contract_data_pda(&payer_pubkey, &mint.pubkey(), &program_id);
+    let transaction = Transaction::new_signed_with_payer(
+        &[init_instruction(&config, payer_pubkey, token_account.pubkey(), mint.pubkey(), program_id, data_account)],
+        Some(&payer_pubkey),
+        &[&context.payer],
+        context.last_blockhash
+    );
+    let err = context.banks_client.process_transaction(transaction).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::MintMismatch as u32)));
+    assert!(context.banks_client.get_account(data_account).await.unwrap().is_none());
+    assert_eq!(get_token_account_data(&token_account.pubkey(), &mut context.banks_client).await.owner, payer_pubkey);
+}
+
+#[tokio::test]
+async fn test_unstake_legacy_layout_position() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let amount = 1000 * unit;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+
+    // Move the position to the owner seeded address, in the layout from before created_ts and boost_bps
+    let legacy_user_data_account = pda::legacy_user_data_pda(&user.pubkey(), &program_id).0;
+    let mut account = context.banks_client.get_account(user_data_account).await.unwrap().unwrap();
+    account.data.truncate(UserData::LEGACY_LEN);
+    account.lamports = Rent::default().minimum_balance(UserData::LEGACY_LEN);
+    context.set_account(&legacy_user_data_account, &account.into());
+    context.set_account(&user_data_account, &Account::default().into());
+    let user_data = get_user_data(&legacy_user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.total_staked, amount);
+    assert_eq!(user_data.created_ts, user_data.stake_ts);
+    assert_eq!(user_data.boost_bps, UserData::BASE_BOOST_BPS);
+    assert_eq!(user_data.pool, Pubkey::default());
+
+    // The legacy account is not accepted in place of the pool seeded one
+    let err = perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        legacy_user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::PositionPoolMismatch as u32))
+    );
+
+    // The legacy layout does not record its pool, so only the pool admin can assign it
+    let err = perform_migrate_user_data(
+        program_id, &user, pool.data_account, None, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::NotEnoughAccountKeys));
+    let err = perform_migrate_user_data(
+        program_id, &user, pool.data_account, Some(&user), &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+    perform_migrate_user_data(
+        program_id, &user, pool.data_account, Some(&context.payer), &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    assert!(context.banks_client.get_account(legacy_user_data_account).await.unwrap().is_none());
+    let account = context.banks_client.get_account(user_data_account).await.unwrap().unwrap();
+    assert_eq!(account.data.len(), UserData::LEN);
+    let migrated = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(migrated.pool, pool.data_account);
+    assert_eq!(migrated.total_staked, amount);
+    assert_eq!(migrated.stake_ts, user_data.stake_ts);
+    let index = simulate_get_user_index(
+        program_id, &context.payer, user.pubkey(), &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(index.positions, vec![pool.data_account]);
+
+    advance_clock(&mut context, 48 * 60 * 60).await;
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+
+    assert!(context.banks_client.get_account(user_data_account).await.unwrap().is_none());
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.total_staked, 0);
+    assert!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount >= user_balance + amount);
+}
+
+#[tokio::test]
+async fn test_get_pool_apr_net_of_transfer_fees() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+
+    // 8% is taken on the deposit and on the 9% grossed up payout:
+    // 0.92 * (1 + 2639%) * 1.09 * 0.92 - 1 = 2426.9% and 0.92 * (1 + 6057%) * 1.09 * 0.92 - 1 = 5580.3%
+    for (stake_type, gross_apy, net_apr) in [
+        (StakeType::NORMAL, config.normal_staking_apy, 24269),
+        (StakeType::LOCKED, config.locked_staking_apy, 55803)
+    ] {
+        let pool_apr = simulate_get_pool_apr(
+            program_id,
+            &context.payer,
+            pool.data_account,
+            pool.mint,
+            stake_type as u8,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await;
+        assert_eq!(pool_apr.gross_apy, gross_apy);
+        assert_eq!(pool_apr.transfer_fee_bps as u64, config.fee_basis_points);
+        assert_eq!(pool_apr.net_apr, net_apr);
+        assert!(pool_apr.net_apr < pool_apr.gross_apy as i64);
+    }
+}
+
+#[tokio::test]
+async fn test_failed_unstake_transfer_reverts_state() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, 48 * 60 * 60).await;
+
+    // Freeze the user's token account so the payout transfer fails after the state is written
+    let mut account = context.banks_client.get_account(user_token_account).await.unwrap().unwrap();
+    let mut token_account = spl_token_2022::extension::StateWithExtensionsMut::<spl_token_2022::state::Account>::unpack(
+        &mut account.data
+    ).unwrap();
+    token_account.base.state = spl_token_2022::state::AccountState::Frozen;
+    token_account.pack_base();
+    context.set_account(&user_token_account, &account.into());
+
+    let user_data_before = context.banks_client.get_account(user_data_account).await.unwrap().unwrap();
+    let contract_data_before = context.banks_client.get_account(pool.data_account).await.unwrap().unwrap();
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    let contract_balance = get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount;
+    let err = perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(
+            0, InstructionError::Custom(spl_token_2022::error::TokenError::AccountFrozen as u32)
+        )
+    );
+
+    // The position, the pool and both balances are untouched
+    assert_eq!(context.banks_client.get_account(user_data_account).await.unwrap().unwrap(), user_data_before);
+    assert_eq!(context.banks_client.get_account(pool.data_account).await.unwrap().unwrap(), contract_data_before);
+    assert_eq!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount, user_balance);
+    assert_eq!(get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount, contract_balance);
+}
+
+#[tokio::test]
+async fn test_early_unstake_burns_penalty_share() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { penalty_burn_bps: 4000, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        1000 * unit,
+        pool.decimals,
+        60 * 60,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let total_staked = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().total_staked;
+    let mint_supply = |data: &[u8]| {
+        spl_token_2022::extension::StateWithExtensions::<spl_token_2022::state::Mint>::unpack(data).unwrap().base.supply
+    };
+    let supply_before = mint_supply(&context.banks_client.get_account(pool.mint).await.unwrap().unwrap().data);
+    let contract_balance = get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount;
+
+    advance_clock(&mut context, 60).await;
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+
+    // 40% of the early withdrawal charge is burned, the rest stays in the contract token account
+    let charge = config.early_withdrawal_fee * total_staked / EARLY_WITHDRAWAL_FEE_DENOMINATOR;
+    let burned = charge * config.penalty_burn_bps / 10000;
+    let supply_after = mint_supply(&context.banks_client.get_account(pool.mint).await.unwrap().unwrap().data);
+    assert_eq!(supply_before - supply_after, burned);
+    let principal_out = total_staked - charge;
+    let payout = principal_out + 9 * principal_out / 100;
+    assert_eq!(
+        get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount,
+        contract_balance - payout - burned
+    );
+}
+
+#[tokio::test]
+async fn test_liabilities_track_pending_interest() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let year: u64 = 365 * 24 * 60 * 60;
+
+    let mut positions = vec![];
+    for (stake_type, amount, lock_duration) in [(StakeType::NORMAL, 1000 * unit, 0), (StakeType::LOCKED, 2000 * unit, year)] {
+        let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+        perform_stake(
+            program_id,
+            &user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            stake_type as u8,
+            amount,
+            pool.decimals,
+            lock_duration,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+        let total_staked = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().total_staked;
+        positions.push((user, user_token_account, user_data_account, total_staked));
+    }
+    let normal_staked = positions[0].3;
+    let locked_staked = positions[1].3;
+    let elapsed = 10 * 24 * 60 * 60;
+    advance_clock(&mut context, elapsed as i64).await;
+
+    let normal_interest = expected_interest(config.normal_staking_apy, normal_staked, elapsed);
+    let locked_interest = expected_interest(config.locked_staking_apy, locked_staked, elapsed);
+    let liabilities = simulate_get_liabilities(
+        program_id, &context.payer, pool.data_account, &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(liabilities.total_staked, normal_staked + locked_staked);
+    assert_eq!(liabilities.total_pending_interest, normal_interest + locked_interest);
+    assert_eq!(liabilities.total_liabilities, normal_staked + locked_staked + normal_interest + locked_interest);
+
+    // Paying out the NORMAL position leaves only the LOCKED obligations
+    let (user, user_token_account, user_data_account, _) = &positions[0];
+    perform_unstake(
+        program_id,
+        user,
+        *user_token_account,
+        pool.token_account,
+        *user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    let liabilities = simulate_get_liabilities(
+        program_id, &context.payer, pool.data_account, &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(liabilities.total_liabilities, locked_staked + locked_interest);
+}
+
+#[tokio::test]
+async fn test_delegate_program_stakes_for_its_pda() {
+    let program_id = Pubkey::new_unique();
+    let delegate_program_id = Pubkey::new_unique();
+    let mut program_test = program_test(program_id);
+    program_test.add_program("mock_delegate", delegate_program_id, processor!(process_delegate_instruction));
+    let mut context = program_test.start_with_context().await;
+    let (delegate, _bump) = delegate_pda(&delegate_program_id);
+    let (owner, _bump) = Pubkey::find_program_address(&[b"owner"], &delegate_program_id);
+    let config = PoolConfig { delegate_authority: delegate, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let amount = 1000 * unit;
+
+    // The owner PDA holds the tokens and approves the delegate
+    let owner_token_account = Keypair::new();
+    set_up_token_account(
+        &context.payer,
+        &owner_token_account,
+        Some(owner),
+        Rent::default(),
+        pool.mint,
+        5000 * unit,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+    let mut account = context.banks_client.get_account(owner_token_account.pubkey()).await.unwrap().unwrap();
+    let mut token_account = spl_token_2022::extension::StateWithExtensionsMut::<spl_token_2022::state::Account>::unpack(
+        &mut account.data
+    ).unwrap();
+    token_account.base.delegate = Some(delegate).into();
+    token_account.base.delegated_amount = amount;
+    token_account.pack_base();
+    context.set_account(&owner_token_account.pubkey(), &account.into());
+    transfer_sol(&context.payer, delegate, LAMPORTS_PER_SOL, &mut context.banks_client, context.last_blockhash).await;
+    perform_create_user_data(program_id, &context.payer, owner, pool.data_account, &mut context.banks_client, context.last_blockhash).await.unwrap();
+    let user_data_account = user_data_pda(&owner, &pool.data_account, &program_id);
+
+    let mut stake_data = vec![INSTRUCTION_VERSION, 1, StakeType::NORMAL as u8];
+    stake_data.extend(amount.to_le_bytes().iter());
+    stake_data.extend(pool.decimals.to_le_bytes().iter());
+    stake_data.extend(0u64.to_le_bytes().iter());
+    perform_raw_instruction(
+        delegate_program_id,
+        &context.payer,
+        &stake_data,
+        vec![
+            AccountMeta::new_readonly(program_id, false),
+            AccountMeta::new_readonly(owner, false),
+            AccountMeta::new(owner_token_account.pubkey(), false),
+            AccountMeta::new(user_data_account, false),
+            AccountMeta::new(pool.token_account, false),
+            AccountMeta::new(pool.data_account, false),
+            AccountMeta::new_readonly(pool.mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new_readonly(solana_program::system_program::ID, false),
+            AccountMeta::new(user_index_pda(&owner, &program_id), false),
+            AccountMeta::new(delegate, false)
+        ],
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.owner_pubkey, owner);
+    assert_eq!(user_data.total_staked, amount);
+    assert_eq!(user_data.delegate, delegate);
+
+    // A user staking on their own keeps the delegate out of their position
+    let (user, user_token_account, user_data_account_of_user) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account_of_user,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    assert_eq!(get_user_data(&user_data_account_of_user, &mut context.banks_client).await.unwrap().delegate, Pubkey::default());
+
+    advance_clock(&mut context, 48 * 60 * 60).await;
+    let mut unstake_data = vec![INSTRUCTION_VERSION, 2];
+    unstake_data.extend(pool.decimals.to_le_bytes().iter());
+    let unstake_accounts = |destination: Pubkey, owner_meta: AccountMeta| vec![
+        AccountMeta::new_readonly(program_id, false),
+        owner_meta,
+        AccountMeta::new(owner_token_account.pubkey(), false),
+        AccountMeta::new(user_data_account, false),
+        AccountMeta::new(pool.token_account, false),
+        AccountMeta::new(pool.data_account, false),
+        AccountMeta::new_readonly(pool.mint, false),
+        AccountMeta::new_readonly(spl_token_2022::ID, false),
+        AccountMeta::new(user_index_pda(&owner, &program_id), false),
+        AccountMeta::new(destination, false),
+        AccountMeta::new(delegate, false)
+    ];
+
+    // The delegate cannot redirect the payout away from the owner
+    let other_token_account = Keypair::new();
+    set_up_token_account(
+        &context.payer,
+        &other_token_account,
+        None,
+        Rent::default(),
+        pool.mint,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+    let err = perform_raw_instruction(
+        delegate_program_id,
+        &context.payer,
+        &unstake_data,
+        unstake_accounts(other_token_account.pubkey(), AccountMeta::new(owner, false)),
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::InvalidDestinationTokenAccount as u32))
+    );
+
+    // Closing the position pays its rent to the owner, never to the pool
+    let err = perform_raw_instruction(
+        delegate_program_id,
+        &context.payer,
+        &unstake_data,
+        unstake_accounts(owner_token_account.pubkey(), AccountMeta::new_readonly(owner, false)),
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::InvalidAccountData));
+
+    let owner_balance = get_token_account_data(&owner_token_account.pubkey(), &mut context.banks_client).await.amount;
+    let user_data_rent = context.banks_client.get_balance(user_data_account).await.unwrap();
+    let owner_lamports = context.banks_client.get_balance(owner).await.unwrap();
+    perform_raw_instruction(
+        delegate_program_id,
+        &context.payer,
+        &unstake_data,
+        unstake_accounts(owner_token_account.pubkey(), AccountMeta::new(owner, false)),
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    assert!(context.banks_client.get_account(user_data_account).await.unwrap().is_none());
+    assert_eq!(context.banks_client.get_balance(owner).await.unwrap(), owner_lamports + user_data_rent);
+    assert!(get_token_account_data(&owner_token_account.pubkey(), &mut context.banks_client).await.amount > owner_balance + amount);
+
+    // The delegate cannot force the unstake of a position the user opened
+    let err = perform_raw_instruction(
+        delegate_program_id,
+        &context.payer,
+        &unstake_data,
+        vec![
+            AccountMeta::new_readonly(program_id, false),
+            AccountMeta::new(user.pubkey(), false),
+            AccountMeta::new(user_token_account, false),
+            AccountMeta::new(user_data_account_of_user, false),
+            AccountMeta::new(pool.token_account, false),
+            AccountMeta::new(pool.data_account, false),
+            AccountMeta::new_readonly(pool.mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new(user_index_pda(&user.pubkey(), &program_id), false),
+            AccountMeta::new(user_token_account, false),
+            AccountMeta::new(delegate, false)
+        ],
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+    assert_eq!(get_user_data(&user_data_account_of_user, &mut context.banks_client).await.unwrap().total_staked, amount);
+}
+
+#[tokio::test]
+async fn test_realloc_legacy_contract_data() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+
+    // Shrink the pool to the original layout, which ended at max_fee
+    let legacy_len = 1 + 32 * 3 + 8 * 9;
+    let mut account = context.banks_client.get_account(pool.data_account).await.unwrap().unwrap();
+    account.data.truncate(legacy_len);
+    account.lamports = Rent::default().minimum_balance(legacy_len);
+    context.set_account(&pool.data_account, &account.into());
+
+    let realloc_accounts = |admin: Pubkey| vec![
+        AccountMeta::new(admin, true),
+        AccountMeta::new(pool.data_account, false),
+        AccountMeta::new_readonly(solana_program::system_program::ID, false)
+    ];
+    let other = Keypair::new();
+    transfer_sol(&context.payer, other.pubkey(), LAMPORTS_PER_SOL, &mut context.banks_client, context.last_blockhash).await;
+    let err = perform_raw_instruction(
+        program_id,
+        &other,
+        &[INSTRUCTION_VERSION, 19],
+        realloc_accounts(other.pubkey()),
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+
+    perform_raw_instruction(
+        program_id,
+        &context.payer,
+        &[INSTRUCTION_VERSION, 19],
+        realloc_accounts(context.payer.pubkey()),
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let account = context.banks_client.get_account(pool.data_account).await.unwrap().unwrap();
+    assert_eq!(account.data.len(), ContractData::LEN);
+    assert!(Rent::default().is_exempt(account.lamports, ContractData::LEN));
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert!(contract_data.is_initialized);
+    assert_eq!(contract_data.admin_pubkey, context.payer.pubkey());
+    assert_eq!(contract_data.stake_token_mint, pool.mint);
+    assert_eq!(contract_data.stake_token_account, pool.token_account);
+    assert_eq!(contract_data.minimum_stake_amount, config.minimum_stake_amount);
+    assert_eq!(contract_data.normal_staking_apy, config.normal_staking_apy);
+    assert_eq!(contract_data.locked_staking_apy, config.locked_staking_apy);
+    assert_eq!(contract_data.early_withdrawal_fee, config.early_withdrawal_fee);
+    assert_eq!(contract_data.max_fee, config.max_fee);
+    assert_eq!(contract_data.apy_checkpoint_count, 0);
+    assert_eq!(contract_data.delegate_authority, Pubkey::default());
+    assert_eq!(contract_data.token_program_id, spl_token_2022::ID);
+}
+
+#[tokio::test]
+async fn test_lock_end_overflow_rejected() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    // No max lock duration is configured, but stake_ts + lock_duration would wrap
+    let err = perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        1000 * unit,
+        pool.decimals,
+        u64::MAX - 10,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::LockDurationTooLong as u32))
+    );
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        1000 * unit,
+        pool.decimals,
+        config.minimum_lock_duration,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+
+    // A position whose lock end already overflows can't be unstaked as if the lock had ended
+    let mut account = context.banks_client.get_account(user_data_account).await.unwrap().unwrap();
+    let mut user_data = UserData::unpack_from_slice(&account.data).unwrap();
+    user_data.lock_duration = u64::MAX;
+    user_data.pack_into_slice(&mut account.data);
+    context.set_account(&user_data_account, &account.into());
+    advance_clock(&mut context, 48 * 60 * 60).await;
+    let err = perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::LockDurationTooLong as u32))
+    );
+    assert_eq!(get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().lock_duration, u64::MAX);
+}
+
+#[tokio::test]
+async fn test_coverage_ratio_of_underfunded_pool() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let mint_decimals = PoolConfig::default().mint_decimals;
+    let unit = 10u64.pow(mint_decimals as u32);
+    let config = PoolConfig { reward_amount: 100 * unit, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    // Nothing is owed before anyone stakes
+    let coverage = simulate_get_coverage_ratio(
+        program_id, &context.payer, pool.data_account, pool.token_account, &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(coverage.available_rewards, config.reward_amount);
+    assert_eq!(coverage.total_pending_interest, 0);
+    assert_eq!(coverage.coverage_bps, u64::MAX);
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let elapsed = 10 * 24 * 60 * 60;
+    advance_clock(&mut context, elapsed as i64).await;
+
+    // The transfer fee on the stake comes out of the funded rewards, and 10 days at the
+    // NORMAL rate owe more than what is left of them
+    let total_staked = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().total_staked;
+    let vault_balance = get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount;
+    let pending_interest = expected_interest(config.normal_staking_apy, total_staked, elapsed);
+    let coverage = simulate_get_coverage_ratio(
+        program_id, &context.payer, pool.data_account, pool.token_account, &mut context.banks_client, context.last_blockhash
+    ).await;
+    // Staked principal is reserved along with the 9% unstake pays on top of it
+    assert_eq!(coverage.available_rewards, vault_balance.saturating_sub(total_staked + 9 * total_staked / 100));
+    assert_eq!(coverage.total_pending_interest, pending_interest);
+    assert_eq!(coverage.coverage_bps, coverage.available_rewards * 10000 / pending_interest);
+    assert!(coverage.coverage_bps < 10000);
+
+    // Only the interest vault of the pool is accepted
+    let transaction = Transaction::new_signed_with_payer(
+        &[solana_program::instruction::Instruction::new_with_bytes(
+            program_id,
+            &[INSTRUCTION_VERSION, 20],
+            vec![
+                AccountMeta::new_readonly(pool.data_account, false),
+                AccountMeta::new_readonly(user_token_account, false)
+            ]
+        )],
+        Some(&context.payer.pubkey()),
+        &[&context.payer],
+        context.last_blockhash
+    );
+    let err = context.banks_client.process_transaction(transaction).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::InvalidContractTokenAccount as u32))
+    );
+}
+
+#[tokio::test]
+async fn test_init_rejects_wrong_system_program() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let rent = Rent::default();
+    let (mint, token_account) = (Keypair::new(), Keypair::new());
+    set_up_mint(
+        &context.payer,
+        &mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        rent,
+        config.mint_decimals,
+        config.fee_basis_points,
+        config.max_fee
+    ).await;
+    let payer_pubkey = context.payer.pubkey();
+    let (data_account, _bump) = pda::contract_data_pda(&payer_pubkey, &mint.pubkey(), &program_id);
+    let mut instructions = init_instructions(
+        &config, payer_pubkey, token_account.pubkey(), rent, mint.pubkey(), program_id, data_account
+    );
+    instructions.last_mut().unwrap().accounts[6].pubkey = Pubkey::new_unique();
+    let transaction = Transaction::new_signed_with_payer(
+        &instructions,
+        Some(&payer_pubkey),
+        &[&context.payer, &token_account],
+        context.last_blockhash
+    );
+    let err = context.banks_client.process_transaction(transaction).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(3, InstructionError::IncorrectProgramId));
+    assert!(context.banks_client.get_account(data_account).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_stake_rejects_wrong_system_program() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 1, StakeType::NORMAL as u8];
+    instruction_data.extend((1000 * unit).to_le_bytes().iter());
+    instruction_data.extend(pool.decimals.to_le_bytes().iter());
+    instruction_data.extend(0u64.to_le_bytes().iter());
+    let err = perform_raw_instruction(
+        program_id,
+        &user,
+        &instruction_data,
+        vec![
+            AccountMeta::new(user.pubkey(), true),
+            AccountMeta::new(user_token_account, false),
+            AccountMeta::new(user_data_account, false),
+            AccountMeta::new(pool.token_account, false),
+            AccountMeta::new(pool.data_account, false),
+            AccountMeta::new_readonly(pool.mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new_readonly(Pubkey::new_unique(), false),
+            AccountMeta::new(user_index_pda(&user.pubkey(), &program_id), false)
+        ],
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::IncorrectProgramId));
+    assert!(!get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().is_initialized);
+    assert_eq!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount, 5000 * unit);
+    assert!(context.banks_client.get_account(user_index_pda(&user.pubkey(), &program_id)).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_create_user_data_before_stake() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let user = Keypair::new();
+    let user_token_account = Keypair::new();
+    set_up_token_account(
+        &context.payer,
+        &user_token_account,
+        Some(user.pubkey()),
+        Rent::default(),
+        pool.mint,
+        5000 * unit,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+    transfer_sol(&context.payer, user.pubkey(), LAMPORTS_PER_SOL, &mut context.banks_client, context.last_blockhash).await;
+    let user_data_account = user_data_pda(&user.pubkey(), &pool.data_account, &program_id);
+
+    // Staking no longer creates the user data account
+    let err = perform_stake(
+        program_id,
+        &user,
+        user_token_account.pubkey(),
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        500 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::UserDataNotCreated as u32))
+    );
+    assert_eq!(get_token_account_data(&user_token_account.pubkey(), &mut context.banks_client).await.amount, 5000 * unit);
+
+    // Anyone may create it, the account holds no position until the first stake
+    perform_create_user_data(program_id, &context.payer, user.pubkey(), pool.data_account, &mut context.banks_client, context.last_blockhash).await.unwrap();
+    let account = context.banks_client.get_account(user_data_account).await.unwrap().unwrap();
+    assert_eq!(account.owner, program_id);
+    assert_eq!(account.data.len(), UserData::LEN);
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert!(!user_data.is_initialized);
+    assert_eq!(user_data.owner_pubkey, user.pubkey());
+    assert_eq!(user_data.total_staked, 0);
+    let err = perform_create_user_data(
+        program_id, &user, user.pubkey(), pool.data_account, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::AccountAlreadyInitialized));
+
+    let lamports_before = context.banks_client.get_balance(user.pubkey()).await.unwrap();
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account.pubkey(),
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert!(user_data.is_initialized);
+    assert_eq!(user_data.total_staked, 1000 * unit);
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.total_staked, 1000 * unit);
+    // Besides the transaction fee the user only paid for the user index, the user data rent was already paid
+    assert_eq!(
+        context.banks_client.get_balance(user.pubkey()).await.unwrap(),
+        lamports_before - 5000 - Rent::default().minimum_balance(PositionIndex::ACCOUNT_LEN)
+    );
+}
+
+#[tokio::test]
+async fn test_interest_rounding_modes() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let unit = 10u64.pow(PoolConfig::default().mint_decimals as u32);
+    // A day of interest on this amount is x.518 units, the modes differ by one unit
+    let amount = 100 * unit + 3;
+    let day: u64 = 24 * 60 * 60;
+
+    let mut payouts = vec![];
+    for interest_rounding in [InterestRounding::FLOOR, InterestRounding::NEAREST] {
+        let config = PoolConfig { fee_basis_points: 0, interest_rounding, ..PoolConfig::default() };
+        let pool = set_up_pool(&mut context, program_id, &config).await;
+        let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, amount).await;
+        perform_stake(
+            program_id,
+            &user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            amount,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+        advance_clock(&mut context, day as i64).await;
+        perform_claim_rewards(
+            program_id,
+            &user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+        payouts.push(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount);
+    }
+
+    let numerator = PoolConfig::default().normal_staking_apy as u128 * day as u128 * amount as u128;
+    let denominator = 31536000000_u128;
+    assert!(numerator % denominator >= denominator / 2);
+    assert_eq!(payouts[0] as u128, numerator / denominator);
+    assert_eq!(payouts[1] as u128, (numerator + denominator / 2) / denominator);
+    assert_eq!(payouts[1], payouts[0] + 1);
+}
+
+#[tokio::test]
+async fn test_unstake_recreates_closed_user_token_account() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let amount = 1000 * unit;
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, amount).await;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+
+    // The emptied token account is closed after staking
+    let transaction = Transaction::new_signed_with_payer(
+        &[spl_token_2022::instruction::close_account(
+            &spl_token_2022::ID, &user_token_account, &user.pubkey(), &user.pubkey(), &[]
+        ).unwrap()],
+        Some(&user.pubkey()),
+        &[&user],
+        context.last_blockhash
+    );
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    assert!(context.banks_client.get_account(user_token_account).await.unwrap().is_none());
+    advance_clock(&mut context, 48 * 60 * 60).await;
+
+    let associated_token_account = spl_associated_token_account::get_associated_token_address_with_program_id(
+        &user.pubkey(), &pool.mint, &spl_token_2022::ID
+    );
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 2];
+    instruction_data.extend(pool.decimals.to_le_bytes().iter());
+    instruction_data.push(0);
+    let accounts = |user_token_account: Pubkey| vec![
+        AccountMeta::new(user.pubkey(), true),
+        AccountMeta::new(user_token_account, false),
+        AccountMeta::new(user_data_account, false),
+        AccountMeta::new(pool.token_account, false),
+        AccountMeta::new(pool.data_account, false),
+        AccountMeta::new_readonly(pool.mint, false),
+        AccountMeta::new_readonly(spl_token_2022::ID, false),
+        AccountMeta::new(user_index_pda(&user.pubkey(), &program_id), false),
+        // The user token account again as the destination, ahead of the accounts creating it
+        AccountMeta::new(user_token_account, false),
+        AccountMeta::new_readonly(solana_program::system_program::ID, false),
+        AccountMeta::new_readonly(spl_associated_token_account::ID, false)
+    ];
+
+    // Only the associated token account of the user can stand in for the closed account
+    let err = perform_raw_instruction(
+        program_id,
+        &user,
+        &instruction_data,
+        accounts(user_token_account),
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::InvalidUserTokenAccount as u32))
+    );
+
+    perform_raw_instruction(
+        program_id,
+        &user,
+        &instruction_data,
+        accounts(associated_token_account),
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let token_account = get_token_account_data(&associated_token_account, &mut context.banks_client).await;
+    assert_eq!(token_account.owner, user.pubkey());
+    assert_eq!(token_account.mint, pool.mint);
+    assert!(token_account.amount > 0);
+    assert!(context.banks_client.get_account(user_data_account).await.unwrap().is_none());
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.total_staked, 0);
+}
+
+#[tokio::test]
+async fn test_max_stakers_cap() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { max_stakers: 2, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+
+    let mut stakers = vec![];
+    for _ in 0..2 {
+        stakers.push(set_up_staker(&mut context, &pool, 5000 * unit).await);
+    }
+    for (user, user_token_account, user_data_account) in &stakers {
+        perform_stake(
+            program_id,
+            user,
+            *user_token_account,
+            pool.token_account,
+            *user_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            1000 * unit,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+    }
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.active_stakers, 2);
+
+    // An existing staker can still add to its position once the cap is hit
+    let (user, user_token_account, user_data_account) = &stakers[0];
+    perform_stake(
+        program_id,
+        user,
+        *user_token_account,
+        pool.token_account,
+        *user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        500 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    assert_eq!(get_user_data(user_data_account, &mut context.banks_client).await.unwrap().total_staked, 1500 * unit);
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.active_stakers, 2);
+    assert_eq!(contract_data.max_stakers, 2);
+}
+
+#[tokio::test]
+async fn test_stake_over_max_stakers_rejected() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { max_stakers: 2, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+
+    let mut stakers = vec![];
+    for _ in 0..3 {
+        stakers.push(set_up_staker(&mut context, &pool, 5000 * unit).await);
+    }
+    for (user, user_token_account, user_data_account) in &stakers[..2] {
+        perform_stake(
+            program_id,
+            user,
+            *user_token_account,
+            pool.token_account,
+            *user_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            1000 * unit,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+    }
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+
+    // A new staker is turned away once the cap is hit and nothing moves
+    let (user, user_token_account, user_data_account) = &stakers[2];
+    let err = perform_stake(
+        program_id,
+        user,
+        *user_token_account,
+        pool.token_account,
+        *user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::MaxStakersReached as u32))
+    );
+    let contract_data_after = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data_after.active_stakers, 2);
+    assert_eq!(contract_data_after.total_staked, contract_data.total_staked);
+    assert_eq!(get_user_data(user_data_account, &mut context.banks_client).await.unwrap().total_staked, 0);
+    assert_eq!(get_token_account_data(user_token_account, &mut context.banks_client).await.amount, 5000 * unit);
+}
+
+#[tokio::test]
+async fn test_restake_after_unstake_frees_max_stakers_slot() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { max_stakers: 2, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+
+    let mut stakers = vec![];
+    for _ in 0..3 {
+        stakers.push(set_up_staker(&mut context, &pool, 5000 * unit).await);
+    }
+    for (user, user_token_account, user_data_account) in &stakers[..2] {
+        perform_stake(
+            program_id,
+            user,
+            *user_token_account,
+            pool.token_account,
+            *user_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            1000 * unit,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+    }
+    advance_clock(&mut context, 48 * 60 * 60).await;
+
+    // A full unstake frees a slot the same user can take again
+    let (user, user_token_account, user_data_account) = &stakers[0];
+    perform_unstake(
+        program_id,
+        user,
+        *user_token_account,
+        pool.token_account,
+        *user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.active_stakers, 1);
+    perform_create_user_data(
+        program_id, user, user.pubkey(), pool.data_account, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    perform_stake(
+        program_id,
+        user,
+        *user_token_account,
+        pool.token_account,
+        *user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await.unwrap();
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.active_stakers, 2);
+
+    // The slot was taken back, a new staker is still turned away
+    let (user, user_token_account, user_data_account) = &stakers[2];
+    let err = perform_stake(
+        program_id,
+        user,
+        *user_token_account,
+        pool.token_account,
+        *user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::MaxStakersReached as u32))
+    );
+
+    // Until another full unstake frees one for it
+    let (other_user, other_token_account, other_user_data_account) = &stakers[1];
+    perform_unstake(
+        program_id,
+        other_user,
+        *other_token_account,
+        pool.token_account,
+        *other_user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        recent_block_hash,
+        pool.decimals
+    ).await.unwrap();
+    perform_stake(
+        program_id,
+        user,
+        *user_token_account,
+        pool.token_account,
+        *user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        2000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await.unwrap();
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.active_stakers, 2);
+    assert_eq!(contract_data.total_staked, 3000 * unit);
+}
+
+#[tokio::test]
+async fn test_set_early_withdrawal_fee_scale() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let amount = 1000 * unit;
+    let year: u64 = 365 * 24 * 60 * 60;
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.early_withdrawal_fee_denominator, EARLY_WITHDRAWAL_FEE_DENOMINATOR);
+    assert_eq!(
+        simulate_preview_rewards(
+            program_id, &context.payer, pool.data_account, StakeType::LOCKED as u8, amount, 0, &mut context.banks_client, context.last_blockhash
+        ).await.early_withdrawal_charge,
+        amount * config.early_withdrawal_fee / EARLY_WITHDRAWAL_FEE_DENOMINATOR);
+
+    // Only the admin may change the scale, and never above 100% of the principal
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let err = perform_set_early_withdrawal_fee(
+        program_id, &user, pool.data_account, 250, 10000, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+    for (early_withdrawal_fee, denominator) in [(10001, 10000), (0, 0)] {
+        let err = perform_set_early_withdrawal_fee(
+            program_id, &context.payer, pool.data_account, early_withdrawal_fee, denominator, &mut context.banks_client, context.last_blockhash
+        ).await.unwrap_err().unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::Custom(StakingError::PenaltyExceedsPrincipal as u32))
+        );
+    }
+
+    // 250 basis points instead of 100/1000
+    perform_set_early_withdrawal_fee(
+        program_id, &context.payer, pool.data_account, 250, 10000, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.early_withdrawal_fee, 250);
+    assert_eq!(contract_data.early_withdrawal_fee_denominator, 10000);
+    assert_eq!(
+        simulate_preview_rewards(
+            program_id, &context.payer, pool.data_account, StakeType::LOCKED as u8, amount, 0, &mut context.banks_client, context.last_blockhash
+        ).await.early_withdrawal_charge,
+        amount * 250 / 10000
+    );
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        amount,
+        pool.decimals,
+        year,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let total_staked = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().total_staked;
+    let balance_before = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    let principal_out = total_staked - total_staked * 250 / 10000;
+    let sent = principal_out + principal_out * 9 / 100;
+    let received = sent - sent * config.fee_basis_points / 10000;
+    assert_eq!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount, balance_before + received);
+}
+
+#[tokio::test]
+async fn test_token_program_must_match_the_pool() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let amount = 1000 * unit;
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    assert_eq!(
+        get_contract_data(&pool.data_account, &mut context.banks_client).await.token_program_id,
+        spl_token_2022::ID
+    );
+
+    let mut stake_data = vec![INSTRUCTION_VERSION, 1, StakeType::NORMAL as u8];
+    stake_data.extend(amount.to_le_bytes().iter());
+    stake_data.extend(pool.decimals.to_le_bytes().iter());
+    stake_data.extend(0u64.to_le_bytes().iter());
+    let err = perform_raw_instruction(
+        program_id,
+        &user,
+        &stake_data,
+        vec![
+            AccountMeta::new(user.pubkey(), true),
+            AccountMeta::new(user_token_account, false),
+            AccountMeta::new(user_data_account, false),
+            AccountMeta::new(pool.token_account, false),
+            AccountMeta::new(pool.data_account, false),
+            AccountMeta::new_readonly(pool.mint, false),
+            AccountMeta::new_readonly(spl_token::ID, false),
+            AccountMeta::new_readonly(solana_program::system_program::ID, false),
+            AccountMeta::new(user_index_pda(&user.pubkey(), &program_id), false)
+        ],
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::IncorrectProgramId));
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, 24 * 60 * 60).await;
+    let mut unstake_data = vec![INSTRUCTION_VERSION, 2];
+    unstake_data.extend(pool.decimals.to_le_bytes().iter());
+    let err = perform_raw_instruction(
+        program_id,
+        &user,
+        &unstake_data,
+        vec![
+            AccountMeta::new(user.pubkey(), true),
+            AccountMeta::new(user_token_account, false),
+            AccountMeta::new(user_data_account, false),
+            AccountMeta::new(pool.token_account, false),
+            AccountMeta::new(pool.data_account, false),
+            AccountMeta::new(pool.mint, false),
+            AccountMeta::new_readonly(spl_token::ID, false),
+            AccountMeta::new(user_index_pda(&user.pubkey(), &program_id), false)
+        ],
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::IncorrectProgramId));
+    assert_eq!(get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().total_staked, amount);
+}
+
+#[tokio::test]
+async fn test_unstake_fails_on_total_staked_drift() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+
+    // The pool total drifted below the position it should include
+    let mut account = context.banks_client.get_account(pool.data_account).await.unwrap().unwrap();
+    let mut contract_data = ContractData::unpack_from_slice(&account.data).unwrap();
+    contract_data.total_staked = 500 * unit;
+    contract_data.pack_into_slice(&mut account.data);
+    context.set_account(&pool.data_account, &account.into());
+    advance_clock(&mut context, 48 * 60 * 60).await;
+    let err = perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::ArithmeticOverflow));
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.total_staked, 500 * unit);
+}
+
+#[tokio::test]
+async fn test_reward_per_second_splits_by_stake() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let unit = 10u64.pow(PoolConfig::default().mint_decimals as u32);
+    let reward_per_second = unit / 100;
+    let config = PoolConfig { reward_per_second, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let (alice, alice_token_account, alice_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let (bob, bob_token_account, bob_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let day: u64 = 24*60*60;
+
+    let (alice_amount, bob_amount) = (1000 * unit, 3000 * unit);
+    for (user, user_token_account, user_data_account, amount) in [
+        (&alice, alice_token_account, alice_data_account, alice_amount),
+        (&bob, bob_token_account, bob_data_account, bob_amount)
+    ] {
+        perform_stake(
+            program_id,
+            user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            amount,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+    }
+    let alice_ts = get_user_data(&alice_data_account, &mut context.banks_client).await.unwrap().stake_ts;
+    let bob_ts = get_user_data(&bob_data_account, &mut context.banks_client).await.unwrap().stake_ts;
+    advance_clock(&mut context, day as i64).await;
+    let now = context.banks_client.get_sysvar::<Clock>().await.unwrap().unix_timestamp as u64;
+
+    // Alice has the stream to herself until Bob joins, then takes a quarter of it
+    let streamed = |seconds: u64, pool_staked: u64| {
+        reward_per_second as u128 * seconds as u128 * REWARD_PER_SHARE_PRECISION / pool_staked as u128
+    };
+    let shared = streamed(now - bob_ts, alice_amount + bob_amount);
+    let alice_interest = ((streamed(bob_ts - alice_ts, alice_amount) + shared) * alice_amount as u128 / REWARD_PER_SHARE_PRECISION) as u64;
+    let bob_interest = (shared * bob_amount as u128 / REWARD_PER_SHARE_PRECISION) as u64;
+    let mut total_earned = 0;
+    for (user, user_token_account, user_data_account, interest) in [
+        (&alice, alice_token_account, alice_data_account, alice_interest),
+        (&bob, bob_token_account, bob_data_account, bob_interest)
+    ] {
+        perform_claim_rewards(
+            program_id,
+            user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+        total_earned += interest;
+        assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.total_earned, total_earned);
+    }
+    // Over the shared stretch Bob earns three times what Alice does
+    let alice_solo = (streamed(bob_ts - alice_ts, alice_amount) * alice_amount as u128 / REWARD_PER_SHARE_PRECISION) as u64;
+    assert!(bob_interest.abs_diff(3 * (alice_interest - alice_solo)) <= 3);
+    // Everything streamed was handed out, up to rounding
+    assert!(total_earned <= reward_per_second * (now - alice_ts));
+    assert!(total_earned + 2 >= reward_per_second * (now - alice_ts));
+
+    // The APY plays no part, another day streams the same amount again
+    advance_clock(&mut context, day as i64).await;
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    perform_claim_rewards(
+        program_id,
+        &bob,
+        bob_token_account,
+        pool.token_account,
+        bob_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await.unwrap();
+    let later = context.banks_client.get_sysvar::<Clock>().await.unwrap().unix_timestamp as u64;
+    let bob_second = (streamed(later - now, alice_amount + bob_amount) * bob_amount as u128 / REWARD_PER_SHARE_PRECISION) as u64;
+    assert_eq!(
+        get_contract_data(&pool.data_account, &mut context.banks_client).await.total_earned,
+        total_earned + bob_second
+    );
+}
+
+#[tokio::test]
+async fn test_restake_never_shortens_unlock_ts() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let day: u64 = 24*60*60;
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        1000 * unit,
+        pool.decimals,
+        30 * day,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let first_unlock_ts = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().stake_ts + 30 * day;
+    assert_eq!(
+        simulate_get_unlock_ts(program_id, &context.payer, user_data_account, &mut context.banks_client, context.last_blockhash).await,
+        first_unlock_ts
+    );
+
+    // A shorter lock added later keeps the original unlock
+    advance_clock(&mut context, (10 * day) as i64).await;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        100 * unit,
+        pool.decimals,
+        config.minimum_lock_duration,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert!(user_data.stake_ts + user_data.lock_duration < first_unlock_ts);
+    assert_eq!(user_data.unlock_ts, first_unlock_ts);
+    assert_eq!(
+        simulate_get_unlock_ts(program_id, &context.payer, user_data_account, &mut context.banks_client, context.last_blockhash).await,
+        first_unlock_ts
+    );
+    // Past the end of the shorter lock, the unstake is still early and charged
+    advance_clock(&mut context, day as i64).await;
+    let balance_before = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    let balance_after = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    let amount_out = user_data.total_staked - config.early_withdrawal_fee * user_data.total_staked / 1000 + user_data.interest_accrued;
+    let amount_out_with_fee = amount_out + 9 * amount_out / 100;
+    let transfer_fee = amount_out_with_fee * config.fee_basis_points / 10000;
+    assert_eq!(balance_after, balance_before + amount_out_with_fee - transfer_fee);
+}
+
+#[tokio::test]
+async fn test_liquidate_expired_pays_owner() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let (keeper, keeper_token_account, _) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let day: u64 = 24*60*60;
+
+    let amount = 1000 * unit;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        amount,
+        pool.decimals,
+        30 * day,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+
+    // A running lock cannot be liquidated, not even within the grace period
+    advance_clock(&mut context, (10 * day) as i64).await;
+    let err = perform_liquidate_expired(
+        program_id, &keeper, pool.data_account, user_data_account, user.pubkey(), user_token_account,
+        pool.token_account, pool.mint, pool.decimals, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::LockNotEnded as u32)));
+
+    advance_clock(&mut context, (20 * day) as i64).await;
+    // Payouts can only go to the owner of the position
+    let err = perform_liquidate_expired(
+        program_id, &keeper, pool.data_account, user_data_account, user.pubkey(), keeper_token_account,
+        pool.token_account, pool.mint, pool.decimals, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::InvalidDestinationTokenAccount as u32))
+    );
+
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    let interest = expected_interest(config.locked_staking_apy, amount, 30 * day);
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    perform_liquidate_expired(
+        program_id, &keeper, pool.data_account, user_data_account, user.pubkey(), user_token_account,
+        pool.token_account, pool.mint, pool.decimals, &mut context.banks_client, recent_block_hash
+    ).await.unwrap();
+    let amount_out = user_data.total_staked + interest;
+    let amount_out_with_fee = amount_out + 9 * amount_out / 100;
+    // Token 2022 rounds the transfer fee up
+    let transfer_fee = (amount_out_with_fee * config.fee_basis_points).div_ceil(10000);
+    assert_eq!(
+        get_token_account_data(&user_token_account, &mut context.banks_client).await.amount,
+        user_balance + amount_out_with_fee - transfer_fee
+    );
+    assert!(get_user_data(&user_data_account, &mut context.banks_client).await.is_err());
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.total_staked, 0);
+    assert_eq!(contract_data.total_earned, interest);
+    assert_eq!(contract_data.active_stakers, 0);
+}
+
+#[tokio::test]
+async fn test_unstake_after_mint_transfer_fee_change() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let day: u64 = 24*60*60;
+
+    let amount = 1000 * unit;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, (2 * day) as i64).await;
+
+    // The new fee and its much lower maximum apply two epochs later, the pool keeps the init values
+    let (new_fee_basis_points, new_max_fee) = (200_u16, 5 * unit);
+    perform_set_transfer_fee(&mut context, pool.mint, new_fee_basis_points, new_max_fee).await;
+    advance_epoch(&mut context, 2).await;
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!((contract_data.fee_basis_points, contract_data.max_fee), (config.fee_basis_points, config.max_fee));
+
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    let amount_out = user_data.total_staked + expected_interest(config.normal_staking_apy, user_data.total_staked, 2 * day);
+    let amount_out_with_fee = amount_out + 9 * amount_out / 100;
+    let transfer_fee = (amount_out_with_fee * new_fee_basis_points as u64).div_ceil(10000).min(new_max_fee);
+    assert_eq!(transfer_fee, new_max_fee);
+    assert_eq!(
+        get_token_account_data(&user_token_account, &mut context.banks_client).await.amount,
+        user_balance + amount_out_with_fee - transfer_fee
+    );
+}
+
+#[tokio::test]
+async fn test_validate_stake_reports_failing_check() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let cases = [
+        (StakeType::LOCKED, 1000 * unit, config.minimum_lock_duration - 1, StakingError::LockDurationTooShort as u64),
+        (StakeType::NORMAL, 6000 * unit, 0, StakingError::InsufficientStakeBalance as u64),
+        (StakeType::LOCKED, 1000 * unit, config.minimum_lock_duration, 0)
+    ];
+    for (stake_type, amount, lock_duration, error_code) in cases {
+        let stake_validation = simulate_validate_stake(
+            program_id, &user, user_token_account, pool.token_account, user_data_account, pool.data_account,
+            pool.mint, stake_type as u8, amount, lock_duration, &mut context.banks_client, context.last_blockhash
+        ).await;
+        assert_eq!(stake_validation.error_code, error_code);
+    }
+    // Nothing moved and the position was not opened
+    assert_eq!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount, 5000 * unit);
+    assert!(!get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().is_initialized);
+
+    // Re-staking into the position with the other stake type is caught as well
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let stake_validation = simulate_validate_stake(
+        program_id, &user, user_token_account, pool.token_account, user_data_account, pool.data_account,
+        pool.mint, StakeType::LOCKED as u8, 1000 * unit, config.minimum_lock_duration,
+        &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(ProgramError::from(stake_validation.error_code), StakingError::StakeTypeMismatch.into());
+}
+
+#[tokio::test]
+async fn test_fee_exempt_user_skips_protocol_fees() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { deposit_fee_bps: 100, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (vip, vip_token_account, vip_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    // Only the admin can grant the exemption, ahead of the first stake
+    let err = perform_set_fee_exempt(
+        program_id, &vip, pool.data_account, vip_data_account, true, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+    perform_set_fee_exempt(
+        program_id, &context.payer, pool.data_account, vip_data_account, true, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+
+    let amount = 1000 * unit;
+    for (staker, token_account, data_account) in [(&vip, vip_token_account, vip_data_account), (&user, user_token_account, user_data_account)] {
+        perform_stake(
+            program_id,
+            staker,
+            token_account,
+            pool.token_account,
+            data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::LOCKED as u8,
+            amount,
+            pool.decimals,
+            30*24*60*60,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+    }
+    let vip_data = get_user_data(&vip_data_account, &mut context.banks_client).await.unwrap();
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert!(vip_data.is_fee_exempt && !user_data.is_fee_exempt);
+    assert_eq!(vip_data.total_staked, amount);
+    assert_eq!(user_data.total_staked, amount - amount * config.deposit_fee_bps / 10000);
+
+    // Leaving early, only the non-exempt user pays the early withdrawal charge. Both pay the mint transfer fee
+    advance_clock(&mut context, 24*60*60).await;
+    for (staker, token_account, data_account, early_withdrawal_charge) in [
+        (&vip, vip_token_account, vip_data_account, 0),
+        (&user, user_token_account, user_data_account, config.early_withdrawal_fee * user_data.total_staked / 1000)
+    ] {
+        let total_staked = get_user_data(&data_account, &mut context.banks_client).await.unwrap().total_staked;
+        let balance = get_token_account_data(&token_account, &mut context.banks_client).await.amount;
+        perform_unstake(
+            program_id,
+            staker,
+            token_account,
+            pool.token_account,
+            data_account,
+            pool.data_account,
+            pool.mint,
+            &mut context.banks_client,
+            context.last_blockhash,
+            pool.decimals
+        ).await.unwrap();
+        let amount_out = total_staked - early_withdrawal_charge;
+        let amount_out_with_fee = amount_out + 9 * amount_out / 100;
+        let transfer_fee = (amount_out_with_fee * config.fee_basis_points).div_ceil(10000);
+        assert_eq!(
+            get_token_account_data(&token_account, &mut context.banks_client).await.amount,
+            balance + amount_out_with_fee - transfer_fee
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_repair_totals_from_live_positions() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (alice, alice_token_account, alice_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let (bob, bob_token_account, bob_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    for (user, user_token_account, user_data_account, stake_type, amount, lock_duration) in [
+        (&alice, alice_token_account, alice_data_account, StakeType::NORMAL, 1000 * unit, 0),
+        (&bob, bob_token_account, bob_data_account, StakeType::LOCKED, 3000 * unit, 30*24*60*60)
+    ] {
+        perform_stake(
+            program_id,
+            user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            stake_type as u8,
+            amount,
+            pool.decimals,
+            lock_duration,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+    }
+
+    // Corrupt both totals
+    let mut account = context.banks_client.get_account(pool.data_account).await.unwrap().unwrap();
+    let mut contract_data = ContractData::unpack_from_slice(&account.data).unwrap();
+    contract_data.total_staked = 500 * unit;
+    contract_data.total_locked_staked = 9000 * unit;
+    contract_data.pack_into_slice(&mut account.data);
+    context.set_account(&pool.data_account, &account.into());
+
+    // Every open position must be passed, and only once
+    for user_data_accounts in [vec![alice_data_account], vec![alice_data_account, alice_data_account]] {
+        let err = perform_repair_totals(
+            program_id, &context.payer, pool.data_account, &user_data_accounts,
+            &mut context.banks_client, context.last_blockhash
+        ).await.unwrap_err().unwrap();
+        assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::PositionsMismatch as u32)));
+    }
+    let err = perform_repair_totals(
+        program_id, &alice, pool.data_account, &[alice_data_account, bob_data_account],
+        &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+
+    // Repairing twice leaves the same totals
+    for _ in 0..2 {
+        let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+        perform_repair_totals(
+            program_id, &context.payer, pool.data_account, &[bob_data_account, alice_data_account],
+            &mut context.banks_client, recent_block_hash
+        ).await.unwrap();
+        let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+        assert_eq!(contract_data.total_staked, 4000 * unit);
+        assert_eq!(contract_data.total_locked_staked, 3000 * unit);
+    }
+
+    // Positions leave the repaired totals without drifting
+    advance_clock(&mut context, 48 * 60 * 60).await;
+    perform_unstake(
+        program_id,
+        &alice,
+        alice_token_account,
+        pool.token_account,
+        alice_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.total_staked, 3000 * unit);
+}
+
+#[test]
+fn test_stake_type_discriminant_round_trip() {
+    for stake_type in [StakeType::NORMAL, StakeType::LOCKED] {
+        let value = u8::from(&stake_type);
+        assert_eq!(u8::from(&StakeType::try_from(value).unwrap()), value);
+        let borsh_data = stake_type.try_to_vec().unwrap();
+        assert_eq!(borsh_data, (value as u64).to_le_bytes());
+        assert_eq!(u8::from(&StakeType::try_from_slice(&borsh_data).unwrap()), value);
+    }
+    assert_eq!(u8::from(&StakeType::NORMAL), 0);
+    assert_eq!(u8::from(&StakeType::LOCKED), 1);
+
+    // Unknown discriminants are rejected by the conversion, the Borsh layout and the instruction decoder
+    assert!(matches!(StakeType::try_from(2), Err(2)));
+    assert!(StakeType::try_from_slice(&2u64.to_le_bytes()).is_err());
+    assert!(StakeType::try_from_slice(&256u64.to_le_bytes()).is_err());
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 10, 2];
+    instruction_data.extend(0u64.to_le_bytes().iter());
+    instruction_data.extend(0u64.to_le_bytes().iter());
+    assert!(matches!(
+        spl_staking::instruction::Instruction::unpack(&instruction_data),
+        Err(ProgramError::InvalidInstructionData)
+    ));
+}
+
+#[tokio::test]
+async fn test_extend_lock_moves_unlock_without_transfer() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { max_lock_duration: 90*24*60*60, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let day: u64 = 24*60*60;
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        1000 * unit,
+        pool.decimals,
+        30 * day,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let stake_ts = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().stake_ts;
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    advance_clock(&mut context, (10 * day) as i64).await;
+
+    // Shortening, keeping and exceeding the maximum lock are rejected
+    for (new_lock_duration, error) in [
+        (20 * day, StakingError::LockDurationTooShort),
+        (30 * day, StakingError::LockDurationTooShort),
+        (91 * day, StakingError::LockDurationTooLong)
+    ] {
+        let err = perform_extend_lock(
+            program_id, &user, user_data_account, pool.data_account, new_lock_duration,
+            &mut context.banks_client, context.last_blockhash
+        ).await.unwrap_err().unwrap();
+        assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(error as u32)));
+    }
+
+    perform_extend_lock(
+        program_id, &user, user_data_account, pool.data_account, 60 * day,
+        &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!((user_data.stake_ts, user_data.lock_duration, user_data.unlock_ts), (stake_ts, 60 * day, stake_ts + 60 * day));
+    assert_eq!(
+        simulate_get_unlock_ts(program_id, &context.payer, user_data_account, &mut context.banks_client, context.last_blockhash).await,
+        stake_ts + 60 * day
+    );
+    assert_eq!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount, user_balance);
+
+    // Past the original unlock the position is still locked and cannot claim yet
+    advance_clock(&mut context, (25 * day) as i64).await;
+    let err = perform_claim_rewards(
+        program_id, &user, user_token_account, pool.token_account, user_data_account, pool.data_account, pool.mint,
+        &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::LockNotExpired as u32)));
+}
+
+#[tokio::test]
+async fn test_reward_per_second_skips_empty_pool() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let unit = 10u64.pow(PoolConfig::default().mint_decimals as u32);
+    let reward_per_second = unit / 100;
+    let config = PoolConfig { reward_per_second, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let (alice, alice_token_account, alice_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let (bob, bob_token_account, bob_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let day: u64 = 24*60*60;
+    let amount = 1000 * unit;
+
+    // The rewards are funded but nobody stakes for 10 days, then Alice stakes for 2 days and leaves.
+    // Bob stakes after another empty stretch of 5 days
+    let mut earned = Vec::new();
+    for (user, user_token_account, user_data_account, empty_days) in [
+        (&alice, alice_token_account, alice_data_account, 10),
+        (&bob, bob_token_account, bob_data_account, 5)
+    ] {
+        advance_clock(&mut context, (empty_days * day) as i64).await;
+        let reward_per_share = get_contract_data(&pool.data_account, &mut context.banks_client).await.reward_per_share;
+        let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+        perform_stake(
+            program_id,
+            user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            amount,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            recent_block_hash
+        ).await.unwrap();
+        let stake_ts = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().stake_ts;
+        let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+        assert_eq!(contract_data.reward_per_share, reward_per_share);
+        assert_eq!(contract_data.reward_per_share_ts, stake_ts);
+
+        advance_clock(&mut context, (2 * day) as i64).await;
+        let total_earned = contract_data.total_earned;
+        perform_unstake(
+            program_id,
+            user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            &mut context.banks_client,
+            context.last_blockhash,
+            pool.decimals
+        ).await.unwrap();
+        earned.push(get_contract_data(&pool.data_account, &mut context.banks_client).await.total_earned - total_earned);
+    }
+    // Each staker earned the stream of their own 2 days only, up to rounding
+    for interest in earned {
+        assert!(interest <= reward_per_second * 2 * day);
+        assert!(interest + 1 >= reward_per_second * 2 * day);
+    }
+}
+
+#[tokio::test]
+async fn test_init_requires_initial_rewards() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let unit = 10u64.pow(PoolConfig::default().mint_decimals as u32);
+
+    // An empty reward account, or one holding less than the threshold, stays with the admin
+    for reward_amount in [0, 99 * unit] {
+        let config = PoolConfig { reward_amount, min_initial_rewards: 100 * unit, ..PoolConfig::default() };
+        let err = try_set_up_pool(&mut context, program_id, &config).await.err().unwrap().unwrap();
+        assert_eq!(err, TransactionError::InstructionError(3, InstructionError::Custom(35)));
+    }
+    assert_eq!(StakingError::InsufficientInitialRewards as u32, 35);
+
+    let config = PoolConfig { reward_amount: 100 * unit, min_initial_rewards: 100 * unit, ..PoolConfig::default() };
+    let pool = try_set_up_pool(&mut context, program_id, &config).await.unwrap();
+    assert!(get_contract_data(&pool.data_account, &mut context.banks_client).await.is_initialized);
+}
+
+#[tokio::test]
+async fn test_claim_split_claims_and_compounds() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let hour: u64 = 60*60;
+
+    let amount = 1000 * unit;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, (48 * hour) as i64).await;
+
+    // More than 100% is rejected
+    let err = perform_claim_split(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        10001,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::InvalidClaimSplit as u32))
+    );
+
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    perform_claim_split(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        3000,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+
+    // 30% of the interest reached the user net of the transfer fee, 70% was added to the principal
+    let interest = expected_interest(config.normal_staking_apy, amount, 48 * hour);
+    let claimed = interest * 3000 / 10000;
+    let transfer_fee = (claimed * config.fee_basis_points).div_ceil(10000);
+    assert_eq!(
+        get_token_account_data(&user_token_account, &mut context.banks_client).await.amount,
+        user_balance + claimed - transfer_fee
+    );
+    let now = context.banks_client.get_sysvar::<Clock>().await.unwrap().unix_timestamp as u64;
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.total_staked, amount + interest - claimed);
+    assert_eq!(user_data.interest_accrued, 0);
+    assert_eq!(user_data.stake_ts, now);
+    assert_eq!(user_data.last_claim_ts, now);
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.total_staked, amount + interest - claimed);
+    assert_eq!(contract_data.total_earned, interest);
+}
+
+#[tokio::test]
+async fn test_restake_after_close_starts_fresh_position() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let amount = 1000 * unit;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        amount,
+        pool.decimals,
+        config.minimum_lock_duration,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, 24*60*60).await;
+
+    let unstake_ix = unstake_instruction(
+        program_id,
+        user.pubkey(),
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        None,
+        false,
+        pool.decimals
+    );
+    let stake_ix = |stake_type: StakeType, lock_duration: u64| stake_instruction(
+        program_id,
+        user.pubkey(),
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        stake_type as u8,
+        amount,
+        pool.decimals,
+        lock_duration,
+        false
+    );
+
+    // Staking into the account closed by the unstake before it is gone would lose the stake with it
+    let mut transaction = Transaction::new_with_payer(
+        &[unstake_ix.clone(), stake_ix(StakeType::NORMAL, 0)],
+        Some(&user.pubkey())
+    );
+    transaction.sign(&[&user], context.last_blockhash);
+    let err = context.banks_client.process_transaction(transaction).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(1, InstructionError::Custom(StakingError::UserDataNotCreated as u32))
+    );
+
+    // Once recreated, the account takes a stake of the other type as a new position
+    let mut transaction = Transaction::new_with_payer(&[unstake_ix], Some(&user.pubkey()));
+    transaction.sign(&[&user], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    assert!(context.banks_client.get_account(user_data_account).await.unwrap().is_none());
+    perform_create_user_data(
+        program_id, &user, user.pubkey(), pool.data_account, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    let mut transaction = Transaction::new_with_payer(&[stake_ix(StakeType::NORMAL, 0)], Some(&user.pubkey()));
+    transaction.sign(&[&user], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    let now = context.banks_client.get_sysvar::<Clock>().await.unwrap().unix_timestamp as u64;
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert!(user_data.is_initialized);
+    assert_eq!(u8::from(&user_data.stake_type), StakeType::NORMAL as u8);
+    assert_eq!(user_data.lock_duration, 0);
+    assert_eq!(user_data.unlock_ts, 0);
+    assert_eq!(user_data.total_staked, amount);
+    assert_eq!(user_data.interest_accrued, 0);
+    assert_eq!(user_data.stake_ts, now);
+    assert_eq!(user_data.created_ts, now);
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.total_staked, amount);
+    assert_eq!(contract_data.total_locked_staked, 0);
+    assert_eq!(contract_data.active_stakers, 1);
+}
+
+#[tokio::test]
+async fn test_normal_hold_counts_from_first_stake() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let hour: i64 = 60*60;
+    for (amount, wait) in [(1000 * unit, 23 * hour), (unit, 0)] {
+        perform_stake(
+            program_id,
+            &user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            StakeType::NORMAL as u8,
+            amount,
+            pool.decimals,
+            0,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+        advance_clock(&mut context, wait).await;
+    }
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.created_ts + 23 * hour as u64, user_data.stake_ts);
+
+    // 23 hours after the first stake the position is still held
+    let err = perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::LockNotExpired as u32))
+    );
+
+    // The dust re-stake did not restart the hold, 24 hours after the first stake it is over
+    advance_clock(&mut context, hour).await;
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        recent_block_hash,
+        pool.decimals
+    ).await.unwrap();
+    assert!(context.banks_client.get_account(user_data_account).await.unwrap().is_none());
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.total_staked, 0);
+}
+
+#[tokio::test]
+async fn test_unstake_returns_receipt_on_time() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let day: u64 = 24*60*60;
+    let amount = 1000 * unit;
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let lock_duration = config.minimum_lock_duration;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        amount,
+        pool.decimals,
+        lock_duration,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, day as i64).await;
+    let receipt = simulate_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        pool.decimals,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+
+    // On time the whole principal and the interest accrued until now are paid
+    assert_eq!(receipt.principal, amount);
+    assert_eq!(receipt.gross_interest, expected_interest(config.locked_staking_apy, amount, day));
+    assert_eq!(receipt.penalty, 0);
+    // The payout is grossed up by 9% before the mint withholds its fee
+    let amount_out = receipt.principal - receipt.penalty + receipt.gross_interest;
+    let amount_out_with_fee = amount_out + 9 * amount_out / 100;
+    assert_eq!(receipt.mint_transfer_fee, (amount_out_with_fee * config.fee_basis_points).div_ceil(10000));
+    assert_eq!(receipt.net_paid, amount_out_with_fee - receipt.mint_transfer_fee);
+
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    assert_eq!(
+        get_token_account_data(&user_token_account, &mut context.banks_client).await.amount,
+        user_balance + receipt.net_paid
+    );
+}
+
+#[tokio::test]
+async fn test_unstake_returns_receipt_on_early_exit() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let day: u64 = 24*60*60;
+    let amount = 1000 * unit;
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let lock_duration = 10 * day;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        amount,
+        pool.decimals,
+        lock_duration,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, day as i64).await;
+    let receipt = simulate_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        pool.decimals,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+
+    // Early the charge is kept and the interest forfeited, the receipt is still written
+    assert_eq!(receipt.principal, amount);
+    assert_eq!(receipt.gross_interest, 0);
+    assert_eq!(receipt.penalty, amount * config.early_withdrawal_fee / EARLY_WITHDRAWAL_FEE_DENOMINATOR);
+    // The payout is grossed up by 9% before the mint withholds its fee
+    let amount_out = receipt.principal - receipt.penalty + receipt.gross_interest;
+    let amount_out_with_fee = amount_out + 9 * amount_out / 100;
+    assert_eq!(receipt.mint_transfer_fee, (amount_out_with_fee * config.fee_basis_points).div_ceil(10000));
+    assert_eq!(receipt.net_paid, amount_out_with_fee - receipt.mint_transfer_fee);
+
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    assert_eq!(
+        get_token_account_data(&user_token_account, &mut context.banks_client).await.amount,
+        user_balance + receipt.net_paid
+    );
+}
+
+#[tokio::test]
+async fn test_init_rejects_existing_pool() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+
+    // Same admin and mint, so the same contract data PDA
+    let token_account = Keypair::new();
+    let mut transaction = construct_init_txn(
+        &PoolConfig { normal_staking_apy: 1, ..PoolConfig::default() },
+        context.payer.pubkey(),
+        token_account.pubkey(),
+        Rent::default(),
+        pool.mint,
+        program_id,
+        pool.data_account
+    );
+    transaction.sign(&[&context.payer, &token_account], context.last_blockhash);
+    let err = context.banks_client.process_transaction(transaction).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(3, InstructionError::AccountAlreadyInitialized));
+    let unchanged = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(unchanged.try_to_vec().unwrap(), contract_data.try_to_vec().unwrap());
+}
+
+#[tokio::test]
+async fn test_tiny_early_unstake_is_rejected() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let day: u64 = 24*60*60;
+
+    // 2 base units leave 2 after the early charge, the transfer fee would withhold 1 of them
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        2,
+        pool.decimals,
+        10 * day,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, day as i64).await;
+    let err = perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::UnstakeAmountTooSmall as u32))
+    );
+    assert_eq!(get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().total_staked, 2);
+
+    // A larger position exits early as before
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        100,
+        pool.decimals,
+        10 * day,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, day as i64).await;
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    assert!(context.banks_client.get_account(user_data_account).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_waive_penalty_covers_next_early_unstake() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let day: u64 = 24*60*60;
+    let amount = 1000 * unit;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        amount,
+        pool.decimals,
+        30 * day,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, day as i64).await;
+
+    // Only the admin can waive the charge
+    let err = perform_waive_penalty(
+        program_id, &user, pool.data_account, user_data_account, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+    perform_waive_penalty(
+        program_id, &context.payer, pool.data_account, user_data_account, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    assert!(get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().penalty_waived);
+
+    // The waiver is used up by the next early unstake, a partial one included
+    let balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    perform_partial_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        300 * unit,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    let amount_out_with_fee = 300 * unit + 9 * 300 * unit / 100;
+    assert_eq!(
+        get_token_account_data(&user_token_account, &mut context.banks_client).await.amount,
+        balance + amount_out_with_fee - (amount_out_with_fee * config.fee_basis_points).div_ceil(10000)
+    );
+    assert!(!get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().penalty_waived);
+    let receipt = simulate_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        pool.decimals,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+    assert_eq!(receipt.penalty, 700 * unit * config.early_withdrawal_fee / EARLY_WITHDRAWAL_FEE_DENOMINATOR);
+
+    // Waived again, the early exit returns the full principal
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    perform_waive_penalty(
+        program_id, &context.payer, pool.data_account, user_data_account, &mut context.banks_client, recent_block_hash
+    ).await.unwrap();
+    let balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    let receipt = simulate_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        pool.decimals,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+    assert_eq!((receipt.principal, receipt.penalty), (700 * unit, 0));
+    perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        pool.decimals
+    ).await.unwrap();
+    assert_eq!(
+        get_token_account_data(&user_token_account, &mut context.banks_client).await.amount,
+        balance + receipt.net_paid
+    );
+}
+
+#[tokio::test]
+async fn test_promo_boost_window_blends_interest() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let day: u64 = 24*60*60;
+    let amount = 1000 * unit;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let stake_ts = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().stake_ts;
+
+    // Windows must start ahead, end after they start and boost above 1x
+    for (start_ts, end_ts, multiplier_bps) in [
+        (stake_ts - 1, stake_ts + day, 20000),
+        (stake_ts + day, stake_ts + day, 20000),
+        (stake_ts + day, stake_ts + 2 * day, 10000),
+        (stake_ts + day, stake_ts + 2 * day, 100001)
+    ] {
+        let err = perform_set_promo_boost(
+            program_id, &context.payer, pool.data_account, start_ts, end_ts, multiplier_bps,
+            &mut context.banks_client, context.last_blockhash
+        ).await.unwrap_err().unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(0, InstructionError::Custom(StakingError::InvalidPromoBoost as u32))
+        );
+    }
+    let err = perform_set_promo_boost(
+        program_id, &user, pool.data_account, stake_ts + day, stake_ts + 2 * day, 20000,
+        &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+
+    // 2x rewards on the second day of the position
+    perform_set_promo_boost(
+        program_id, &context.payer, pool.data_account, stake_ts + day, stake_ts + 2 * day, 20000,
+        &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, (3 * day / 2) as i64).await;
+    let err = perform_set_promo_boost(
+        program_id, &context.payer, pool.data_account, 0, 0, 0, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::PromoBoostActive as u32))
+    );
+
+    // Three days staked pay four days of interest, the boosted day counting twice
+    advance_clock(&mut context, (3 * day / 2) as i64).await;
+    perform_claim_rewards(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let now = context.banks_client.get_sysvar::<Clock>().await.unwrap().unix_timestamp as u64;
+    assert!(now >= stake_ts + 2 * day);
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.total_earned, expected_interest(config.normal_staking_apy, amount, now - stake_ts + day));
+}
+
+#[tokio::test]
+async fn test_get_config_returns_init_values() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig {
+        max_lock_duration: 365 * 24 * 60 * 60,
+        claim_cooldown: 60,
+        history_enabled: true,
+        max_stakers: 3,
+        ..PoolConfig::default()
+    };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+
+    let snapshot = simulate_get_config(
+        program_id, &context.payer, pool.data_account, &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(snapshot.config_version, CONFIG_VERSION);
+    assert_eq!(snapshot.minimum_stake_amount, config.minimum_stake_amount);
+    assert_eq!(snapshot.normal_staking_apy, config.normal_staking_apy);
+    assert_eq!(snapshot.locked_staking_apy, config.locked_staking_apy);
+    assert_eq!(snapshot.early_withdrawal_fee, config.early_withdrawal_fee);
+    assert_eq!(snapshot.max_lock_duration, config.max_lock_duration);
+    assert_eq!(snapshot.claim_cooldown, config.claim_cooldown);
+    assert_eq!(snapshot.max_stakers, config.max_stakers);
+    assert!(snapshot.history_enabled);
+    assert!(!snapshot.is_paused);
+    assert_eq!(snapshot.stake_token_mint, pool.mint);
+}
+
+#[tokio::test]
+async fn test_operator_updates_config_but_cannot_move_funds() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let operator = Keypair::new();
+    transfer_sol(&context.payer, operator.pubkey(), LAMPORTS_PER_SOL, &mut context.banks_client, context.last_blockhash).await;
+
+    // Not an operator yet, and only the admin can appoint one
+    let err = perform_update_apy(
+        program_id, &operator, pool.data_account, 100, 200, 0, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+    let err = perform_set_operator(
+        program_id, &operator, pool.data_account, operator.pubkey(), &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+    perform_set_operator(
+        program_id, &context.payer, pool.data_account, operator.pubkey(), &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.operator_pubkey, operator.pubkey());
+
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    perform_update_apy(
+        program_id, &operator, pool.data_account, 100, 200, 0, &mut context.banks_client, recent_block_hash
+    ).await.unwrap();
+    perform_set_early_withdrawal_fee(
+        program_id, &operator, pool.data_account, 50, 1000, &mut context.banks_client, recent_block_hash
+    ).await.unwrap();
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!((contract_data.normal_staking_apy, contract_data.locked_staking_apy), (100, 200));
+    assert_eq!(contract_data.early_withdrawal_fee, 50);
+
+    // Fund moving instructions stay with the admin, even while paused
+    perform_set_paused(
+        program_id, &context.payer, pool.data_account, true, &mut context.banks_client, recent_block_hash
+    ).await.unwrap();
+    let recovery_account = Keypair::new();
+    set_up_token_account(
+        &context.payer,
+        &recovery_account,
+        None,
+        Rent::default(),
+        pool.mint,
+        0,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await;
+    let err = perform_emergency_withdraw(
+        program_id,
+        &operator,
+        pool.data_account,
+        pool.token_account,
+        recovery_account.pubkey(),
+        pool.mint,
+        10u64.pow(pool.decimals as u32),
+        &mut context.banks_client,
+        recent_block_hash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+    let err = perform_set_operator(
+        program_id, &operator, pool.data_account, Pubkey::default(), &mut context.banks_client, recent_block_hash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+
+    // The admin can still remove the operator
+    perform_set_operator(
+        program_id, &context.payer, pool.data_account, Pubkey::default(), &mut context.banks_client, recent_block_hash
+    ).await.unwrap();
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    let err = perform_update_apy(
+        program_id, &operator, pool.data_account, 300, 400, 0, &mut context.banks_client, recent_block_hash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+}
+
+#[tokio::test]
+async fn test_decimals_above_u8_are_rejected() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    // 300 would wrap to 44 when cast for the token program
+    let err = perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        300,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::InvalidInstructionData));
+    let err = perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        300
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::InvalidInstructionData));
+    assert_eq!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount, 5000 * unit);
+}
+
+#[tokio::test]
+async fn test_stake_with_setup_opens_position_for_new_user() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let year: u64 = 365 * 24 * 60 * 60;
+    let user = Keypair::new();
+    let user_token_account = Keypair::new();
+    set_up_token_account(
+        &context.payer,
+        &user_token_account,
+        Some(user.pubkey()),
+        Rent::default(),
+        pool.mint,
+        5000 * unit,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+    transfer_sol(&context.payer, user.pubkey(), LAMPORTS_PER_SOL, &mut context.banks_client, context.last_blockhash).await;
+    let user_data_account = user_data_pda(&user.pubkey(), &pool.data_account, &program_id);
+    assert!(context.banks_client.get_account(user_data_account).await.unwrap().is_none());
+
+    perform_stake_with_setup(
+        program_id,
+        &user,
+        user_token_account.pubkey(),
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        1000 * unit,
+        pool.decimals,
+        year,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let account = context.banks_client.get_account(user_data_account).await.unwrap().unwrap();
+    assert_eq!(account.owner, program_id);
+    assert!(Rent::default().is_exempt(account.lamports, account.data.len()));
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert!(user_data.is_initialized);
+    assert_eq!(user_data.owner_pubkey, user.pubkey());
+    assert_eq!(u8::from(&user_data.stake_type), StakeType::LOCKED as u8);
+    let staked = user_data.total_staked;
+    assert!(staked > 0);
+    assert_eq!(get_token_account_data(&user_token_account.pubkey(), &mut context.banks_client).await.amount, 4000 * unit);
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!((contract_data.total_staked, contract_data.active_stakers), (staked, 1));
+
+    // Later stakes reuse the existing account
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    perform_stake_with_setup(
+        program_id,
+        &user,
+        user_token_account.pubkey(),
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        1000 * unit,
+        pool.decimals,
+        year,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await.unwrap();
+    assert_eq!(get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().total_staked, 2 * staked);
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.active_stakers, 1);
+}
+
+#[tokio::test]
+async fn test_position_is_bound_to_its_pool() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let other_pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let year: u64 = 365 * 24 * 60 * 60;
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let other_token_account = Keypair::new();
+    set_up_token_account(
+        &context.payer,
+        &other_token_account,
+        Some(user.pubkey()),
+        Rent::default(),
+        other_pool.mint,
+        5000 * unit,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::LOCKED as u8,
+        1000 * unit,
+        pool.decimals,
+        year,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    assert_eq!(get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().pool, pool.data_account);
+
+    // The same user data account cannot be paid out of, nor added to, another pool
+    let err = perform_unstake(
+        program_id,
+        &user,
+        other_token_account.pubkey(),
+        other_pool.token_account,
+        user_data_account,
+        other_pool.data_account,
+        other_pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        other_pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::PositionPoolMismatch as u32))
+    );
+    let err = perform_stake(
+        program_id,
+        &user,
+        other_token_account.pubkey(),
+        other_pool.token_account,
+        user_data_account,
+        other_pool.data_account,
+        other_pool.mint,
+        StakeType::LOCKED as u8,
+        1000 * unit,
+        other_pool.decimals,
+        year,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::PdaMismatch as u32)));
+    assert_eq!(get_token_account_data(&other_token_account.pubkey(), &mut context.banks_client).await.amount, 5000 * unit);
+    assert_eq!(get_contract_data(&other_pool.data_account, &mut context.banks_client).await.total_staked, 0);
+
+    // The user holds a second position in the other pool at its own address
+    perform_create_user_data(
+        program_id, &context.payer, user.pubkey(), other_pool.data_account, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    let other_user_data_account = user_data_pda(&user.pubkey(), &other_pool.data_account, &program_id);
+    assert_ne!(other_user_data_account, user_data_account);
+    perform_stake(
+        program_id,
+        &user,
+        other_token_account.pubkey(),
+        other_pool.token_account,
+        other_user_data_account,
+        other_pool.data_account,
+        other_pool.mint,
+        StakeType::NORMAL as u8,
+        2000 * unit,
+        other_pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let other_user_data = get_user_data(&other_user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(other_user_data.pool, other_pool.data_account);
+    assert_eq!(other_user_data.total_staked, 2000 * unit);
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.pool, pool.data_account);
+    assert_eq!(user_data.total_staked, 1000 * unit);
+    assert_eq!(get_contract_data(&other_pool.data_account, &mut context.banks_client).await.total_staked, 2000 * unit);
+
+    // A legacy position seeded by the owner alone fails the strict pool check until it is migrated
+    let legacy_account = context.banks_client.get_account(user_data_account).await.unwrap().unwrap();
+    let legacy_user_data_account = pda::legacy_user_data_pda(&user.pubkey(), &program_id).0;
+    context.set_account(&legacy_user_data_account, &legacy_account.into());
+    let err = perform_unstake(
+        program_id,
+        &user,
+        other_token_account.pubkey(),
+        other_pool.token_account,
+        legacy_user_data_account,
+        other_pool.data_account,
+        other_pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        other_pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::PositionPoolMismatch as u32))
+    );
+    let err = perform_migrate_user_data(
+        program_id, &user, other_pool.data_account, None, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::PositionPoolMismatch as u32))
+    );
+}
+
+#[tokio::test]
+async fn test_stake_from_frozen_account_is_rejected() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    // As handed out by a mint with a frozen DefaultAccountState
+    let freeze_txn = Transaction::new_signed_with_payer(
+        &[spl_token_2022::instruction::freeze_account(
+            &spl_token_2022::ID, &user_token_account, &pool.mint, &context.payer.pubkey(), &[]
+        ).unwrap()],
+        Some(&context.payer.pubkey()),
+        &[&context.payer],
+        context.last_blockhash
+    );
+    context.banks_client.process_transaction(freeze_txn).await.unwrap();
+    let err = perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::Custom(StakingError::TokenAccountFrozen as u32))
+    );
+    assert_eq!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount, 5000 * unit);
+}
+
+#[tokio::test]
+async fn test_rewards_pause_excludes_frozen_interval() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let day: u64 = 24 * 60 * 60;
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    let (amount, stake_ts) = (user_data.total_staked, user_data.stake_ts);
+
+    advance_clock(&mut context, (2 * day) as i64).await;
+    let err = perform_set_rewards_paused(
+        program_id, &user, pool.data_account, true, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(0, InstructionError::Custom(StakingError::Unauthorized as u32)));
+    perform_set_rewards_paused(
+        program_id, &context.payer, pool.data_account, true, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert!(contract_data.rewards_paused && !contract_data.is_paused);
+    let frozen_since = contract_data.accrual_frozen_since;
+
+    // Deposits are still accepted while accrual is frozen
+    advance_clock(&mut context, (3 * day) as i64).await;
+    let (other_user, other_token_account, other_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    perform_stake(
+        program_id,
+        &other_user,
+        other_token_account,
+        pool.token_account,
+        other_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    perform_set_rewards_paused(
+        program_id, &context.payer, pool.data_account, false, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert!(!contract_data.rewards_paused);
+    let slot = ((contract_data.apy_checkpoint_count - 1) % APY_CHECKPOINTS as u64) as usize;
+    let resumed_at = contract_data.apy_checkpoints[slot].ts;
+    assert!(resumed_at >= frozen_since + 3 * day);
+
+    // Only the time outside of the freeze earns interest
+    advance_clock(&mut context, (2 * day) as i64).await;
+    perform_claim_rewards(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let now = context.banks_client.get_sysvar::<Clock>().await.unwrap().unix_timestamp as u64;
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    let earning_seconds = (now - stake_ts) - (resumed_at - frozen_since);
+    assert_eq!(contract_data.total_earned, expected_interest(config.normal_staking_apy, amount, earning_seconds));
+}
+
+#[tokio::test]
+async fn test_claimable_is_zero_during_cooldown() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let day: u64 = 24 * 60 * 60;
+    let config = PoolConfig { claim_cooldown: day, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    let amount = 1000 * unit;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        amount,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, day as i64).await;
+
+    // Past the cooldown everything accrued can be claimed
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    let rewards = simulate_get_claimable(
+        program_id,
+        &user,
+        user_data_account,
+        pool.data_account,
+        pool.token_account,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await;
+    assert_eq!(rewards.gross_interest, expected_interest(config.normal_staking_apy, amount, day));
+    assert_eq!(rewards.claimable, rewards.gross_interest);
+
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    perform_claim_rewards(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await.unwrap();
+    let last_claim_ts = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().last_claim_ts;
+
+    // Mid cooldown interest keeps accruing but nothing is claimable yet
+    advance_clock(&mut context, (day / 2) as i64).await;
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    let rewards = simulate_get_claimable(
+        program_id,
+        &user,
+        user_data_account,
+        pool.data_account,
+        pool.token_account,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await;
+    assert!(rewards.gross_interest > 0);
+    assert_eq!(rewards.claimable, 0);
+    assert_eq!(rewards.claimable_from_ts, last_claim_ts + day);
+
+    // Once the cooldown ended the interest since the last claim is claimable and paid out
+    advance_clock(&mut context, (day / 2) as i64).await;
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    let rewards = simulate_get_claimable(
+        program_id,
+        &user,
+        user_data_account,
+        pool.data_account,
+        pool.token_account,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await;
+    assert_eq!(rewards.gross_interest, expected_interest(config.normal_staking_apy, amount, day));
+    assert_eq!(rewards.claimable, rewards.gross_interest);
+    let total_earned = get_contract_data(&pool.data_account, &mut context.banks_client).await.total_earned;
+    let user_balance = get_token_account_data(&user_token_account, &mut context.banks_client).await.amount;
+    perform_claim_rewards(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await.unwrap();
+    assert_eq!(
+        get_contract_data(&pool.data_account, &mut context.banks_client).await.total_earned,
+        total_earned + rewards.claimable
+    );
+    assert!(get_token_account_data(&user_token_account, &mut context.banks_client).await.amount > user_balance);
+    assert!(get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().last_claim_ts >= last_claim_ts + day);
+}
+
+#[tokio::test]
+async fn test_claimable_matches_what_claim_pays_from_underfunded_vault() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let unit = 10u64.pow(PoolConfig::default().mint_decimals as u32);
+    let config = PoolConfig { reward_amount: 200 * unit, partial_reward_ok: true, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, 10 * 24 * 60 * 60).await;
+
+    // The staked principal is reserved with the 9% unstake pays on top of it
+    let total_staked = get_contract_data(&pool.data_account, &mut context.banks_client).await.total_staked;
+    let vault_balance = get_token_account_data(&pool.token_account, &mut context.banks_client).await.amount;
+    let affordable_interest = vault_balance - total_staked - 9 * total_staked / 100;
+    let rewards = simulate_get_claimable(
+        program_id,
+        &user,
+        user_data_account,
+        pool.data_account,
+        pool.token_account,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+    assert!(rewards.gross_interest > affordable_interest);
+    assert_eq!(rewards.claimable, affordable_interest);
+
+    perform_claim_rewards(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    assert_eq!(get_contract_data(&pool.data_account, &mut context.banks_client).await.total_earned, rewards.claimable);
+}
+
+#[tokio::test]
+async fn test_aliased_accounts_are_rejected() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig::default();
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let duplicate_account = TransactionError::InstructionError(
+        0, InstructionError::Custom(StakingError::DuplicateAccount as u32)
+    );
+
+    // Init with the contract data account also passed as the token account
+    let payer_pubkey = context.payer.pubkey();
+    let mint = Keypair::new();
+    let (data_account, _bump) = pda::contract_data_pda(&payer_pubkey, &mint.pubkey(), &program_id);
+    let transaction = Transaction::new_signed_with_payer(
+        &[init_instruction(&config, payer_pubkey, data_account, mint.pubkey(), program_id, data_account)],
+        Some(&payer_pubkey),
+        &[&context.payer],
+        context.last_blockhash
+    );
+    let err = context.banks_client.process_transaction(transaction).await.unwrap_err().unwrap();
+    assert_eq!(err, duplicate_account);
+
+    // Stake and unstake with the contract data account also passed as the contract token account
+    let err = perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.data_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, duplicate_account);
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        1000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, 24*60*60).await;
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    let err = perform_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        pool.data_account,
+        pool.data_account,
+        pool.mint,
+        &mut context.banks_client,
+        recent_block_hash,
+        pool.decimals
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, duplicate_account);
+    let user_data = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap();
+    assert_eq!(user_data.total_staked, 1000 * unit);
+}
+
+#[tokio::test]
+async fn test_update_apy_above_max_apy_is_rejected() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let max_apy = 100000;
+    let config = PoolConfig { max_apy, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let apy_above_maximum = TransactionError::InstructionError(
+        0, InstructionError::Custom(StakingError::ApyAboveMaximum as u32)
+    );
+
+    // Neither APY can go above the cap, whether applied now or scheduled
+    let err = perform_update_apy(
+        program_id,
+        &context.payer,
+        pool.data_account,
+        config.normal_staking_apy,
+        max_apy + 1,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, apy_above_maximum);
+    let now = context.banks_client.get_sysvar::<Clock>().await.unwrap().unix_timestamp as u64;
+    let err = perform_update_apy(
+        program_id,
+        &context.payer,
+        pool.data_account,
+        max_apy + 1,
+        config.locked_staking_apy,
+        now + 60,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap_err().unwrap();
+    assert_eq!(err, apy_above_maximum);
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!(contract_data.locked_staking_apy, config.locked_staking_apy);
+    assert_eq!(contract_data.max_apy, max_apy);
+
+    perform_update_apy(
+        program_id,
+        &context.payer,
+        pool.data_account,
+        max_apy,
+        max_apy,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let contract_data = get_contract_data(&pool.data_account, &mut context.banks_client).await;
+    assert_eq!((contract_data.normal_staking_apy, contract_data.locked_staking_apy), (max_apy, max_apy));
+
+    // A pool cannot start above its own cap either
+    let config = PoolConfig { max_apy: config.locked_staking_apy - 1, ..PoolConfig::default() };
+    let err = try_set_up_pool(&mut context, program_id, &config).await.err().unwrap().unwrap();
+    assert_eq!(err, TransactionError::InstructionError(3, InstructionError::Custom(StakingError::ApyAboveMaximum as u32)));
+}
+
+#[tokio::test]
+async fn test_earned_checkpoints_read_back_in_order() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { earned_checkpoints_enabled: true, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+
+    perform_stake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        StakeType::NORMAL as u8,
+        2000 * unit,
+        pool.decimals,
+        0,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+
+    // Three claims and the final unstake each record the running total after paying out
+    let mut expected = Vec::new();
+    for _ in 0..3 {
+        advance_clock(&mut context, 24*60*60).await;
+        let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+        perform_checkpoint_claim_rewards(
+            program_id,
+            &user,
+            user_token_account,
+            pool.token_account,
+            user_data_account,
+            pool.data_account,
+            pool.mint,
+            &mut context.banks_client,
+            recent_block_hash
+        ).await.unwrap();
+        let ts = context.banks_client.get_sysvar::<Clock>().await.unwrap().unix_timestamp as u64;
+        let total_earned = get_contract_data(&pool.data_account, &mut context.banks_client).await.total_earned;
+        expected.push(EarnedCheckpoint { ts, total_earned });
+    }
+    advance_clock(&mut context, 24*60*60).await;
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    perform_checkpoint_unstake(
+        program_id,
+        &user,
+        user_token_account,
+        pool.token_account,
+        user_data_account,
+        pool.data_account,
+        pool.mint,
+        0,
+        pool.decimals,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await.unwrap();
+    let ts = context.banks_client.get_sysvar::<Clock>().await.unwrap().unix_timestamp as u64;
+    let total_earned = get_contract_data(&pool.data_account, &mut context.banks_client).await.total_earned;
+    expected.push(EarnedCheckpoint { ts, total_earned });
+    assert!(expected.windows(2).all(|pair| pair[0].ts < pair[1].ts && pair[0].total_earned < pair[1].total_earned));
+
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    let checkpoints = simulate_read_earned_checkpoints(
+        program_id,
+        &user,
+        pool.data_account,
+        0,
+        10,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await;
+    assert_eq!(checkpoints, expected);
+
+    // Pages continue from any checkpoint
+    let page = simulate_read_earned_checkpoints(
+        program_id,
+        &user,
+        pool.data_account,
+        1,
+        2,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await;
+    assert_eq!(page, expected[1..3]);
+}
+
+#[tokio::test]
+async fn test_compounds_and_force_unstakes_are_logged() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let config = PoolConfig { history_enabled: true, earned_checkpoints_enabled: true, ..PoolConfig::default() };
+    let pool = set_up_pool(&mut context, program_id, &config).await;
+    let unit = 10u64.pow(pool.decimals as u32);
+    let (user, user_token_account, user_data_account) = set_up_staker(&mut context, &pool, 5000 * unit).await;
+    let day: u64 = 24*60*60;
+    let start_ts = context.banks_client.get_sysvar::<Clock>().await.unwrap().unix_timestamp as u64;
+
+    perform_history_stake(
+        program_id, &user, user_token_account, pool.token_account, user_data_account, pool.data_account,
+        pool.mint, 1000 * unit, pool.decimals, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    advance_clock(&mut context, day as i64).await;
+    perform_checkpoint_claim_and_stake(
+        program_id, &user, user_data_account, pool.data_account, pool.token_account, &mut context.banks_client, context.last_blockhash
+    ).await.unwrap();
+    let compounded = get_contract_data(&pool.data_account, &mut context.banks_client).await.total_earned;
+    assert!(compounded > 0);
+    let total_staked = get_user_data(&user_data_account, &mut context.banks_client).await.unwrap().total_staked;
+
+    advance_clock(&mut context, day as i64).await;
+    perform_logged_force_unstake(
+        program_id,
+        &context.payer,
+        pool.data_account,
+        user_data_account,
+        user.pubkey(),
+        user_token_account,
+        pool.token_account,
+        pool.mint,
+        pool.decimals,
+        true,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await.unwrap();
+    let total_earned = get_contract_data(&pool.data_account, &mut context.banks_client).await.total_earned;
+    assert!(total_earned > compounded);
+
+    let checkpoints = simulate_read_earned_checkpoints(
+        program_id, &user, pool.data_account, 0, 10, &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(checkpoints, vec![
+        EarnedCheckpoint { ts: start_ts + day, total_earned: compounded },
+        EarnedCheckpoint { ts: start_ts + 2 * day, total_earned }
+    ]);
+    let records = simulate_read_history(
+        program_id, &context.payer, pool.data_account, 0, 10, &mut context.banks_client, context.last_blockhash
+    ).await;
+    assert_eq!(records, vec![
+        HistoryRecord { ts: start_ts, user: user.pubkey(), amount: 1000 * unit, action: HistoryAction::Stake },
+        HistoryRecord { ts: start_ts + 2 * day, user: user.pubkey(), amount: total_staked, action: HistoryAction::Unstake }
+    ]);
+}
@@ -1,5 +1,10 @@
+use solana_program::account_info::AccountInfo;
+use solana_program::clock::Clock;
+use solana_program::entrypoint::ProgramResult;
 use solana_program::hash::Hash;
+use solana_program::program::invoke_signed;
 use solana_program::instruction::{AccountMeta, Instruction};
+use solana_program::native_token::LAMPORTS_PER_SOL;
 use solana_program_test::*;
 use solana_sdk::{
     pubkey::Pubkey,
@@ -12,13 +17,18 @@ use solana_program::program_error::ProgramError;
 use solana_program::rent::Rent;
 use solana_program::sysvar::rent;
 use spl_token_2022::state::{Account as TokenAccount, Mint};
-use spl_token_2022::extension::ExtensionType;
-use spl_staking::state::{ContractData, UserData};
+use spl_token_2022::extension::{ExtensionType, StateWithExtensionsMut};
+use spl_token_group_interface::state::TokenGroupMember;
+use solana_sdk::account::Account;
+use borsh::BorshDeserialize;
+use spl_staking::instruction::INSTRUCTION_VERSION;
+use spl_staking::pda;
+use spl_staking::state::{ClaimableRewards, ConfigSnapshot, ContractData, EarnedCheckpoint, HistoryRecord, InterestRounding, PoolApr, PoolCoverage, PoolLiabilities, PositionSnapshot, RewardPreview, StakeValidation, UnstakeReceipt, UserData, UserIndex};
 
 
 pub async fn get_user_data(pubkey: &Pubkey, banks_client: &mut BanksClient) -> Result<UserData, ProgramError> {
     let user_account = banks_client
-        .get_account(pubkey.clone())
+        .get_account(*pubkey)
         .await
         .expect("get_account");
     match user_account {
@@ -30,7 +40,7 @@ pub async fn get_user_data(pubkey: &Pubkey, banks_client: &mut BanksClient) -> R
 
 pub async fn get_contract_data(pubkey: &Pubkey, banks_client: &mut BanksClient) -> ContractData {
     let contract_account = banks_client
-        .get_account(pubkey.clone())
+        .get_account(*pubkey)
         .await
         .expect("get_account")
         .expect("contract pda data account not found");
@@ -41,7 +51,7 @@ pub async fn get_contract_data(pubkey: &Pubkey, banks_client: &mut BanksClient)
 
 pub async fn get_token_account_data(pubkey: &Pubkey, banks_client: & mut BanksClient) -> TokenAccount {
     let token_account = banks_client
-        .get_account(pubkey.clone())
+        .get_account(*pubkey)
         .await
         .expect("get_account")
         .expect("token account not found");
@@ -72,6 +82,7 @@ pub async fn transfer_sol(
     banks_client.process_transaction(txn).await.unwrap();
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn set_up_mint(
     payer: &Keypair,
     mint: &Keypair,
@@ -106,7 +117,7 @@ pub async fn set_up_mint(
                 &spl_token_2022::ID,
                 &mint.pubkey(),
                 &payer.pubkey(),
-                None,
+                Some(&payer.pubkey()),
                 mint_decimals as u8
             ).unwrap()
         ],
@@ -143,15 +154,58 @@ pub fn get_create_and_init_token_account_ix(
     )
 }
 
+pub fn init_instruction(
+    config: &PoolConfig,
+    payer_pubkey: Pubkey,
+    token_acct_pubkey: Pubkey,
+    mint_pubkey: Pubkey,
+    program_id: Pubkey,
+    data_acct_pda: Pubkey
+) -> Instruction {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 0];
+    instruction_data.extend(config.minimum_stake_amount.to_le_bytes().iter());
+    instruction_data.extend(config.minimum_lock_duration.to_le_bytes().iter());
+    instruction_data.extend(config.normal_staking_apy.to_le_bytes().iter());
+    instruction_data.extend(config.locked_staking_apy.to_le_bytes().iter());
+    instruction_data.extend(config.early_withdrawal_fee.to_le_bytes().iter());
+    instruction_data.extend(config.fee_basis_points.to_le_bytes().iter());
+    instruction_data.extend(config.max_fee.to_le_bytes().iter());
+    instruction_data.extend(config.max_lock_duration.to_le_bytes().iter());
+    instruction_data.extend(config.max_reward_per_position.to_le_bytes().iter());
+    instruction_data.extend(config.lock_grace_period.to_le_bytes().iter());
+    instruction_data.extend(config.deposit_fee_bps.to_le_bytes().iter());
+    instruction_data.extend(config.gate_collection.as_ref());
+    instruction_data.extend(config.max_boost_bps.to_le_bytes().iter());
+    instruction_data.extend(config.claim_cooldown.to_le_bytes().iter());
+    instruction_data.extend(config.accrual_period.to_le_bytes().iter());
+    instruction_data.push(config.partial_reward_ok as u8);
+    instruction_data.push(config.history_enabled as u8);
+    instruction_data.extend(config.min_restake_interval.to_le_bytes().iter());
+    instruction_data.extend(config.penalty_burn_bps.to_le_bytes().iter());
+    instruction_data.extend(config.delegate_authority.as_ref());
+    instruction_data.push(config.interest_rounding as u8);
+    instruction_data.extend(config.max_stakers.to_le_bytes().iter());
+    instruction_data.extend(config.compound_cooldown.to_le_bytes().iter());
+    instruction_data.extend(config.reward_per_second.to_le_bytes().iter());
+    instruction_data.extend(config.min_initial_rewards.to_le_bytes().iter());
+    instruction_data.extend(config.max_apy.to_le_bytes().iter());
+    instruction_data.push(config.earned_checkpoints_enabled as u8);
+    Instruction::new_with_bytes(
+        program_id,
+        &instruction_data,
+        vec![
+            AccountMeta::new(payer_pubkey, true),
+            AccountMeta::new(data_acct_pda, false),
+            AccountMeta::new(token_acct_pubkey, false),
+            AccountMeta::new_readonly(mint_pubkey, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new_readonly(rent::ID, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ],
+    )
+}
 pub fn construct_init_txn(
-    minimum_stake_amount: u64,
-    minimum_lock_duration: u64,
-    normal_staking_apy: u64,
-    locked_staking_apy: u64,
-    mint_amount: u64,
-    early_withdrawal_fee: u64,
-    fee_basis_points: u64,
-    max_fee: u64,
+    config: &PoolConfig,
     payer_pubkey: Pubkey,
     token_acct_pubkey: Pubkey,
     rent: Rent,
@@ -159,50 +213,50 @@ pub fn construct_init_txn(
     program_id: Pubkey,
     data_acct_pda: Pubkey
 ) -> Transaction {
-    let mut instruction_data = vec![0];
-    instruction_data.extend(minimum_stake_amount.to_le_bytes().iter());
-    instruction_data.extend(minimum_lock_duration.to_le_bytes().iter());
-    instruction_data.extend(normal_staking_apy.to_le_bytes().iter());
-    instruction_data.extend(locked_staking_apy.to_le_bytes().iter());
-    instruction_data.extend(early_withdrawal_fee.to_le_bytes().iter());
-    instruction_data.extend(fee_basis_points.to_le_bytes().iter());
-    instruction_data.extend(max_fee.to_le_bytes().iter());
-    let (create_ix, init_ix) = get_create_and_init_token_account_ix(
-        payer_pubkey.clone(),
-        token_acct_pubkey.clone(),
-        rent.clone(),
-        mint_pubkey.clone()
-    );
     Transaction::new_with_payer(
-        &[
-            create_ix,
-            init_ix,
-            spl_token_2022::instruction::mint_to(
-                &spl_token_2022::ID,
-                &mint_pubkey,
-                &token_acct_pubkey,
-                &payer_pubkey,
-                &[],
-                mint_amount
-            ).unwrap(),
-            Instruction::new_with_bytes(
-                program_id,
-                &instruction_data,
-                vec![
-                    AccountMeta::new(payer_pubkey, true),
-                    AccountMeta::new(data_acct_pda, false),
-                    AccountMeta::new(token_acct_pubkey, false),
-                    AccountMeta::new_readonly(mint_pubkey, false),
-                    AccountMeta::new_readonly(spl_token_2022::ID, false),
-                    AccountMeta::new_readonly(rent::ID, false),
-                    AccountMeta::new_readonly(system_program::ID, false),
-                ],
-            )
-        ],
+        &init_instructions(config, payer_pubkey, token_acct_pubkey, rent, mint_pubkey, program_id, data_acct_pda),
         Some(&payer_pubkey),
     )
 }
+/// Creates and funds the contract token account, followed by the init instruction
+pub fn init_instructions(
+    config: &PoolConfig,
+    payer_pubkey: Pubkey,
+    token_acct_pubkey: Pubkey,
+    rent: Rent,
+    mint_pubkey: Pubkey,
+    program_id: Pubkey,
+    data_acct_pda: Pubkey
+) -> Vec<Instruction> {
+    let (create_ix, init_ix) = get_create_and_init_token_account_ix(
+        payer_pubkey,
+        token_acct_pubkey,
+        rent,
+        mint_pubkey
+    );
+    vec![
+        create_ix,
+        init_ix,
+        spl_token_2022::instruction::mint_to(
+            &spl_token_2022::ID,
+            &mint_pubkey,
+            &token_acct_pubkey,
+            &payer_pubkey,
+            &[],
+            config.reward_amount
+        ).unwrap(),
+        init_instruction(
+            config,
+            payer_pubkey,
+            token_acct_pubkey,
+            mint_pubkey,
+            program_id,
+            data_acct_pda
+        )
+    ]
+}
 
+#[allow(clippy::too_many_arguments)]
 pub async fn set_up_token_account(
     payer: &Keypair,
     token_account_keypair: &Keypair,
@@ -245,30 +299,28 @@ pub async fn set_up_token_account(
         recent_block_hash
     );
     banks_client.process_transaction(txn).await.unwrap();
-    match owner {
-        Some(pk) => {
-            let change_owner_ix = spl_token_2022::instruction::set_authority(
-                &spl_token_2022::ID,
-                &token_account_keypair.pubkey(),
-                Some(&pk),
-                spl_token_2022::instruction::AuthorityType::AccountOwner,
-                &payer.pubkey(),
-                &[&payer.pubkey()]
-            ).unwrap();
-            let txn = Transaction::new_signed_with_payer(
-                &[
-                    change_owner_ix
-                ],
-                Some(&payer.pubkey()),
-                &[&payer],
-                recent_block_hash
-            );
-            banks_client.process_transaction(txn).await.unwrap();
-        },
-        None => {}
+    if let Some(pk) = owner {
+        let change_owner_ix = spl_token_2022::instruction::set_authority(
+            &spl_token_2022::ID,
+            &token_account_keypair.pubkey(),
+            Some(&pk),
+            spl_token_2022::instruction::AuthorityType::AccountOwner,
+            &payer.pubkey(),
+            &[&payer.pubkey()]
+        ).unwrap();
+        let txn = Transaction::new_signed_with_payer(
+            &[
+                change_owner_ix
+            ],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_block_hash
+        );
+        banks_client.process_transaction(txn).await.unwrap();
     };
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn perform_stake(
     program_id: Pubkey,
     payer: &Keypair,
@@ -283,34 +335,140 @@ pub async fn perform_stake(
     lock_duration: u64,
     banks_client: & mut BanksClient,
     recent_block_hash: Hash
-) {
-    let mut instruction_data = vec![1, stake_type];
-    instruction_data.extend(amount.to_le_bytes().iter());
-    instruction_data.extend(decimals.to_le_bytes().iter());
-    instruction_data.extend(lock_duration.to_le_bytes().iter());
+) -> Result<(), BanksClientError> {
+    perform_stake_wrapping(
+        program_id,
+        payer,
+        user_tkn_acct_pk,
+        contract_tkn_acct_pk,
+        user_data_acct_pk,
+        contract_data_acct_pk,
+        mint,
+        stake_type,
+        amount,
+        decimals,
+        lock_duration,
+        false,
+        banks_client,
+        recent_block_hash
+    ).await
+}
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_stake_wrapping(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    mint: Pubkey,
+    stake_type: u8,
+    amount: u64,
+    decimals: u64,
+    lock_duration: u64,
+    wrap_native: bool,
+    banks_client: & mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
     let mut stake_txn = Transaction::new_with_payer(
         &[
-            Instruction::new_with_bytes(
+            stake_instruction(
                 program_id,
-                &instruction_data,
-                vec![
-                    AccountMeta::new(payer.pubkey(), true),
-                    AccountMeta::new(user_tkn_acct_pk, false),
-                    AccountMeta::new(user_data_acct_pk, false),
-                    AccountMeta::new(contract_tkn_acct_pk, false),
-                    AccountMeta::new(contract_data_acct_pk, false),
-                    AccountMeta::new_readonly(mint, false),
-                    AccountMeta::new_readonly(spl_token_2022::ID, false),
-                    AccountMeta::new_readonly(system_program::ID, false)
-                ]
+                payer.pubkey(),
+                user_tkn_acct_pk,
+                contract_tkn_acct_pk,
+                user_data_acct_pk,
+                contract_data_acct_pk,
+                mint,
+                stake_type,
+                amount,
+                decimals,
+                lock_duration,
+                wrap_native
             )
         ],
         Some(&payer.pubkey())
     );
     stake_txn.sign(&[&payer], recent_block_hash);
-    banks_client.process_transaction(stake_txn).await.unwrap();
+    banks_client.process_transaction(stake_txn).await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn stake_instruction(
+    program_id: Pubkey,
+    payer_pubkey: Pubkey,
+    user_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    mint: Pubkey,
+    stake_type: u8,
+    amount: u64,
+    decimals: u64,
+    lock_duration: u64,
+    wrap_native: bool
+) -> Instruction {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 1, stake_type];
+    instruction_data.extend(amount.to_le_bytes().iter());
+    instruction_data.extend(decimals.to_le_bytes().iter());
+    instruction_data.extend(lock_duration.to_le_bytes().iter());
+    instruction_data.push(wrap_native as u8);
+    Instruction::new_with_bytes(
+        program_id,
+        &instruction_data,
+        vec![
+            AccountMeta::new(payer_pubkey, true),
+            AccountMeta::new(user_tkn_acct_pk, false),
+            AccountMeta::new(user_data_acct_pk, false),
+            AccountMeta::new(contract_tkn_acct_pk, false),
+            AccountMeta::new(contract_data_acct_pk, false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new(user_index_pda(&payer_pubkey, &program_id), false)
+        ]
+    )
+}
+
+/// Opens a position with `StakeWithSetup`, creating the user data account of `payer` when missing
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_stake_with_setup(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    mint: Pubkey,
+    stake_type: u8,
+    amount: u64,
+    decimals: u64,
+    lock_duration: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    // Same layout and accounts as Stake under another tag
+    let mut instruction = stake_instruction(
+        program_id,
+        payer.pubkey(),
+        user_tkn_acct_pk,
+        contract_tkn_acct_pk,
+        user_data_acct_pk,
+        contract_data_acct_pk,
+        mint,
+        stake_type,
+        amount,
+        decimals,
+        lock_duration,
+        false
+    );
+    instruction.data[1] = 34;
+    let mut txn = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+    txn.sign(&[&payer], recent_block_hash);
+    banks_client.process_transaction(txn).await
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn perform_unstake(
     program_id: Pubkey,
     payer: &Keypair,
@@ -322,43 +480,171 @@ pub async fn perform_unstake(
     banks_client: & mut BanksClient,
     recent_block_hash: Hash,
     decimals: u64
-) {
-    let mut instruction_data = vec![2];
-    instruction_data.extend(decimals.to_le_bytes().iter());
+) -> Result<(), BanksClientError> {
+    perform_unstake_to(
+        program_id,
+        payer,
+        user_tkn_acct_pk,
+        contract_tkn_acct_pk,
+        user_data_acct_pk,
+        contract_data_acct_pk,
+        mint,
+        None,
+        false,
+        banks_client,
+        recent_block_hash,
+        decimals
+    ).await
+}
+
+/// Unstakes with an optional destination token account override
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_unstake_to(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    mint: Pubkey,
+    destination_tkn_acct_pk: Option<Pubkey>,
+    unwrap_native: bool,
+    banks_client: & mut BanksClient,
+    recent_block_hash: Hash,
+    decimals: u64
+) -> Result<(), BanksClientError> {
     let mut unstake_txn = Transaction::new_with_payer(
         &[
-            Instruction::new_with_bytes(
+            unstake_instruction(
                 program_id,
-                &instruction_data,
-                vec![
-                    AccountMeta::new(payer.pubkey(), true),
-                    AccountMeta::new(user_tkn_acct_pk, false),
-                    AccountMeta::new(user_data_acct_pk, false),
-                    AccountMeta::new(contract_tkn_acct_pk, false),
-                    AccountMeta::new(contract_data_acct_pk, false),
-                    AccountMeta::new_readonly(mint, false),
-                    AccountMeta::new_readonly(spl_token_2022::ID, false)
-                ]
+                payer.pubkey(),
+                user_tkn_acct_pk,
+                contract_tkn_acct_pk,
+                user_data_acct_pk,
+                contract_data_acct_pk,
+                mint,
+                destination_tkn_acct_pk,
+                unwrap_native,
+                decimals
             )
         ],
         Some(&payer.pubkey())
     );
     unstake_txn.sign(&[&payer], recent_block_hash);
-    banks_client.process_transaction(unstake_txn).await.unwrap();
+    banks_client.process_transaction(unstake_txn).await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn unstake_instruction(
+    program_id: Pubkey,
+    payer_pubkey: Pubkey,
+    user_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    mint: Pubkey,
+    destination_tkn_acct_pk: Option<Pubkey>,
+    unwrap_native: bool,
+    decimals: u64
+) -> Instruction {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 2];
+    instruction_data.extend(decimals.to_le_bytes().iter());
+    instruction_data.push(unwrap_native as u8);
+    let mut accounts = vec![
+        AccountMeta::new(payer_pubkey, true),
+        AccountMeta::new(user_tkn_acct_pk, false),
+        AccountMeta::new(user_data_acct_pk, false),
+        AccountMeta::new(contract_tkn_acct_pk, false),
+        AccountMeta::new(contract_data_acct_pk, false),
+        AccountMeta::new(mint, false),
+        AccountMeta::new_readonly(spl_token_2022::ID, false),
+        AccountMeta::new(user_index_pda(&payer_pubkey, &program_id), false)
+    ];
+    if let Some(destination) = destination_tkn_acct_pk {
+        accounts.push(AccountMeta::new(destination, false));
+    }
+    Instruction::new_with_bytes(program_id, &instruction_data, accounts)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_partial_unstake(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    mint: Pubkey,
+    amount: u64,
+    banks_client: & mut BanksClient,
+    recent_block_hash: Hash,
+    decimals: u64
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 2];
+    instruction_data.extend(decimals.to_le_bytes().iter());
+    instruction_data.push(0);
+    instruction_data.extend(amount.to_le_bytes().iter());
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(user_tkn_acct_pk, false),
+            AccountMeta::new(user_data_acct_pk, false),
+            AccountMeta::new(contract_tkn_acct_pk, false),
+            AccountMeta::new(contract_data_acct_pk, false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new(user_index_pda(&payer.pubkey(), &program_id), false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_convert_stake_type(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    new_type: u8,
+    lock_duration: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 11, new_type];
+    instruction_data.extend(lock_duration.to_le_bytes().iter());
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new_readonly(payer.pubkey(), true),
+            AccountMeta::new(user_data_acct_pk, false),
+            AccountMeta::new(contract_data_acct_pk, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn perform_update_apy(
     program_id: Pubkey,
     payer: &Keypair,
     contract_data_account: Pubkey,
     normal_staking_apy: u64,
     locked_staking_apy: u64,
+    apy_effective_ts: u64,
     banks_client: &mut BanksClient,
     recent_block_hash: Hash
-) {
-    let mut instruction_data = vec![3];
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 3];
     instruction_data.extend(normal_staking_apy.to_le_bytes().iter());
     instruction_data.extend(locked_staking_apy.to_le_bytes().iter());
+    instruction_data.extend(apy_effective_ts.to_le_bytes().iter());
 
     let mut txn = Transaction::new_with_payer(
         &[
@@ -374,5 +660,1677 @@ pub async fn perform_update_apy(
         Some(&payer.pubkey())
     );
     txn.sign(&[&payer], recent_block_hash);
-    banks_client.process_transaction(txn).await.unwrap();
-}
\ No newline at end of file
+    banks_client.process_transaction(txn).await
+}
+/// Init parameters for a pool set up through `set_up_pool`
+pub struct PoolConfig {
+    pub mint_decimals: u64,
+    pub minimum_stake_amount: u64,
+    pub minimum_lock_duration: u64,
+    pub normal_staking_apy: u64,
+    pub locked_staking_apy: u64,
+    pub early_withdrawal_fee: u64,
+    pub fee_basis_points: u64,
+    pub max_fee: u64,
+    pub max_lock_duration: u64,
+    pub max_reward_per_position: u64,
+    pub lock_grace_period: u64,
+    pub deposit_fee_bps: u64,
+    pub gate_collection: Pubkey,
+    pub max_boost_bps: u64,
+    pub claim_cooldown: u64,
+    pub accrual_period: u64,
+    pub partial_reward_ok: bool,
+    pub history_enabled: bool,
+    pub min_restake_interval: u64,
+    pub penalty_burn_bps: u64,
+    pub delegate_authority: Pubkey,
+    pub interest_rounding: InterestRounding,
+    pub max_stakers: u64,
+    pub compound_cooldown: u64,
+    pub reward_per_second: u64,
+    pub min_initial_rewards: u64,
+    pub max_apy: u64,
+    pub earned_checkpoints_enabled: bool,
+    pub reward_amount: u64
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        let mint_decimals = 9_u64;
+        PoolConfig {
+            mint_decimals,
+            minimum_stake_amount: 100 * 10u64.pow(mint_decimals as u32),
+            minimum_lock_duration: 100,
+            normal_staking_apy: 26390,
+            locked_staking_apy: 60570,
+            early_withdrawal_fee: 100,
+            fee_basis_points: 800,
+            max_fee: 9536743164 * 10u64.pow(mint_decimals as u32),
+            max_lock_duration: 0,
+            max_reward_per_position: 0,
+            lock_grace_period: 0,
+            deposit_fee_bps: 0,
+            gate_collection: Pubkey::default(),
+            max_boost_bps: 0,
+            claim_cooldown: 0,
+            accrual_period: 0,
+            partial_reward_ok: false,
+            history_enabled: false,
+            min_restake_interval: 0,
+            penalty_burn_bps: 0,
+            delegate_authority: Pubkey::default(),
+            interest_rounding: InterestRounding::FLOOR,
+            max_stakers: 0,
+            compound_cooldown: 0,
+            reward_per_second: 0,
+            min_initial_rewards: 0,
+            max_apy: 0,
+            earned_checkpoints_enabled: false,
+            reward_amount: 10000 * 10u64.pow(mint_decimals as u32)
+        }
+    }
+}
+
+/// Addresses of an initialized pool
+pub struct Pool {
+    pub program_id: Pubkey,
+    pub mint: Pubkey,
+    pub data_account: Pubkey,
+    pub token_account: Pubkey,
+    pub decimals: u64
+}
+
+pub fn program_test(program_id: Pubkey) -> ProgramTest {
+    ProgramTest::new(
+        "spl_staking",
+        program_id,
+        processor!(spl_staking::entrypoint::process_instruction),
+    )
+}
+
+pub async fn set_up_pool(
+    context: &mut ProgramTestContext,
+    program_id: Pubkey,
+    config: &PoolConfig
+) -> Pool {
+    try_set_up_pool(context, program_id, config).await.unwrap()
+}
+
+/// Like `set_up_pool`, returning the error when the init instruction fails
+pub async fn try_set_up_pool(
+    context: &mut ProgramTestContext,
+    program_id: Pubkey,
+    config: &PoolConfig
+) -> Result<Pool, BanksClientError> {
+    let rent = Rent::default();
+    let token_mint = Keypair::new();
+    let token_account = Keypair::new();
+    let payer_pubkey = context.payer.pubkey();
+    let (data_account, _bump) = pda::contract_data_pda(&payer_pubkey, &token_mint.pubkey(), &program_id);
+    set_up_mint(
+        &context.payer,
+        &token_mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        rent,
+        config.mint_decimals,
+        config.fee_basis_points,
+        config.max_fee
+    ).await;
+    let mut transaction = construct_init_txn(
+        config,
+        payer_pubkey,
+        token_account.pubkey(),
+        rent,
+        token_mint.pubkey(),
+        program_id,
+        data_account
+    );
+    transaction.sign(&[&context.payer, &token_account], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await?;
+    Ok(Pool {
+        program_id,
+        mint: token_mint.pubkey(),
+        data_account,
+        token_account: token_account.pubkey(),
+        decimals: config.mint_decimals
+    })
+}
+
+/// Creates a funded wallet holding `amount` pool tokens.
+/// Returns the wallet, its token account and its user data PDA
+pub async fn set_up_staker(
+    context: &mut ProgramTestContext,
+    pool: &Pool,
+    amount: u64
+) -> (Keypair, Pubkey, Pubkey) {
+    let user = Keypair::new();
+    let (user_token_account, user_data_account) = fund_staker(context, pool, &user, amount).await;
+    (user, user_token_account, user_data_account)
+}
+
+/// Funds `user` with SOL and a token account holding `amount` pool tokens, and creates its user data account.
+/// Returns the token account and the user data PDA
+pub async fn fund_staker(
+    context: &mut ProgramTestContext,
+    pool: &Pool,
+    user: &Keypair,
+    amount: u64
+) -> (Pubkey, Pubkey) {
+    let user_token_account = Keypair::new();
+    // The same funding transfer may already have gone out to `user` under the last blockhash
+    let recent_block_hash = context.get_new_latest_blockhash().await.unwrap();
+    transfer_sol(
+        &context.payer,
+        user.pubkey(),
+        10 * LAMPORTS_PER_SOL,
+        &mut context.banks_client,
+        recent_block_hash
+    ).await;
+    set_up_token_account(
+        &context.payer,
+        &user_token_account,
+        Some(user.pubkey()),
+        Rent::default(),
+        pool.mint,
+        amount,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+    let user_data_account = user_data_pda(&user.pubkey(), &pool.data_account, &pool.program_id);
+    // Tests may have placed an account at the PDA already
+    if context.banks_client.get_account(user_data_account).await.unwrap().is_none() {
+        perform_create_user_data(
+            pool.program_id,
+            &context.payer,
+            user.pubkey(),
+            pool.data_account,
+            &mut context.banks_client,
+            context.last_blockhash
+        ).await.unwrap();
+    }
+    (user_token_account.pubkey(), user_data_account)
+}
+
+/// Creates the user data account of `owner` in the pool at `contract_data_acct_pk`, paid for by `payer`
+pub async fn perform_create_user_data(
+    program_id: Pubkey,
+    payer: &Keypair,
+    owner: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 21],
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new_readonly(owner, false),
+            AccountMeta::new(user_data_pda(&owner, &contract_data_acct_pk, &program_id), false),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new_readonly(contract_data_acct_pk, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+pub fn user_data_pda(user: &Pubkey, contract_data: &Pubkey, program_id: &Pubkey) -> Pubkey {
+    pda::user_data_pda(user, contract_data, program_id).0
+}
+
+pub fn user_index_pda(user: &Pubkey, program_id: &Pubkey) -> Pubkey {
+    pda::user_index_pda(user, program_id).0
+}
+
+pub fn history_log_pda(contract_data: &Pubkey, program_id: &Pubkey) -> Pubkey {
+    pda::history_log_pda(contract_data, program_id).0
+}
+
+pub fn earned_log_pda(contract_data: &Pubkey, program_id: &Pubkey) -> Pubkey {
+    pda::earned_log_pda(contract_data, program_id).0
+}
+
+/// Delegate authority PDA of the mock delegate program
+pub fn delegate_pda(delegate_program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"delegate"], delegate_program_id)
+}
+
+/// Mock vault program: forwards its instruction data to the program passed as the first account,
+/// signing with its delegate PDA on behalf of the owners it controls
+pub fn process_delegate_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8]
+) -> ProgramResult {
+    let (target_program, forwarded) = accounts.split_first().unwrap();
+    let (delegate, bump) = delegate_pda(program_id);
+    let instruction = Instruction {
+        program_id: *target_program.key,
+        accounts: forwarded
+            .iter()
+            .map(|account| AccountMeta {
+                pubkey: *account.key,
+                is_signer: account.is_signer || *account.key == delegate,
+                is_writable: account.is_writable
+            })
+            .collect(),
+        data: instruction_data.to_vec()
+    };
+    invoke_signed(&instruction, accounts, &[&[b"delegate", &[bump]]])
+}
+
+pub async fn advance_clock(context: &mut ProgramTestContext, seconds: i64) {
+    let mut clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+    clock.unix_timestamp += seconds;
+    context.set_sysvar(&clock);
+}
+
+/// Moves the clock `epochs` epochs forward, e.g. for a Token 2022 transfer fee change to take effect
+pub async fn advance_epoch(context: &mut ProgramTestContext, epochs: u64) {
+    let mut clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+    clock.epoch += epochs;
+    context.set_sysvar(&clock);
+}
+
+/// Changes the transfer fee of a pool mint, the context payer being its transfer fee config authority
+pub async fn perform_set_transfer_fee(
+    context: &mut ProgramTestContext,
+    mint: Pubkey,
+    fee_basis_points: u16,
+    max_fee: u64
+) {
+    let txn = Transaction::new_signed_with_payer(
+        &[
+            spl_token_2022::extension::transfer_fee::instruction::set_transfer_fee(
+                &spl_token_2022::ID,
+                &mint,
+                &context.payer.pubkey(),
+                &[],
+                fee_basis_points,
+                max_fee
+            ).unwrap()
+        ],
+        Some(&context.payer.pubkey()),
+        &[&context.payer],
+        context.last_blockhash
+    );
+    context.banks_client.process_transaction(txn).await.unwrap();
+}
+
+/// Writes an NFT mint that is a member of `collection` and a token account of `owner` holding it.
+/// Returns the token account and the mint
+pub fn set_up_gate_nft(context: &mut ProgramTestContext, collection: Pubkey, owner: Pubkey) -> (Pubkey, Pubkey) {
+    let rent = Rent::default();
+    let mint = Pubkey::new_unique();
+    let mint_len = ExtensionType::try_calculate_account_len::<Mint>(&[ExtensionType::TokenGroupMember]).unwrap();
+    let mut mint_data = vec![0; mint_len];
+    let mut mint_state = StateWithExtensionsMut::<Mint>::unpack_uninitialized(&mut mint_data).unwrap();
+    *mint_state.init_extension::<TokenGroupMember>(true).unwrap() = TokenGroupMember::new(&mint, &collection, 1);
+    mint_state.base = Mint { supply: 1, decimals: 0, is_initialized: true, ..Mint::default() };
+    mint_state.pack_base();
+    mint_state.init_account_type().unwrap();
+
+    let token_account = Pubkey::new_unique();
+    let mut token_account_data = vec![0; TokenAccount::LEN];
+    TokenAccount {
+        mint,
+        owner,
+        amount: 1,
+        state: spl_token_2022::state::AccountState::Initialized,
+        ..TokenAccount::default()
+    }.pack_into_slice(&mut token_account_data);
+
+    for (pubkey, data) in [(mint, mint_data), (token_account, token_account_data)] {
+        context.set_account(&pubkey, &Account {
+            lamports: rent.minimum_balance(data.len()),
+            data,
+            owner: spl_token_2022::ID,
+            executable: false,
+            rent_epoch: 0
+        }.into());
+    }
+    (token_account, mint)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_gated_stake(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    mint: Pubkey,
+    stake_type: u8,
+    amount: u64,
+    decimals: u64,
+    lock_duration: u64,
+    gate_token_acct_pk: Pubkey,
+    gate_mint: Pubkey,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 1, stake_type];
+    instruction_data.extend(amount.to_le_bytes().iter());
+    instruction_data.extend(decimals.to_le_bytes().iter());
+    instruction_data.extend(lock_duration.to_le_bytes().iter());
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(user_tkn_acct_pk, false),
+            AccountMeta::new(user_data_acct_pk, false),
+            AccountMeta::new(contract_tkn_acct_pk, false),
+            AccountMeta::new(contract_data_acct_pk, false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new(user_index_pda(&payer.pubkey(), &program_id), false),
+            AccountMeta::new_readonly(gate_token_acct_pk, false),
+            AccountMeta::new_readonly(gate_mint, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+/// Stakes into a pool keeping a history, passing the history log after the stake accounts
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_history_stake(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    mint: Pubkey,
+    amount: u64,
+    decimals: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 1, 0];
+    instruction_data.extend(amount.to_le_bytes().iter());
+    instruction_data.extend(decimals.to_le_bytes().iter());
+    instruction_data.extend(0u64.to_le_bytes().iter());
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(user_tkn_acct_pk, false),
+            AccountMeta::new(user_data_acct_pk, false),
+            AccountMeta::new(contract_tkn_acct_pk, false),
+            AccountMeta::new(contract_data_acct_pk, false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new(user_index_pda(&payer.pubkey(), &program_id), false),
+            AccountMeta::new(history_log_pda(&contract_data_acct_pk, &program_id), false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+/// Unstakes `amount` (0 = everything) from a pool keeping a history,
+/// passing the user token account as destination ahead of the history accounts
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_history_unstake(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    mint: Pubkey,
+    amount: u64,
+    decimals: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 2];
+    instruction_data.extend(decimals.to_le_bytes().iter());
+    instruction_data.push(0);
+    instruction_data.extend(amount.to_le_bytes().iter());
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(user_tkn_acct_pk, false),
+            AccountMeta::new(user_data_acct_pk, false),
+            AccountMeta::new(contract_tkn_acct_pk, false),
+            AccountMeta::new(contract_data_acct_pk, false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new(user_index_pda(&payer.pubkey(), &program_id), false),
+            AccountMeta::new(user_tkn_acct_pk, false),
+            AccountMeta::new(history_log_pda(&contract_data_acct_pk, &program_id), false),
+            AccountMeta::new_readonly(system_program::ID, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+pub async fn perform_set_boost(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    user_data_account: Pubkey,
+    boost_bps: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 12];
+    instruction_data.extend(boost_bps.to_le_bytes().iter());
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new_readonly(payer.pubkey(), true),
+            AccountMeta::new_readonly(contract_data_account, false),
+            AccountMeta::new(user_data_account, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+pub async fn perform_set_fee_exempt(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    user_data_account: Pubkey,
+    is_fee_exempt: bool,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 26, is_fee_exempt as u8],
+        vec![
+            AccountMeta::new_readonly(payer.pubkey(), true),
+            AccountMeta::new_readonly(contract_data_account, false),
+            AccountMeta::new(user_data_account, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_set_promo_boost(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    start_ts: u64,
+    end_ts: u64,
+    multiplier_bps: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 31];
+    instruction_data.extend(start_ts.to_le_bytes().iter());
+    instruction_data.extend(end_ts.to_le_bytes().iter());
+    instruction_data.extend(multiplier_bps.to_le_bytes().iter());
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new_readonly(payer.pubkey(), true),
+            AccountMeta::new(contract_data_account, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+pub async fn perform_set_operator(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    operator: Pubkey,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 33];
+    instruction_data.extend(operator.as_ref());
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(contract_data_account, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+pub async fn perform_waive_penalty(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    user_data_account: Pubkey,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 30],
+        vec![
+            AccountMeta::new_readonly(payer.pubkey(), true),
+            AccountMeta::new_readonly(contract_data_account, false),
+            AccountMeta::new(user_data_account, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+pub async fn perform_repair_totals(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    user_data_accounts: &[Pubkey],
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(payer.pubkey(), true),
+        AccountMeta::new(contract_data_account, false)
+    ];
+    accounts.extend(user_data_accounts.iter().map(|user_data_account| AccountMeta::new_readonly(*user_data_account, false)));
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 27],
+        accounts,
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+pub async fn perform_extend_lock(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_data_account: Pubkey,
+    contract_data_account: Pubkey,
+    new_lock_duration: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 28];
+    instruction_data.extend(new_lock_duration.to_le_bytes().iter());
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new_readonly(payer.pubkey(), true),
+            AccountMeta::new(user_data_account, false),
+            AccountMeta::new_readonly(contract_data_account, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_claim_rewards(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    mint: Pubkey,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 13],
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(user_tkn_acct_pk, false),
+            AccountMeta::new(user_data_acct_pk, false),
+            AccountMeta::new(contract_tkn_acct_pk, false),
+            AccountMeta::new(contract_data_acct_pk, false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+/// Claims the rewards of a position in a pool keeping earned checkpoints
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_checkpoint_claim_rewards(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    mint: Pubkey,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 13],
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(user_tkn_acct_pk, false),
+            AccountMeta::new(user_data_acct_pk, false),
+            AccountMeta::new(contract_tkn_acct_pk, false),
+            AccountMeta::new(contract_data_acct_pk, false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new(earned_log_pda(&contract_data_acct_pk, &program_id), false),
+            AccountMeta::new_readonly(system_program::ID, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+/// Unstakes `amount` of a position in a pool keeping earned checkpoints
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_checkpoint_unstake(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    mint: Pubkey,
+    amount: u64,
+    decimals: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 2];
+    instruction_data.extend(decimals.to_le_bytes().iter());
+    instruction_data.push(0);
+    instruction_data.extend(amount.to_le_bytes().iter());
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(user_tkn_acct_pk, false),
+            AccountMeta::new(user_data_acct_pk, false),
+            AccountMeta::new(contract_tkn_acct_pk, false),
+            AccountMeta::new(contract_data_acct_pk, false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new(user_index_pda(&payer.pubkey(), &program_id), false),
+            AccountMeta::new(user_tkn_acct_pk, false),
+            AccountMeta::new(earned_log_pda(&contract_data_acct_pk, &program_id), false),
+            AccountMeta::new_readonly(system_program::ID, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_claim_split(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    mint: Pubkey,
+    claim_bps: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 29];
+    instruction_data.extend(claim_bps.to_le_bytes().iter());
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(user_tkn_acct_pk, false),
+            AccountMeta::new(user_data_acct_pk, false),
+            AccountMeta::new(contract_tkn_acct_pk, false),
+            AccountMeta::new(contract_data_acct_pk, false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+pub async fn perform_claim_and_stake(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 16],
+        vec![
+            AccountMeta::new_readonly(payer.pubkey(), true),
+            AccountMeta::new(user_data_acct_pk, false),
+            AccountMeta::new(contract_data_acct_pk, false),
+            AccountMeta::new_readonly(contract_tkn_acct_pk, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+/// Compounds the interest of a position in a pool keeping earned checkpoints
+pub async fn perform_checkpoint_claim_and_stake(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 16],
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(user_data_acct_pk, false),
+            AccountMeta::new(contract_data_acct_pk, false),
+            AccountMeta::new_readonly(contract_tkn_acct_pk, false),
+            AccountMeta::new(earned_log_pda(&contract_data_acct_pk, &program_id), false),
+            AccountMeta::new_readonly(system_program::ID, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_force_unstake(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    owner: Pubkey,
+    owner_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    mint: Pubkey,
+    decimals: u64,
+    include_interest: bool,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 14];
+    instruction_data.extend(decimals.to_le_bytes().iter());
+    instruction_data.push(include_interest as u8);
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new_readonly(payer.pubkey(), true),
+            AccountMeta::new(contract_data_acct_pk, false),
+            AccountMeta::new(user_data_acct_pk, false),
+            AccountMeta::new(owner, false),
+            AccountMeta::new(owner_tkn_acct_pk, false),
+            AccountMeta::new(contract_tkn_acct_pk, false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new(user_index_pda(&owner, &program_id), false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+/// Force unstakes a position in a pool keeping both a history and earned checkpoints
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_logged_force_unstake(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    owner: Pubkey,
+    owner_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    mint: Pubkey,
+    decimals: u64,
+    include_interest: bool,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 14];
+    instruction_data.extend(decimals.to_le_bytes().iter());
+    instruction_data.push(include_interest as u8);
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(contract_data_acct_pk, false),
+            AccountMeta::new(user_data_acct_pk, false),
+            AccountMeta::new(owner, false),
+            AccountMeta::new(owner_tkn_acct_pk, false),
+            AccountMeta::new(contract_tkn_acct_pk, false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new(user_index_pda(&owner, &program_id), false),
+            AccountMeta::new(history_log_pda(&contract_data_acct_pk, &program_id), false),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new(earned_log_pda(&contract_data_acct_pk, &program_id), false),
+            AccountMeta::new_readonly(system_program::ID, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_liquidate_expired(
+    program_id: Pubkey,
+    keeper: &Keypair,
+    contract_data_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    owner: Pubkey,
+    owner_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    mint: Pubkey,
+    decimals: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 24];
+    instruction_data.extend(decimals.to_le_bytes().iter());
+    perform_raw_instruction(
+        program_id,
+        keeper,
+        &instruction_data,
+        vec![
+            AccountMeta::new_readonly(keeper.pubkey(), true),
+            AccountMeta::new(contract_data_acct_pk, false),
+            AccountMeta::new(user_data_acct_pk, false),
+            AccountMeta::new(owner, false),
+            AccountMeta::new(owner_tkn_acct_pk, false),
+            AccountMeta::new(contract_tkn_acct_pk, false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new(user_index_pda(&owner, &program_id), false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+
+/// Interest formula used by the processor (apy decimals = 1, duration in seconds)
+pub fn expected_interest(apy: u64, amount: u64, duration: u64) -> u64 {
+    ((apy as u128 * amount as u128 * duration as u128) / 31536000000_u128) as u64
+}
+
+pub async fn perform_close_user_data(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_data_account: Pubkey,
+    contract_data_account: Pubkey,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut txn = Transaction::new_with_payer(
+        &[
+            Instruction::new_with_bytes(
+                program_id,
+                &[INSTRUCTION_VERSION, 4],
+                vec![
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new(user_data_account, false),
+                    AccountMeta::new_readonly(contract_data_account, false)
+                ]
+            )
+        ],
+        Some(&payer.pubkey())
+    );
+    txn.sign(&[&payer], recent_block_hash);
+    banks_client.process_transaction(txn).await
+}
+
+/// Moves the legacy user data account of `owner` to its address in the pool at
+/// `contract_data_account`, `admin` signs for a legacy account that does not record its pool
+pub async fn perform_migrate_user_data(
+    program_id: Pubkey,
+    owner: &Keypair,
+    contract_data_account: Pubkey,
+    admin: Option<&Keypair>,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut accounts = vec![
+        AccountMeta::new(owner.pubkey(), true),
+        AccountMeta::new(pda::legacy_user_data_pda(&owner.pubkey(), &program_id).0, false),
+        AccountMeta::new(user_data_pda(&owner.pubkey(), &contract_data_account, &program_id), false),
+        AccountMeta::new_readonly(contract_data_account, false),
+        AccountMeta::new_readonly(system_program::ID, false),
+        AccountMeta::new(user_index_pda(&owner.pubkey(), &program_id), false)
+    ];
+    let mut signers = vec![owner];
+    if let Some(admin) = admin {
+        accounts.push(AccountMeta::new_readonly(admin.pubkey(), true));
+        signers.push(admin);
+    }
+    let mut txn = Transaction::new_with_payer(
+        &[Instruction::new_with_bytes(program_id, &[INSTRUCTION_VERSION, 38], accounts)],
+        Some(&owner.pubkey())
+    );
+    txn.sign(&signers, recent_block_hash);
+    banks_client.process_transaction(txn).await
+}
+
+pub async fn perform_raw_instruction(
+    program_id: Pubkey,
+    payer: &Keypair,
+    instruction_data: &[u8],
+    accounts: Vec<AccountMeta>,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut txn = Transaction::new_with_payer(
+        &[Instruction::new_with_bytes(program_id, instruction_data, accounts)],
+        Some(&payer.pubkey())
+    );
+    txn.sign(&[&payer], recent_block_hash);
+    banks_client.process_transaction(txn).await
+}
+pub async fn set_up_native_token_account(
+    context: &mut ProgramTestContext,
+    owner: Pubkey
+) -> Pubkey {
+    let token_account = Keypair::new();
+    let rent = Rent::default();
+    let txn = Transaction::new_signed_with_payer(
+        &[
+            system_instruction::create_account(
+                &context.payer.pubkey(),
+                &token_account.pubkey(),
+                rent.minimum_balance(TokenAccount::LEN),
+                TokenAccount::LEN as u64,
+                &spl_token_2022::ID
+            ),
+            spl_token_2022::instruction::initialize_account(
+                &spl_token_2022::ID,
+                &token_account.pubkey(),
+                &spl_token_2022::native_mint::ID,
+                &owner
+            ).unwrap()
+        ],
+        Some(&context.payer.pubkey()),
+        &[&context.payer, &token_account],
+        context.last_blockhash
+    );
+    context.banks_client.process_transaction(txn).await.unwrap();
+    token_account.pubkey()
+}
+pub async fn set_up_native_pool(
+    context: &mut ProgramTestContext,
+    program_id: Pubkey,
+    config: &PoolConfig
+) -> Pool {
+    let payer_pubkey = context.payer.pubkey();
+    let mint = spl_token_2022::native_mint::ID;
+    let (data_account, _bump) = pda::contract_data_pda(&payer_pubkey, &mint, &program_id);
+    let txn = Transaction::new_signed_with_payer(
+        &[spl_token_2022::instruction::create_native_mint(&spl_token_2022::ID, &payer_pubkey).unwrap()],
+        Some(&payer_pubkey),
+        &[&context.payer],
+        context.last_blockhash
+    );
+    context.banks_client.process_transaction(txn).await.unwrap();
+    let token_account = set_up_native_token_account(context, payer_pubkey).await;
+    let txn = Transaction::new_signed_with_payer(
+        &[
+            system_instruction::transfer(&payer_pubkey, &token_account, config.reward_amount),
+            spl_token_2022::instruction::sync_native(&spl_token_2022::ID, &token_account).unwrap(),
+            init_instruction(config, payer_pubkey, token_account, mint, program_id, data_account)
+        ],
+        Some(&payer_pubkey),
+        &[&context.payer],
+        context.last_blockhash
+    );
+    context.banks_client.process_transaction(txn).await.unwrap();
+    Pool {
+        program_id,
+        mint,
+        data_account,
+        token_account,
+        decimals: spl_token_2022::native_mint::DECIMALS as u64
+    }
+}
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_update_token_account(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    new_contract_tkn_acct_pk: Pubkey,
+    mint: Pubkey,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash,
+    decimals: u64
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 5];
+    instruction_data.extend(decimals.to_le_bytes().iter());
+
+    let mut txn = Transaction::new_with_payer(
+        &[
+            Instruction::new_with_bytes(
+                program_id,
+                &instruction_data,
+                vec![
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new(contract_data_account, false),
+                    AccountMeta::new(contract_tkn_acct_pk, false),
+                    AccountMeta::new(new_contract_tkn_acct_pk, false),
+                    AccountMeta::new_readonly(mint, false),
+                    AccountMeta::new_readonly(spl_token_2022::ID, false)
+                ]
+            )
+        ],
+        Some(&payer.pubkey())
+    );
+    txn.sign(&[&payer], recent_block_hash);
+    banks_client.process_transaction(txn).await
+}
+/// Simulates a read only instruction and returns the return data it set
+pub async fn simulate_return_data(
+    program_id: Pubkey,
+    payer: &Keypair,
+    instruction_data: &[u8],
+    accounts: Vec<AccountMeta>,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Vec<u8> {
+    let mut txn = Transaction::new_with_payer(
+        &[Instruction::new_with_bytes(program_id, instruction_data, accounts)],
+        Some(&payer.pubkey())
+    );
+    txn.sign(&[&payer], recent_block_hash);
+    let simulation = banks_client.simulate_transaction(txn).await.unwrap();
+    simulation.result.unwrap().unwrap();
+    let return_data = simulation.simulation_details.unwrap().return_data.unwrap();
+    assert_eq!(return_data.program_id, program_id);
+    return_data.data
+}
+/// Simulates `SnapshotPosition` and decodes the snapshot from the return data
+pub async fn simulate_snapshot_position(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_data_account: Pubkey,
+    weight_by_lock: bool,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> PositionSnapshot {
+    let data = simulate_return_data(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 6, weight_by_lock as u8],
+        vec![AccountMeta::new_readonly(user_data_account, false)],
+        banks_client,
+        recent_block_hash
+    ).await;
+    PositionSnapshot::try_from_slice(&data).unwrap()
+}
+/// Simulates `GetUserIndex` and decodes the index from the return data
+pub async fn simulate_get_user_index(
+    program_id: Pubkey,
+    payer: &Keypair,
+    owner: Pubkey,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> UserIndex {
+    let data = simulate_return_data(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 9],
+        vec![
+            AccountMeta::new_readonly(owner, false),
+            AccountMeta::new_readonly(user_index_pda(&owner, &program_id), false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await;
+    UserIndex::try_from_slice(&data).unwrap()
+}
+/// Simulates `PreviewRewards` and decodes the preview from the return data
+#[allow(clippy::too_many_arguments)]
+pub async fn simulate_preview_rewards(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    stake_type: u8,
+    amount: u64,
+    duration: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> RewardPreview {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 10, stake_type];
+    instruction_data.extend(amount.to_le_bytes().iter());
+    instruction_data.extend(duration.to_le_bytes().iter());
+    let data = simulate_return_data(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![AccountMeta::new_readonly(contract_data_account, false)],
+        banks_client,
+        recent_block_hash
+    ).await;
+    RewardPreview::try_from_slice(&data).unwrap()
+}
+/// Simulates `GetPoolApr` and decodes the yield from the return data
+pub async fn simulate_get_pool_apr(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    mint: Pubkey,
+    stake_type: u8,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> PoolApr {
+    let data = simulate_return_data(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 17, stake_type],
+        vec![
+            AccountMeta::new_readonly(contract_data_account, false),
+            AccountMeta::new_readonly(mint, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await;
+    PoolApr::try_from_slice(&data).unwrap()
+}
+/// Simulates `GetLiabilities` and decodes the liabilities from the return data
+pub async fn simulate_get_liabilities(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> PoolLiabilities {
+    let data = simulate_return_data(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 18],
+        vec![AccountMeta::new_readonly(contract_data_account, false)],
+        banks_client,
+        recent_block_hash
+    ).await;
+    PoolLiabilities::try_from_slice(&data).unwrap()
+}
+/// Simulates `GetConfig` and decodes the config snapshot from the return data
+pub async fn simulate_get_config(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> ConfigSnapshot {
+    let data = simulate_return_data(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 32],
+        vec![AccountMeta::new_readonly(contract_data_account, false)],
+        banks_client,
+        recent_block_hash
+    ).await;
+    ConfigSnapshot::try_from_slice(&data).unwrap()
+}
+/// Simulates `GetClaimable` and decodes the claimable rewards from the return data
+pub async fn simulate_get_claimable(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_data_account: Pubkey,
+    contract_data_account: Pubkey,
+    interest_vault: Pubkey,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> ClaimableRewards {
+    let data = simulate_return_data(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 36],
+        vec![
+            AccountMeta::new_readonly(user_data_account, false),
+            AccountMeta::new_readonly(contract_data_account, false),
+            AccountMeta::new_readonly(interest_vault, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await;
+    ClaimableRewards::try_from_slice(&data).unwrap()
+}
+/// Simulates `GetCoverageRatio` and decodes the coverage from the return data
+pub async fn simulate_get_coverage_ratio(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    interest_vault: Pubkey,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> PoolCoverage {
+    let data = simulate_return_data(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 20],
+        vec![
+            AccountMeta::new_readonly(contract_data_account, false),
+            AccountMeta::new_readonly(interest_vault, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await;
+    PoolCoverage::try_from_slice(&data).unwrap()
+}
+/// Simulates `GetUnlockTs` and decodes the unlock timestamp from the return data
+pub async fn simulate_get_unlock_ts(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_data_account: Pubkey,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> u64 {
+    let data = simulate_return_data(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 23],
+        vec![AccountMeta::new_readonly(user_data_account, false)],
+        banks_client,
+        recent_block_hash
+    ).await;
+    u64::try_from_slice(&data).unwrap()
+}
+/// Simulates `ValidateStake` with the stake accounts of `payer` and decodes the outcome from the return data
+#[allow(clippy::too_many_arguments)]
+pub async fn simulate_validate_stake(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    mint: Pubkey,
+    stake_type: u8,
+    amount: u64,
+    lock_duration: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> StakeValidation {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 25, stake_type];
+    instruction_data.extend(amount.to_le_bytes().iter());
+    instruction_data.extend(lock_duration.to_le_bytes().iter());
+    let data = simulate_return_data(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(user_tkn_acct_pk, false),
+            AccountMeta::new(user_data_acct_pk, false),
+            AccountMeta::new(contract_tkn_acct_pk, false),
+            AccountMeta::new(contract_data_acct_pk, false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new_readonly(spl_token_2022::ID, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new(user_index_pda(&payer.pubkey(), &program_id), false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await;
+    StakeValidation::try_from_slice(&data).unwrap()
+}
+/// Simulates a full `UnStake` and decodes the receipt from the return data
+#[allow(clippy::too_many_arguments)]
+pub async fn simulate_unstake(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    mint: Pubkey,
+    decimals: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> UnstakeReceipt {
+    let instruction = unstake_instruction(
+        program_id,
+        payer.pubkey(),
+        user_tkn_acct_pk,
+        contract_tkn_acct_pk,
+        user_data_acct_pk,
+        contract_data_acct_pk,
+        mint,
+        None,
+        false,
+        decimals
+    );
+    let data = simulate_return_data(
+        program_id,
+        payer,
+        &instruction.data,
+        instruction.accounts,
+        banks_client,
+        recent_block_hash
+    ).await;
+    UnstakeReceipt::try_from_slice(&data).unwrap()
+}
+pub async fn simulate_read_history(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    start: u64,
+    count: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Vec<HistoryRecord> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 15];
+    instruction_data.extend(start.to_le_bytes().iter());
+    instruction_data.extend(count.to_le_bytes().iter());
+    let data = simulate_return_data(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new_readonly(contract_data_account, false),
+            AccountMeta::new_readonly(history_log_pda(&contract_data_account, &program_id), false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await;
+    Vec::<HistoryRecord>::try_from_slice(&data).unwrap()
+}
+/// Simulates `ReadEarnedCheckpoints` and decodes the page of checkpoints from the return data
+pub async fn simulate_read_earned_checkpoints(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    start: u64,
+    count: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Vec<EarnedCheckpoint> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 37];
+    instruction_data.extend(start.to_le_bytes().iter());
+    instruction_data.extend(count.to_le_bytes().iter());
+    let data = simulate_return_data(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new_readonly(contract_data_account, false),
+            AccountMeta::new_readonly(earned_log_pda(&contract_data_account, &program_id), false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await;
+    Vec::<EarnedCheckpoint>::try_from_slice(&data).unwrap()
+}
+pub async fn perform_set_paused(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    paused: bool,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut txn = Transaction::new_with_payer(
+        &[
+            Instruction::new_with_bytes(
+                program_id,
+                &[INSTRUCTION_VERSION, 7, paused as u8],
+                vec![
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new(contract_data_account, false)
+                ]
+            )
+        ],
+        Some(&payer.pubkey())
+    );
+    txn.sign(&[&payer], recent_block_hash);
+    banks_client.process_transaction(txn).await
+}
+pub async fn perform_set_rewards_paused(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    paused: bool,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &[INSTRUCTION_VERSION, 35, paused as u8],
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(contract_data_account, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+pub async fn perform_set_early_withdrawal_fee(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    early_withdrawal_fee: u64,
+    denominator: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 22];
+    instruction_data.extend(early_withdrawal_fee.to_le_bytes().iter());
+    instruction_data.extend(denominator.to_le_bytes().iter());
+    perform_raw_instruction(
+        program_id,
+        payer,
+        &instruction_data,
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(contract_data_account, false)
+        ],
+        banks_client,
+        recent_block_hash
+    ).await
+}
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_emergency_withdraw(
+    program_id: Pubkey,
+    payer: &Keypair,
+    contract_data_account: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    destination_tkn_acct_pk: Pubkey,
+    mint: Pubkey,
+    amount: u64,
+    banks_client: &mut BanksClient,
+    recent_block_hash: Hash,
+    decimals: u64
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 8];
+    instruction_data.extend(amount.to_le_bytes().iter());
+    instruction_data.extend(decimals.to_le_bytes().iter());
+
+    let mut txn = Transaction::new_with_payer(
+        &[
+            Instruction::new_with_bytes(
+                program_id,
+                &instruction_data,
+                vec![
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new(contract_data_account, false),
+                    AccountMeta::new(contract_tkn_acct_pk, false),
+                    AccountMeta::new(destination_tkn_acct_pk, false),
+                    AccountMeta::new_readonly(mint, false),
+                    AccountMeta::new_readonly(spl_token_2022::ID, false)
+                ]
+            )
+        ],
+        Some(&payer.pubkey())
+    );
+    txn.sign(&[&payer], recent_block_hash);
+    banks_client.process_transaction(txn).await
+}
+pub struct RewardToken {
+    pub mint: Pubkey,
+    pub vault: Pubkey
+}
+/// Sets up a pool paying interest in a separate, fee free reward token holding `reward_amount`
+pub async fn set_up_reward_pool(
+    context: &mut ProgramTestContext,
+    program_id: Pubkey,
+    config: &PoolConfig,
+    reward_amount: u64
+) -> (Pool, RewardToken) {
+    let rent = Rent::default();
+    let token_mint = Keypair::new();
+    let token_account = Keypair::new();
+    let reward_mint = Keypair::new();
+    let reward_vault = Keypair::new();
+    let payer_pubkey = context.payer.pubkey();
+    let (data_account, _bump) = pda::contract_data_pda(&payer_pubkey, &token_mint.pubkey(), &program_id);
+    set_up_mint(
+        &context.payer,
+        &token_mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        rent,
+        config.mint_decimals,
+        config.fee_basis_points,
+        config.max_fee
+    ).await;
+    set_up_mint(
+        &context.payer,
+        &reward_mint,
+        &mut context.banks_client,
+        context.last_blockhash,
+        rent,
+        config.mint_decimals,
+        0,
+        0
+    ).await;
+    set_up_token_account(
+        &context.payer,
+        &reward_vault,
+        None,
+        rent,
+        reward_mint.pubkey(),
+        reward_amount,
+        &mut context.banks_client,
+        context.last_blockhash
+    ).await;
+    let mut instructions = init_instructions(
+        config,
+        payer_pubkey,
+        token_account.pubkey(),
+        rent,
+        token_mint.pubkey(),
+        program_id,
+        data_account
+    );
+    let init_ix = instructions.last_mut().unwrap();
+    init_ix.accounts.push(AccountMeta::new_readonly(reward_mint.pubkey(), false));
+    init_ix.accounts.push(AccountMeta::new(reward_vault.pubkey(), false));
+    let transaction = Transaction::new_signed_with_payer(
+        &instructions,
+        Some(&payer_pubkey),
+        &[&context.payer, &token_account],
+        context.last_blockhash
+    );
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    (
+        Pool {
+            program_id,
+            mint: token_mint.pubkey(),
+            data_account,
+            token_account: token_account.pubkey(),
+            decimals: config.mint_decimals
+        },
+        RewardToken {
+            mint: reward_mint.pubkey(),
+            vault: reward_vault.pubkey()
+        }
+    )
+}
+#[allow(clippy::too_many_arguments)]
+pub async fn perform_unstake_with_reward(
+    program_id: Pubkey,
+    payer: &Keypair,
+    user_tkn_acct_pk: Pubkey,
+    contract_tkn_acct_pk: Pubkey,
+    user_data_acct_pk: Pubkey,
+    contract_data_acct_pk: Pubkey,
+    mint: Pubkey,
+    reward: &RewardToken,
+    user_reward_tkn_acct_pk: Pubkey,
+    banks_client: & mut BanksClient,
+    recent_block_hash: Hash,
+    decimals: u64
+) -> Result<(), BanksClientError> {
+    let mut instruction_data = vec![INSTRUCTION_VERSION, 2];
+    instruction_data.extend(decimals.to_le_bytes().iter());
+    let mut unstake_txn = Transaction::new_with_payer(
+        &[
+            Instruction::new_with_bytes(
+                program_id,
+                &instruction_data,
+                vec![
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new(user_tkn_acct_pk, false),
+                    AccountMeta::new(user_data_acct_pk, false),
+                    AccountMeta::new(contract_tkn_acct_pk, false),
+                    AccountMeta::new(contract_data_acct_pk, false),
+                    AccountMeta::new_readonly(mint, false),
+                    AccountMeta::new_readonly(spl_token_2022::ID, false),
+                    AccountMeta::new(user_index_pda(&payer.pubkey(), &program_id), false),
+                    AccountMeta::new(user_tkn_acct_pk, false),
+                    AccountMeta::new(reward.vault, false),
+                    AccountMeta::new_readonly(reward.mint, false),
+                    AccountMeta::new(user_reward_tkn_acct_pk, false)
+                ]
+            )
+        ],
+        Some(&payer.pubkey())
+    );
+    unstake_txn.sign(&[&payer], recent_block_hash);
+    banks_client.process_transaction(unstake_txn).await
+}